@@ -0,0 +1,120 @@
+/// How many of each top list to show.
+const TOP_N: usize = 5;
+
+#[derive(Debug, PartialEq)]
+pub struct BackendStats {
+    pub name: String,
+    pub requests: usize,
+    pub failed: usize,
+}
+
+/// A summary of the usage log.
+#[derive(Debug, PartialEq)]
+pub struct Report {
+    pub requests: usize,
+
+    /// Threads with the most requests, and how many.
+    pub busiest_threads: Vec<(serenity::model::id::ChannelId, usize)>,
+
+    /// Users whose requests used the most tokens, and how many.
+    pub top_users: Vec<(serenity::model::id::UserId, u64)>,
+
+    pub backends: Vec<BackendStats>,
+
+    /// Hours of the day (UTC) with the most requests, and how many.
+    pub peak_hours: Vec<(u32, usize)>,
+}
+
+/// The keys with the highest values, highest first. Ties go to the lower key, so the order is stable.
+fn top<K: Ord + Copy, V: Ord + Copy>(counts: &std::collections::BTreeMap<K, V>, n: usize) -> Vec<(K, V)> {
+    let mut entries = counts.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>();
+    entries.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    entries.truncate(n);
+    entries
+}
+
+impl Report {
+    pub fn new(records: &[crate::store::UsageRecord]) -> Self {
+        let mut threads = std::collections::BTreeMap::new();
+        let mut users = std::collections::BTreeMap::new();
+        let mut backends = std::collections::BTreeMap::<&str, (usize, usize)>::new();
+        let mut hours = std::collections::BTreeMap::new();
+        for record in records {
+            *threads.entry(record.thread_id).or_insert(0) += 1;
+            *users.entry(record.user_id).or_insert(0) += record.prompt_tokens as u64 + record.completion_tokens as u64;
+            let backend = backends.entry(&record.backend).or_default();
+            backend.0 += 1;
+            if record.failed {
+                backend.1 += 1;
+            }
+            *hours.entry(chrono::Timelike::hour(&record.at)).or_insert(0) += 1;
+        }
+
+        Self {
+            requests: records.len(),
+            busiest_threads: top(&threads, TOP_N),
+            top_users: top(&users, TOP_N),
+            backends: backends
+                .into_iter()
+                .map(|(name, (requests, failed))| BackendStats {
+                    name: name.to_string(),
+                    requests,
+                    failed,
+                })
+                .collect(),
+            peak_hours: top(&hours, 3),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(at: &str, thread_id: u64, user_id: u64, backend: &str, tokens: u32, failed: bool) -> crate::store::UsageRecord {
+        crate::store::UsageRecord {
+            at: chrono::DateTime::parse_from_rfc3339(at).unwrap().with_timezone(&chrono::Utc),
+            thread_id: serenity::model::id::ChannelId(thread_id),
+            user_id: serenity::model::id::UserId(user_id),
+            backend: backend.to_string(),
+            prompt_tokens: tokens,
+            completion_tokens: 0,
+            failed,
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let report = Report::new(&[
+            record("2023-03-01T12:00:00+00:00", 1, 10, "gpt4", 100, false),
+            record("2023-03-01T12:30:00+00:00", 2, 20, "gpt4", 500, true),
+            record("2023-03-01T13:00:00+00:00", 2, 10, "claude", 50, false),
+            record("2023-03-02T12:15:00+00:00", 2, 30, "gpt4", 10, false),
+        ]);
+        assert_eq!(
+            report,
+            Report {
+                requests: 4,
+                busiest_threads: vec![(serenity::model::id::ChannelId(2), 3), (serenity::model::id::ChannelId(1), 1)],
+                top_users: vec![
+                    (serenity::model::id::UserId(20), 500),
+                    (serenity::model::id::UserId(10), 150),
+                    (serenity::model::id::UserId(30), 10)
+                ],
+                backends: vec![
+                    BackendStats {
+                        name: "claude".to_string(),
+                        requests: 1,
+                        failed: 0,
+                    },
+                    BackendStats {
+                        name: "gpt4".to_string(),
+                        requests: 3,
+                        failed: 1,
+                    },
+                ],
+                peak_hours: vec![(12, 3), (13, 1)],
+            }
+        );
+    }
+}
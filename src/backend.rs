@@ -1,14 +1,16 @@
 pub mod cohere;
+pub mod cohere_chat;
 pub mod openai_chat;
+pub mod token_counter;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Hash)]
 pub enum Role {
     System,
     Assistant,
     User(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Message {
     pub role: Role,
     pub name: Option<String>,
@@ -16,6 +18,22 @@ pub struct Message {
     pub mentioned: bool,
 }
 
+/// Whether a name can go in an API message's name field as it is: only letters, digits, underscores and dashes, and at most 64 characters.
+pub fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Token counts for a request, as reported by the API rather than estimated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+/// Where a request's stream leaves its usage, if the backend reports it, usually once the response is finished. Each request has its own,
+/// so concurrent requests don't see each other's.
+pub type UsageSlot = std::sync::Arc<parking_lot::Mutex<Option<Usage>>>;
+
 #[derive(thiserror::Error, Debug)]
 pub enum RequestStreamError {
     #[error("content filter")]
@@ -28,27 +46,128 @@ pub enum RequestStreamError {
     Other(#[from] anyhow::Error),
 }
 
+const REASONING_PREFIX: &str = "||💭 ";
+const REASONING_SUFFIX: &str = "||\n\n";
+const REASONING_MAX_LENGTH: usize = 1500;
+
+/// Formats a model's reasoning as a spoiler to go before its response. A spoiler can't span several Discord messages, so the reasoning is
+/// cut short to fit in one.
+pub fn format_reasoning(reasoning: &str) -> String {
+    let reasoning = reasoning.trim().replace("||", "|\u{200b}|");
+    let mut excerpt = reasoning.chars().take(REASONING_MAX_LENGTH).collect::<String>();
+    if excerpt.len() < reasoning.len() {
+        excerpt.push('…');
+    }
+    format!("{}{}{}", REASONING_PREFIX, excerpt, REASONING_SUFFIX)
+}
+
+/// Strips reasoning added by format_reasoning from a response, so it isn't fed back to the model.
+pub fn strip_reasoning(content: &str) -> &str {
+    content
+        .strip_prefix(REASONING_PREFIX)
+        .and_then(|rest| rest.split_once(REASONING_SUFFIX))
+        .map(|(_, rest)| rest)
+        .unwrap_or(content)
+}
+
+/// How many tokens are left for the response once the input is counted, up to the `max_response_tokens` parameter if it's set. Fails
+/// rather than asking for nothing, since a request with no room for a response is bound to fail anyway.
+pub fn max_output_tokens(max_total_tokens: u32, input_tokens: usize, max_response_tokens: Option<u32>) -> Result<u32, anyhow::Error> {
+    let input_tokens = u32::try_from(input_tokens).unwrap_or(u32::MAX);
+    match max_total_tokens.saturating_sub(input_tokens) {
+        0 => Err(anyhow::format_err!(
+            "input of {} tokens leaves no room for a response in {} tokens",
+            input_tokens,
+            max_total_tokens
+        )),
+        n => Ok(max_response_tokens.map_or(n, |max_response_tokens| n.min(max_response_tokens))),
+    }
+}
+
+/// A request as it can be logged, with the messages replaced by how many there were. Prompts are only logged if `log_prompts` is set.
+pub fn loggable_request(req: &impl serde::Serialize) -> serde_json::Value {
+    let mut value = serde_json::to_value(req).unwrap_or_default();
+    if let Some(messages) = value.get_mut("messages") {
+        *messages = format!("[{} messages]", messages.as_array().map(|messages| messages.len()).unwrap_or(0)).into();
+    }
+    value
+}
+
 #[async_trait::async_trait]
 pub trait Backend {
     async fn request(
         &self,
         messages: &[Message],
         parameters: &toml::Value,
+        user: Option<&str>,
+        usage: UsageSlot,
     ) -> Result<std::pin::Pin<Box<dyn futures_core::stream::Stream<Item = Result<String, RequestStreamError>> + Send>>, anyhow::Error>;
+    fn validate_parameters(&self, parameters: &toml::Value) -> Result<(), anyhow::Error>;
+    fn supports_name_field(&self) -> bool;
     fn count_message_tokens(&self, message: &Message) -> usize;
     fn num_overhead_tokens(&self) -> usize;
+    fn max_total_tokens(&self) -> u32;
+
+    /// The configured model.
+    fn model(&self) -> &str;
+
+    /// Models the backend's server can serve, which may be chosen with the `model` parameter. None if the backend doesn't support
+    /// choosing a model.
+    async fn list_models(&self) -> Result<Option<Vec<String>>, anyhow::Error> {
+        Ok(None)
+    }
+
+    /// Several whole responses to the same prompt, for backends whose APIs can give more than one per request. None if the backend can't,
+    /// in which case each is requested on its own.
+    async fn request_choices(
+        &self,
+        _messages: &[Message],
+        _parameters: &toml::Value,
+        _user: Option<&str>,
+        _n: usize,
+    ) -> Result<Option<Vec<String>>, anyhow::Error> {
+        Ok(None)
+    }
+
+    /// Whether the model can see images, so describe_image works.
+    fn supports_images(&self) -> bool {
+        false
+    }
+
+    /// Describes the image at a URL as instructed by a prompt, for backends that support images.
+    async fn describe_image(&self, _prompt: &str, _image_url: &str) -> Result<String, anyhow::Error> {
+        Err(anyhow::format_err!("backend can't see images"))
+    }
+
+    /// Whether a prompt that ends with an assistant message is continued from there, rather than answered with a new message.
+    fn supports_prefill(&self) -> bool {
+        false
+    }
+
+    /// Whether the backend's server caches the starts of prompts it has seen, so prompts should be trimmed in a way that keeps their starts
+    /// the same from one request to the next.
+    fn caches_prompt_prefixes(&self) -> bool {
+        false
+    }
 }
 
 pub fn new_backend_from_config(typ: String, config: toml::Value) -> Result<Box<dyn Backend + Send + Sync>, anyhow::Error> {
     Ok(match typ.as_str() {
         "openai_chat" => {
-            let config = config.try_into()?;
+            let config: openai_chat::Config = config.try_into()?;
+            log::info!("openai_chat backend: {:?}", config);
             Box::new(openai_chat::Backend::new(&config)?)
         }
         "cohere" => {
-            let config = config.try_into()?;
+            let config: cohere::Config = config.try_into()?;
+            log::info!("cohere backend: {:?}", config);
             Box::new(cohere::Backend::new(&config)?)
         }
+        "cohere_chat" => {
+            let config: cohere_chat::Config = config.try_into()?;
+            log::info!("cohere_chat backend: {:?}", config);
+            Box::new(cohere_chat::Backend::new(&config)?)
+        }
         _ => {
             return Err(anyhow::format_err!("unknown backend type: {}", typ));
         }
@@ -2,14 +2,21 @@ pub struct Backend {
     client: reqwest::Client,
     model: String,
     max_total_tokens: u32,
-    tokenizer: tiktoken_rs::CoreBPE,
+    token_counter: super::token_counter::TokenCounter,
 }
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, Debug)]
 pub struct Config {
     model: String,
-    api_key: String,
+    api_key: crate::secret::Secret,
     max_total_tokens: u32,
+
+    #[serde(default)]
+    http: crate::http::Config,
+
+    /// Counts tokens with Cohere's tokenize endpoint rather than estimating them locally.
+    #[serde(default)]
+    remote_tokenizer: bool,
 }
 
 fn convert_message(message: &super::Message) -> String {
@@ -34,20 +41,30 @@ fn convert_message(message: &super::Message) -> String {
 
 impl Backend {
     pub fn new(config: &Config) -> Result<Self, anyhow::Error> {
+        let client = config
+            .http
+            .client_builder()?
+            .default_headers({
+                let mut headers = reqwest::header::HeaderMap::new();
+                headers.insert(reqwest::header::ACCEPT, "application/json".parse().unwrap());
+                headers.insert(reqwest::header::CONTENT_TYPE, "application/json".parse().unwrap());
+                headers.insert(reqwest::header::AUTHORIZATION, crate::secret::bearer_header(config.api_key.expose()));
+                headers
+            })
+            .build()?;
+        let bpe = tiktoken_rs::cl100k_base()?; // Not technically the right tokenizer, but close enough.
         Ok(Self {
-            client: reqwest::ClientBuilder::new()
-                .default_headers({
-                    let mut headers = reqwest::header::HeaderMap::new();
-                    headers.insert(reqwest::header::ACCEPT, "application/json".parse().unwrap());
-                    headers.insert(reqwest::header::CONTENT_TYPE, "application/json".parse().unwrap());
-                    headers.insert(reqwest::header::AUTHORIZATION, format!("Bearer {}", config.api_key).parse().unwrap());
-                    headers
-                })
-                .build()
-                .unwrap(),
+            token_counter: if config.remote_tokenizer {
+                super::token_counter::TokenCounter::remote(
+                    Box::new(super::token_counter::CohereTokenizer::new(client.clone(), config.model.clone())),
+                    bpe,
+                )
+            } else {
+                super::token_counter::TokenCounter::local(bpe)
+            },
+            client,
             model: config.model.clone(),
             max_total_tokens: config.max_total_tokens,
-            tokenizer: tiktoken_rs::cl100k_base()?, // Not technically the right tokenizer, but close enough.
         })
     }
 }
@@ -60,6 +77,8 @@ struct Parameters {
     pub p: Option<u32>,
     pub frequency_penalty: Option<f64>,
     pub presence_penalty: Option<f64>,
+    /// Caps the response below whatever room the prompt leaves for it.
+    pub max_response_tokens: Option<u32>,
 }
 
 #[derive(serde::Serialize)]
@@ -87,12 +106,24 @@ impl super::Backend for Backend {
         &self,
         messages: &[super::Message],
         parameters: &toml::Value,
+        _user: Option<&str>,
+        _usage: super::UsageSlot,
     ) -> Result<std::pin::Pin<Box<dyn futures_core::stream::Stream<Item = Result<String, crate::backend::RequestStreamError>> + Send>>, anyhow::Error>
     {
         let parameters: Parameters = parameters.clone().try_into()?;
 
+        // The reply picks up where a trailing assistant message leaves off.
+        let (history, prefill) = match messages.split_last() {
+            Some((last, rest)) if last.role == super::Role::Assistant => (rest, format!(" {}", last.content)),
+            _ => (messages, String::new()),
+        };
+
         let req = Request {
-            prompt: format!("{}assistant:", messages.iter().map(|m| convert_message(m)).collect::<Vec<_>>().join("")),
+            prompt: format!(
+                "{}assistant:{}",
+                history.iter().map(|m| convert_message(m)).collect::<Vec<_>>().join(""),
+                prefill
+            ),
             model: self.model.clone(),
             temperature: parameters.temperature,
             k: parameters.k,
@@ -101,9 +132,11 @@ impl super::Backend for Backend {
             presence_penalty: parameters.presence_penalty,
             end_sequences: Some(vec!["user:".to_string(), "User:".to_string()]),
             stream: true,
-            max_tokens: Some(
-                self.max_total_tokens - (self.num_overhead_tokens() + messages.iter().map(|m| self.count_message_tokens(m)).sum::<usize>()) as u32,
-            ),
+            max_tokens: Some(super::max_output_tokens(
+                self.max_total_tokens,
+                self.num_overhead_tokens() + messages.iter().map(|m| self.count_message_tokens(m)).sum::<usize>(),
+                parameters.max_response_tokens,
+            )?),
         };
 
         let mut resp = self
@@ -140,11 +173,32 @@ impl super::Backend for Backend {
         }))
     }
 
+    fn validate_parameters(&self, parameters: &toml::Value) -> Result<(), anyhow::Error> {
+        let _: Parameters = parameters.clone().try_into()?;
+        Ok(())
+    }
+
+    fn supports_name_field(&self) -> bool {
+        false
+    }
+
+    fn supports_prefill(&self) -> bool {
+        true
+    }
+
     fn count_message_tokens(&self, message: &super::Message) -> usize {
-        self.tokenizer.encode_ordinary(&convert_message(message)).len()
+        self.token_counter.count(&convert_message(message))
     }
 
     fn num_overhead_tokens(&self) -> usize {
-        self.tokenizer.encode_ordinary("assistant:").len()
+        self.token_counter.count("assistant:")
+    }
+
+    fn max_total_tokens(&self) -> u32 {
+        self.max_total_tokens
+    }
+
+    fn model(&self) -> &str {
+        &self.model
     }
 }
@@ -0,0 +1,294 @@
+const BASE_URL: &str = "https://api.cohere.com";
+
+pub struct Backend {
+    client: reqwest::Client,
+    model: String,
+    max_total_tokens: u32,
+    token_counter: super::token_counter::TokenCounter,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct Config {
+    model: String,
+    api_key: crate::secret::Secret,
+    max_total_tokens: u32,
+
+    #[serde(default)]
+    http: crate::http::Config,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Parameters {
+    pub temperature: Option<f64>,
+    pub k: Option<u32>,
+    pub p: Option<f64>,
+    pub frequency_penalty: Option<f64>,
+    pub presence_penalty: Option<f64>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub seed: Option<i64>,
+    /// Caps the response below whatever room the prompt leaves for it.
+    pub max_response_tokens: Option<u32>,
+}
+
+const MAX_STOP_SEQUENCES: usize = 5;
+
+#[derive(serde::Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct Message {
+    role: Role,
+    content: String,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct Request {
+    model: String,
+    messages: Vec<Message>,
+    stream: bool,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    k: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    p: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+}
+
+/// These are sent as floats, even though they're whole numbers.
+#[derive(serde::Deserialize, Debug)]
+struct Tokens {
+    input_tokens: f64,
+    output_tokens: f64,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct Usage {
+    tokens: Option<Tokens>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct Text {
+    text: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct DeltaMessage {
+    content: Option<Text>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+enum FinishReason {
+    Complete,
+    StopSequence,
+    MaxTokens,
+    ToolCall,
+    Error,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct Delta {
+    message: Option<DeltaMessage>,
+    finish_reason: Option<FinishReason>,
+    usage: Option<Usage>,
+}
+
+/// Only content-delta and message-end events matter here, the rest are skipped.
+#[derive(serde::Deserialize, Debug)]
+struct Event {
+    r#type: String,
+    delta: Option<Delta>,
+}
+
+fn convert_message(message: &super::Message) -> Message {
+    Message {
+        role: match message.role {
+            super::Role::System => Role::System,
+            super::Role::Assistant => Role::Assistant,
+            super::Role::User(..) => Role::User,
+        },
+        content: message.content.clone(),
+    }
+}
+
+impl Backend {
+    pub fn new(config: &Config) -> Result<Self, anyhow::Error> {
+        let client = config
+            .http
+            .client_builder()?
+            .default_headers({
+                let mut headers = reqwest::header::HeaderMap::new();
+                headers.insert(reqwest::header::ACCEPT, "application/json".parse().unwrap());
+                headers.insert(reqwest::header::CONTENT_TYPE, "application/json".parse().unwrap());
+                headers.insert(reqwest::header::AUTHORIZATION, crate::secret::bearer_header(config.api_key.expose()));
+                headers
+            })
+            .build()?;
+        Ok(Self {
+            token_counter: super::token_counter::TokenCounter::remote(
+                Box::new(super::token_counter::CohereTokenizer::new(client.clone(), config.model.clone())),
+                tiktoken_rs::cl100k_base()?,
+            ),
+            client,
+            model: config.model.clone(),
+            max_total_tokens: config.max_total_tokens,
+        })
+    }
+
+    fn check_parameters(&self, parameters: &Parameters) -> Result<(), anyhow::Error> {
+        if parameters
+            .stop_sequences
+            .as_ref()
+            .map(|stop| stop.len() > MAX_STOP_SEQUENCES)
+            .unwrap_or(false)
+        {
+            return Err(anyhow::format_err!("at most {} stop sequences are allowed", MAX_STOP_SEQUENCES));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl super::Backend for Backend {
+    async fn request(
+        &self,
+        messages: &[super::Message],
+        parameters: &toml::Value,
+        _user: Option<&str>,
+        usage: super::UsageSlot,
+    ) -> Result<std::pin::Pin<Box<dyn futures_core::stream::Stream<Item = Result<String, crate::backend::RequestStreamError>> + Send>>, anyhow::Error>
+    {
+        let parameters: Parameters = parameters.clone().try_into()?;
+        self.check_parameters(&parameters)?;
+
+        let req = Request {
+            model: self.model.clone(),
+            messages: messages.iter().map(convert_message).collect(),
+            stream: true,
+            max_tokens: Some(super::max_output_tokens(
+                self.max_total_tokens,
+                self.num_overhead_tokens() + messages.iter().map(|m| self.count_message_tokens(m)).sum::<usize>(),
+                parameters.max_response_tokens,
+            )?),
+            temperature: parameters.temperature,
+            k: parameters.k,
+            p: parameters.p,
+            frequency_penalty: parameters.frequency_penalty,
+            presence_penalty: parameters.presence_penalty,
+            stop_sequences: parameters.stop_sequences,
+            seed: parameters.seed,
+        };
+        log::info!("cohere request: {}", super::loggable_request(&req));
+
+        let mut resp = self
+            .client
+            .post(format!("{}/v2/chat", BASE_URL))
+            .json(&req)
+            .send()
+            .await
+            .map_err(|e| e.without_url())?;
+
+        if let Err(e) = resp.error_for_status_ref() {
+            let body = resp.text().await.map_err(|e| e.without_url())?;
+            return Err(anyhow::format_err!("{:?} ({:?})", e.without_url(), body));
+        }
+
+        let mut parser = crate::sse::Parser::default();
+
+        Ok(Box::pin(async_stream::try_stream! {
+            'outer: while let Some(c) = resp.chunk().await.map_err(|e| crate::backend::RequestStreamError::Other(e.without_url().into()))? {
+                for payload in parser.push(&c) {
+                    let event = serde_json::from_slice::<Event>(&payload).map_err(|e| crate::backend::RequestStreamError::Other(e.into()))?;
+                    let delta = if let Some(delta) = event.delta {
+                        delta
+                    } else {
+                        continue;
+                    };
+
+                    match event.r#type.as_str() {
+                        "content-delta" => {
+                            if let Some(text) = delta.message.and_then(|message| message.content).and_then(|content| content.text) {
+                                yield text;
+                            }
+                        }
+                        "message-end" => {
+                            if let Some(tokens) = delta.usage.and_then(|usage| usage.tokens) {
+                                *usage.lock() = Some(super::Usage {
+                                    prompt_tokens: tokens.input_tokens as u32,
+                                    completion_tokens: tokens.output_tokens as u32,
+                                });
+                            }
+
+                            match delta.finish_reason {
+                                Some(FinishReason::MaxTokens) => {
+                                    Err(crate::backend::RequestStreamError::Length)?;
+                                }
+                                Some(FinishReason::ToolCall) => {
+                                    Err(crate::backend::RequestStreamError::Other(anyhow::anyhow!("unexpected tool call")))?;
+                                }
+                                Some(FinishReason::Error) => {
+                                    Err(crate::backend::RequestStreamError::Other(anyhow::anyhow!("generation failed")))?;
+                                }
+                                Some(FinishReason::Complete) | Some(FinishReason::StopSequence) | Some(FinishReason::Other) | None => {}
+                            }
+                            break 'outer;
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }))
+    }
+
+    fn validate_parameters(&self, parameters: &toml::Value) -> Result<(), anyhow::Error> {
+        let parameters: Parameters = parameters.clone().try_into()?;
+        self.check_parameters(&parameters)
+    }
+
+    fn supports_name_field(&self) -> bool {
+        false
+    }
+
+    fn count_message_tokens(&self, message: &super::Message) -> usize {
+        3 + // every message is wrapped in <|START_OF_TURN_TOKEN|><|{role}_TOKEN|>...<|END_OF_TURN_TOKEN|>
+        self.token_counter.count(&message.content)
+    }
+
+    fn num_overhead_tokens(&self) -> usize {
+        3 // every reply is primed with <BOS_TOKEN>...<|START_OF_TURN_TOKEN|><|CHATBOT_TOKEN|>
+    }
+
+    fn max_total_tokens(&self) -> u32 {
+        self.max_total_tokens
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+}
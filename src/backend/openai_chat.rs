@@ -5,13 +5,70 @@ pub struct Backend {
     model: String,
     max_total_tokens: u32,
     bpe: tiktoken_rs::CoreBPE,
+    reasoning: bool,
+    stream: bool,
+    show_reasoning: bool,
+    prompt_caching: bool,
+    vision: bool,
+    user_salt: Option<String>,
 }
 
-#[derive(serde::Deserialize)]
+fn convert_usage(usage: &crate::openai::chat::completions::Usage) -> super::Usage {
+    super::Usage {
+        prompt_tokens: usage.prompt_tokens,
+        completion_tokens: usage.completion_tokens,
+    }
+}
+
+const fn stream_default() -> bool {
+    true
+}
+
+#[derive(serde::Deserialize, Debug)]
 pub struct Config {
-    api_key: String,
+    api_key: crate::secret::Secret,
     model: String,
     max_total_tokens: u32,
+
+    #[serde(default)]
+    organization: Option<String>,
+
+    #[serde(default)]
+    project: Option<String>,
+
+    /// If set, the requesting user's Discord ID is sent hashed with this salt, so abuse can be attributed to them without revealing who
+    /// they are.
+    #[serde(default)]
+    user_salt: Option<crate::secret::Secret>,
+
+    /// For OpenAI-compatible servers, e.g. `http://localhost:8000/v1`.
+    #[serde(default)]
+    base_url: Option<String>,
+
+    /// Whether the model is a reasoning model, which rejects sampling parameters and counts reasoning tokens against its output. If not
+    /// set, this is guessed from the model name.
+    #[serde(default)]
+    reasoning: Option<bool>,
+
+    /// Some reasoning models can't stream, so their responses have to be sent all at once instead.
+    #[serde(default = "stream_default")]
+    stream: bool,
+
+    /// Posts the model's reasoning in a spoiler before its response, if the server sends it.
+    #[serde(default)]
+    show_reasoning: bool,
+
+    /// Whether the server caches prompt prefixes, as OpenAI does automatically. If not set, this is assumed for OpenAI but not for other
+    /// servers.
+    #[serde(default)]
+    prompt_caching: Option<bool>,
+
+    /// Whether the model can see images.
+    #[serde(default)]
+    vision: bool,
+
+    #[serde(default)]
+    http: crate::http::Config,
 }
 
 #[derive(serde::Deserialize)]
@@ -21,24 +78,197 @@ struct Parameters {
     pub top_p: Option<f64>,
     pub frequency_penalty: Option<f64>,
     pub presence_penalty: Option<f64>,
+    pub stop: Option<Vec<String>>,
+    pub seed: Option<i64>,
+    /// Biases by token string rather than by token ID, so they don't need to be looked up by hand.
+    pub logit_bias: Option<std::collections::HashMap<String, f64>>,
+    /// Sent as-is, for server-specific parameters that aren't supported here otherwise.
+    pub extra: Option<toml::Table>,
+    /// For reasoning models only: `low`, `medium` or `high`.
+    pub reasoning_effort: Option<String>,
+    /// Overrides the configured model, for servers that serve several.
+    pub model: Option<String>,
+    /// Caps the response below whatever room the prompt leaves for it.
+    pub max_response_tokens: Option<u32>,
 }
 
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// Image descriptions are meant to be short, so they don't need the room a whole response would get.
+const MAX_DESCRIPTION_TOKENS: u32 = 100;
+
+const REASONING_MODEL_PREFIXES: &[&str] = &["o1", "o3", "o4"];
+
+const REASONING_EFFORTS: &[&str] = &["low", "medium", "high"];
+
+/// Fields that are set either by the backend itself or by the parameters above, so they can't be set by extra parameters as well.
+const RESERVED_EXTRA_KEYS: &[&str] = &[
+    "model",
+    "messages",
+    "stream",
+    "n",
+    "max_tokens",
+    "max_completion_tokens",
+    "user",
+    "temperature",
+    "top_p",
+    "frequency_penalty",
+    "presence_penalty",
+    "stop",
+    "seed",
+    "logit_bias",
+    "reasoning_effort",
+    "stream_options",
+];
+
 impl Backend {
     pub fn new(config: &Config) -> Result<Self, anyhow::Error> {
         Ok(Self {
-            client: crate::openai::Client::new(config.api_key.clone()),
+            client: crate::openai::Client::new(
+                config.api_key.expose(),
+                config.organization.as_deref(),
+                config.project.as_deref(),
+                config
+                    .base_url
+                    .as_deref()
+                    .unwrap_or(crate::openai::DEFAULT_BASE_URL)
+                    .trim_end_matches('/'),
+                config.http.client_builder()?,
+            )?,
             model: config.model.clone(),
             max_total_tokens: config.max_total_tokens,
-            bpe: tiktoken_rs::get_bpe_from_model(&config.model)?,
+            // Models on OpenAI-compatible servers won't be known to tiktoken, but this is close enough for them.
+            bpe: tiktoken_rs::get_bpe_from_model(&config.model).or_else(|_| tiktoken_rs::cl100k_base())?,
+            reasoning: config
+                .reasoning
+                .unwrap_or_else(|| REASONING_MODEL_PREFIXES.iter().any(|prefix| config.model.starts_with(prefix))),
+            stream: config.stream,
+            show_reasoning: config.show_reasoning,
+            prompt_caching: config.prompt_caching.unwrap_or(config.base_url.is_none()),
+            vision: config.vision,
+            user_salt: config.user_salt.as_ref().map(|user_salt| user_salt.expose().to_string()),
         })
     }
+
+    /// Maps logit biases by token string onto token IDs. Each string must be exactly one token.
+    fn logit_bias(&self, logit_bias: &std::collections::HashMap<String, f64>) -> Result<std::collections::HashMap<u32, f64>, anyhow::Error> {
+        logit_bias
+            .iter()
+            .map(|(token, &bias)| {
+                if !(-100.0..=100.0).contains(&bias) {
+                    return Err(anyhow::format_err!("logit bias for {:?} must be between -100 and 100", token));
+                }
+                match self.bpe.encode_ordinary(token).as_slice() {
+                    &[id] => Ok((id as u32, bias)),
+                    _ => Err(anyhow::format_err!("{:?} is not a single token", token)),
+                }
+            })
+            .collect()
+    }
+
+    fn check_parameters(&self, parameters: &Parameters) -> Result<(), anyhow::Error> {
+        if parameters.stop.as_ref().map(|stop| stop.len() > MAX_STOP_SEQUENCES).unwrap_or(false) {
+            return Err(anyhow::format_err!("at most {} stop sequences are allowed", MAX_STOP_SEQUENCES));
+        }
+        if let Some(logit_bias) = parameters.logit_bias.as_ref() {
+            self.logit_bias(logit_bias)?;
+        }
+        if let Some(key) = parameters
+            .extra
+            .iter()
+            .flat_map(|extra| extra.keys())
+            .find(|key| RESERVED_EXTRA_KEYS.contains(&key.as_str()))
+        {
+            return Err(anyhow::format_err!("{} can't be set in extra", key));
+        }
+        if let Some(reasoning_effort) = parameters.reasoning_effort.as_ref() {
+            if !self.reasoning {
+                return Err(anyhow::format_err!("reasoning_effort is only supported by reasoning models"));
+            }
+            if !REASONING_EFFORTS.contains(&reasoning_effort.as_str()) {
+                return Err(anyhow::format_err!("reasoning_effort must be one of {}", REASONING_EFFORTS.join(", ")));
+            }
+        }
+        Ok(())
+    }
+
+    fn create_request(
+        &self,
+        messages: &[super::Message],
+        parameters: &toml::Value,
+        user: Option<&str>,
+    ) -> Result<crate::openai::chat::completions::CreateRequest, anyhow::Error> {
+        let parameters: Parameters = parameters.clone().try_into()?;
+        self.check_parameters(&parameters)?;
+
+        let mut req = crate::openai::chat::completions::CreateRequest::new(
+            parameters.model.clone().unwrap_or_else(|| self.model.clone()),
+            messages.iter().map(|m| convert_message(m, self.reasoning)).collect(),
+        );
+        if self.reasoning {
+            // Reasoning models reject these outright, so leave them out rather than failing presets shared with other models.
+            if parameters.temperature.is_some()
+                || parameters.top_p.is_some()
+                || parameters.frequency_penalty.is_some()
+                || parameters.presence_penalty.is_some()
+                || parameters.logit_bias.is_some()
+            {
+                log::warn!("ignoring sampling parameters unsupported by reasoning model {}", self.model);
+            }
+        } else {
+            req.temperature = parameters.temperature;
+            req.top_p = parameters.top_p;
+            req.frequency_penalty = parameters.frequency_penalty;
+            req.presence_penalty = parameters.presence_penalty;
+            req.logit_bias = parameters.logit_bias.as_ref().map(|logit_bias| self.logit_bias(logit_bias)).transpose()?;
+        }
+        req.stop = parameters.stop;
+        req.seed = parameters.seed;
+        req.reasoning_effort = parameters.reasoning_effort;
+        req.user = self.user_salt.as_deref().zip(user).map(|(salt, user)| hash_user(salt, user));
+        if let Some(extra) = parameters.extra.as_ref() {
+            req.extra = serde_json::to_value(extra)?.as_object().cloned().unwrap_or_default();
+        }
+        let max_tokens = super::max_output_tokens(
+            self.max_total_tokens,
+            super::Backend::num_overhead_tokens(self) + messages.iter().map(|m| super::Backend::count_message_tokens(self, m)).sum::<usize>(),
+            parameters.max_response_tokens,
+        )?;
+        if self.reasoning {
+            req.max_completion_tokens = Some(max_tokens);
+        } else {
+            req.max_tokens = Some(max_tokens);
+        }
+        Ok(req)
+    }
+}
+
+fn hash_user(salt: &str, user: &str) -> String {
+    use sha1::Digest;
+    format!("{:x}", sha1::Sha1::digest(format!("{}:{}", salt, user)))
 }
 
-fn convert_message(m: &super::Message) -> crate::openai::chat::completions::Message {
+/// Names may only contain letters, digits, underscores and dashes, and be at most 64 characters long.
+fn sanitize_name(name: &str) -> Option<String> {
+    let name = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' })
+        .take(64)
+        .collect::<String>();
+    if name.trim_matches('_').is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Reasoning models take developer messages in place of system messages.
+fn convert_message(m: &super::Message, reasoning: bool) -> crate::openai::chat::completions::Message {
     crate::openai::chat::completions::Message {
-        content: m.content.clone(),
-        name: m.name.clone(),
+        content: crate::openai::chat::completions::Content::Text(m.content.clone()),
+        name: m.name.as_deref().and_then(sanitize_name),
         role: match m.role {
+            super::Role::System if reasoning => crate::openai::chat::completions::Role::Developer,
             super::Role::System => crate::openai::chat::completions::Role::System,
             super::Role::Assistant => crate::openai::chat::completions::Role::Assistant,
             super::Role::User(..) => crate::openai::chat::completions::Role::User,
@@ -52,28 +282,66 @@ impl super::Backend for Backend {
         &self,
         messages: &[super::Message],
         parameters: &toml::Value,
+        user: Option<&str>,
+        usage: super::UsageSlot,
     ) -> Result<std::pin::Pin<Box<dyn futures_core::stream::Stream<Item = Result<String, crate::backend::RequestStreamError>> + Send>>, anyhow::Error>
     {
-        let parameters: Parameters = parameters.clone().try_into()?;
+        let mut req = self.create_request(messages, parameters, user)?;
+        if self.stream {
+            req.stream_options = Some(crate::openai::chat::completions::StreamOptions { include_usage: true });
+        }
+        log::info!("openai request: {}", super::loggable_request(&req));
 
-        let req = {
-            let mut req = crate::openai::chat::completions::CreateRequest::new(self.model.clone(), messages.iter().map(convert_message).collect());
-            req.temperature = parameters.temperature;
-            req.top_p = parameters.top_p;
-            req.frequency_penalty = parameters.frequency_penalty;
-            req.presence_penalty = parameters.presence_penalty;
-            req.max_tokens = Some(
-                self.max_total_tokens - (self.num_overhead_tokens() + messages.iter().map(|m| self.count_message_tokens(m)).sum::<usize>()) as u32,
-            );
-            req
-        };
-        log::info!("openai request: {:?}", req);
+        if !self.stream {
+            let resp = self.client.create_chat_completion_unstreamed(&req).await?;
+            *usage.lock() = resp.usage.as_ref().map(convert_usage);
+            let choice = resp
+                .choices
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow::format_err!("response has no choices"))?;
+            let show_reasoning = self.show_reasoning;
+            return Ok(Box::pin(async_stream::try_stream! {
+                let mut content = String::new();
+                if let Some(reasoning) = choice.message.reasoning_content.as_ref().filter(|_| show_reasoning) {
+                    content.push_str(&super::format_reasoning(reasoning));
+                }
+                content.push_str(choice.message.content.as_deref().unwrap_or(""));
+                if !content.is_empty() {
+                    yield content;
+                }
+
+                match choice.finish_reason {
+                    Some(crate::openai::chat::completions::FinishReason::Length) => {
+                        Err(crate::backend::RequestStreamError::Length)?;
+                    },
+                    Some(crate::openai::chat::completions::FinishReason::ContentFilter) => {
+                        Err(crate::backend::RequestStreamError::ContentFilter)?;
+                    },
+                    Some(crate::openai::chat::completions::FinishReason::FunctionCall) => {
+                        Err(crate::backend::RequestStreamError::Other(anyhow::anyhow!("unexpected function_call")))?;
+                    },
+                    Some(crate::openai::chat::completions::FinishReason::Stop) | None => {},
+                }
+            }));
+        }
 
+        let show_reasoning = self.show_reasoning;
         let mut stream = Box::pin(self.client.create_chat_completion(&req).await?);
         Ok(Box::pin(async_stream::try_stream! {
+            let mut reasoning = String::new();
             while let Some(chunk) = stream.next().await {
                 let chunk = chunk.map_err(|e| crate::backend::RequestStreamError::Other(e.into()))?;
-                let choice = &chunk.choices[0];
+                if let Some(chunk_usage) = chunk.usage.as_ref() {
+                    *usage.lock() = Some(convert_usage(chunk_usage));
+                }
+
+                // The usage chunk comes after the one with the finish reason, so keep reading until the end of the stream.
+                let choice = if let Some(choice) = chunk.choices.first() {
+                    choice
+                } else {
+                    continue;
+                };
 
                 if let Some(finish_reason) = &choice.finish_reason {
                     match *finish_reason {
@@ -86,23 +354,101 @@ impl super::Backend for Backend {
                         crate::openai::chat::completions::FinishReason::FunctionCall => {
                             Err(crate::backend::RequestStreamError::Other(anyhow::anyhow!("unexpected function_call")))?;
                         },
-                        crate::openai::chat::completions::FinishReason::Stop => {
-                            break;
-                        },
+                        crate::openai::chat::completions::FinishReason::Stop => {},
                     }
                 }
 
                 let delta = &choice.delta;
+                if let Some(reasoning_content) = delta.reasoning_content.as_ref() {
+                    reasoning.push_str(reasoning_content);
+                }
                 let content = if let Some(content) = delta.content.as_ref() {
                     content
                 } else {
                     continue;
                 };
+                if show_reasoning && !reasoning.is_empty() {
+                    yield super::format_reasoning(&std::mem::take(&mut reasoning));
+                }
                 yield content.clone();
             }
         }))
     }
 
+    async fn request_choices(
+        &self,
+        messages: &[super::Message],
+        parameters: &toml::Value,
+        user: Option<&str>,
+        n: usize,
+    ) -> Result<Option<Vec<String>>, anyhow::Error> {
+        let mut req = self.create_request(messages, parameters, user)?;
+        req.n = Some(u32::try_from(n)?);
+        log::info!("openai request: {}", super::loggable_request(&req));
+
+        let resp = self.client.create_chat_completion_unstreamed(&req).await?;
+        Ok(Some(
+            resp.choices
+                .into_iter()
+                .map(|choice| {
+                    let mut content = String::new();
+                    if let Some(reasoning) = choice.message.reasoning_content.as_ref().filter(|_| self.show_reasoning) {
+                        content.push_str(&super::format_reasoning(reasoning));
+                    }
+                    content.push_str(choice.message.content.as_deref().unwrap_or(""));
+                    content
+                })
+                .collect(),
+        ))
+    }
+
+    fn supports_images(&self) -> bool {
+        self.vision
+    }
+
+    async fn describe_image(&self, prompt: &str, image_url: &str) -> Result<String, anyhow::Error> {
+        let mut req = crate::openai::chat::completions::CreateRequest::new(
+            self.model.clone(),
+            vec![crate::openai::chat::completions::Message {
+                role: crate::openai::chat::completions::Role::User,
+                name: None,
+                content: crate::openai::chat::completions::Content::Parts(vec![
+                    crate::openai::chat::completions::ContentPart::Text { text: prompt.to_string() },
+                    crate::openai::chat::completions::ContentPart::ImageUrl {
+                        image_url: crate::openai::chat::completions::ImageUrl { url: image_url.to_string() },
+                    },
+                ]),
+            }],
+        );
+        // Reasoning models spend some of their output on reasoning, so they're left to decide how much they need.
+        if !self.reasoning {
+            req.max_tokens = Some(MAX_DESCRIPTION_TOKENS);
+        }
+        log::info!("openai request: {}", super::loggable_request(&req));
+
+        let resp = self.client.create_chat_completion_unstreamed(&req).await?;
+        resp.choices
+            .into_iter()
+            .next()
+            .and_then(|choice| choice.message.content)
+            .map(|content| content.trim().to_string())
+            .filter(|content| !content.is_empty())
+            .ok_or_else(|| anyhow::format_err!("no description"))
+    }
+
+    fn validate_parameters(&self, parameters: &toml::Value) -> Result<(), anyhow::Error> {
+        let parameters: Parameters = parameters.clone().try_into()?;
+        self.check_parameters(&parameters)
+    }
+
+    fn supports_name_field(&self) -> bool {
+        true
+    }
+
+    fn caches_prompt_prefixes(&self) -> bool {
+        self.prompt_caching
+    }
+
     fn count_message_tokens(&self, message: &super::Message) -> usize {
         let (tokens_per_message, tokens_per_name) = if self.model.starts_with("gpt-3.5") {
             (
@@ -125,8 +471,8 @@ impl super::Backend for Backend {
                 .unwrap(),
             )
             .len() + // role
-            if let Some(name) = &message.name { // name
-                self.bpe.encode_ordinary(name).len().wrapping_add_signed(tokens_per_name)
+            if let Some(name) = message.name.as_deref().and_then(sanitize_name) { // name
+                self.bpe.encode_ordinary(&name).len().wrapping_add_signed(tokens_per_name)
             } else {
                 0
             } +
@@ -136,4 +482,95 @@ impl super::Backend for Backend {
     fn num_overhead_tokens(&self) -> usize {
         3 // every reply is primed with <|start|>assistant<|message|>
     }
+
+    fn max_total_tokens(&self) -> u32 {
+        self.max_total_tokens
+    }
+
+    fn model(&self) -> &str {
+        &self.model
+    }
+
+    async fn list_models(&self) -> Result<Option<Vec<String>>, anyhow::Error> {
+        let e = match self.client.list_models().await {
+            Ok(resp) => {
+                return Ok(Some(resp.data.into_iter().map(|model| model.id).collect()));
+            }
+            Err(e) => e,
+        };
+
+        // Older versions of Ollama don't list models in their OpenAI-compatible API.
+        if let Ok(resp) = self.client.list_ollama_models().await {
+            return Ok(Some(resp.models.into_iter().map(|model| model.name).collect()));
+        }
+        Err(e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!(sanitize_name("alice"), Some("alice".to_string()));
+        assert_eq!(sanitize_name("Alice Smith!"), Some("Alice_Smith_".to_string()));
+        assert_eq!(sanitize_name("👻"), None);
+        assert_eq!(sanitize_name(&"a".repeat(100)).map(|name| name.len()), Some(64));
+    }
+
+    #[test]
+    fn test_logit_bias() {
+        let backend = Backend::new(&Config {
+            api_key: "".into(),
+            model: "gpt-3.5-turbo".to_string(),
+            max_total_tokens: 4096,
+            organization: None,
+            project: None,
+            user_salt: None,
+            base_url: None,
+            reasoning: None,
+            stream: true,
+            show_reasoning: false,
+            prompt_caching: None,
+            vision: false,
+            http: Default::default(),
+        })
+        .unwrap();
+        let hello = backend.bpe.encode_ordinary(" hello")[0] as u32;
+        assert_eq!(
+            backend
+                .logit_bias(&std::collections::HashMap::from([(" hello".to_string(), -100.0)]))
+                .unwrap(),
+            std::collections::HashMap::from([(hello, -100.0)])
+        );
+        assert!(backend
+            .logit_bias(&std::collections::HashMap::from([(" supercalifragilistic".to_string(), -100.0)]))
+            .is_err());
+        assert!(backend
+            .logit_bias(&std::collections::HashMap::from([(" hello".to_string(), 200.0)]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_hash_user() {
+        assert_eq!(hash_user("salt", "1234"), hash_user("salt", "1234"));
+        assert_ne!(hash_user("salt", "1234"), hash_user("pepper", "1234"));
+        assert!(!hash_user("salt", "1234").contains("1234"));
+    }
+
+    #[test]
+    fn test_convert_message_reasoning() {
+        let m = super::super::Message {
+            role: super::super::Role::System,
+            name: None,
+            content: "be nice".to_string(),
+            mentioned: false,
+        };
+        assert!(matches!(convert_message(&m, false).role, crate::openai::chat::completions::Role::System));
+        assert!(matches!(
+            convert_message(&m, true).role,
+            crate::openai::chat::completions::Role::Developer
+        ));
+    }
 }
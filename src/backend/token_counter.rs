@@ -0,0 +1,136 @@
+const CACHE_SIZE: usize = 4096;
+
+/// Counts tokens with a provider's API, for models whose tokenizers aren't available locally.
+#[async_trait::async_trait]
+pub trait RemoteTokenizer {
+    async fn count_tokens(&self, text: &str) -> Result<usize, anyhow::Error>;
+}
+
+/// Counts tokens either locally with a tiktoken vocabulary, or with a remote tokenizer. Remote counts are cached, and if the remote
+/// tokenizer fails, the local vocabulary is used as an estimate instead.
+pub struct TokenCounter {
+    bpe: tiktoken_rs::CoreBPE,
+    remote: Option<Box<dyn RemoteTokenizer + Send + Sync>>,
+    cache: parking_lot::Mutex<lru::LruCache<String, usize>>,
+}
+
+impl TokenCounter {
+    pub fn local(bpe: tiktoken_rs::CoreBPE) -> Self {
+        Self {
+            bpe,
+            remote: None,
+            cache: parking_lot::Mutex::new(lru::LruCache::new(std::num::NonZeroUsize::new(CACHE_SIZE).unwrap())),
+        }
+    }
+
+    pub fn remote(remote: Box<dyn RemoteTokenizer + Send + Sync>, fallback: tiktoken_rs::CoreBPE) -> Self {
+        Self {
+            remote: Some(remote),
+            ..Self::local(fallback)
+        }
+    }
+
+    /// Token counting isn't async, so this blocks the calling thread while the remote tokenizer is asked about uncached text.
+    pub fn count(&self, text: &str) -> usize {
+        let remote = if let Some(remote) = self.remote.as_ref() {
+            remote
+        } else {
+            return self.bpe.encode_ordinary(text).len();
+        };
+
+        if let Some(&count) = self.cache.lock().get(text) {
+            return count;
+        }
+
+        let count = match tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(remote.count_tokens(text))) {
+            Ok(count) => count,
+            Err(e) => {
+                log::warn!("falling back to estimated token count: {}", e);
+                return self.bpe.encode_ordinary(text).len();
+            }
+        };
+        self.cache.lock().put(text.to_string(), count);
+        count
+    }
+}
+
+#[derive(serde::Serialize)]
+struct CohereTokenizeRequest<'a> {
+    text: &'a str,
+    model: &'a str,
+}
+
+#[derive(serde::Deserialize)]
+struct CohereTokenizeResponse {
+    tokens: Vec<i64>,
+}
+
+/// Cohere's `/v1/tokenize` endpoint.
+pub struct CohereTokenizer {
+    client: reqwest::Client,
+    model: String,
+}
+
+impl CohereTokenizer {
+    /// The client should already be set up to authenticate with Cohere.
+    pub fn new(client: reqwest::Client, model: String) -> Self {
+        Self { client, model }
+    }
+}
+
+#[async_trait::async_trait]
+impl RemoteTokenizer for CohereTokenizer {
+    async fn count_tokens(&self, text: &str) -> Result<usize, anyhow::Error> {
+        let resp = self
+            .client
+            .post("https://api.cohere.com/v1/tokenize")
+            .json(&CohereTokenizeRequest { text, model: &self.model })
+            .send()
+            .await
+            .map_err(|e| e.without_url())?;
+
+        if let Err(e) = resp.error_for_status_ref() {
+            let body = resp.text().await.map_err(|e| e.without_url())?;
+            return Err(anyhow::format_err!("{:?} ({:?})", e.without_url(), body));
+        }
+
+        Ok(resp.json::<CohereTokenizeResponse>().await.map_err(|e| e.without_url())?.tokens.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeTokenizer {
+        calls: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl RemoteTokenizer for FakeTokenizer {
+        async fn count_tokens(&self, text: &str) -> Result<usize, anyhow::Error> {
+            self.calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            if text.is_empty() {
+                return Err(anyhow::anyhow!("empty"));
+            }
+            Ok(text.split_whitespace().count())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_remote_cached() {
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = TokenCounter::remote(Box::new(FakeTokenizer { calls: calls.clone() }), tiktoken_rs::cl100k_base().unwrap());
+        assert_eq!(counter.count("one two three"), 3);
+        assert_eq!(counter.count("one two three"), 3);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        // Failures fall back to the local vocabulary and aren't cached.
+        assert_eq!(counter.count(""), 0);
+        assert_eq!(counter.count(""), 0);
+        assert_eq!(calls.load(std::sync::atomic::Ordering::Relaxed), 3);
+
+        // Token counting is also done on the blocking thread pool.
+        assert_eq!(tokio::task::spawn_blocking(move || counter.count("four five")).await.unwrap(), 2);
+    }
+}
@@ -0,0 +1,166 @@
+/// How much a backend may spend, going by the tokens it reports using. Prices are per million tokens, in whatever currency the limits
+/// are in. Days and months start at midnight in `utc_offset` from the config file.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub prompt_price: f64,
+    pub completion_price: f64,
+
+    #[serde(default)]
+    pub daily_limit: Option<f64>,
+
+    #[serde(default)]
+    pub monthly_limit: Option<f64>,
+
+    /// New requests go to this backend instead once a limit is reached, until the day or month is over. If not set, the backend just
+    /// carries on, and the limits only raise alerts.
+    #[serde(default)]
+    pub fallback_backend: Option<String>,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if !(self.prompt_price >= 0.0 && self.completion_price >= 0.0) {
+            return Err(anyhow::format_err!("prices can't be negative"));
+        }
+        if !self.daily_limit.into_iter().chain(self.monthly_limit).all(|limit| limit > 0.0) {
+            return Err(anyhow::format_err!("limits must be positive"));
+        }
+        Ok(())
+    }
+
+    pub fn cost(&self, prompt_tokens: u32, completion_tokens: u32) -> f64 {
+        (prompt_tokens as f64 * self.prompt_price + completion_tokens as f64 * self.completion_price) / 1_000_000.0
+    }
+
+    fn limit(&self, period: Period) -> Option<f64> {
+        match period {
+            Period::Day => self.daily_limit,
+            Period::Month => self.monthly_limit,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Period {
+    Day,
+    Month,
+}
+
+impl Period {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Period::Day => "daily",
+            Period::Month => "monthly",
+        }
+    }
+}
+
+/// A backend's spending so far in one period.
+#[derive(Default)]
+struct Spend {
+    spent: f64,
+    over: bool,
+}
+
+/// Keeps track of what each backend has spent today and this month.
+pub struct Tracker {
+    today: chrono::NaiveDate,
+    spend: std::collections::HashMap<(String, Period), Spend>,
+}
+
+impl Tracker {
+    pub fn new(now: chrono::DateTime<chrono::FixedOffset>) -> Self {
+        Self {
+            today: now.date_naive(),
+            spend: std::collections::HashMap::new(),
+        }
+    }
+
+    /// Starts over for whichever periods have ended since the last time.
+    fn roll_over(&mut self, now: chrono::DateTime<chrono::FixedOffset>) {
+        let today = now.date_naive();
+        if today == self.today {
+            return;
+        }
+        let new_month = chrono::Datelike::month(&today) != chrono::Datelike::month(&self.today)
+            || chrono::Datelike::year(&today) != chrono::Datelike::year(&self.today);
+        self.spend.retain(|(_, period), _| *period == Period::Month && !new_month);
+        self.today = today;
+    }
+
+    /// Adds to what a backend has spent, returning the periods whose limits that takes it over, with what it's spent in each.
+    pub fn record(&mut self, backend: &str, config: &Config, cost: f64, now: chrono::DateTime<chrono::FixedOffset>) -> Vec<(Period, f64)> {
+        self.roll_over(now);
+        let mut crossed = vec![];
+        for period in [Period::Day, Period::Month] {
+            let spend = self.spend.entry((backend.to_string(), period)).or_default();
+            spend.spent += cost;
+            if !spend.over && config.limit(period).map(|limit| spend.spent >= limit).unwrap_or(false) {
+                spend.over = true;
+                crossed.push((period, spend.spent));
+            }
+        }
+        crossed
+    }
+
+    /// Whether a backend has reached any of its limits for the current periods.
+    pub fn is_over(&mut self, backend: &str, now: chrono::DateTime<chrono::FixedOffset>) -> bool {
+        self.roll_over(now);
+        [Period::Day, Period::Month]
+            .into_iter()
+            .any(|period| self.spend.get(&(backend.to_string(), period)).map(|spend| spend.over).unwrap_or(false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> chrono::DateTime<chrono::FixedOffset> {
+        chrono::DateTime::parse_from_rfc3339(s).unwrap()
+    }
+
+    #[test]
+    fn test_tracker_daily() {
+        let config = Config {
+            prompt_price: 1.0,
+            completion_price: 2.0,
+            daily_limit: Some(1.0),
+            monthly_limit: None,
+            fallback_backend: None,
+        };
+        assert_eq!(config.cost(500_000, 100_000), 0.7);
+
+        let mut tracker = Tracker::new(at("2024-05-01T10:00:00+09:00"));
+        assert!(tracker.record("gpt-4", &config, 0.7, at("2024-05-01T10:00:00+09:00")).is_empty());
+        assert_eq!(
+            tracker.record("gpt-4", &config, 0.7, at("2024-05-01T11:00:00+09:00")),
+            vec![(Period::Day, 1.4)]
+        );
+        assert!(tracker.is_over("gpt-4", at("2024-05-01T23:59:00+09:00")));
+        assert!(!tracker.is_over("gpt-3.5", at("2024-05-01T23:59:00+09:00")));
+        // Crossing it again the same day doesn't alert again.
+        assert!(tracker.record("gpt-4", &config, 0.7, at("2024-05-01T23:59:00+09:00")).is_empty());
+        assert!(!tracker.is_over("gpt-4", at("2024-05-02T00:00:00+09:00")));
+    }
+
+    #[test]
+    fn test_tracker_monthly() {
+        let config = Config {
+            prompt_price: 1.0,
+            completion_price: 1.0,
+            daily_limit: None,
+            monthly_limit: Some(2.0),
+            fallback_backend: Some("gpt-3.5".to_string()),
+        };
+        let mut tracker = Tracker::new(at("2024-05-30T12:00:00+00:00"));
+        assert!(tracker.record("gpt-4", &config, 1.5, at("2024-05-30T12:00:00+00:00")).is_empty());
+        assert_eq!(
+            tracker.record("gpt-4", &config, 1.5, at("2024-05-31T12:00:00+00:00")),
+            vec![(Period::Month, 3.0)]
+        );
+        assert!(tracker.is_over("gpt-4", at("2024-05-31T23:00:00+00:00")));
+        assert!(!tracker.is_over("gpt-4", at("2024-06-01T00:00:00+00:00")));
+    }
+}
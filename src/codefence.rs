@@ -0,0 +1,210 @@
+/// Code lines in a row it takes for them to be fenced. Fewer than this are more likely to be prose that happens to look like code.
+const MIN_LINES: usize = 3;
+
+const CODE_PREFIXES: &[&str] = &[
+    "def ",
+    "fn ",
+    "pub ",
+    "impl ",
+    "function ",
+    "class ",
+    "import ",
+    "from ",
+    "#include",
+    "let ",
+    "const ",
+    "var ",
+    "return ",
+    "if (",
+    "for (",
+    "while (",
+    "use ",
+    "package ",
+    "public ",
+    "private ",
+    "func ",
+    "print(",
+    "console.",
+    "//",
+    "#!/",
+    "elif ",
+    "else:",
+    "try:",
+    "except",
+];
+
+/// Markers of each language, the most of which wins.
+const LANGUAGE_MARKERS: &[(&str, &[&str])] = &[
+    ("rust", &["fn ", "let mut ", "pub ", "impl ", "-> ", "::", "use std"]),
+    ("python", &["def ", "import ", "self.", "elif ", "print(", "None", "):"]),
+    ("javascript", &["function ", "const ", "=> ", "console.log", "===", "let "]),
+    ("cpp", &["#include", "std::", "cout", "int main("]),
+    ("java", &["public class", "System.out", "public static", "private "]),
+    ("go", &["func ", ":= ", "package ", "fmt."]),
+];
+
+fn is_list_item(trimmed: &str) -> bool {
+    trimmed.starts_with("- ")
+        || trimmed.starts_with("* ")
+        || trimmed
+            .split_once(". ")
+            .map(|(n, _)| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+            .unwrap_or(false)
+}
+
+fn looks_like_code(line: &str) -> bool {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') && !trimmed.starts_with("#include") && !trimmed.starts_with("#!") || is_list_item(trimmed) {
+        return false;
+    }
+    line.starts_with("    ")
+        || line.starts_with('\t')
+        || trimmed.ends_with(';')
+        || trimmed.ends_with('{')
+        || trimmed.starts_with('}')
+        || CODE_PREFIXES.iter().any(|prefix| trimmed.starts_with(prefix))
+}
+
+pub fn guess_language(code: &str) -> &'static str {
+    LANGUAGE_MARKERS
+        .iter()
+        .map(|(language, markers)| (*language, markers.iter().map(|marker| code.matches(marker).count()).sum::<usize>()))
+        .filter(|(_, score)| *score > 0)
+        .max_by_key(|(_, score)| *score)
+        .map(|(language, _)| language)
+        .unwrap_or("")
+}
+
+/// Wraps runs of lines that look like code in fenced code blocks, a line at a time so it can be used on a stream. Code that's already
+/// fenced is left alone.
+#[derive(Default)]
+pub struct Fencer {
+    pending: String,
+    run: Vec<String>,
+    in_fence: bool,
+}
+
+impl Fencer {
+    /// Pushes a piece of the stream, returning whatever is ready. Lines that might be code are held back until it's clear whether they
+    /// are.
+    pub fn push(&mut self, s: &str) -> String {
+        self.pending.push_str(s);
+        let mut out = String::new();
+        while let Some(i) = self.pending.find('\n') {
+            let line = self.pending[..i].to_string();
+            self.pending.drain(..=i);
+            out.push_str(&self.line(line));
+        }
+        out
+    }
+
+    pub fn flush(mut self) -> String {
+        let unterminated = !self.pending.is_empty();
+        let mut out = if unterminated {
+            let line = std::mem::take(&mut self.pending);
+            self.line(line)
+        } else {
+            String::new()
+        };
+        out.push_str(&self.end_run());
+        if unterminated && out.ends_with('\n') {
+            out.pop();
+        }
+        out
+    }
+
+    fn line(&mut self, line: String) -> String {
+        if self.in_fence {
+            if line.trim_start().starts_with("```") {
+                self.in_fence = false;
+            }
+            return format!("{}\n", line);
+        }
+
+        if line.trim_start().starts_with("```") {
+            self.in_fence = true;
+            let mut out = self.end_run();
+            out.push_str(&line);
+            out.push('\n');
+            return out;
+        }
+
+        // Blank lines can be part of a run of code, as long as more code follows them.
+        if looks_like_code(&line) || (!self.run.is_empty() && line.trim().is_empty()) {
+            self.run.push(line);
+            return String::new();
+        }
+
+        let mut out = self.end_run();
+        out.push_str(&line);
+        out.push('\n');
+        out
+    }
+
+    fn end_run(&mut self) -> String {
+        let mut run = std::mem::take(&mut self.run);
+        let num_trailing_blank = run.iter().rev().take_while(|line| line.trim().is_empty()).count();
+        let trailing_blank = run.split_off(run.len() - num_trailing_blank);
+
+        let mut out = String::new();
+        if run.len() >= MIN_LINES {
+            let code = run.join("\n");
+            out.push_str(&format!("```{}\n{}\n```\n", guess_language(&code), code));
+        } else {
+            for line in run {
+                out.push_str(&line);
+                out.push('\n');
+            }
+        }
+        for line in trailing_blank {
+            out.push_str(&line);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fence() {
+        let mut fencer = Fencer::default();
+        let mut out = fencer.push("Try this:\ndef add(a, b):\n    return a + b\n\nprint(add(1, 2))\n\nThat prints 3.");
+        out.push_str(&fencer.flush());
+        assert_eq!(
+            out,
+            "Try this:\n```python\ndef add(a, b):\n    return a + b\n\nprint(add(1, 2))\n```\n\nThat prints 3."
+        );
+    }
+
+    #[test]
+    fn test_fence_streamed() {
+        let mut fencer = Fencer::default();
+        let mut out = String::new();
+        for piece in ["fn main() {\n    let mut x", " = 1;\n    x += 1;\n}", "\nDone!"] {
+            out.push_str(&fencer.push(piece));
+        }
+        out.push_str(&fencer.flush());
+        assert_eq!(out, "```rust\nfn main() {\n    let mut x = 1;\n    x += 1;\n}\n```\nDone!");
+    }
+
+    #[test]
+    fn test_already_fenced() {
+        let s = "```js\nconst a = 1;\nconst b = 2;\nconsole.log(a + b);\n```\n";
+        let mut fencer = Fencer::default();
+        let mut out = fencer.push(s);
+        out.push_str(&fencer.flush());
+        assert_eq!(out, s);
+    }
+
+    #[test]
+    fn test_prose_untouched() {
+        let s = "Here's a list:\n- one;\n- two;\n- three;\nAnd a short one-liner: let x = 1;";
+        let mut fencer = Fencer::default();
+        let mut out = fencer.push(s);
+        out.push_str(&fencer.flush());
+        assert_eq!(out, s);
+    }
+}
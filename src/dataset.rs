@@ -0,0 +1,227 @@
+const fn scrub_default() -> bool {
+    true
+}
+
+static MENTION_REGEX: once_cell::sync::Lazy<regex::Regex> = once_cell::sync::Lazy::new(|| regex::Regex::new(r"<(?P<kind>@!?|@&|#)\d+>").unwrap());
+
+static SNOWFLAKE_REGEX: once_cell::sync::Lazy<regex::Regex> = once_cell::sync::Lazy::new(|| regex::Regex::new(r"\b\d{17,20}\b").unwrap());
+
+/// How rated replies are turned into a dataset with `peebot export-dataset`.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Replaces the names of the people in each conversation with `user_1`, `user_2` and so on, wherever they appear.
+    #[serde(default = "scrub_default")]
+    pub scrub_names: bool,
+
+    /// Replaces Discord mentions and anything that looks like a Discord ID with placeholders.
+    #[serde(default = "scrub_default")]
+    pub scrub_ids: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            scrub_names: scrub_default(),
+            scrub_ids: scrub_default(),
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum Format {
+    /// OpenAI's chat fine-tuning format: one `{"messages": [...]}` object per line.
+    OpenaiJsonl,
+}
+
+#[derive(serde::Serialize, Debug, PartialEq)]
+struct OpenaiMessage {
+    role: &'static str,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+
+    content: String,
+}
+
+#[derive(serde::Serialize, Debug, PartialEq)]
+struct OpenaiExample {
+    messages: Vec<OpenaiMessage>,
+}
+
+/// The replies people rated well on balance, with their prompts. Only each person's latest rating of a reply counts, and a reply that was
+/// regenerated is judged by the ratings of its latest version.
+pub fn preferred(ratings: &[crate::store::Rating]) -> Vec<&crate::store::Rating> {
+    let mut latest = std::collections::BTreeMap::new();
+    for rating in ratings {
+        latest.insert((rating.message_id, rating.user_id), rating);
+    }
+
+    let mut replies = std::collections::BTreeMap::<_, (&crate::store::Rating, i64)>::new();
+    for rating in latest.into_values() {
+        let (reply, score) = replies.entry(rating.message_id).or_insert((rating, 0));
+        if rating.response != reply.response {
+            if rating.at < reply.at {
+                continue;
+            }
+            *reply = rating;
+            *score = 0;
+        }
+        *score += if rating.good { 1 } else { -1 };
+    }
+
+    replies.into_values().filter(|(_, score)| *score > 0).map(|(rating, _)| rating).collect()
+}
+
+struct Scrubber<'a> {
+    config: &'a Config,
+    names: Vec<(String, String)>,
+}
+
+impl<'a> Scrubber<'a> {
+    fn new(config: &'a Config, prompt: &[crate::transcript::Message]) -> Self {
+        let mut names: Vec<String> = vec![];
+        for name in prompt.iter().filter_map(|m| m.name.as_ref()) {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        let mut names = names
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| (name, format!("user_{}", i + 1)))
+            .collect::<Vec<_>>();
+        // Longer names go first, so a name that's part of another doesn't break it up.
+        names.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+        Self { config, names }
+    }
+
+    fn name(&self, name: &str) -> String {
+        if !self.config.scrub_names {
+            return name.to_string();
+        }
+        self.names
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, pseudonym)| pseudonym.clone())
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    fn content(&self, s: &str) -> String {
+        let mut s = s.to_string();
+        if self.config.scrub_ids {
+            s = MENTION_REGEX
+                .replace_all(&s, |c: &regex::Captures| match &c["kind"] {
+                    "#" => "#channel",
+                    "@&" => "@role",
+                    _ => "@someone",
+                })
+                .into_owned();
+            s = SNOWFLAKE_REGEX.replace_all(&s, "[id]").into_owned();
+        }
+        if self.config.scrub_names {
+            for (name, pseudonym) in self.names.iter() {
+                s = s.replace(name.as_str(), pseudonym);
+            }
+        }
+        s
+    }
+}
+
+fn to_openai_example(config: &Config, rating: &crate::store::Rating) -> OpenaiExample {
+    let scrubber = Scrubber::new(config, &rating.prompt);
+    let mut messages = rating
+        .prompt
+        .iter()
+        .map(|m| OpenaiMessage {
+            role: match m.role {
+                crate::transcript::Role::System => "system",
+                crate::transcript::Role::Assistant => "assistant",
+                crate::transcript::Role::User => "user",
+            },
+            name: m.name.as_deref().map(|name| scrubber.name(name)),
+            content: scrubber.content(&m.content),
+        })
+        .collect::<Vec<_>>();
+    messages.push(OpenaiMessage {
+        role: "assistant",
+        name: None,
+        content: scrubber.content(&rating.response),
+    });
+    OpenaiExample { messages }
+}
+
+/// Writes the preferred replies out as a dataset, returning how many there were.
+pub fn write(config: &Config, format: Format, ratings: &[crate::store::Rating], w: &mut impl std::io::Write) -> Result<usize, anyhow::Error> {
+    let preferred = preferred(ratings);
+    for rating in preferred.iter() {
+        match format {
+            Format::OpenaiJsonl => {
+                serde_json::to_writer(&mut *w, &to_openai_example(config, rating))?;
+                w.write_all(b"\n")?;
+            }
+        }
+    }
+    Ok(preferred.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(at: &str, message_id: u64, user_id: u64, good: bool, response: &str) -> crate::store::Rating {
+        crate::store::Rating {
+            at: chrono::DateTime::parse_from_rfc3339(at).unwrap().with_timezone(&chrono::Utc),
+            thread_id: serenity::model::id::ChannelId(1),
+            message_id: serenity::model::id::MessageId(message_id),
+            user_id: serenity::model::id::UserId(user_id),
+            backend: "gpt4".to_string(),
+            good,
+            prompt: vec![
+                crate::transcript::Message {
+                    role: crate::transcript::Role::System,
+                    name: None,
+                    content: "You are peebot.".to_string(),
+                },
+                crate::transcript::Message {
+                    role: crate::transcript::Role::User,
+                    name: Some("alice".to_string()),
+                    content: "hi, I'm alice, and this is <@123456789012345678>".to_string(),
+                },
+            ],
+            response: response.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_preferred() {
+        let ratings = [
+            // Changed their mind.
+            rating("2023-03-01T12:00:00+00:00", 1, 10, false, "hello"),
+            rating("2023-03-01T12:01:00+00:00", 1, 10, true, "hello"),
+            // Outvoted.
+            rating("2023-03-01T12:00:00+00:00", 2, 10, true, "hey"),
+            rating("2023-03-01T12:00:00+00:00", 2, 20, false, "hey"),
+            rating("2023-03-01T12:00:00+00:00", 2, 30, false, "hey"),
+            // Regenerated after a bad rating.
+            rating("2023-03-01T12:00:00+00:00", 3, 10, false, "yo"),
+            rating("2023-03-01T12:05:00+00:00", 3, 20, true, "hello there"),
+        ];
+        assert_eq!(
+            preferred(&ratings).iter().map(|r| r.response.as_str()).collect::<Vec<_>>(),
+            vec!["hello", "hello there"]
+        );
+    }
+
+    #[test]
+    fn test_to_openai_example() {
+        assert_eq!(
+            serde_json::to_string(&to_openai_example(
+                &Config::default(),
+                &rating("2023-03-01T12:00:00+00:00", 1, 10, true, "hi alice")
+            ))
+            .unwrap(),
+            r#"{"messages":[{"role":"system","content":"You are peebot."},{"role":"user","name":"user_1","content":"hi, I'm user_1, and this is @someone"},{"role":"assistant","content":"hi user_1"}]}"#
+        );
+    }
+}
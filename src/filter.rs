@@ -0,0 +1,191 @@
+fn redaction_default() -> String {
+    "[redacted]".to_string()
+}
+
+const fn escape_mentions_default() -> bool {
+    true
+}
+
+static MENTION_REGEX: once_cell::sync::Lazy<regex::Regex> =
+    once_cell::sync::Lazy::new(|| regex::Regex::new(r"@(?P<mass>everyone|here)\b|<@&(?P<role_id>\d+)>").unwrap());
+
+static INVITE_REGEX: once_cell::sync::Lazy<regex::Regex> =
+    once_cell::sync::Lazy::new(|| regex::Regex::new(r"(?i)\b(?:https?://)?(?:www\.)?(?:discord\.gg|discord(?:app)?\.com/invite)/[\w-]+").unwrap());
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Prompts matching any of these patterns are refused.
+    #[serde(default)]
+    pub input: Vec<String>,
+
+    /// Anything in a response matching any of these patterns is redacted.
+    #[serde(default)]
+    pub output: Vec<String>,
+
+    #[serde(default = "redaction_default")]
+    pub redaction: String,
+
+    /// Escapes `@everyone`, `@here` and role mentions in responses, so they show as plain text. Responses never ping anyone but the person
+    /// being replied to either way.
+    #[serde(default = "escape_mentions_default")]
+    pub escape_mentions: bool,
+
+    /// Redacts Discord invite links in responses.
+    #[serde(default)]
+    pub redact_invites: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            input: vec![],
+            output: vec![],
+            redaction: redaction_default(),
+            escape_mentions: escape_mentions_default(),
+            redact_invites: false,
+        }
+    }
+}
+
+pub struct Filters {
+    input: regex::RegexSet,
+    output: Vec<regex::Regex>,
+    redaction: String,
+    escape_mentions: bool,
+    redact_invites: bool,
+}
+
+impl Filters {
+    pub fn new(config: &Config) -> Result<Self, regex::Error> {
+        Ok(Self {
+            input: regex::RegexSet::new(&config.input)?,
+            output: config
+                .output
+                .iter()
+                .map(|pattern| regex::Regex::new(pattern))
+                .collect::<Result<Vec<_>, _>>()?,
+            redaction: config.redaction.clone(),
+            escape_mentions: config.escape_mentions,
+            redact_invites: config.redact_invites,
+        })
+    }
+
+    pub fn matches_input(&self, s: &str) -> bool {
+        self.input.is_match(s)
+    }
+
+    pub fn redact(&self, s: &str) -> String {
+        let mut s = s.to_string();
+        for re in self.output.iter() {
+            if re.is_match(&s) {
+                s = re.replace_all(&s, regex::NoExpand(&self.redaction)).into_owned();
+            }
+        }
+        if self.redact_invites {
+            s = INVITE_REGEX.replace_all(&s, regex::NoExpand(&self.redaction)).into_owned();
+        }
+        if self.escape_mentions {
+            // A zero-width space keeps Discord from treating them as mentions.
+            s = MENTION_REGEX
+                .replace_all(&s, |c: &regex::Captures| {
+                    if let Some(mass) = c.name("mass") {
+                        format!("@\u{200b}{}", mass.as_str())
+                    } else {
+                        format!("<@\u{200b}&{}>", &c["role_id"])
+                    }
+                })
+                .into_owned();
+        }
+        s
+    }
+
+    pub fn redactor(&self) -> Redactor<'_> {
+        Redactor {
+            filters: self,
+            pending: String::new(),
+        }
+    }
+}
+
+/// Redacts streamed output a line at a time, so matches that straddle pieces of the stream are still caught.
+pub struct Redactor<'a> {
+    filters: &'a Filters,
+    pending: String,
+}
+
+impl<'a> Redactor<'a> {
+    /// Pushes a piece of the stream, returning whatever complete lines are ready.
+    pub fn push(&mut self, s: &str) -> String {
+        self.pending.push_str(s);
+        let i = if let Some(i) = self.pending.rfind('\n') {
+            i + 1
+        } else {
+            return String::new();
+        };
+        let rest = self.pending.split_off(i);
+        self.filters.redact(&std::mem::replace(&mut self.pending, rest))
+    }
+
+    pub fn flush(self) -> String {
+        self.filters.redact(&self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters() -> Filters {
+        Filters::new(&Config {
+            input: vec![r"(?i)discord\.gg/".to_string()],
+            output: vec![r"\d{3}-\d{4}".to_string()],
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_matches_input() {
+        let filters = filters();
+        assert!(filters.matches_input("join us at Discord.gg/abc"));
+        assert!(!filters.matches_input("join us"));
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(filters().redact("call 555-1234 or 555-5678"), "call [redacted] or [redacted]");
+    }
+
+    #[test]
+    fn test_escape_mentions() {
+        assert_eq!(
+            filters().redact("hey @everyone and @here, ask <@&1234>, not <@5678>"),
+            "hey @\u{200b}everyone and @\u{200b}here, ask <@\u{200b}&1234>, not <@5678>"
+        );
+    }
+
+    #[test]
+    fn test_redact_invites() {
+        let filters = Filters::new(&Config {
+            redact_invites: true,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(
+            filters.redact("join discord.gg/abc-123 or https://discord.com/invite/xyz"),
+            "join [redacted] or [redacted]"
+        );
+        assert_eq!(filters.redact("see discord.com/channels/1/2"), "see discord.com/channels/1/2");
+    }
+
+    #[test]
+    fn test_redactor() {
+        let filters = filters();
+        let mut redactor = filters.redactor();
+        assert_eq!(redactor.push("call 55"), "");
+        assert_eq!(redactor.push("5-12"), "");
+        assert_eq!(redactor.push("34\nor "), "call [redacted]\n");
+        assert_eq!(redactor.flush(), "or ");
+    }
+}
@@ -0,0 +1,86 @@
+static GIF_LINK_REGEX: once_cell::sync::Lazy<regex::Regex> =
+    once_cell::sync::Lazy::new(|| regex::Regex::new(r"https?://(?:www\.|media\d*\.)?(?:tenor\.com|giphy\.com)/[^\s<>]+").unwrap());
+
+/// Guesses what a Tenor or Giphy GIF is of from the words in its link, e.g. `https://tenor.com/view/cat-dance-gif-12345` is of a cat
+/// dance. Links with no words in them, like direct links to the GIF file, give nothing.
+pub fn title_from_url(url: &str) -> Option<String> {
+    let url = url.split(['?', '#']).next().unwrap();
+    let (host, path) = url.split_once("://")?.1.split_once('/')?;
+    let segments = path.split('/').filter(|segment| !segment.is_empty()).collect::<Vec<_>>();
+
+    let slug = if host.ends_with("tenor.com") {
+        // Links may have a locale before `view`, e.g. `/fr/view/...`.
+        let slug = segments.iter().skip_while(|segment| **segment != "view").nth(1)?;
+        let slug = slug.rsplit_once('-').filter(|(_, id)| id.chars().all(|c| c.is_ascii_digit()))?.0;
+        slug.strip_suffix("-gif").unwrap_or(slug)
+    } else if host == "giphy.com" || host == "www.giphy.com" {
+        if segments.first() != Some(&"gifs") {
+            return None;
+        }
+        // The ID is the last word, and a link with only an ID in it says nothing.
+        segments.get(1)?.rsplit_once('-')?.0
+    } else {
+        return None;
+    };
+
+    let title = slug.split('-').filter(|word| !word.is_empty()).collect::<Vec<_>>().join(" ");
+    if title.is_empty() {
+        None
+    } else {
+        Some(title)
+    }
+}
+
+/// Replaces links to Tenor and Giphy GIFs, which Discord shows as the GIF itself, with what they're of, e.g. `[GIF: cat dance]`. `title`
+/// gives a better title for a link than its words, if there is one, e.g. from its embed.
+pub fn replace_links(content: &str, title: impl Fn(&str) -> Option<String>) -> String {
+    GIF_LINK_REGEX
+        .replace_all(content, |c: &regex::Captures| {
+            let url = &c[0];
+            match title(url).or_else(|| title_from_url(url)) {
+                Some(title) => format!("[GIF: {}]", title),
+                None => "[GIF]".to_string(),
+            }
+        })
+        .into_owned()
+}
+
+/// Whether a link is to a Tenor or Giphy GIF, which replace_links takes care of.
+pub fn is_link(url: &str) -> bool {
+    GIF_LINK_REGEX.find(url).is_some_and(|m| m.start() == 0 && m.end() == url.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_title_from_url() {
+        assert_eq!(
+            title_from_url("https://tenor.com/view/cat-dance-funny-gif-12345678"),
+            Some("cat dance funny".to_string())
+        );
+        assert_eq!(title_from_url("https://tenor.com/fr/view/oh-no-gif-987"), Some("oh no".to_string()));
+        assert_eq!(title_from_url("https://tenor.com/bR4pq.gif"), None);
+        assert_eq!(
+            title_from_url("https://giphy.com/gifs/netflix-shrug-3o7btPCcdNniyf0ArS?utm_source=x"),
+            Some("netflix shrug".to_string())
+        );
+        assert_eq!(title_from_url("https://giphy.com/gifs/3o7btPCcdNniyf0ArS"), None);
+        assert_eq!(title_from_url("https://media.giphy.com/media/3o7btPCcdNniyf0ArS/giphy.gif"), None);
+    }
+
+    #[test]
+    fn test_replace_links() {
+        assert_eq!(
+            replace_links("lol https://tenor.com/view/cat-dance-gif-123 and https://tenor.com/bR4pq.gif", |_| None),
+            "lol [GIF: cat dance] and [GIF]"
+        );
+        assert_eq!(
+            replace_links("https://giphy.com/gifs/shrug-abc", |_| Some("Shrug GIF by Netflix".to_string())),
+            "[GIF: Shrug GIF by Netflix]"
+        );
+        assert!(is_link("https://giphy.com/gifs/shrug-abc"));
+        assert!(!is_link("https://example.com/https://giphy.com/gifs/shrug-abc"));
+    }
+}
@@ -0,0 +1,37 @@
+/// How a backend connects to its API, for self-hosted endpoints and networks that go through a proxy or intercept TLS.
+#[derive(serde::Deserialize, Default, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// HTTP(S) or SOCKS5 proxy, e.g. `http://proxy:3128` or `socks5://proxy:1080`. If not set, the usual proxy environment variables are
+    /// used.
+    #[serde(default)]
+    pub proxy: Option<String>,
+
+    /// PEM file of an extra root certificate to trust.
+    #[serde(default)]
+    pub ca_cert: Option<std::path::PathBuf>,
+
+    /// Accepts any certificate at all. Only use this if there's no other way.
+    #[serde(default)]
+    pub danger_accept_invalid_certs: bool,
+}
+
+impl Config {
+    pub fn client_builder(&self) -> Result<reqwest::ClientBuilder, anyhow::Error> {
+        let mut builder = reqwest::ClientBuilder::new();
+        if let Some(proxy) = self.proxy.as_ref() {
+            builder = builder.proxy(reqwest::Proxy::all(proxy).map_err(|e| anyhow::format_err!("proxy {}: {}", proxy, e))?);
+        }
+        if let Some(ca_cert) = self.ca_cert.as_ref() {
+            builder = builder.add_root_certificate(
+                reqwest::Certificate::from_pem(&std::fs::read(ca_cert).map_err(|e| anyhow::format_err!("ca_cert {}: {}", ca_cert.display(), e))?)
+                    .map_err(|e| anyhow::format_err!("ca_cert {}: {}", ca_cert.display(), e))?,
+            );
+        }
+        if self.danger_accept_invalid_certs {
+            log::warn!("accepting invalid certificates");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder)
+    }
+}
@@ -0,0 +1,111 @@
+static MATH_REGEX: once_cell::sync::Lazy<regex::Regex> =
+    once_cell::sync::Lazy::new(|| regex::Regex::new(r"(?s)```.*?(?:```|$)|\$\$(?P<dollars>.+?)\$\$|\\\[(?P<brackets>.+?)\\\]").unwrap());
+
+fn url_default() -> String {
+    r"https://latex.codecogs.com/png.image?\dpi{200}\bg{white}{{latex}}".to_string()
+}
+
+const fn max_images_default() -> usize {
+    // Discord doesn't allow more attachments than this on a message.
+    10
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// URL of a service that renders LaTeX to PNG, with a `{{latex}}` placeholder for the URL-encoded math.
+    #[serde(default = "url_default")]
+    pub url: String,
+
+    /// Most images attached to a reply. Any further math is left as-is.
+    #[serde(default = "max_images_default")]
+    pub max_images: usize,
+
+    #[serde(default)]
+    pub http: crate::http::Config,
+}
+
+/// Finds display math, `$$...$$` or `\[...\]`, outside of code blocks.
+pub fn extract(s: &str) -> Vec<String> {
+    MATH_REGEX
+        .captures_iter(s)
+        .filter_map(|c| c.name("dollars").or_else(|| c.name("brackets")))
+        .map(|m| m.as_str().trim().to_string())
+        .filter(|latex| !latex.is_empty())
+        .collect()
+}
+
+/// Percent-encodes everything but unreserved characters, since render services take the math as a raw query string or path.
+fn encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+pub struct Renderer {
+    client: reqwest::Client,
+    url: String,
+    pub max_images: usize,
+}
+
+impl Renderer {
+    pub fn new(config: &Config) -> Result<Self, anyhow::Error> {
+        if !crate::template::has_placeholders(&config.url) {
+            return Err(anyhow::format_err!("url has no {{{{latex}}}} placeholder: {}", config.url));
+        }
+        Ok(Self {
+            client: config.http.client_builder()?.build()?,
+            url: config.url.clone(),
+            max_images: config.max_images,
+        })
+    }
+
+    fn url(&self, latex: &str) -> String {
+        crate::template::expand(&self.url, &std::collections::HashMap::from([("latex", encode(latex))]))
+    }
+
+    pub async fn render(&self, latex: &str) -> Result<Vec<u8>, anyhow::Error> {
+        let resp = self
+            .client
+            .get(self.url(latex))
+            .send()
+            .await
+            .map_err(|e| e.without_url())?
+            .error_for_status()
+            .map_err(|e| e.without_url())?;
+        Ok(resp.bytes().await.map_err(|e| e.without_url())?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract() {
+        assert_eq!(
+            extract("So $$ e^{i\\pi} + 1 = 0 $$ and\n\\[\n\\int_0^1 x\\,dx = \\frac{1}{2}\n\\]\nbut not $x$ or\n```\n$$code$$\n```"),
+            vec!["e^{i\\pi} + 1 = 0".to_string(), "\\int_0^1 x\\,dx = \\frac{1}{2}".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_url() {
+        let renderer = Renderer::new(&Config {
+            url: url_default(),
+            max_images: max_images_default(),
+            http: Default::default(),
+        })
+        .unwrap();
+        assert_eq!(
+            renderer.url("x^2 + y"),
+            "https://latex.codecogs.com/png.image?\\dpi{200}\\bg{white}x%5E2%20%2B%20y"
+        );
+    }
+}
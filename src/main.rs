@@ -1,9 +1,30 @@
+mod analytics;
 mod backend;
+mod budget;
+mod codefence;
+mod dataset;
+mod filter;
+mod gif;
+mod http;
+mod latex;
 mod openai;
+mod preset;
+mod repetition;
+mod retention;
+mod secret;
+mod sentence;
+mod sse;
+mod stop;
+mod store;
+mod template;
+mod think;
+mod transcript;
+mod typing;
 mod unichunk;
 
 use clap::Parser;
 use futures_util::StreamExt;
+use serenity::model::mention::Mentionable;
 
 #[derive(Debug, PartialEq)]
 enum ThreadMode {
@@ -15,9 +36,38 @@ enum ThreadMode {
 struct ChatSettings {
     system_message: String,
     parameters: toml::Value,
+    preset: Option<String>,
+    multi_format: Option<template::MultiFormat>,
+    persona: Option<preset::Persona>,
+    greeting: Option<String>,
+    overflow: preset::Overflow,
+    system_placement: Option<preset::SystemPlacement>,
+    system_segments: Vec<String>,
+    prefix: Option<String>,
+    suffix: Option<String>,
+    assistant_prefill: Option<String>,
+    trim_incomplete_sentence: bool,
+    current_time: bool,
 }
 
-static FORGET_EMOJI: &str = "❌";
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ReactionAction {
+    /// Leaves the message out of the prompt.
+    Exclude,
+
+    /// Always includes the message in the prompt, right after the system message.
+    Pin,
+
+    /// Deletes the bot's reply and replies again.
+    Regenerate,
+
+    /// Deletes the bot's reply.
+    Delete,
+}
+
+/// Messages from the thread author starting with this marker are appended to the settings in the primary message.
+static SETTINGS_CONTINUATION_MARKER: &str = "+++";
 
 impl ChatSettings {
     fn new(s: &str) -> Result<Self, anyhow::Error> {
@@ -33,19 +83,176 @@ impl ChatSettings {
             .take(2)
             .collect::<Vec<_>>();
 
+        static PRESET_REFERENCE_REGEX: once_cell::sync::Lazy<regex::Regex> =
+            once_cell::sync::Lazy::new(|| regex::Regex::new(r"^preset:[ \t]*(?P<name>\S+)[ \t]*(?:\n|$)").unwrap());
+
+        let system_message = parts[0].unwrap();
+        let (system_message, preset) = if let Some(captures) = PRESET_REFERENCE_REGEX.captures(system_message) {
+            (&system_message[captures.get(0).unwrap().end()..], Some(captures["name"].to_string()))
+        } else {
+            (system_message, None)
+        };
+
         Ok(ChatSettings {
-            system_message: parts[0].unwrap().to_string(),
+            system_message: system_message.to_string(),
             parameters: parts[1].map_or_else(|| Ok(toml::Table::new().into()), |v| toml::from_str::<toml::Value>(v))?,
+            preset,
+            multi_format: None,
+            persona: None,
+            greeting: None,
+            overflow: preset::Overflow::default(),
+            system_placement: None,
+            system_segments: vec![],
+            prefix: None,
+            suffix: None,
+            assistant_prefill: None,
+            trim_incomplete_sentence: false,
+            current_time: false,
         })
     }
+
+    /// Whether the text has parameters or a preset reference, which a plain question wouldn't.
+    fn has_settings_syntax(s: &str) -> bool {
+        s.contains("\n---\n") || s.starts_with("preset:")
+    }
+
+    fn apply_preset(&mut self, preset: &preset::Preset) {
+        self.system_message = if self.system_message.trim().is_empty() {
+            preset.system_message.clone()
+        } else {
+            format!("{}\n\n{}", preset.system_message, self.system_message)
+        };
+        self.apply_default_parameters(preset.parameters.clone());
+        if preset.multi_format.is_some() {
+            self.multi_format = preset.multi_format.clone();
+        }
+        if preset.persona.is_some() {
+            self.persona = preset.persona.clone();
+        }
+        if preset.greeting.is_some() {
+            self.greeting = preset.greeting.clone();
+        }
+        if let Some(overflow) = preset.overflow {
+            self.overflow = overflow;
+        }
+        if preset.system_placement.is_some() {
+            self.system_placement = preset.system_placement;
+        }
+        self.system_segments = preset.system_segments.clone();
+        self.prefix = preset.prefix.clone();
+        self.suffix = preset.suffix.clone();
+        self.assistant_prefill = preset.assistant_prefill.clone();
+        self.current_time = preset.current_time;
+    }
+
+    /// Takes out the parameters that are about what's done with a reply rather than how it's requested, so the backend doesn't see them.
+    fn take_reply_parameters(&mut self) -> Result<(), anyhow::Error> {
+        if let Some(value) = self.parameters.as_table_mut().and_then(|t| t.remove("trim_incomplete_sentence")) {
+            self.trim_incomplete_sentence = value
+                .as_bool()
+                .ok_or_else(|| anyhow::format_err!("trim_incomplete_sentence must be true or false"))?;
+        }
+        Ok(())
+    }
+
+    /// Applies parameters underneath the current ones, i.e. the current parameters take precedence.
+    fn apply_default_parameters(&mut self, defaults: toml::Value) {
+        let mut parameters = defaults;
+        merge_parameters(&mut parameters, std::mem::replace(&mut self.parameters, toml::Table::new().into()));
+        self.parameters = parameters;
+    }
+}
+
+/// Merges overrides into base parameters, recursing into tables.
+fn merge_parameters(base: &mut toml::Value, overrides: toml::Value) {
+    match (base, overrides) {
+        (toml::Value::Table(base), toml::Value::Table(overrides)) => {
+            for (k, v) in overrides {
+                if let Some(base_v) = base.get_mut(&k) {
+                    merge_parameters(base_v, v);
+                } else {
+                    base.insert(k, v);
+                }
+            }
+        }
+        (base, overrides) => {
+            *base = overrides;
+        }
+    }
 }
 
 #[derive(Debug)]
 struct ThreadInfo {
+    guild_id: serenity::model::id::GuildId,
     primary_message: serenity::model::channel::Message,
+    /// The primary message is a stand-in for one that was deleted.
+    primary_message_missing: bool,
+    title: String,
     messages: std::collections::BTreeMap<serenity::model::id::MessageId, serenity::model::channel::Message>,
+    /// Every message from this one on is loaded. If not set, the whole thread is.
+    loaded_from: Option<serenity::model::id::MessageId>,
     mode: ThreadMode,
     backend: Option<String>,
+    preset: Option<String>,
+    /// Presets from every preset tag, in order. With more than one, the thread is an ensemble, and they take turns speaking.
+    cast: Vec<String>,
+    /// The preset of whichever of the cast is speaking right now, which takes the place of the thread's own.
+    speaker: Option<String>,
+    /// Whoever asked for the reply being generated right now, if that isn't whoever wrote its prompt, e.g. whoever started autoplay.
+    requester: Option<serenity::model::id::UserId>,
+    tag_parameters: toml::Value,
+    /// Overrides the preset's overflow strategy.
+    overflow: Option<preset::Overflow>,
+    reply_on_edit: bool,
+    branching: bool,
+    no_mention: bool,
+    ambient: bool,
+    nsfw: bool,
+    translate: bool,
+    self_chat: bool,
+    last_ambient_reply: Option<std::time::Instant>,
+    last_request: Option<std::time::Instant>,
+    /// When the reactions on loaded messages were last fetched, rather than kept up to date from events.
+    reactions_refreshed_at: std::time::Instant,
+    /// For backends that cache prompt prefixes, the oldest message the last trimmed prompt started from. Later prompts start from the same
+    /// message for as long as they fit, so their prefix stays cached.
+    cache_floor: parking_lot::Mutex<Option<serenity::model::id::MessageId>>,
+    /// The first message after the primary message, if it was fetched on its own with the rest of the thread's opening.
+    opening: Option<serenity::model::id::MessageId>,
+    reported_settings_error: Option<String>,
+    /// People who've been told that the thread's backend isn't for them, so they're only told once.
+    told_backend_fallback: std::collections::HashSet<serenity::model::id::UserId>,
+    state: store::ThreadState,
+    /// Token counts of messages as they were last sent to each backend, by a fingerprint of their content, so long threads don't need
+    /// their whole history counted again for every prompt. Edited messages get new fingerprints, so their old counts just age out.
+    token_counts: parking_lot::Mutex<lru::LruCache<(String, u64), usize>>,
+    /// For threads with the translate tag, messages as the backend saw or wrote them before they were translated, by the ID of the message
+    /// in the thread. The rest of a translated reply that was split into several messages maps to empty strings. These aren't stored, so
+    /// after a restart the backend sees the translated messages instead.
+    translations: parking_lot::Mutex<lru::LruCache<serenity::model::id::MessageId, String>>,
+    /// Replies that can be rated, by the message they reply to, with the backend, prompt and response. These aren't stored either, so
+    /// replies from before a restart can't be rated.
+    rateable: parking_lot::Mutex<lru::LruCache<serenity::model::id::MessageId, RateableReply>>,
+    /// Replies that were written several ways, by the message they reply to. These aren't stored either.
+    reply_variants: parking_lot::Mutex<lru::LruCache<serenity::model::id::MessageId, ReplyVariants>>,
+}
+
+#[derive(Clone)]
+struct RateableReply {
+    backend: String,
+    prompt: Vec<transcript::Message>,
+    response: String,
+}
+
+#[derive(Clone)]
+struct ReplyVariants {
+    /// Whoever the reply is to, who's the only one who can pick between its variants.
+    requester: serenity::model::id::UserId,
+    persona: Option<preset::Persona>,
+    /// The messages the selected variant is posted as.
+    message_ids: Vec<serenity::model::id::MessageId>,
+    variants: Vec<String>,
+    selected: usize,
 }
 
 impl ThreadInfo {
@@ -53,45 +260,506 @@ impl ThreadInfo {
         http: impl AsRef<serenity::http::Http>,
         id: serenity::model::id::ChannelId,
         tags: &std::collections::HashMap<serenity::model::id::ForumTagId, String>,
-        message_history_size: usize,
-    ) -> Result<Self, serenity::Error> {
-        let primary_message = id.message(&http, id.0).await?;
+        store: &store::Store,
+        config: &Config,
+    ) -> Result<Self, anyhow::Error> {
+        let channel = if let serenity::model::prelude::Channel::Guild(guild_channel) = http.as_ref().get_channel(id.0).await? {
+            guild_channel
+        } else {
+            unreachable!();
+        };
+
+        let mut state = store.load_thread(id).await?;
+        let (primary_message, missing) = Self::fetch_primary_message(&http, &channel, config).await?;
+        if missing && !state.missing_first_post_noticed && state.settings.is_none() {
+            id.send_message(&http, |m| {
+                m.embed(|e| {
+                    e.title("First post missing")
+                        .color(serenity::utils::colours::css::WARNING)
+                        .description(format!(
+                            "This thread's first post was deleted, so I'm using the default settings. Use /{} to give it a system prompt.",
+                            SET_SYSTEM_COMMAND_NAME
+                        ))
+                })
+            })
+            .await?;
+            state.missing_first_post_noticed = true;
+            store.save_thread(id, &state).await?;
+        }
+        let (messages, loaded_from) = Self::fetch_history(&http, &primary_message, &state, config).await?;
+
+        let mut ti = Self {
+            guild_id: channel.guild_id,
+            primary_message,
+            primary_message_missing: missing,
+            title: channel.name.clone(),
+            messages,
+            loaded_from,
+            mode: ThreadMode::Single,
+            backend: None,
+            preset: None,
+            cast: vec![],
+            speaker: None,
+            requester: None,
+            tag_parameters: toml::Table::new().into(),
+            overflow: None,
+            reply_on_edit: false,
+            branching: false,
+            no_mention: false,
+            ambient: false,
+            nsfw: false,
+            translate: false,
+            self_chat: false,
+            last_ambient_reply: None,
+            last_request: None,
+            reactions_refreshed_at: std::time::Instant::now(),
+            cache_floor: parking_lot::Mutex::new(None),
+            opening: None,
+            reported_settings_error: None,
+            told_backend_fallback: std::collections::HashSet::new(),
+            state,
+            // Each message is counted both for loading history and for the prompt itself.
+            token_counts: parking_lot::Mutex::new(lru::LruCache::new(
+                std::num::NonZeroUsize::new(config.message_history_size.max(1) * 2).unwrap(),
+            )),
+            translations: parking_lot::Mutex::new(lru::LruCache::new(
+                std::num::NonZeroUsize::new(config.message_history_size.max(1)).unwrap(),
+            )),
+            rateable: parking_lot::Mutex::new(lru::LruCache::new(
+                std::num::NonZeroUsize::new(config.message_history_size.max(1)).unwrap(),
+            )),
+            reply_variants: parking_lot::Mutex::new(lru::LruCache::new(
+                std::num::NonZeroUsize::new(config.message_history_size.max(1)).unwrap(),
+            )),
+        };
+
+        ti.update_from_channel(&channel, &tags, config);
+
+        Ok(ti)
+    }
+
+    /// Fetches a thread's first post, which holds its settings. If it was deleted, a stand-in with `missing_first_post_settings` from the
+    /// config file is made up instead, and true is returned with it. Nobody is its author, so only moderators can manage the thread.
+    async fn fetch_primary_message(
+        http: impl AsRef<serenity::http::Http>,
+        channel: &serenity::model::channel::GuildChannel,
+        config: &Config,
+    ) -> Result<(serenity::model::channel::Message, bool), anyhow::Error> {
+        match channel.id.message(&http, channel.id.0).await {
+            Ok(message) => Ok((message, false)),
+            Err(serenity::Error::Http(e)) if e.status_code() == Some(reqwest::StatusCode::NOT_FOUND) => {
+                log::warn!("first post of {} is missing, using default settings", channel.id);
+                let message = serde_json::from_value(serde_json::json!({
+                    "id": channel.id,
+                    "channel_id": channel.id,
+                    "guild_id": channel.guild_id,
+                    "author": {
+                        "id": serenity::model::id::UserId(0),
+                        "username": "",
+                        "discriminator": "0000",
+                        "avatar": null,
+                    },
+                    "content": config
+                        .defaults
+                        .missing_first_post_settings
+                        .clone()
+                        .or_else(|| config.defaults.system_message.clone())
+                        .unwrap_or_default(),
+                    "timestamp": channel.id.created_at(),
+                    "edited_timestamp": null,
+                    "tts": false,
+                    "mention_everyone": false,
+                    "mentions": [],
+                    "mention_roles": [],
+                    "attachments": [],
+                    "embeds": [],
+                    "pinned": false,
+                    "type": 0,
+                }))?;
+                Ok((message, true))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Fetches a thread's recent history, along with older messages that are pinned one way or another. Also returns the oldest message
+    /// that was fetched, if the history goes back further.
+    async fn fetch_history(
+        http: impl AsRef<serenity::http::Http>,
+        primary_message: &serenity::model::channel::Message,
+        state: &store::ThreadState,
+        config: &Config,
+    ) -> Result<
+        (
+            std::collections::BTreeMap<serenity::model::id::MessageId, serenity::model::channel::Message>,
+            Option<serenity::model::id::MessageId>,
+        ),
+        anyhow::Error,
+    > {
+        let id = primary_message.channel_id;
         let mut messages = std::collections::BTreeMap::new();
 
-        let mut messages_it = Box::pin(id.messages_iter(&http)).take(message_history_size);
+        let mut oldest_id = None;
+        let mut reached_start = false;
+        let mut messages_it = Box::pin(id.messages_iter(&http)).take(config.message_history_size);
         while let Some(message) = messages_it.next().await {
             let message = message?;
             if message.id.0 == id.0 {
+                reached_start = true;
                 break;
             }
+            oldest_id = Some(message.id);
             messages.insert(message.id, message);
         }
+        let loaded_from = if reached_start || messages.len() < config.message_history_size {
+            None
+        } else {
+            oldest_id
+        };
 
-        let channel = if let serenity::model::prelude::Channel::Guild(guild_channel) = http.as_ref().get_channel(id.0).await? {
-            guild_channel
+        // Pinned messages from the thread author continue the settings, so make sure we have them even if they're old.
+        for message in id.pins(&http).await? {
+            if message.id.0 == id.0 || message.author.id != primary_message.author.id {
+                continue;
+            }
+            messages.entry(message.id).or_insert(message);
+        }
+
+        // Likewise for messages pinned to the prompt.
+        for &message_id in state.pinned.iter() {
+            if messages.contains_key(&message_id) {
+                continue;
+            }
+            match id.message(&http, message_id).await {
+                Ok(message) => {
+                    messages.insert(message.id, message);
+                }
+                Err(e) => {
+                    log::warn!("could not fetch pinned message {} in {}: {:?}", message_id, id, e);
+                }
+            }
+        }
+
+        Ok((messages, loaded_from))
+    }
+
+    /// Fetches the thread's history again and replaces what's loaded with it, for when the two have drifted apart, e.g. because of missed
+    /// events.
+    async fn resync(&mut self, http: impl AsRef<serenity::http::Http>, config: &Config) -> Result<(), anyhow::Error> {
+        let channel_id = self.primary_message.channel_id;
+        // A stand-in for a deleted first post stays as it is.
+        match channel_id.message(&http, channel_id.0).await {
+            Ok(message) => {
+                self.primary_message = message;
+            }
+            Err(serenity::Error::Http(e)) if e.status_code() == Some(reqwest::StatusCode::NOT_FOUND) => {}
+            Err(e) => {
+                return Err(e.into());
+            }
+        }
+        (self.messages, self.loaded_from) = Self::fetch_history(&http, &self.primary_message, &self.state, config).await?;
+        self.reactions_refreshed_at = std::time::Instant::now();
+        Ok(())
+    }
+
+    /// Whether a message that should be loaded isn't, i.e. it's in the thread but the bot never saw it come in. Messages newer than any
+    /// loaded one don't count, since events for them can overtake the events for their being sent.
+    fn is_missing(&self, message_id: serenity::model::id::MessageId) -> bool {
+        message_id != self.primary_message.id
+            && !self.messages.contains_key(&message_id)
+            && self.loaded_from.map(|loaded_from| message_id > loaded_from).unwrap_or(true)
+            && self.messages.keys().next_back().map(|newest| message_id < *newest).unwrap_or(false)
+    }
+
+    /// Fetches the messages sent after the newest one known, e.g. ones missed while the bot was offline. Returns how many there were.
+    async fn backfill(&mut self, http: impl AsRef<serenity::http::Http>) -> Result<usize, anyhow::Error> {
+        let channel_id = self.primary_message.channel_id;
+        let mut after = self.messages.keys().next_back().copied().unwrap_or(self.primary_message.id);
+        let mut num_fetched = 0;
+        loop {
+            let page = channel_id.messages(&http, |b| b.after(after).limit(HISTORY_PAGE_SIZE)).await?;
+            let done = page.len() < HISTORY_PAGE_SIZE as usize;
+            num_fetched += page.len();
+            for message in page {
+                after = std::cmp::max(after, message.id);
+                self.messages.insert(message.id, message);
+            }
+            if done {
+                return Ok(num_fetched);
+            }
+        }
+    }
+
+    /// Fetches the reactions on every loaded message again, in case any reaction events were missed, e.g. while the bot was disconnected,
+    /// so no exclude reactions are overlooked.
+    async fn refresh_reactions(&mut self, http: impl AsRef<serenity::http::Http>) -> Result<(), anyhow::Error> {
+        let channel_id = self.primary_message.channel_id;
+        let mut before: Option<serenity::model::id::MessageId> = None;
+        loop {
+            let page = channel_id
+                .messages(&http, |b| {
+                    if let Some(before) = before {
+                        b.before(before);
+                    }
+                    b.limit(HISTORY_PAGE_SIZE)
+                })
+                .await
+                .map_err(|e| anyhow::format_err!("messages: {}", e))?;
+            let done = page.len() < HISTORY_PAGE_SIZE as usize || page.iter().any(|m| self.loaded_from.map(|from| m.id <= from).unwrap_or(false));
+            for message in page {
+                before = Some(before.map(|before| std::cmp::min(before, message.id)).unwrap_or(message.id));
+                if message.id == self.primary_message.id {
+                    self.primary_message.reactions = message.reactions;
+                } else if let Some(cached) = self.messages.get_mut(&message.id) {
+                    cached.reactions = message.reactions;
+                }
+            }
+            if done {
+                break;
+            }
+        }
+        self.reactions_refreshed_at = std::time::Instant::now();
+        Ok(())
+    }
+
+    /// Fetches the first messages after the primary message, if not all of the thread is loaded and they aren't already.
+    async fn load_opening(&mut self, http: impl AsRef<serenity::http::Http>) -> Result<(), anyhow::Error> {
+        if self.has_opening() {
+            return Ok(());
+        }
+
+        let page = self
+            .primary_message
+            .channel_id
+            .messages(&http, |b| b.after(self.primary_message.id).limit(OPENING_PAGE_SIZE))
+            .await
+            .map_err(|e| anyhow::format_err!("messages: {}", e))?;
+        self.opening = page.iter().map(|m| m.id).min();
+        for message in page {
+            self.messages.entry(message.id).or_insert(message);
+        }
+        Ok(())
+    }
+
+    /// Whether the start of the thread is loaded, either along with the rest of it or with load_opening.
+    fn has_opening(&self) -> bool {
+        self.loaded_from.is_none() || self.opening.map(|id| self.messages.contains_key(&id)).unwrap_or(false)
+    }
+
+    /// Messages since a point in time that mention the bot but that it never replied to.
+    fn missed_mentions(&self, since: chrono::DateTime<chrono::Utc>, me_id: serenity::model::id::UserId) -> Vec<&serenity::model::channel::Message> {
+        self.messages
+            .values()
+            .filter(|m| m.author.id != me_id && !m.author.bot && m.timestamp.with_timezone(&chrono::Utc) >= since && m.mentions_user_id(me_id))
+            .filter(|m| {
+                !self
+                    .messages
+                    .range(m.id..)
+                    .any(|(_, r)| r.author.id == me_id && r.message_reference.as_ref().and_then(|r| r.message_id) == Some(m.id))
+            })
+            .collect()
+    }
+
+    /// The message a prompt would be assembled in reply to if the bot replied now. Messages fetched over HTTP don't say which guild
+    /// they're from, so that's filled in.
+    fn latest_message(&self) -> serenity::model::channel::Message {
+        let mut message = self.messages.values().next_back().unwrap_or(&self.primary_message).clone();
+        message.guild_id = Some(self.guild_id);
+        message
+    }
+
+    /// The chain of messages leading up to a message, newest first. Each message follows the message it replies to, or the message before it
+    /// if it isn't a reply, so replying to an older message starts a new branch of the conversation.
+    fn ancestry(&self, message_id: serenity::model::id::MessageId, me_id: serenity::model::id::UserId) -> Vec<&serenity::model::channel::Message> {
+        let mut chain = vec![];
+        let mut next = self.messages.get(&message_id);
+        while let Some(message) = next {
+            chain.push(message);
+
+            let previous = self.messages.range(..message.id).next_back().map(|(_, m)| m);
+            next = if let Some(reference_id) = message.message_reference.as_ref().and_then(|r| r.message_id) {
+                // Replies that span several messages all reference the same message, so they need to be followed in order.
+                if message.author.id == me_id
+                    && previous
+                        .map(|p| p.author.id == me_id && p.message_reference.as_ref().and_then(|r| r.message_id) == Some(reference_id))
+                        .unwrap_or(false)
+                {
+                    previous
+                } else {
+                    self.messages.get(&reference_id)
+                }
+            } else {
+                previous
+            };
+        }
+        chain
+    }
+
+    /// Fetches the page of messages just before the loaded history. Returns false if there was nothing left to fetch.
+    async fn extend_history(&mut self, http: impl AsRef<serenity::http::Http>) -> Result<bool, anyhow::Error> {
+        let loaded_from = if let Some(loaded_from) = self.loaded_from {
+            loaded_from
         } else {
-            unreachable!();
+            return Ok(false);
         };
 
-        let mut ti = Self {
-            primary_message,
-            messages,
-            mode: ThreadMode::Single,
-            backend: None,
+        let channel_id = self.primary_message.channel_id;
+        let page = channel_id
+            .messages(&http, |b| b.before(loaded_from).limit(HISTORY_PAGE_SIZE))
+            .await
+            .map_err(|e| anyhow::format_err!("messages: {}", e))?;
+
+        self.loaded_from = if page.len() < HISTORY_PAGE_SIZE as usize {
+            None
+        } else {
+            page.iter().map(|m| m.id).min()
         };
+        for message in page {
+            if message.id.0 == channel_id.0 {
+                self.loaded_from = None;
+                continue;
+            }
+            self.messages.entry(message.id).or_insert(message);
+        }
+        Ok(true)
+    }
 
-        ti.update_from_tags(&channel, &tags);
+    /// Whether the prompt's history stops at this message, either because it's a /forget or because it's before the forget cutoff.
+    fn is_forget_break(&self, message: &serenity::model::channel::Message, me_id: serenity::model::id::UserId) -> bool {
+        (message.author.id == me_id
+            && message
+                .interaction
+                .as_ref()
+                .map(|i| i.kind == serenity::model::application::interaction::InteractionType::ApplicationCommand && i.name == FORGET_COMMAND_NAME)
+                .unwrap_or(false))
+            || self.state.forget_before.map(|forget_before| message.id < forget_before).unwrap_or(false)
+    }
 
-        Ok(ti)
+    fn is_settings_continuation(&self, message: &serenity::model::channel::Message) -> bool {
+        message.author.id == self.primary_message.author.id && (message.pinned || message.content.starts_with(SETTINGS_CONTINUATION_MARKER))
+    }
+
+    /// Whether the user may change the thread's settings: they must either have created it or be able to manage threads.
+    fn can_manage(&self, user_id: serenity::model::id::UserId, permissions: Option<serenity::model::permissions::Permissions>) -> bool {
+        user_id == self.state.owner.unwrap_or(self.primary_message.author.id) || permissions.map(|p| p.manage_threads()).unwrap_or(false)
+    }
+
+    /// The raw settings text: either the stored override, or the primary message and its continuations.
+    fn settings_source(&self) -> String {
+        if let Some(settings) = self.state.settings.as_ref() {
+            return settings.clone();
+        }
+
+        let mut s = self.primary_message.content.clone();
+        for message in self.messages.values().filter(|m| self.is_settings_continuation(m)) {
+            let content = if let Some(rest) = message.content.strip_prefix(SETTINGS_CONTINUATION_MARKER) {
+                let rest = rest.trim_start_matches([' ', '\t']);
+                rest.strip_prefix('\n').unwrap_or(rest)
+            } else {
+                &message.content
+            };
+            s.push('\n');
+            s.push_str(content);
+        }
+        s
+    }
+
+    /// With a default system message configured, a primary message without any settings syntax is the thread's first prompt instead of
+    /// its settings. A stand-in for a deleted one never is.
+    fn primary_is_prompt(&self, defaults: &DefaultsConfig) -> bool {
+        !self.primary_message_missing
+            && defaults.system_message.is_some()
+            && self.state.settings.is_none()
+            && !ChatSettings::has_settings_syntax(&self.settings_source())
+    }
+
+    fn settings(
+        &self,
+        presets: &std::collections::HashMap<String, preset::Preset>,
+        defaults: &DefaultsConfig,
+    ) -> Result<ChatSettings, anyhow::Error> {
+        let mut settings = if let Some(system_message) = defaults.system_message.as_ref().filter(|_| self.primary_is_prompt(defaults)) {
+            ChatSettings {
+                system_message: system_message.clone(),
+                parameters: toml::Table::new().into(),
+                preset: None,
+                multi_format: None,
+                persona: None,
+                greeting: None,
+                overflow: preset::Overflow::default(),
+                system_placement: None,
+                system_segments: vec![],
+                prefix: None,
+                suffix: None,
+                assistant_prefill: None,
+                trim_incomplete_sentence: false,
+                current_time: false,
+            }
+        } else {
+            ChatSettings::new(&self.settings_source())?
+        };
+        settings.apply_default_parameters(self.tag_parameters.clone());
+        if self.primary_is_prompt(defaults) {
+            settings.apply_default_parameters(defaults.parameters.clone());
+        }
+        if let Some(preset_name) = self.speaker.as_ref().or(settings.preset.as_ref()).or(self.preset.as_ref()) {
+            let preset = presets
+                .get(preset_name)
+                .ok_or_else(|| anyhow::format_err!("unknown preset: {}", preset_name))?;
+            settings.apply_preset(preset);
+        }
+        if let Some(model) = self.state.model.as_ref() {
+            merge_parameters(&mut settings.parameters, toml::toml! { model = (model.clone()) }.into());
+        }
+        if let Some(overflow) = self.overflow {
+            settings.overflow = overflow;
+        }
+        if let Some(assistant_prefill) = self.state.assistant_prefill.as_ref() {
+            settings.assistant_prefill = Some(assistant_prefill.clone());
+        }
+        settings.take_reply_parameters()?;
+        Ok(settings)
+    }
+
+    fn insert_message(&mut self, message: serenity::model::channel::Message, message_history_size: usize) {
+        // Evict the oldest messages first, but never settings continuations or pinned messages: they're needed no matter how old they are.
+        while self.messages.len() >= message_history_size {
+            let id = if let Some(id) = self
+                .messages
+                .values()
+                .find(|m| !self.is_settings_continuation(m) && !self.state.pinned.contains(&m.id))
+                .map(|m| m.id)
+            {
+                id
+            } else {
+                break;
+            };
+            self.messages.remove(&id);
+            self.loaded_from = Some(self.messages.range(id..).next().map(|(id, _)| *id).unwrap_or(message.id));
+        }
+        self.messages.insert(message.id, message);
     }
 
-    fn update_from_tags(
+    fn update_from_channel(
         &mut self,
         thread: &serenity::model::channel::GuildChannel,
         tags: &std::collections::HashMap<serenity::model::id::ForumTagId, String>,
+        config: &Config,
     ) {
+        self.title = thread.name.clone();
         self.mode = ThreadMode::Single;
         self.backend = None;
+        self.preset = None;
+        self.cast = vec![];
+        self.tag_parameters = toml::Table::new().into();
+        self.overflow = None;
+        self.reply_on_edit = false;
+        self.branching = false;
+        self.no_mention = false;
+        self.ambient = false;
+        self.nsfw = false;
+        self.translate = false;
+        self.self_chat = false;
 
         for tag in thread.applied_tags.iter() {
             let tag_name = if let Some(tag_name) = tags.get(&tag) {
@@ -102,8 +770,31 @@ impl ThreadInfo {
 
             if tag_name == "multi" {
                 self.mode = ThreadMode::Multi;
+            } else if tag_name == "reply on edit" {
+                self.reply_on_edit = true;
+            } else if tag_name == "branching" {
+                self.branching = true;
+            } else if tag_name == "no-mention" {
+                self.no_mention = true;
+            } else if tag_name == "ambient" {
+                self.ambient = true;
+            } else if tag_name == "nsfw" {
+                self.nsfw = true;
+            } else if tag_name == "translate" {
+                self.translate = true;
+            } else if tag_name == "self-chat" {
+                self.self_chat = true;
             } else if let Some(backend_name) = tag_name.strip_prefix("use ") {
                 self.backend = Some(backend_name.to_string());
+            } else if let Some(preset_name) = tag_name.strip_prefix("preset:") {
+                self.preset = Some(preset_name.trim().to_string());
+                self.cast.push(preset_name.trim().to_string());
+            } else if let Some(overflow) = tag_name.strip_prefix("overflow:").and_then(|v| preset::Overflow::parse(v.trim())) {
+                self.overflow = Some(overflow);
+            } else if let Some(temperature) = tag_name.strip_prefix("temp ").and_then(|v| v.trim().parse::<f64>().ok()) {
+                merge_parameters(&mut self.tag_parameters, toml::toml! { temperature = temperature }.into());
+            } else if let Some(parameters) = config.parameter_tags.get(tag_name) {
+                merge_parameters(&mut self.tag_parameters, parameters.clone());
             }
         }
     }
@@ -111,13 +802,91 @@ impl ThreadInfo {
 
 struct Resolver {
     display_names: lru::LruCache<(serenity::model::id::GuildId, serenity::model::id::UserId), String>,
+    guild_names: std::collections::HashMap<serenity::model::id::GuildId, String>,
+    /// Descriptions of custom emoji, or None for ones that couldn't be described, so they aren't tried again until the bot restarts.
+    emoji_descriptions: std::collections::HashMap<serenity::model::id::EmojiId, Option<String>>,
+    /// Descriptions of avatars by URL, likewise.
+    avatar_descriptions: std::collections::HashMap<String, Option<String>>,
+    /// Members prefetching didn't find, e.g. because they've left the guild, so it doesn't look for them again.
+    absent_members: lru::LruCache<(serenity::model::id::GuildId, serenity::model::id::UserId), ()>,
+    cache: Option<std::sync::Arc<serenity::cache::Cache>>,
 }
 
+/// The most members Discord returns in one page of a guild's member list.
+const GUILD_MEMBERS_PAGE_SIZE: u64 = 1000;
+
+/// When prefetching fewer display names than this, just request each member individually instead of paging through the member list.
+const PREFETCH_INDIVIDUAL_THRESHOLD: usize = 3;
+
+/// The most pages of a guild's member list to go through when prefetching. Anyone not found by then is looked up on their own if needed.
+const MAX_PREFETCH_MEMBER_PAGES: usize = 10;
+
 impl Resolver {
     fn new(cache_size: usize) -> Self {
         Self {
             display_names: lru::LruCache::new(std::num::NonZeroUsize::new(cache_size).unwrap()),
+            guild_names: std::collections::HashMap::new(),
+            emoji_descriptions: std::collections::HashMap::new(),
+            avatar_descriptions: std::collections::HashMap::new(),
+            absent_members: lru::LruCache::new(std::num::NonZeroUsize::new(cache_size).unwrap()),
+            cache: None,
+        }
+    }
+
+    fn set_cache(&mut self, cache: std::sync::Arc<serenity::cache::Cache>) {
+        self.cache = Some(cache);
+    }
+
+    fn cached_display_name(&self, guild_id: serenity::model::id::GuildId, user_id: serenity::model::id::UserId) -> Option<String> {
+        self.cache
+            .as_ref()
+            .and_then(|cache| cache.member(guild_id, user_id))
+            .map(|member| member.display_name().into_owned())
+    }
+
+    /// Someone's roles in a guild, highest first, if they're cached.
+    fn cached_role_names(&self, guild_id: serenity::model::id::GuildId, user_id: serenity::model::id::UserId) -> Vec<String> {
+        let cache = if let Some(cache) = self.cache.as_ref() {
+            cache
+        } else {
+            return vec![];
+        };
+        let member = if let Some(member) = cache.member(guild_id, user_id) {
+            member
+        } else {
+            return vec![];
+        };
+        let mut roles = member
+            .roles
+            .iter()
+            .filter_map(|role_id| cache.role(guild_id, *role_id))
+            .collect::<Vec<_>>();
+        roles.sort_by_key(|role| std::cmp::Reverse(role.position));
+        roles.into_iter().map(|role| role.name).collect()
+    }
+
+    fn hint_guild_name(&mut self, guild_id: serenity::model::id::GuildId, name: String) {
+        self.guild_names.insert(guild_id, name);
+    }
+
+    fn hint_emoji_description(&mut self, emoji_id: serenity::model::id::EmojiId, description: Option<String>) {
+        self.emoji_descriptions.insert(emoji_id, description);
+    }
+
+    fn hint_avatar_description(&mut self, url: String, description: Option<String>) {
+        self.avatar_descriptions.insert(url, description);
+    }
+
+    async fn resolve_guild_name(
+        &mut self,
+        http: impl AsRef<serenity::http::Http>,
+        guild_id: serenity::model::id::GuildId,
+    ) -> Result<&str, serenity::Error> {
+        if !self.guild_names.contains_key(&guild_id) {
+            let guild = http.as_ref().get_guild(guild_id.0).await?;
+            self.guild_names.insert(guild_id, guild.name);
         }
+        Ok(self.guild_names.get(&guild_id).unwrap())
     }
 
     fn hint_display_name(&mut self, guild_id: serenity::model::id::GuildId, user_id: serenity::model::id::UserId, name: String) {
@@ -135,12 +904,74 @@ impl Resolver {
         user_id: serenity::model::id::UserId,
     ) -> Result<&str, serenity::Error> {
         if self.display_names.get(&(guild_id, user_id)).is_none() {
-            let member = http.as_ref().get_member(guild_id.0, user_id.0).await?;
-            self.display_names.put((guild_id, user_id), member.display_name().into_owned());
+            let display_name = if let Some(display_name) = self.cached_display_name(guild_id, user_id) {
+                display_name
+            } else {
+                http.as_ref().get_member(guild_id.0, user_id.0).await?.display_name().into_owned()
+            };
+            self.display_names.put((guild_id, user_id), display_name);
         }
         Ok(self.display_names.get(&(guild_id, user_id)).unwrap())
     }
 
+    /// Resolves many display names at once, e.g. everyone in a thread, so they don't each need a request while a prompt is being put
+    /// together. Members that aren't in the gateway cache are looked up by paging through the guild's member list.
+    async fn prefetch_display_names(
+        &mut self,
+        http: impl AsRef<serenity::http::Http>,
+        guild_id: serenity::model::id::GuildId,
+        user_ids: &std::collections::HashSet<serenity::model::id::UserId>,
+    ) -> Result<(), serenity::Error> {
+        let mut missing = std::collections::HashSet::new();
+        for &user_id in user_ids.iter() {
+            if self.display_names.contains(&(guild_id, user_id)) {
+                continue;
+            }
+            if let Some(display_name) = self.cached_display_name(guild_id, user_id) {
+                self.display_names.put((guild_id, user_id), display_name);
+                continue;
+            }
+            if self.absent_members.contains(&(guild_id, user_id)) {
+                continue;
+            }
+            missing.insert(user_id);
+        }
+
+        if missing.len() < PREFETCH_INDIVIDUAL_THRESHOLD {
+            for user_id in missing {
+                match self.resolve_display_name(&http, guild_id, user_id).await {
+                    Ok(_) => {}
+                    Err(serenity::Error::Http(e)) if e.status_code() == Some(reqwest::StatusCode::NOT_FOUND) => {
+                        self.absent_members.put((guild_id, user_id), ());
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            return Ok(());
+        }
+
+        let mut after = None;
+        for _ in 0..MAX_PREFETCH_MEMBER_PAGES {
+            if missing.is_empty() {
+                break;
+            }
+            let members = http.as_ref().get_guild_members(guild_id.0, Some(GUILD_MEMBERS_PAGE_SIZE), after).await?;
+            for member in members.iter() {
+                if missing.remove(&member.user.id) {
+                    self.display_names.put((guild_id, member.user.id), member.display_name().into_owned());
+                }
+            }
+            if (members.len() as u64) < GUILD_MEMBERS_PAGE_SIZE {
+                break;
+            }
+            after = members.last().map(|member| member.user.id.0);
+        }
+        for user_id in missing {
+            self.absent_members.put((guild_id, user_id), ());
+        }
+        Ok(())
+    }
+
     async fn resolve_message(
         &mut self,
         http: impl AsRef<serenity::http::Http>,
@@ -150,21 +981,33 @@ impl Resolver {
         let mut s = String::new();
         let mut last_index = 0;
 
-        static RESOLVE_MESSAGE_REGEX: once_cell::sync::Lazy<regex::Regex> =
-            once_cell::sync::Lazy::new(|| regex::Regex::new(r"<@!?(?P<user_id>\d+)>|<a?:(?P<emoji_name>\w+):\d+>|<#(?P<channel_id>\d+)>").unwrap());
+        static RESOLVE_MESSAGE_REGEX: once_cell::sync::Lazy<regex::Regex> = once_cell::sync::Lazy::new(|| {
+            regex::Regex::new(r"<@!?(?P<user_id>\d+)>|<a?:(?P<emoji_name>\w+):(?P<emoji_id>\d+)>|<#(?P<channel_id>\d+)>").unwrap()
+        });
 
         for capture in RESOLVE_MESSAGE_REGEX.captures_iter(content) {
             let m = capture.get(0).unwrap();
 
             s.push_str(&content[last_index..m.start()]);
 
+            // IDs too big to be real ones are just text someone typed, so they're left as they are.
             let repl = if let Some(subm) = capture.name("user_id") {
-                let user_id = subm.as_str().parse::<u64>().unwrap();
-                self.resolve_display_name(&http, guild_id, user_id.into()).await?.to_string()
+                if let Ok(user_id) = subm.as_str().parse::<u64>() {
+                    self.resolve_display_name(&http, guild_id, user_id.into()).await?.to_string()
+                } else {
+                    m.as_str().to_string()
+                }
             } else if let Some(subm) = capture.name("emoji_name") {
-                format!(":{}:", subm.as_str())
-            } else if let Some(subm) = capture.name("channel_id") {
-                let _channel_id = subm.as_str().parse::<u64>().unwrap();
+                if let Ok(emoji_id) = capture["emoji_id"].parse::<u64>() {
+                    if let Some(Some(description)) = self.emoji_descriptions.get(&serenity::model::id::EmojiId(emoji_id)) {
+                        format!(":{}: ({})", subm.as_str(), description)
+                    } else {
+                        format!(":{}:", subm.as_str())
+                    }
+                } else {
+                    m.as_str().to_string()
+                }
+            } else if capture.name("channel_id").is_some() {
                 "#".to_string()
             } else {
                 "".to_string()
@@ -177,93 +1020,6225 @@ impl Resolver {
     }
 }
 
-struct BackendBinding {
-    max_input_tokens: u32,
-    request_timeout: std::time::Duration,
-    chunk_timeout: std::time::Duration,
-    backend: Box<dyn backend::Backend + Send + Sync>,
+/// The most custom emoji to describe before a reply, so a message full of new emoji doesn't hold the reply up for long. The rest are
+/// described before later replies.
+const MAX_EMOJI_DESCRIPTIONS_PER_REPLY: usize = 3;
+
+/// The most people to list in a cast list, most recently active first, so a busy thread doesn't crowd out the conversation.
+const MAX_CAST_LIST_SIZE: usize = 20;
+
+/// The most avatars to describe before a reply, as with emoji.
+const MAX_AVATAR_DESCRIPTIONS_PER_REPLY: usize = 3;
+
+/// A link to someone's avatar as a small image, or None if they don't have one of their own.
+fn avatar_url(user: &serenity::model::user::User) -> Option<String> {
+    user.avatar
+        .as_ref()
+        .map(|avatar| format!("https://cdn.discordapp.com/avatars/{}/{}.png?size=128", user.id.0, avatar))
 }
 
-struct Handler {
-    resolver: tokio::sync::Mutex<Resolver>,
-    me_id: parking_lot::Mutex<serenity::model::id::UserId>,
-    config: Config,
-    parent_channel_id: serenity::model::id::ChannelId,
-    backends: indexmap::IndexMap<String, BackendBinding>,
-    thread_cache: tokio::sync::Mutex<ThreadCache>,
-    tags: tokio::sync::Mutex<std::collections::HashMap<serenity::model::id::ForumTagId, String>>,
+/// The custom emoji in a message, by ID and name.
+fn custom_emoji(content: &str) -> impl Iterator<Item = (serenity::model::id::EmojiId, &str)> {
+    static CUSTOM_EMOJI_REGEX: once_cell::sync::Lazy<regex::Regex> =
+        once_cell::sync::Lazy::new(|| regex::Regex::new(r"<a?:(?P<name>\w+):(?P<id>\d+)>").unwrap());
+
+    CUSTOM_EMOJI_REGEX.captures_iter(content).filter_map(|capture| {
+        Some((
+            serenity::model::id::EmojiId(capture["id"].parse::<u64>().ok()?),
+            capture.name("name").unwrap().as_str(),
+        ))
+    })
 }
 
-struct ThreadCache {
-    ids: std::collections::HashSet<serenity::model::id::ChannelId>,
-    infos: lru::LruCache<serenity::model::id::ChannelId, std::sync::Arc<tokio::sync::Mutex<ThreadInfo>>>,
+/// Counts tokens for each message on the blocking thread pool, since encoding a long history can stall the event loop for a while.
+async fn count_message_tokens(
+    backend: &std::sync::Arc<dyn backend::Backend + Send + Sync>,
+    messages: Vec<backend::Message>,
+) -> Result<(Vec<backend::Message>, Vec<usize>), anyhow::Error> {
+    let backend = backend.clone();
+    Ok(tokio::task::spawn_blocking(move || {
+        let counts = messages.iter().map(|m| backend.count_message_tokens(m)).collect();
+        (messages, counts)
+    })
+    .await?)
 }
 
-impl ThreadCache {
-    fn new(cache_size: usize) -> Self {
-        Self {
-            ids: std::collections::HashSet::new(),
-            infos: lru::LruCache::new(std::num::NonZeroUsize::new(cache_size).unwrap()),
+fn message_fingerprint(message: &backend::Message) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    message.role.hash(&mut hasher);
+    message.name.hash(&mut hasher);
+    message.content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Like count_message_tokens, but only counts messages whose counts aren't already cached on the thread.
+async fn count_thread_message_tokens(
+    thread: &ThreadInfo,
+    backend_name: &str,
+    backend: &std::sync::Arc<dyn backend::Backend + Send + Sync>,
+    messages: Vec<backend::Message>,
+) -> Result<(Vec<backend::Message>, Vec<usize>), anyhow::Error> {
+    let keys = messages
+        .iter()
+        .map(|m| (backend_name.to_string(), message_fingerprint(m)))
+        .collect::<Vec<_>>();
+    let mut counts = {
+        let mut token_counts = thread.token_counts.lock();
+        keys.iter().map(|key| token_counts.get(key).copied()).collect::<Vec<_>>()
+    };
+
+    let uncached = (0..messages.len()).filter(|&i| counts[i].is_none()).collect::<Vec<_>>();
+    if !uncached.is_empty() {
+        let (_, uncached_counts) = count_message_tokens(backend, uncached.iter().map(|&i| messages[i].clone()).collect()).await?;
+        let mut token_counts = thread.token_counts.lock();
+        for (i, count) in uncached.into_iter().zip(uncached_counts) {
+            token_counts.put(keys[i].clone(), count);
+            counts[i] = Some(count);
         }
     }
 
-    fn flush(&mut self) {
-        self.infos.clear();
-    }
+    Ok((messages, counts.into_iter().map(|count| count.unwrap()).collect()))
+}
 
-    fn add(&mut self, thread_id: serenity::model::id::ChannelId) {
+/// A breakdown of how a thread's next prompt would fill the input budget.
+struct ContextUsage {
+    overhead_tokens: usize,
+    system_tokens: usize,
+    pinned_tokens: usize,
+    num_pinned: usize,
+    history_tokens: usize,
+    num_history: usize,
+    /// The newest message that didn't fit, if any.
+    cutoff: Option<serenity::model::id::MessageId>,
+    reached_forget_break: bool,
+}
+
+impl ContextUsage {
+    fn total_tokens(&self) -> usize {
+        self.overhead_tokens + self.system_tokens + self.pinned_tokens + self.history_tokens
+    }
+}
+
+struct BackendBinding {
+    max_input_tokens: u32,
+    reserved_output_tokens: u32,
+    request_timeout: std::time::Duration,
+    chunk_timeout: std::time::Duration,
+    multi_format: template::MultiFormat,
+    nsfw_allowed: bool,
+    sfw_allowed: bool,
+    system_placement: preset::SystemPlacement,
+    think_tags: think::Config,
+    stop_sequences: Vec<String>,
+    allowed_roles: Vec<u64>,
+    budget: Option<budget::Config>,
+    /// The models the backend listed at startup, if it supports choosing one.
+    models: Option<Vec<String>>,
+    backend: std::sync::Arc<dyn backend::Backend + Send + Sync>,
+}
+
+impl BackendBinding {
+    /// How many tokens of input can be sent, leaving room for the response.
+    fn input_budget(&self) -> usize {
+        self.max_input_tokens
+            .min(self.backend.max_total_tokens().saturating_sub(self.reserved_output_tokens)) as usize
+    }
+
+    /// Sends a request to the backend and waits for the whole response.
+    async fn complete(&self, messages: &[backend::Message], parameters: &toml::Value) -> Result<String, anyhow::Error> {
+        let mut stream = tokio::time::timeout(self.request_timeout, self.backend.request(messages, parameters, None, Default::default()))
+            .await
+            .map_err(|e| anyhow::format_err!("timed out: {}", e))??;
+
+        let mut response = String::new();
+        let mut stopper = stop::Stopper::new(&self.stop_sequences);
+        while let Some(content) = tokio::time::timeout(self.chunk_timeout, stream.next())
+            .await
+            .map_err(|e| anyhow::format_err!("timed out: {}", e))?
+        {
+            response.push_str(&stopper.push(&content?));
+            if stopper.stopped() {
+                break;
+            }
+        }
+        response.push_str(&stopper.flush());
+        Ok(self.think_tags.strip(&response))
+    }
+
+    /// Requests several whole responses to the same prompt: all at once if the backend can, and otherwise side by side.
+    async fn complete_variants(&self, messages: &[backend::Message], parameters: &toml::Value, n: usize) -> Result<Vec<String>, anyhow::Error> {
+        let choices = tokio::time::timeout(self.request_timeout, self.backend.request_choices(messages, parameters, None, n))
+            .await
+            .map_err(|e| anyhow::format_err!("timed out: {}", e))??;
+        if let Some(choices) = choices {
+            return Ok(choices
+                .iter()
+                .map(|choice| {
+                    let mut stopper = stop::Stopper::new(&self.stop_sequences);
+                    let mut response = stopper.push(choice);
+                    response.push_str(&stopper.flush());
+                    self.think_tags.strip(&response)
+                })
+                .collect());
+        }
+        futures_util::future::try_join_all((0..n).map(|_| self.complete(messages, parameters))).await
+    }
+}
+
+/// What the bot learns from the gateway, shared by the events of every shard.
+#[derive(Default)]
+struct GatewayState {
+    /// The bot's own user, the same on every shard.
+    me_id: once_cell::sync::OnceCell<serenity::model::id::UserId>,
+
+    /// Shards that are connected and ready.
+    ready_shards: parking_lot::Mutex<std::collections::BTreeSet<u64>>,
+
+    /// When a shard last lost its connection, until it's caught up on what it missed.
+    offline_since: parking_lot::Mutex<Option<chrono::DateTime<chrono::Utc>>>,
+
+    /// Whether mentions missed before starting up have been caught up on.
+    caught_up_at_startup: std::sync::atomic::AtomicBool,
+
+    /// Whether the forum channel is age-restricted. Threads are age-restricted if their forum is.
+    parent_nsfw: std::sync::atomic::AtomicBool,
+
+    tags: tokio::sync::Mutex<std::collections::HashMap<serenity::model::id::ForumTagId, String>>,
+}
+
+struct Handler {
+    resolver: tokio::sync::Mutex<Resolver>,
+    gateway: GatewayState,
+    config: Config,
+    parent_channel_id: serenity::model::id::ChannelId,
+    backends: indexmap::IndexMap<String, BackendBinding>,
+    presets: std::collections::HashMap<String, preset::Preset>,
+    store: store::Store,
+    thread_cache: std::sync::Arc<tokio::sync::Mutex<ThreadCache>>,
+    filters: filter::Filters,
+    latex: Option<latex::Renderer>,
+    last_user_requests: parking_lot::Mutex<std::collections::HashMap<serenity::model::id::UserId, std::time::Instant>>,
+    /// Threads being autoplayed. Stopping one takes it out, and it stops before its next turn.
+    autoplaying: parking_lot::Mutex<std::collections::HashSet<serenity::model::id::ChannelId>>,
+    /// Everyone's /bio. The lock is held while they're saved, so saves happen in the same order as changes.
+    bios: tokio::sync::Mutex<std::collections::HashMap<serenity::model::id::UserId, String>>,
+    /// What backends with budgets have spent today and this month.
+    budget: parking_lot::Mutex<budget::Tracker>,
+}
+
+impl Handler {
+    /// The bot's own user, or a user that matches nobody until the first shard is ready.
+    fn me_id(&self) -> serenity::model::id::UserId {
+        self.gateway.me_id.get().copied().unwrap_or_default()
+    }
+
+    fn resolve_backend(&self, thread: &ThreadInfo) -> Option<(&String, &BackendBinding)> {
+        // The nsfw tag only counts in forums that allow it, and only if they're age-restricted.
+        let nsfw_backend = if thread.nsfw && self.config.nsfw_allowed && self.gateway.parent_nsfw.load(std::sync::atomic::Ordering::Relaxed) {
+            self.config.nsfw_backend.as_ref()
+        } else {
+            None
+        };
+
+        thread
+            .state
+            .backend
+            .as_ref()
+            .or(nsfw_backend)
+            .or(thread.backend.as_ref())
+            .or(self.config.defaults.backend.as_ref())
+            .and_then(|backend_name| self.backends.get_key_value(backend_name))
+            .or_else(|| self.backends.first())
+    }
+
+    /// Looks up the configured action for a reaction. Custom emoji are matched by name.
+    fn reaction_action(&self, reaction_type: &serenity::model::channel::ReactionType) -> Option<ReactionAction> {
+        let name = match reaction_type {
+            serenity::model::channel::ReactionType::Unicode(name) => name,
+            serenity::model::channel::ReactionType::Custom { name: Some(name), .. } => name,
+            _ => {
+                return None;
+            }
+        };
+
+        // Emoji may or may not come with a trailing variation selector, so ignore it when comparing.
+        let name = name.trim_end_matches('\u{fe0f}');
+        self.config
+            .reactions
+            .iter()
+            .find(|(emoji, _)| emoji.trim_end_matches('\u{fe0f}') == name)
+            .map(|(_, action)| *action)
+    }
+
+    fn has_reaction_action(&self, message: &serenity::model::channel::Message, action: ReactionAction) -> bool {
+        message.reactions.iter().any(|r| self.reaction_action(&r.reaction_type) == Some(action))
+    }
+
+    /// Pins or unpins a message to the prompt. Pins are persisted, so pinned messages are kept no matter how old they get.
+    async fn set_pinned(&self, thread: &mut ThreadInfo, message_id: serenity::model::id::MessageId, pinned: bool) -> Result<(), anyhow::Error> {
+        if thread.state.pinned.contains(&message_id) == pinned {
+            return Ok(());
+        }
+
+        if pinned {
+            thread.state.pinned.push(message_id);
+        } else {
+            thread.state.pinned.retain(|id| *id != message_id);
+        }
+        self.store.save_thread(thread.primary_message.channel_id, &thread.state).await
+    }
+
+    /// Forgets a deleted message, along with any state that refers to it.
+    async fn remove_message(&self, thread: &mut ThreadInfo, message_id: serenity::model::id::MessageId) -> Result<(), anyhow::Error> {
+        thread.messages.remove(&message_id);
+        let was_injected = thread.state.injected.remove(&message_id).is_some();
+        let was_cut_off = thread.state.incomplete_replies.remove(&message_id).is_some();
+        if was_injected || was_cut_off {
+            self.store.save_thread(thread.primary_message.channel_id, &thread.state).await?;
+        }
+        self.set_pinned(thread, message_id, false).await
+    }
+
+    /// Computes a member's permissions in the parent channel, which threads inherit their permissions from.
+    async fn member_permissions(
+        &self,
+        http: impl AsRef<serenity::http::Http>,
+        guild_id: serenity::model::id::GuildId,
+        user_id: serenity::model::id::UserId,
+    ) -> Result<serenity::model::permissions::Permissions, anyhow::Error> {
+        let http = http.as_ref();
+        let guild = http.get_guild(guild_id.0).await?;
+        let member = http.get_member(guild_id.0, user_id.0).await?;
+        let parent_channel = if let serenity::model::channel::Channel::Guild(guild_channel) = http.get_channel(self.parent_channel_id.0).await? {
+            guild_channel
+        } else {
+            return Err(anyhow::format_err!("parent channel is not a guild channel"));
+        };
+        Ok(guild.user_permissions_in(&parent_channel, &member)?)
+    }
+
+    async fn load_thread(
+        &self,
+        http: impl AsRef<serenity::http::Http>,
+        thread_id: serenity::model::id::ChannelId,
+    ) -> Result<Option<std::sync::Arc<tokio::sync::Mutex<ThreadInfo>>>, anyhow::Error> {
+        {
+            let mut thread_cache = self.thread_cache.lock().await;
+            if !thread_cache.contains(thread_id) {
+                return Ok(None);
+            }
+            if let Some(thread) = thread_cache.get(thread_id) {
+                return Ok(Some(thread));
+            }
+        }
+
+        // The cache isn't held while the thread's history is fetched, so loading one thread doesn't hold up every other.
+        let tags = self.gateway.tags.lock().await.clone();
+        let thread_info = ThreadInfo::new(&http, thread_id, &tags, &self.store, &self.config).await?;
+        let thread = if let Some(thread) = self.thread_cache.lock().await.insert(thread_id, thread_info) {
+            thread
+        } else {
+            return Ok(None);
+        };
+
+        // Look up everyone in a newly loaded thread in one go, rather than one at a time when the bot replies.
+        let (guild_id, user_ids) = {
+            let thread = thread.lock().await;
+            (
+                thread.guild_id,
+                std::iter::once(&thread.primary_message)
+                    .chain(thread.messages.values())
+                    .map(|m| m.author.id)
+                    .collect::<std::collections::HashSet<_>>(),
+            )
+        };
+        if let Err(e) = self.resolver.lock().await.prefetch_display_names(&http, guild_id, &user_ids).await {
+            log::warn!("failed to prefetch display names for {}: {:?}", thread_id, e);
+        }
+
+        Ok(Some(thread))
+    }
+
+    /// Parses the thread's settings and validates them against its backend.
+    fn validate_settings(&self, thread: &ThreadInfo) -> Result<ChatSettings, anyhow::Error> {
+        let settings = thread.settings(&self.presets, &self.config.defaults)?;
+        if let Some((_, BackendBinding { backend, .. })) = self.resolve_backend(thread) {
+            backend.validate_parameters(&settings.parameters)?;
+        }
+        Ok(settings)
+    }
+
+    /// Parses and validates the thread's settings against its backend. If they're invalid, the error is posted to the thread (only once
+    /// per distinct error) and None is returned.
+    async fn check_settings(&self, http: impl AsRef<serenity::http::Http>, thread: &mut ThreadInfo) -> Result<Option<ChatSettings>, anyhow::Error> {
+        let e = match self.validate_settings(thread) {
+            Ok(settings) => {
+                thread.reported_settings_error = None;
+                return Ok(Some(settings));
+            }
+            Err(e) => e.to_string(),
+        };
+
+        if thread.reported_settings_error.as_ref() == Some(&e) {
+            return Ok(None);
+        }
+
+        thread
+            .primary_message
+            .channel_id
+            .send_message(&http, |m| {
+                m.embed(|em| {
+                    em.title("Invalid settings")
+                        .color(serenity::utils::colours::css::DANGER)
+                        .description(format!(
+                            "I couldn't understand the settings for this thread, so I can't reply until they're fixed.\n```\n{}\n```",
+                            e
+                        ))
+                })
+            })
+            .await?;
+        thread.reported_settings_error = Some(e);
+
+        Ok(None)
+    }
+
+    /// Posts the settings that override the first post in a pinned message, editing the one posted before if there is one, or deletes
+    /// it if nothing overrides the first post anymore.
+    async fn sync_settings_message(&self, http: &serenity::http::Http, thread: &mut ThreadInfo) -> Result<(), anyhow::Error> {
+        if !self.config.settings_message {
+            return Ok(());
+        }
+        let channel_id = thread.primary_message.channel_id;
+
+        let settings = if let Some(settings) = thread.state.settings.as_ref() {
+            settings
+        } else {
+            if let Some(message_id) = thread.state.settings_message.take() {
+                self.store.save_thread(channel_id, &thread.state).await?;
+                channel_id.delete_message(http, message_id).await?;
+            }
+            return Ok(());
+        };
+
+        let description = format!("```\n{}\n```", settings.replace("```", "`\u{200b}``"));
+        let description = if description.chars().count() > EMBED_DESCRIPTION_MAX_LENGTH {
+            "These settings are too long to show here. Use /settings to see them.".to_string()
+        } else {
+            description
+        };
+
+        if let Some(message_id) = thread.state.settings_message {
+            match channel_id
+                .edit_message(http, message_id, |m| {
+                    m.embed(|e| {
+                        e.title("Settings")
+                            .color(serenity::utils::colours::css::POSITIVE)
+                            .description(&description)
+                    })
+                })
+                .await
+            {
+                Ok(_) => {
+                    return Ok(());
+                }
+                // Someone deleted it, so post it again.
+                Err(serenity::Error::Http(e)) if e.status_code() == Some(reqwest::StatusCode::NOT_FOUND) => {}
+                Err(e) => {
+                    return Err(e.into());
+                }
+            }
+        }
+
+        let message = channel_id
+            .send_message(http, |m| {
+                m.embed(|e| {
+                    e.title("Settings")
+                        .color(serenity::utils::colours::css::POSITIVE)
+                        .description(&description)
+                })
+            })
+            .await?;
+        thread.state.settings_message = Some(message.id);
+        self.store.save_thread(channel_id, &thread.state).await?;
+        if let Err(e) = message.pin(http).await {
+            log::warn!("could not pin settings message: {:?}", e);
+        }
+        Ok(())
+    }
+
+    async fn handle_settings_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let thread = thread.lock().await;
+
+        if !thread.can_manage(app_command.user.id, app_command.member.as_ref().and_then(|m| m.permissions)) {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("Only the creator of this thread or moderators can change its settings.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+
+        let source = thread.settings_source();
+        let (system_message, parameters) = source.split_once("\n---\n").unwrap_or((&source, ""));
+
+        if system_message.chars().count() > MODAL_INPUT_TEXT_MAX_LENGTH || parameters.chars().count() > MODAL_INPUT_TEXT_MAX_LENGTH {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("These settings are too long to edit here, sorry!"))
+                })
+                .await?;
+            return Ok(());
+        }
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(serenity::model::application::interaction::InteractionResponseType::Modal)
+                    .interaction_response_data(|d| {
+                        d.custom_id(SETTINGS_MODAL_ID).title("Thread settings").components(|c| {
+                            c.create_action_row(|row| {
+                                row.create_input_text(|t| {
+                                    t.custom_id(SETTINGS_MODAL_SYSTEM_MESSAGE_ID)
+                                        .label("System prompt")
+                                        .style(serenity::model::application::component::InputTextStyle::Paragraph)
+                                        .max_length(MODAL_INPUT_TEXT_MAX_LENGTH as u64)
+                                        .required(false)
+                                        .value(system_message)
+                                })
+                            })
+                            .create_action_row(|row| {
+                                row.create_input_text(|t| {
+                                    t.custom_id(SETTINGS_MODAL_PARAMETERS_ID)
+                                        .label("Model parameters (TOML)")
+                                        .style(serenity::model::application::component::InputTextStyle::Paragraph)
+                                        .max_length(MODAL_INPUT_TEXT_MAX_LENGTH as u64)
+                                        .required(false)
+                                        .value(parameters)
+                                })
+                            })
+                        })
+                    })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_model_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let backend_name = if let Some(backend_name) = app_command
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == "backend")
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_str())
+        {
+            backend_name
+        } else {
+            return Ok(());
+        };
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        if !thread.can_manage(app_command.user.id, app_command.member.as_ref().and_then(|m| m.permissions)) {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("Only the creator of this thread or moderators can change its backend.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+
+        let binding = if let Some(binding) = self.backends.get(backend_name) {
+            binding
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content(format!("I don't know of a backend called {}.", backend_name)))
+                })
+                .await?;
+            return Ok(());
+        };
+
+        let model = app_command
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == "model")
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_str());
+        if let Some(model) = model {
+            let known = binding.models.as_ref().map(|models| models.iter().any(|m| m == model));
+            if known != Some(true) {
+                app_command
+                    .create_interaction_response(&ctx.http, |r| {
+                        r.interaction_response_data(|d| {
+                            d.ephemeral(true).content(if known.is_none() {
+                                format!("The {} backend doesn't support choosing a model.", backend_name)
+                            } else {
+                                format!("The {} backend doesn't have a model called {}.", backend_name, model)
+                            })
+                        })
+                    })
+                    .await?;
+                return Ok(());
+            }
+        }
+
+        let old_backend = std::mem::replace(&mut thread.state.backend, Some(backend_name.to_string()));
+        let old_model = std::mem::replace(&mut thread.state.model, model.map(|model| model.to_string()));
+        if let Err(e) = self.validate_settings(&thread) {
+            thread.state.backend = old_backend;
+            thread.state.model = old_model;
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true).content(format!(
+                            "This thread's settings don't work with {}, so I didn't switch to it.\n```\n{}\n```",
+                            backend_name, e
+                        ))
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+        self.store.save_thread(app_command.channel_id, &thread.state).await?;
+        thread.reported_settings_error = None;
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| {
+                    d.embed(|e| {
+                        e.color(serenity::utils::colours::css::POSITIVE).description(format!(
+                            "{} switched this thread to **{}**.",
+                            app_command.user.mention(),
+                            if let Some(model) = model {
+                                format!("{} ({})", backend_name, model)
+                            } else {
+                                backend_name.to_string()
+                            }
+                        ))
+                    })
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_prefill_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let text = app_command
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == "text")
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_str())
+            .filter(|text| !text.is_empty())
+            .map(|text| text.replace("\\n", "\n"));
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        if !thread.can_manage(app_command.user.id, app_command.member.as_ref().and_then(|m| m.permissions)) {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("Only the creator of this thread or moderators can change how its replies start.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+
+        thread.state.assistant_prefill = text.clone();
+        self.store.save_thread(app_command.channel_id, &thread.state).await?;
+
+        let supported = self
+            .resolve_backend(&thread)
+            .map(|(_, binding)| binding.backend.supports_prefill())
+            .unwrap_or(false);
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| {
+                    d.embed(|e| {
+                        let mut description = if let Some(text) = text.as_ref() {
+                            format!("{} set this thread's replies to start with:\n```\n{}\n```", app_command.user.mention(), text)
+                        } else {
+                            format!("{} stopped setting how this thread's replies start.", app_command.user.mention())
+                        };
+                        if text.is_some() && !supported {
+                            description.push_str("\nThis thread's backend doesn't support this, so it won't take effect unless the thread is switched to one that does.");
+                        }
+                        e.color(serenity::utils::colours::css::POSITIVE).description(description)
+                    })
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_bio_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        if !self.config.cast_list.as_ref().map(|cast_list| cast_list.bios).unwrap_or(false) {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("Bios aren't turned on here."))
+                })
+                .await?;
+            return Ok(());
+        }
+
+        let subcommand = if let Some(subcommand) = app_command.data.options.first() {
+            subcommand
+        } else {
+            return Ok(());
+        };
+        let text = match subcommand.name.as_str() {
+            BIO_SET_SUBCOMMAND_NAME => {
+                let text = subcommand
+                    .options
+                    .iter()
+                    .find(|o| o.name == "text")
+                    .and_then(|o| o.value.as_ref())
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("")
+                    .split_whitespace()
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                if text.chars().count() > MAX_BIO_LENGTH {
+                    app_command
+                        .create_interaction_response(&ctx.http, |r| {
+                            r.interaction_response_data(|d| {
+                                d.ephemeral(true)
+                                    .content(format!("Bios can be at most {} characters long.", MAX_BIO_LENGTH))
+                            })
+                        })
+                        .await?;
+                    return Ok(());
+                }
+                Some(text).filter(|text| !text.is_empty())
+            }
+            BIO_CLEAR_SUBCOMMAND_NAME => None,
+            _ => {
+                return Ok(());
+            }
+        };
+
+        {
+            let mut bios = self.bios.lock().await;
+            if let Some(text) = text.as_ref() {
+                bios.insert(app_command.user.id, text.clone());
+            } else {
+                bios.remove(&app_command.user.id);
+            }
+            self.store.save_bios(&bios).await?;
+        }
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| {
+                    d.ephemeral(true).embed(|e| {
+                        e.color(serenity::utils::colours::css::POSITIVE)
+                            .description(if let Some(text) = text.as_ref() {
+                                format!("Your bio is now:\n>>> {}", text)
+                            } else {
+                                "Your bio is cleared.".to_string()
+                            })
+                    })
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_variants_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let count = app_command
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == "count")
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_u64())
+            .map(|count| (count as usize).clamp(1, MAX_VARIANTS))
+            .unwrap_or(1);
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        if !thread.can_manage(app_command.user.id, app_command.member.as_ref().and_then(|m| m.permissions)) {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("Only the creator of this thread or moderators can change how many ways replies are written.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+
+        thread.state.variants = if count > 1 { Some(count) } else { None };
+        self.store.save_thread(app_command.channel_id, &thread.state).await?;
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| {
+                    d.embed(|e| {
+                        e.color(serenity::utils::colours::css::POSITIVE).description(if count > 1 {
+                            format!(
+                                "{} set replies in this thread to be written {} ways, for whoever asked to pick one.",
+                                app_command.user.mention(),
+                                count
+                            )
+                        } else {
+                            format!("{} set replies in this thread to be written just one way.", app_command.user.mention())
+                        })
+                    })
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_status_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let thread = thread.lock().await;
+
+        // Counting tokens can take longer than Discord waits for a response.
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(serenity::model::application::interaction::InteractionResponseType::DeferredChannelMessageWithSource)
+            })
+            .await?;
+
+        let (backend_name, binding) = if let Some((backend_name, binding)) = self.resolve_backend(&thread) {
+            (backend_name, binding)
+        } else {
+            return Ok(());
+        };
+
+        let mut flags = vec![];
+        if thread.no_mention {
+            flags.push("no mention");
+        }
+        if thread.ambient {
+            flags.push("ambient");
+        }
+        if thread.branching {
+            flags.push("branching");
+        }
+        if thread.reply_on_edit {
+            flags.push("reply on edit");
+        }
+        if thread.translate {
+            flags.push("translate");
+        }
+        let mode = format!(
+            "{}{}",
+            match thread.mode {
+                ThreadMode::Single => "single-user",
+                ThreadMode::Multi => "multi-user",
+            },
+            if flags.is_empty() {
+                "".to_string()
+            } else {
+                format!(" ({})", flags.join(", "))
+            }
+        );
+
+        let (parameters, budget) = match self.validate_settings(&thread) {
+            Ok(settings) => {
+                let parameters = toml::to_string(&settings.parameters)?;
+                let usage = self.context_usage(&thread, backend_name, binding, &settings).await?;
+                (
+                    if parameters.trim().is_empty() {
+                        "Backend defaults".to_string()
+                    } else {
+                        format!("```toml\n{}```", parameters)
+                    },
+                    format!(
+                        "About {} of {} tokens, covering {} message{}",
+                        usage.total_tokens(),
+                        binding.input_budget(),
+                        usage.num_history,
+                        if usage.num_history == 1 { "" } else { "s" }
+                    ),
+                )
+            }
+            Err(e) => (format!("Invalid: {}", e), "Unknown".to_string()),
+        };
+
+        app_command
+            .edit_original_interaction_response(&ctx.http, |r| {
+                r.embed(|e| {
+                    e.title("Thread status")
+                        .color(serenity::utils::colours::css::POSITIVE)
+                        .field("Mode", mode, true)
+                        .field(
+                            "Backend",
+                            format!("{} ({})", backend_name, thread.state.model.as_deref().unwrap_or(binding.backend.model())),
+                            true,
+                        )
+                        .field("Pinned messages", thread.state.pinned.len(), true)
+                        .field("Parameters", parameters, false)
+                        .field("Context", budget, false)
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_tokens_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let thread = thread.lock().await;
+
+        let settings = match self.validate_settings(&thread) {
+            Ok(settings) => settings,
+            Err(e) => {
+                app_command
+                    .create_interaction_response(&ctx.http, |r| {
+                        r.interaction_response_data(|d| {
+                            d.ephemeral(true)
+                                .content(format!("This thread's settings are invalid, so I can't tell.\n```\n{}\n```", e))
+                        })
+                    })
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(serenity::model::application::interaction::InteractionResponseType::DeferredChannelMessageWithSource)
+                    .interaction_response_data(|d| d.ephemeral(true))
+            })
+            .await?;
+
+        let (backend_name, binding) = if let Some((backend_name, binding)) = self.resolve_backend(&thread) {
+            (backend_name, binding)
+        } else {
+            return Ok(());
+        };
+        let usage = self.context_usage(&thread, backend_name, binding, &settings).await?;
+
+        let cutoff = if let Some(cutoff) = usage.cutoff {
+            format!(
+                "Everything from {} back is left out, since it doesn't fit.",
+                cutoff.link(thread.primary_message.channel_id, Some(thread.guild_id))
+            )
+        } else if usage.reached_forget_break {
+            "Everything since the last /forget fits.".to_string()
+        } else if thread.loaded_from.is_some() {
+            "Everything I've loaded fits, and I'll load more of the thread when I reply if there's room.".to_string()
+        } else {
+            "The whole thread fits.".to_string()
+        };
+
+        app_command
+            .edit_original_interaction_response(&ctx.http, |r| {
+                r.embed(|e| {
+                    e.title(format!("Token budget for {}", backend_name))
+                        .color(serenity::utils::colours::css::POSITIVE)
+                        .field("System prompt", usage.system_tokens, true)
+                        .field(format!("Pinned messages ({})", usage.num_pinned), usage.pinned_tokens, true)
+                        .field("Overhead", usage.overhead_tokens, true)
+                        .field(format!("History ({} messages)", usage.num_history), usage.history_tokens, true)
+                        .field("Total", format!("{} of {}", usage.total_tokens(), binding.input_budget()), true)
+                        .field(
+                            "Reserved for output",
+                            format!("{} of {}", binding.reserved_output_tokens, binding.backend.max_total_tokens()),
+                            true,
+                        )
+                        .description(cutoff)
+                        .footer(|f| f.text("Counts are estimates, and don't include formatting added for multi-user threads."))
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_debug_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        match app_command.data.options.first().map(|o| o.name.as_str()) {
+            Some(DEBUG_PROMPT_SUBCOMMAND_NAME) => self.handle_debug_prompt_command(ctx, app_command).await,
+            _ => Ok(()),
+        }
+    }
+
+    async fn handle_debug_prompt_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        let settings = match self.validate_settings(&thread) {
+            Ok(settings) => settings,
+            Err(e) => {
+                app_command
+                    .create_interaction_response(&ctx.http, |r| {
+                        r.interaction_response_data(|d| d.ephemeral(true).content(format!("This thread's settings are invalid.\n```\n{}\n```", e)))
+                    })
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(serenity::model::application::interaction::InteractionResponseType::DeferredChannelMessageWithSource)
+                    .interaction_response_data(|d| d.ephemeral(true))
+            })
+            .await?;
+
+        let (backend_name, _, new_message, messages, input_tokens) =
+            if let Some(prompt) = self.build_prompt_for_latest(&ctx.http, &mut thread, &settings).await? {
+                prompt
+            } else {
+                return Ok(());
+            };
+
+        app_command
+            .create_followup_message(&ctx.http, |f| {
+                f.ephemeral(true)
+                    .content(format!(
+                        "The prompt {} would be sent in reply to {}, about {} tokens.",
+                        backend_name,
+                        new_message.link(),
+                        input_tokens
+                    ))
+                    .add_file(serenity::model::channel::AttachmentType::Bytes {
+                        data: format!("{:?}\n\n{:#?}\n", settings.parameters, messages).into_bytes().into(),
+                        filename: "prompt.txt".to_string(),
+                    })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Loads as much of a thread's history as a reply to its newest message needs, and builds the prompt for it, for commands that look at
+    /// or try out a reply without the usual reply going out. None if there's no backend to send it to.
+    async fn build_prompt_for_latest(
+        &self,
+        http: &serenity::http::Http,
+        thread: &mut ThreadInfo,
+        settings: &ChatSettings,
+    ) -> Result<Option<(&String, &BackendBinding, serenity::model::channel::Message, Vec<backend::Message>, usize)>, anyhow::Error> {
+        let (backend_name, binding) = if let Some((backend_name, binding)) = self.resolve_backend(thread) {
+            (backend_name, binding)
+        } else {
+            return Ok(None);
+        };
+
+        let new_message = thread.latest_message();
+
+        if let Err(e) = self
+            .load_history_for_prompt(http, thread, new_message.id, backend_name, &binding.backend, binding.input_budget())
+            .await
+        {
+            log::warn!("failed to load more history for {}: {:?}", new_message.channel_id, e);
+        }
+
+        if settings.overflow == preset::Overflow::DropMiddle {
+            if let Err(e) = thread.load_opening(http).await {
+                log::warn!("failed to load the opening of {}: {:?}", new_message.channel_id, e);
+            }
+        }
+
+        if thread.reactions_refreshed_at.elapsed() >= self.config.reaction_refresh_interval {
+            if let Err(e) = thread.refresh_reactions(http).await {
+                log::warn!("failed to refresh reactions in {}: {:?}", new_message.channel_id, e);
+            }
+        }
+        let (messages, input_tokens, _) = self
+            .build_prompt(http, thread, &new_message, settings, backend_name, binding, binding.input_budget())
+            .await?;
+
+        Ok(Some((backend_name, binding, new_message, messages, input_tokens)))
+    }
+
+    async fn handle_sweep_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let option = |name: &str| {
+            app_command
+                .data
+                .options
+                .iter()
+                .find(|o| o.name == name)
+                .and_then(|o| o.value.as_ref())
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+                .to_string()
+        };
+        let parameter = option("parameter");
+        let values = option("values")
+            .split_whitespace()
+            .map(|value| {
+                toml::from_str::<toml::Table>(&format!("value = {}", value))
+                    .ok()
+                    .and_then(|mut table| table.remove("value"))
+                    .ok_or_else(|| value.to_string())
+            })
+            .collect::<Result<Vec<_>, _>>();
+        let values = match values {
+            Ok(values) if !values.is_empty() && values.len() <= MAX_SWEEP_VALUES && !parameter.is_empty() => values,
+            Ok(_) => {
+                app_command
+                    .create_interaction_response(&ctx.http, |r| {
+                        r.interaction_response_data(|d| {
+                            d.ephemeral(true)
+                                .content(format!("Give a parameter and between 1 and {} values to try it at.", MAX_SWEEP_VALUES))
+                        })
+                    })
+                    .await?;
+                return Ok(());
+            }
+            Err(value) => {
+                app_command
+                    .create_interaction_response(&ctx.http, |r| {
+                        r.interaction_response_data(|d| {
+                            d.ephemeral(true)
+                                .content(format!("`{}` isn't a valid value. Strings need quotes, e.g. `\"high\"`.", value))
+                        })
+                    })
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        let settings = match self.validate_settings(&thread) {
+            Ok(settings) => settings,
+            Err(e) => {
+                app_command
+                    .create_interaction_response(&ctx.http, |r| {
+                        r.interaction_response_data(|d| d.ephemeral(true).content(format!("This thread's settings are invalid.\n```\n{}\n```", e)))
+                    })
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(serenity::model::application::interaction::InteractionResponseType::DeferredChannelMessageWithSource)
+            })
+            .await?;
+
+        let (backend_name, binding, new_message, mut messages, _) =
+            if let Some(prompt) = self.build_prompt_for_latest(&ctx.http, &mut thread, &settings).await? {
+                prompt
+            } else {
+                return Ok(());
+            };
+        push_prefill(&mut messages, binding, &settings);
+
+        // Everything is posted as titled embeds, which are notices rather than part of the conversation, so the sweep doesn't end up in the
+        // prompt of the next reply.
+        app_command
+            .edit_original_interaction_response(&ctx.http, |r| {
+                r.embed(|e| {
+                    e.title(format!("Sweeping {}", parameter)).description(format!(
+                        "Replies from {} to {} at each value, without posting them as replies.",
+                        backend_name,
+                        new_message.link()
+                    ))
+                })
+            })
+            .await?;
+
+        for value in values {
+            let mut parameters = settings.parameters.clone();
+            merge_parameters(
+                &mut parameters,
+                toml::Value::Table(toml::Table::from_iter([(parameter.clone(), value.clone())])),
+            );
+            let result = match binding.backend.validate_parameters(&parameters) {
+                Ok(()) => binding.complete(&messages, &parameters).await,
+                Err(e) => Err(e),
+            };
+            let (color, reply) = match result {
+                Ok(reply) => (serenity::utils::colours::css::POSITIVE, self.filters.redact(&reply)),
+                Err(e) => (serenity::utils::colours::css::DANGER, format!("Failed: {}", e)),
+            };
+            let mut chunker = unichunk::Chunker::new(EMBED_DESCRIPTION_MAX_LENGTH);
+            let description = chunker.push(&reply).into_iter().next().unwrap_or_else(|| chunker.flush());
+            app_command
+                .create_followup_message(&ctx.http, |f| {
+                    f.embed(|e| e.title(format!("{} = {}", parameter, value)).color(color).description(description))
+                })
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle_export_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let format = app_command
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == "format")
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_str())
+            .unwrap_or(EXPORT_FORMAT_MARKDOWN);
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        let settings = match self.validate_settings(&thread) {
+            Ok(settings) => settings,
+            Err(e) => {
+                app_command
+                    .create_interaction_response(&ctx.http, |r| {
+                        r.interaction_response_data(|d| d.ephemeral(true).content(format!("This thread's settings are invalid.\n```\n{}\n```", e)))
+                    })
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(serenity::model::application::interaction::InteractionResponseType::DeferredChannelMessageWithSource)
+                    .interaction_response_data(|d| d.ephemeral(true))
+            })
+            .await?;
+
+        let (backend_name, binding) = if let Some((backend_name, binding)) = self.resolve_backend(&thread) {
+            (backend_name, binding)
+        } else {
+            return Ok(());
+        };
+
+        // Everything since the last forget break is exported, whether or not it would fit in the backend's budget.
+        let new_message = thread.latest_message();
+        if let Err(e) = self
+            .load_history_for_prompt(&ctx.http, &mut thread, new_message.id, backend_name, &binding.backend, usize::MAX)
+            .await
+        {
+            log::warn!("failed to load more history for {}: {:?}", new_message.channel_id, e);
+        }
+        let (messages, _, _) = self
+            .build_prompt(&ctx.http, &thread, &new_message, &settings, backend_name, binding, usize::MAX)
+            .await?;
+
+        let transcript = transcript::Transcript {
+            title: Some(thread.title.clone()),
+            messages: messages.iter().map(transcript::Message::from).collect(),
+        };
+        let (data, extension) = if format == EXPORT_FORMAT_JSON {
+            (transcript.to_json(), "json")
+        } else {
+            (transcript.to_markdown(), "md")
+        };
+
+        app_command
+            .create_followup_message(&ctx.http, |f| {
+                f.ephemeral(true).add_file(serenity::model::channel::AttachmentType::Bytes {
+                    data: data.into_bytes().into(),
+                    filename: format!("{}.{}", thread.primary_message.channel_id, extension),
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Has the bot reply to itself a number of times, as the next of an ensemble's cast each time if it's an ensemble. Only whoever started it
+    /// sees how it's going, and can stop it.
+    async fn handle_autoplay_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let turns = app_command
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == "turns")
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_u64())
+            .unwrap_or(1)
+            .min(self.config.autoplay_max_turns as u64);
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+
+        {
+            let thread = thread.lock().await;
+            if !thread.self_chat {
+                app_command
+                    .create_interaction_response(&ctx.http, |r| {
+                        r.interaction_response_data(|d| d.ephemeral(true).content("This only works in threads with the self-chat tag."))
+                    })
+                    .await?;
+                return Ok(());
+            }
+            if !thread.can_manage(app_command.user.id, app_command.member.as_ref().and_then(|m| m.permissions)) {
+                app_command
+                    .create_interaction_response(&ctx.http, |r| {
+                        r.interaction_response_data(|d| d.ephemeral(true).content("Only the creator of this thread or moderators can do that."))
+                    })
+                    .await?;
+                return Ok(());
+            }
+        }
+
+        if !self.autoplaying.lock().insert(app_command.channel_id) {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("I'm already autoplaying this thread."))
+                })
+                .await?;
+            return Ok(());
+        }
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| {
+                    d.ephemeral(true).content(format!("Autoplaying {} turns.", turns)).components(|c| {
+                        c.create_action_row(|row| {
+                            row.create_button(|b| {
+                                b.custom_id(format!("{}{}", STOP_AUTOPLAY_BUTTON_ID_PREFIX, app_command.channel_id.0))
+                                    .label("Stop")
+                                    .style(serenity::model::application::component::ButtonStyle::Danger)
+                            })
+                        })
+                    })
+                })
+            })
+            .await?;
+
+        let r = async {
+            let mut previous = None;
+            let mut played = 0;
+            for turn in 0..turns {
+                if turn > 0 {
+                    tokio::time::sleep(self.config.autoplay_delay).await;
+                }
+                if !self.autoplaying.lock().contains(&app_command.channel_id) {
+                    break;
+                }
+
+                let mut thread = thread.lock().await;
+                let mut prompt = if let Some(prompt) = thread.messages.values().rev().find(|m| self.is_prompt_message(&thread, m)) {
+                    prompt.clone()
+                } else {
+                    thread.primary_message.clone()
+                };
+                prompt.guild_id = Some(thread.guild_id);
+
+                let speaker = if thread.cast.len() > 1 {
+                    let previous = previous.clone().or_else(|| thread.state.speakers.get(&prompt.id).cloned());
+                    self.pick_speaker(&thread, &prompt, previous.as_deref(), true).await?
+                } else {
+                    None
+                };
+                thread.requester = Some(app_command.user.id);
+                let r = self.speak(ctx, &mut thread, &prompt, speaker.clone()).await;
+                thread.requester = None;
+                if r?.is_none() {
+                    break;
+                }
+                previous = speaker;
+                played += 1;
+            }
+            Ok::<_, anyhow::Error>(played)
+        }
+        .await;
+        self.autoplaying.lock().remove(&app_command.channel_id);
+        let played = r?;
+
+        // The response can't be edited once the interaction expires, but by then nobody's waiting on it anyway.
+        if let Err(e) = app_command
+            .edit_original_interaction_response(&ctx.http, |r| {
+                r.content(format!("Autoplayed {} of {} turns.", played, turns)).components(|c| c)
+            })
+            .await
+        {
+            log::warn!("failed to update autoplay status in {}: {:?}", app_command.channel_id, e);
+        }
+        Ok(())
+    }
+
+    async fn handle_analytics_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(serenity::model::application::interaction::InteractionResponseType::DeferredChannelMessageWithSource)
+                    .interaction_response_data(|d| d.ephemeral(true))
+            })
+            .await?;
+
+        let records = self.store.load_usage(chrono::Utc::now() - chrono::Duration::days(7)).await?;
+        if records.is_empty() {
+            app_command
+                .edit_original_interaction_response(&ctx.http, |r| r.content("Nothing has been requested in the last week."))
+                .await?;
+            return Ok(());
+        }
+        let report = analytics::Report::new(&records);
+
+        app_command
+            .edit_original_interaction_response(&ctx.http, |r| {
+                r.embed(|e| {
+                    e.title("Last 7 days")
+                        .description(format!("{} requests", report.requests))
+                        .field(
+                            "Busiest threads",
+                            report
+                                .busiest_threads
+                                .iter()
+                                .map(|(thread_id, requests)| format!("<#{}> — {} requests", thread_id.0, requests))
+                                .collect::<Vec<_>>()
+                                .join("\n"),
+                            false,
+                        )
+                        .field(
+                            "Top users",
+                            report
+                                .top_users
+                                .iter()
+                                .map(|(user_id, tokens)| format!("<@{}> — {} tokens", user_id.0, tokens))
+                                .collect::<Vec<_>>()
+                                .join("\n"),
+                            false,
+                        )
+                        .field(
+                            "Backends",
+                            report
+                                .backends
+                                .iter()
+                                .map(|backend| {
+                                    format!(
+                                        "{} — {} requests, {:.1}% failed",
+                                        backend.name,
+                                        backend.requests,
+                                        backend.failed as f64 * 100.0 / backend.requests as f64
+                                    )
+                                })
+                                .collect::<Vec<_>>()
+                                .join("\n"),
+                            false,
+                        )
+                        .field(
+                            "Peak hours (UTC)",
+                            report
+                                .peak_hours
+                                .iter()
+                                .map(|(hour, requests)| format!("{:02}:00 — {} requests", hour, requests))
+                                .collect::<Vec<_>>()
+                                .join("\n"),
+                            false,
+                        )
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Estimates how much of the input budget the thread's next prompt would use, and where its history would be cut off.
+    async fn context_usage(
+        &self,
+        thread: &ThreadInfo,
+        backend_name: &str,
+        binding: &BackendBinding,
+        settings: &ChatSettings,
+    ) -> Result<ContextUsage, anyhow::Error> {
+        let me_id = self.me_id();
+        let input_budget = binding.input_budget();
+
+        // Like load_history_for_prompt, this only needs to be a rough estimate, so messages are counted without formatting.
+        let to_backend_message = |message: &serenity::model::channel::Message| backend::Message {
+            role: if message.author.id == me_id {
+                backend::Role::Assistant
+            } else {
+                backend::Role::User(message.author.name.clone())
+            },
+            name: None,
+            content: message.content.clone(),
+            mentioned: false,
+        };
+
+        let mut pinned_messages = vec![backend::Message {
+            role: backend::Role::System,
+            name: None,
+            content: settings.system_message.clone(),
+            mentioned: false,
+        }];
+        pinned_messages.extend(
+            thread
+                .messages
+                .values()
+                .filter(|message| self.is_prompt_message(thread, message) && thread.state.pinned.contains(&message.id))
+                .map(to_backend_message),
+        );
+        let num_pinned = pinned_messages.len() - 1;
+        let (_, counts) = count_thread_message_tokens(thread, backend_name, &binding.backend, pinned_messages).await?;
+        let mut usage = ContextUsage {
+            overhead_tokens: binding.backend.num_overhead_tokens(),
+            system_tokens: counts[0],
+            pinned_tokens: counts[1..].iter().sum(),
+            num_pinned,
+            history_tokens: 0,
+            num_history: 0,
+            cutoff: None,
+            reached_forget_break: false,
+        };
+
+        let history = thread.messages.values().rev().collect::<Vec<_>>();
+        let forget_break = history.iter().position(|message| thread.is_forget_break(message, me_id));
+        usage.reached_forget_break = forget_break.is_some();
+        let history = history
+            .into_iter()
+            .take(forget_break.unwrap_or(usize::MAX))
+            .filter(|message| self.is_prompt_message(thread, message) && !thread.state.pinned.contains(&message.id))
+            .collect::<Vec<_>>();
+
+        'history: for batch in history.chunks(TOKEN_COUNT_BATCH_SIZE) {
+            let (_, counts) = count_thread_message_tokens(
+                thread,
+                backend_name,
+                &binding.backend,
+                batch.iter().map(|m| to_backend_message(m)).collect(),
+            )
+            .await?;
+            for (message, message_tokens) in batch.iter().zip(counts) {
+                if usage.total_tokens() + message_tokens > input_budget {
+                    usage.cutoff = Some(message.id);
+                    break 'history;
+                }
+                usage.history_tokens += message_tokens;
+                usage.num_history += 1;
+            }
+        }
+
+        Ok(usage)
+    }
+
+    async fn handle_model_autocomplete(
+        &self,
+        ctx: &serenity::client::Context,
+        autocomplete: &serenity::model::application::interaction::autocomplete::AutocompleteInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let focused = if let Some(focused) = autocomplete.data.options.iter().find(|o| o.focused) {
+            focused
+        } else {
+            return Ok(());
+        };
+        let partial = focused.value.as_ref().and_then(|v| v.as_str()).unwrap_or("").to_lowercase();
+
+        // Models are offered from whichever backend has been picked so far.
+        let choices = if focused.name == "model" {
+            autocomplete
+                .data
+                .options
+                .iter()
+                .find(|o| o.name == "backend")
+                .and_then(|o| o.value.as_ref())
+                .and_then(|v| v.as_str())
+                .and_then(|backend_name| self.backends.get(backend_name))
+                .and_then(|binding| binding.models.as_ref())
+                .map(|models| models.iter().collect::<Vec<_>>())
+                .unwrap_or_default()
+        } else {
+            self.backends.keys().collect::<Vec<_>>()
+        };
+
+        autocomplete
+            .create_autocomplete_response(&ctx.http, |r| {
+                for choice in choices
+                    .into_iter()
+                    .filter(|choice| choice.to_lowercase().contains(&partial))
+                    .take(AUTOCOMPLETE_MAX_CHOICES)
+                {
+                    r.add_string_choice(choice, choice);
+                }
+                r
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_settings_modal(
+        &self,
+        ctx: &serenity::client::Context,
+        modal_submit: &serenity::model::application::interaction::modal::ModalSubmitInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, modal_submit.channel_id).await? {
+            thread
+        } else {
+            modal_submit
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("I can't find this thread anymore, so I didn't save those settings.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        if !thread.can_manage(modal_submit.user.id, modal_submit.member.as_ref().and_then(|m| m.permissions)) {
+            modal_submit
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("Only the creator of this thread or moderators can change its settings.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+
+        let mut system_message = "";
+        let mut parameters = "";
+        for row in modal_submit.data.components.iter() {
+            for component in row.components.iter() {
+                if let serenity::model::application::component::ActionRowComponent::InputText(input_text) = component {
+                    match input_text.custom_id.as_str() {
+                        SETTINGS_MODAL_SYSTEM_MESSAGE_ID => system_message = input_text.value.trim_end(),
+                        SETTINGS_MODAL_PARAMETERS_ID => parameters = input_text.value.trim(),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        // Submitting nothing at all reverts to the settings in the primary message.
+        let settings = if system_message.is_empty() && parameters.is_empty() {
+            None
+        } else if parameters.is_empty() {
+            Some(system_message.to_string())
+        } else {
+            Some(format!("{}\n---\n{}", system_message, parameters))
+        };
+
+        let old_settings = std::mem::replace(&mut thread.state.settings, settings);
+        if let Err(e) = self.validate_settings(&thread) {
+            thread.state.settings = old_settings;
+            modal_submit
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content(format!("I couldn't understand those settings, so I didn't save them.\n```\n{}\n```", e))
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+        self.store.save_thread(modal_submit.channel_id, &thread.state).await?;
+        thread.reported_settings_error = None;
+        if let Err(e) = self.sync_settings_message(&ctx.http, &mut thread).await {
+            log::warn!("failed to update settings message in {}: {:?}", modal_submit.channel_id, e);
+        }
+
+        modal_submit
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| {
+                    d.embed(|e| {
+                        e.color(serenity::utils::colours::css::POSITIVE)
+                            .description(if thread.state.settings.is_some() {
+                                format!("{} updated the settings for this thread.", modal_submit.user.mention())
+                            } else {
+                                format!(
+                                    "{} reset the settings for this thread to the ones in the first post.",
+                                    modal_submit.user.mention()
+                                )
+                            })
+                    })
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Says something directly, recording what role it should play in the prompt.
+    async fn handle_inject_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+        role: store::InjectedRole,
+    ) -> Result<(), anyhow::Error> {
+        let option = |name: &str| {
+            app_command
+                .data
+                .options
+                .iter()
+                .find(|o| o.name == name)
+                .and_then(|o| o.value.as_ref())
+                .and_then(|v| v.as_str())
+        };
+
+        let content = if let Some(content) = option("content") {
+            content
+        } else {
+            return Ok(());
+        };
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| d.content(content).allowed_mentions(reply_mentions))
+            })
+            .await?;
+        let message = app_command.get_interaction_response(&ctx.http).await?;
+
+        thread.state.injected.insert(
+            message.id,
+            store::InjectedMessage {
+                role,
+                name: option("name").map(|name| name.to_string()),
+            },
+        );
+        self.store.save_thread(app_command.channel_id, &thread.state).await?;
+
+        Ok(())
+    }
+
+    async fn handle_import_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let attachment = if let Some(serenity::model::application::interaction::application_command::CommandDataOptionValue::Attachment(attachment)) =
+            app_command
+                .data
+                .options
+                .iter()
+                .find(|o| o.name == "file")
+                .and_then(|o| o.resolved.as_ref())
+        {
+            attachment
+        } else {
+            return Ok(());
+        };
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        let transcript = if attachment.size > IMPORT_MAX_SIZE {
+            Err(anyhow::format_err!("the file is over {} bytes", IMPORT_MAX_SIZE))
+        } else {
+            attachment
+                .download()
+                .await
+                .map_err(|e| e.into())
+                .and_then(|buf| Ok(String::from_utf8(buf)?))
+                .and_then(|s| transcript::Transcript::parse(&s))
+        };
+        let transcript = match transcript {
+            Ok(transcript) => transcript,
+            Err(e) => {
+                app_command
+                    .create_interaction_response(&ctx.http, |r| {
+                        r.interaction_response_data(|d| {
+                            d.ephemeral(true)
+                                .content(format!("I couldn't read {} as a transcript.\n```\n{}\n```", attachment.filename, e))
+                        })
+                    })
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        // The thread already has a system prompt of its own, which would be the first message of an exported transcript.
+        let mut messages = transcript.messages.as_slice();
+        if let [first, rest @ ..] = messages {
+            if first.role == transcript::Role::System {
+                messages = rest;
+            }
+        }
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| {
+                    d.embed(|e| {
+                        e.color(serenity::utils::colours::css::POSITIVE).description(format!(
+                            "{} is importing {} messages from {}.",
+                            app_command.user.mention(),
+                            messages.len(),
+                            attachment.filename
+                        ))
+                    })
+                })
+            })
+            .await?;
+
+        post_injected_messages(&ctx.http, app_command.channel_id, &mut thread.state, messages).await?;
+        self.store.save_thread(app_command.channel_id, &thread.state).await?;
+
+        Ok(())
+    }
+
+    async fn handle_checkpoint_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let name = if let Some(name) = app_command
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == "name")
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_str())
+        {
+            name.trim().to_string()
+        } else {
+            return Ok(());
+        };
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        let checkpoint = store::Checkpoint {
+            message_id: thread.latest_message().id,
+            settings: thread.state.settings.clone(),
+            backend: thread.state.backend.clone(),
+            model: thread.state.model.clone(),
+            pinned: thread.state.pinned.clone(),
+            excluded: thread.state.excluded.clone(),
+            forget_before: thread.state.forget_before,
+        };
+        thread.state.checkpoints.insert(name.clone(), checkpoint);
+        self.store.save_thread(app_command.channel_id, &thread.state).await?;
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| {
+                    d.embed(|e| {
+                        e.color(serenity::utils::colours::css::POSITIVE).description(format!(
+                            "{} made a checkpoint named **{}**.",
+                            app_command.user.mention(),
+                            name
+                        ))
+                    })
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_set_system_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let prompt = app_command
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == "prompt")
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .replace("\\n", "\n");
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        if !thread.can_manage(app_command.user.id, app_command.member.as_ref().and_then(|m| m.permissions)) {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("Only the creator of this thread or moderators can change its system prompt.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+
+        // The parameters stay as they were.
+        let source = thread.settings_source();
+        let settings = match source.split_once("\n---\n") {
+            Some((_, parameters)) => format!("{}\n---\n{}", prompt, parameters),
+            None => prompt,
+        };
+        let previous = thread.state.settings.replace(settings);
+        if let Err(e) = self.validate_settings(&thread) {
+            thread.state.settings = previous;
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content(format!("Those settings don't work:\n```\n{}\n```", e)))
+                })
+                .await?;
+            return Ok(());
+        }
+        thread.reported_settings_error = None;
+        self.store.save_thread(app_command.channel_id, &thread.state).await?;
+        if let Err(e) = self.sync_settings_message(&ctx.http, &mut thread).await {
+            log::warn!("failed to update settings message in {}: {:?}", app_command.channel_id, e);
+        }
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| {
+                    d.embed(|e| {
+                        e.color(serenity::utils::colours::css::POSITIVE)
+                            .description(format!("{} replaced this thread's system prompt.", app_command.user.mention()))
+                    })
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_resync_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+
+        // Fetching the whole history again can take longer than an interaction may go unanswered.
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(serenity::model::application::interaction::InteractionResponseType::DeferredChannelMessageWithSource)
+                    .interaction_response_data(|d| d.ephemeral(true))
+            })
+            .await?;
+
+        let mut thread = thread.lock().await;
+        let num_before = thread.messages.len();
+        thread.resync(&ctx.http, &self.config).await?;
+        let num_after = thread.messages.len();
+        drop(thread);
+
+        app_command
+            .edit_original_interaction_response(&ctx.http, |r| {
+                r.content(format!("Fetched the thread again: {} messages loaded, {} before.", num_after, num_before))
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Puts the thread's pins, exclusions and overrides back the way they were at a checkpoint, and leaves out everything since.
+    async fn handle_rollback_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let name = if let Some(name) = app_command
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == "name")
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_str())
+        {
+            name.trim().to_string()
+        } else {
+            return Ok(());
+        };
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        if !thread.can_manage(app_command.user.id, app_command.member.as_ref().and_then(|m| m.permissions)) {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("Only the creator of this thread or moderators can roll it back.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+
+        let checkpoint = if let Some(checkpoint) = thread.state.checkpoints.get(&name) {
+            checkpoint.clone()
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content(format!("There's no checkpoint named {}.", name)))
+                })
+                .await?;
+            return Ok(());
+        };
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(serenity::model::application::interaction::InteractionResponseType::DeferredChannelMessageWithSource)
+            })
+            .await?;
+
+        // Older messages might not be loaded, so everything since the checkpoint is fetched.
+        let mut excluded = checkpoint.excluded.clone();
+        let mut after = checkpoint.message_id;
+        loop {
+            let page = app_command
+                .channel_id
+                .messages(&ctx.http, |b| b.after(after).limit(ROLLBACK_PAGE_SIZE))
+                .await?;
+            after = if let Some(newest) = page.iter().map(|m| m.id).max() {
+                newest
+            } else {
+                break;
+            };
+            excluded.extend(page.into_iter().map(|m| m.id));
+        }
+        excluded.sort();
+        excluded.dedup();
+
+        thread.state.settings = checkpoint.settings;
+        thread.state.backend = checkpoint.backend;
+        thread.state.model = checkpoint.model;
+        thread.state.pinned = checkpoint.pinned;
+        thread.state.excluded = excluded;
+        thread.state.forget_before = checkpoint.forget_before;
+        self.store.save_thread(app_command.channel_id, &thread.state).await?;
+        if let Err(e) = self.sync_settings_message(&ctx.http, &mut thread).await {
+            log::warn!("failed to update settings message in {}: {:?}", app_command.channel_id, e);
+        }
+
+        app_command
+            .edit_original_interaction_response(&ctx.http, |r| {
+                r.embed(|e| {
+                    e.color(serenity::utils::colours::css::POSITIVE).description(format!(
+                        "{} rolled back to **{}**. Everything after {} is forgotten.",
+                        app_command.user.mention(),
+                        name,
+                        checkpoint.message_id.link(app_command.channel_id, app_command.guild_id)
+                    ))
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_rollback_autocomplete(
+        &self,
+        ctx: &serenity::client::Context,
+        autocomplete: &serenity::model::application::interaction::autocomplete::AutocompleteInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let partial = autocomplete
+            .data
+            .options
+            .iter()
+            .find(|o| o.focused)
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_lowercase();
+
+        let names = if let Some(thread) = self.load_thread(&ctx.http, autocomplete.channel_id).await? {
+            thread.lock().await.state.checkpoints.keys().cloned().collect::<Vec<_>>()
+        } else {
+            vec![]
+        };
+
+        autocomplete
+            .create_autocomplete_response(&ctx.http, |r| {
+                for name in names
+                    .iter()
+                    .filter(|name| name.to_lowercase().contains(&partial))
+                    .take(AUTOCOMPLETE_MAX_CHOICES)
+                {
+                    r.add_string_choice(name, name);
+                }
+                r
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Starts a new thread in the forum with the same first post, tags and settings, and optionally the conversation up to a message, as
+    /// injected messages. The fork belongs to whoever made it.
+    async fn handle_fork_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        let until = if let Some(message_link) = app_command
+            .data
+            .options
+            .iter()
+            .find(|o| o.name == "message_link")
+            .and_then(|o| o.value.as_ref())
+            .and_then(|v| v.as_str())
+        {
+            let message_id = MESSAGE_LINK_REGEX
+                .captures(message_link.trim())
+                .filter(|c| c["channel_id"].parse::<u64>().ok() == Some(app_command.channel_id.0))
+                .and_then(|c| c["message_id"].parse::<u64>().ok())
+                .map(serenity::model::id::MessageId);
+            let mut message = if let Some(message) = message_id.and_then(|message_id| {
+                thread
+                    .messages
+                    .get(&message_id)
+                    .or_else(|| Some(&thread.primary_message).filter(|m| m.id == message_id))
+            }) {
+                message.clone()
+            } else {
+                app_command
+                    .create_interaction_response(&ctx.http, |r| {
+                        r.interaction_response_data(|d| d.ephemeral(true).content("That isn't a link to a message in this thread."))
+                    })
+                    .await?;
+                return Ok(());
+            };
+            message.guild_id = Some(thread.guild_id);
+            Some(message)
+        } else {
+            None
+        };
+
+        let settings = match self.validate_settings(&thread) {
+            Ok(settings) => settings,
+            Err(e) => {
+                app_command
+                    .create_interaction_response(&ctx.http, |r| {
+                        r.interaction_response_data(|d| d.ephemeral(true).content(format!("This thread's settings are invalid.\n```\n{}\n```", e)))
+                    })
+                    .await?;
+                return Ok(());
+            }
+        };
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(serenity::model::application::interaction::InteractionResponseType::DeferredChannelMessageWithSource)
+                    .interaction_response_data(|d| d.ephemeral(true))
+            })
+            .await?;
+
+        // The context is taken as the bot sees it, without the system message: the fork has the same settings, so it has its own.
+        let messages = if let Some(until) = until.as_ref() {
+            let (backend_name, binding) = if let Some((backend_name, binding)) = self.resolve_backend(&thread) {
+                (backend_name, binding)
+            } else {
+                return Ok(());
+            };
+            let input_budget = binding.input_budget();
+            if let Err(e) = self
+                .load_history_for_prompt(&ctx.http, &mut thread, until.id, backend_name, &binding.backend, input_budget)
+                .await
+            {
+                log::warn!("failed to load more history for {}: {:?}", until.channel_id, e);
+            }
+            let (messages, _, _) = self
+                .build_prompt(&ctx.http, &thread, until, &settings, backend_name, binding, input_budget)
+                .await?;
+            let mut messages = messages.iter().skip(1).map(transcript::Message::from).collect::<Vec<_>>();
+            // A first post that's a question is copied as the fork's first post, so it shouldn't be injected as well.
+            if thread.primary_is_prompt(&self.config.defaults) {
+                let question = STRIP_SINGLE_USER_REGEX.replace(&thread.primary_message.content, "").trim().to_string();
+                if let Some(i) = messages
+                    .iter()
+                    .position(|m| m.role == transcript::Role::User && m.content.contains(&question))
+                {
+                    messages.remove(i);
+                }
+            }
+            messages
+        } else {
+            vec![]
+        };
+
+        let mut state = store::ThreadState {
+            backend: thread.state.backend.clone(),
+            model: thread.state.model.clone(),
+            owner: Some(app_command.user.id),
+            ..Default::default()
+        };
+        // Continuations are only continuations when they're from the thread's creator, so they're folded into the settings instead.
+        state.settings = if thread.state.settings.is_some() || thread.messages.values().any(|m| thread.is_settings_continuation(m)) {
+            Some(thread.settings_source())
+        } else {
+            None
+        };
+        let title = thread.title.clone();
+        let content = thread.primary_message.content.clone();
+        // Nothing else needs the thread, and it shouldn't be held up while the fork is posted.
+        drop(thread);
+
+        let applied_tags = if let serenity::model::channel::Channel::Guild(channel) = app_command.channel_id.to_channel(&ctx.http).await? {
+            channel.applied_tags
+        } else {
+            vec![]
+        };
+
+        // Forum posts are created with the same request as threads that don't start from a message.
+        let mut map = serenity::json::JsonMap::new();
+        map.insert("name".to_string(), serenity::json::Value::from(format!("{} (fork)", title)));
+        map.insert(
+            "applied_tags".to_string(),
+            serenity::json::Value::from(applied_tags.iter().map(|tag| tag.0.to_string()).collect::<Vec<_>>()),
+        );
+        map.insert(
+            "message".to_string(),
+            serde_json::json!({
+                "content": content,
+                "allowed_mentions": { "parse": [] },
+            }),
+        );
+        let fork = ctx.http.create_private_thread(self.parent_channel_id.0, &map).await?;
+
+        post_injected_messages(&ctx.http, fork.id, &mut state, &messages).await?;
+        self.store.save_thread(fork.id, &state).await?;
+        // If the new thread was loaded in the meantime, it was loaded without its state.
+        self.thread_cache.lock().await.evict(fork.id);
+
+        app_command
+            .edit_original_interaction_response(&ctx.http, |r| {
+                r.content(format!(
+                    "Forked into {}{}.",
+                    fork.id.mention(),
+                    if messages.is_empty() { "" } else { ", with the conversation so far" }
+                ))
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Adds a break in the chat log, or with options, forgets only the latest messages or everything before a message. Those are kept in
+    /// the thread's state rather than marked by the response, which only the person who used the command sees.
+    async fn handle_forget_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let option = |name: &str| app_command.data.options.iter().find(|o| o.name == name).and_then(|o| o.value.as_ref());
+        let count = option("count").and_then(|v| v.as_u64()).map(|count| count as usize);
+        let message_link = option("message_link").and_then(|v| v.as_str());
+
+        if count.is_none() && message_link.is_none() {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.embed(|e| {
+                            e.color(serenity::utils::colours::css::POSITIVE)
+                                .description("Okay, forgetting everything from here. If you want me to remember, just delete this message.")
+                        })
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        if !thread.can_manage(app_command.user.id, app_command.member.as_ref().and_then(|m| m.permissions)) {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("Only the creator of this thread or moderators can choose what I remember.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+
+        let mut state = thread.state.clone();
+        let mut descriptions = vec![];
+
+        if let Some(message_link) = message_link {
+            let message_id = if let Some(message_id) = MESSAGE_LINK_REGEX
+                .captures(message_link.trim())
+                .filter(|c| c["channel_id"].parse::<u64>().ok() == Some(app_command.channel_id.0))
+                .and_then(|c| c["message_id"].parse::<u64>().ok())
+            {
+                serenity::model::id::MessageId(message_id)
+            } else {
+                app_command
+                    .create_interaction_response(&ctx.http, |r| {
+                        r.interaction_response_data(|d| d.ephemeral(true).content("That isn't a link to a message in this thread."))
+                    })
+                    .await?;
+                return Ok(());
+            };
+            state.forget_before = if message_id.0 == app_command.channel_id.0 {
+                None
+            } else {
+                Some(message_id)
+            };
+            descriptions.push(format!(
+                "Okay, forgetting everything before {}.",
+                message_id.link(app_command.channel_id, app_command.guild_id)
+            ));
+        }
+
+        if let Some(count) = count {
+            let message_ids = thread
+                .messages
+                .values()
+                .rev()
+                .filter(|m| self.is_prompt_message(&thread, m))
+                .take(count)
+                .map(|m| m.id)
+                .collect::<Vec<_>>();
+            for message_id in message_ids.iter() {
+                if !state.excluded.contains(message_id) {
+                    state.excluded.push(*message_id);
+                }
+            }
+            descriptions.push(format!(
+                "Okay, forgetting the last {} messages. If you want me to remember one, use **{}** on it.",
+                message_ids.len(),
+                INCLUDE_COMMAND_NAME
+            ));
+        }
+
+        self.store.save_thread(app_command.channel_id, &state).await?;
+        thread.state = state;
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| {
+                    d.ephemeral(true)
+                        .embed(|e| e.color(serenity::utils::colours::css::POSITIVE).description(descriptions.join("\n")))
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_forget_before_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let message_id = if let Some(target_id) = app_command.data.target_id {
+            target_id.to_message_id()
+        } else {
+            return Ok(());
+        };
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        if !thread.can_manage(app_command.user.id, app_command.member.as_ref().and_then(|m| m.permissions)) {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("Only the creator of this thread or moderators can choose what I remember.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+
+        // There's nothing before the first post, so forgetting everything before it just means remembering everything again.
+        let mut state = thread.state.clone();
+        state.forget_before = if message_id.0 == app_command.channel_id.0 {
+            None
+        } else {
+            Some(message_id)
+        };
+        self.store.save_thread(app_command.channel_id, &state).await?;
+        thread.state = state;
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| {
+                    d.embed(|e| {
+                        e.color(serenity::utils::colours::css::POSITIVE)
+                            .description(if thread.state.forget_before.is_some() {
+                                format!(
+                                    "Okay, forgetting everything before {}. If you want me to remember, use this on the first post.",
+                                    message_id.link(app_command.channel_id, app_command.guild_id)
+                                )
+                            } else {
+                                "Okay, remembering everything again.".to_string()
+                            })
+                    })
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Excludes a message from the prompt, or includes it again. This is the same as the exclude reaction, but works for people who can't add
+    /// reactions.
+    async fn handle_exclude_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+        excluded: bool,
+    ) -> Result<(), anyhow::Error> {
+        let message_id = if let Some(target_id) = app_command.data.target_id {
+            target_id.to_message_id()
+        } else {
+            return Ok(());
+        };
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        if !thread.can_manage(app_command.user.id, app_command.member.as_ref().and_then(|m| m.permissions)) {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("Only the creator of this thread or moderators can choose which messages I read.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+
+        // The thread's state is only changed once it's saved, so it doesn't end up different from what's stored if anything fails.
+        let mut state = thread.state.clone();
+        if excluded {
+            if !state.excluded.contains(&message_id) {
+                state.excluded.push(message_id);
+            }
+        } else {
+            state.excluded.retain(|id| *id != message_id);
+
+            // Including a message again also has to undo any exclude reactions on it.
+            let exclude_reaction_types = if let Some(message) = thread.messages.get(&message_id) {
+                message
+                    .reactions
+                    .iter()
+                    .filter(|r| self.reaction_action(&r.reaction_type) == Some(ReactionAction::Exclude))
+                    .map(|r| r.reaction_type.clone())
+                    .collect::<Vec<_>>()
+            } else {
+                vec![]
+            };
+            for reaction_type in exclude_reaction_types {
+                app_command
+                    .channel_id
+                    .delete_reaction_emoji(&ctx.http, message_id, reaction_type.clone())
+                    .await?;
+                if let Some(message) = thread.messages.get_mut(&message_id) {
+                    message.reactions.retain(|r| r.reaction_type != reaction_type);
+                }
+            }
+        }
+        self.store.save_thread(app_command.channel_id, &state).await?;
+        thread.state = state;
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| {
+                    d.ephemeral(true).content(if excluded {
+                        "Okay, I'll leave that message out from now on."
+                    } else {
+                        "Okay, I'll read that message again."
+                    })
+                })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    async fn handle_rewrite_command(
+        &self,
+        ctx: &serenity::client::Context,
+        app_command: &serenity::model::application::interaction::application_command::ApplicationCommandInteraction,
+    ) -> Result<(), anyhow::Error> {
+        let me_id = self.me_id();
+
+        let message = if let Some(message) = app_command
+            .data
+            .target_id
+            .and_then(|target_id| app_command.data.resolved.messages.get(&target_id.to_message_id()))
+        {
+            message
+        } else {
+            return Ok(());
+        };
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, app_command.channel_id).await? {
+            thread
+        } else {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("This command only works in my threads."))
+                })
+                .await?;
+            return Ok(());
+        };
+        let thread = thread.lock().await;
+
+        if !thread.can_manage(app_command.user.id, app_command.member.as_ref().and_then(|m| m.permissions)) {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("Only the creator of this thread or moderators can rewrite my replies.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+
+        if message.author.id != me_id || message.content.is_empty() {
+            app_command
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("I can only rewrite my own replies."))
+                })
+                .await?;
+            return Ok(());
+        }
+
+        app_command
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(serenity::model::application::interaction::InteractionResponseType::Modal)
+                    .interaction_response_data(|d| {
+                        d.custom_id(format!("{}{}", REWRITE_MODAL_ID_PREFIX, message.id.0))
+                            .title("Rewrite reply")
+                            .components(|c| {
+                                c.create_action_row(|row| {
+                                    row.create_input_text(|t| {
+                                        t.custom_id(REWRITE_MODAL_CONTENT_ID)
+                                            .label("Content")
+                                            .style(serenity::model::application::component::InputTextStyle::Paragraph)
+                                            .max_length(MODAL_INPUT_TEXT_MAX_LENGTH as u64)
+                                            .required(true)
+                                            .value(&message.content)
+                                    })
+                                })
+                            })
+                    })
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// Replaces the content of one of the bot's replies. If the new content is too long for one message, the rest is sent as further replies.
+    async fn handle_rewrite_modal(
+        &self,
+        ctx: &serenity::client::Context,
+        modal_submit: &serenity::model::application::interaction::modal::ModalSubmitInteraction,
+        message_id: serenity::model::id::MessageId,
+    ) -> Result<(), anyhow::Error> {
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, modal_submit.channel_id).await? {
+            thread
+        } else {
+            modal_submit
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("I can't find this thread anymore, so I didn't rewrite that reply.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        };
+        let thread = thread.lock().await;
+
+        if !thread.can_manage(modal_submit.user.id, modal_submit.member.as_ref().and_then(|m| m.permissions)) {
+            modal_submit
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("Only the creator of this thread or moderators can rewrite my replies.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+
+        let mut content = "";
+        for row in modal_submit.data.components.iter() {
+            for component in row.components.iter() {
+                if let serenity::model::application::component::ActionRowComponent::InputText(input_text) = component {
+                    if input_text.custom_id == REWRITE_MODAL_CONTENT_ID {
+                        content = input_text.value.trim_end();
+                    }
+                }
+            }
+        }
+
+        if content.is_empty() {
+            modal_submit
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("I can't rewrite a reply to be empty, so I left it as it was."))
+                })
+                .await?;
+            return Ok(());
+        }
+
+        let mut chunker = unichunk::Chunker::new(MESSAGE_MAX_LENGTH);
+        let mut chunks = chunker.push(content);
+        let c = chunker.flush();
+        if !c.is_empty() {
+            chunks.push(c);
+        }
+        let mut chunks = chunks.into_iter();
+
+        modal_submit
+            .channel_id
+            .edit_message(&ctx.http, message_id, |m| {
+                m.content(chunks.next().unwrap_or_default()).allowed_mentions(reply_mentions)
+            })
+            .await?;
+
+        let message_reference = thread.messages.get(&message_id).and_then(|m| m.message_reference.clone());
+        for c in chunks {
+            modal_submit
+                .channel_id
+                .send_message(&ctx.http, |m| {
+                    m.content(c).allowed_mentions(reply_mentions);
+                    if let Some(message_reference) = message_reference.clone() {
+                        m.reference_message(message_reference);
+                    }
+                    m
+                })
+                .await?;
+        }
+
+        modal_submit
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| d.ephemeral(true).content("Okay, I rewrote that reply."))
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /// In ambient threads, decides whether to chime in on a message that isn't addressed to the bot: always if the message says the bot's
+    /// name, otherwise at random, but never more often than the cooldown allows.
+    async fn should_reply_ambiently(
+        &self,
+        http: impl AsRef<serenity::http::Http>,
+        thread: &mut ThreadInfo,
+        message: &serenity::model::channel::Message,
+    ) -> Result<bool, anyhow::Error> {
+        // Single-user threads only read messages addressed to the bot, so this only makes sense for multi-user ones.
+        if !thread.ambient || thread.mode != ThreadMode::Multi {
+            return Ok(false);
+        }
+
+        if thread
+            .last_ambient_reply
+            .map(|last_ambient_reply| last_ambient_reply.elapsed() < self.config.ambient_cooldown)
+            .unwrap_or(false)
+        {
+            return Ok(false);
+        }
+
+        let me_id = self.me_id();
+        let name = self
+            .resolver
+            .lock()
+            .await
+            .resolve_display_name(http, message.guild_id.unwrap(), me_id)
+            .await
+            .map_err(|e| anyhow::format_err!("resolve_display_name: {}", e))?
+            .to_lowercase();
+
+        let named = !name.is_empty() && message.content.to_lowercase().contains(&name);
+        if !named && rand::random::<f64>() >= self.config.ambient_reply_probability {
+            return Ok(false);
+        }
+
+        thread.last_ambient_reply = Some(std::time::Instant::now());
+        Ok(true)
+    }
+
+    /// How much longer a user has to wait before prompting the bot in a thread again, if they're on either cooldown.
+    fn cooldown_remaining(&self, thread: &ThreadInfo, user_id: serenity::model::id::UserId) -> Option<std::time::Duration> {
+        let remaining = |cooldown: Option<std::time::Duration>, last_request: Option<std::time::Instant>| {
+            cooldown
+                .zip(last_request)
+                .and_then(|(cooldown, last_request)| cooldown.checked_sub(last_request.elapsed()))
+                .filter(|remaining| !remaining.is_zero())
+        };
+        let user_remaining = remaining(self.config.user_cooldown, self.last_user_requests.lock().get(&user_id).copied());
+        let thread_remaining = remaining(self.config.thread_cooldown, thread.last_request);
+        user_remaining.max(thread_remaining)
+    }
+
+    fn record_request(&self, thread: &mut ThreadInfo, user_id: serenity::model::id::UserId) {
+        let now = std::time::Instant::now();
+        thread.last_request = Some(now);
+        if let Some(user_cooldown) = self.config.user_cooldown {
+            let mut last_user_requests = self.last_user_requests.lock();
+            last_user_requests.retain(|_, last_request| last_request.elapsed() < user_cooldown);
+            last_user_requests.insert(user_id, now);
+        }
+    }
+
+    /// Replies to a message with a notice that deletes itself after a while, since messages can't be ephemeral.
+    async fn send_transient_notice(
+        &self,
+        ctx: &serenity::client::Context,
+        message: &serenity::model::channel::Message,
+        colour: serenity::utils::Colour,
+        description: &str,
+    ) -> Result<(), anyhow::Error> {
+        let notice = message
+            .channel_id
+            .send_message(&ctx.http, |m| {
+                m.embed(|e| e.color(colour).description(description)).reference_message(message)
+            })
+            .await?;
+
+        let http = ctx.http.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(TRANSIENT_NOTICE_LIFETIME).await;
+            if let Err(e) = notice.delete(&http).await {
+                log::warn!("failed to delete notice {}: {:?}", notice.id, e);
+            }
+        });
+        Ok(())
+    }
+
+    /// Deletes the bot's replies to a message. Replies are tracked by the message they reference.
+    async fn delete_replies(
+        &self,
+        http: impl AsRef<serenity::http::Http>,
+        thread: &mut ThreadInfo,
+        prompt_id: serenity::model::id::MessageId,
+    ) -> Result<(), anyhow::Error> {
+        let me_id = self.me_id();
+
+        let reply_ids = thread
+            .messages
+            .values()
+            .filter(|m| m.author.id == me_id && m.message_reference.as_ref().and_then(|r| r.message_id) == Some(prompt_id))
+            .map(|m| m.id)
+            .collect::<Vec<_>>();
+        for reply_id in reply_ids {
+            match thread.primary_message.channel_id.delete_message(&http, reply_id).await {
+                Ok(()) => {}
+                // It may have been deleted along with its prompt, e.g. in a bulk delete.
+                Err(serenity::Error::Http(e)) if e.status_code() == Some(reqwest::StatusCode::NOT_FOUND) => {}
+                Err(e) => return Err(e.into()),
+            }
+            thread.messages.remove(&reply_id);
+        }
+        Ok(())
+    }
+
+    /// Deletes the bot's replies to a message and replies to it again.
+    async fn regenerate(
+        &self,
+        ctx: &serenity::client::Context,
+        thread: &mut ThreadInfo,
+        prompt: &serenity::model::channel::Message,
+    ) -> Result<(), anyhow::Error> {
+        self.delete_replies(&ctx.http, thread, prompt.id).await?;
+        if thread.state.incomplete_replies.remove(&prompt.id).is_some() {
+            self.store.save_thread(thread.primary_message.channel_id, &thread.state).await?;
+        }
+        self.reply(ctx, thread, prompt).await
+    }
+
+    /// Whether a thread message can be part of the prompt at all.
+    fn is_prompt_message(&self, thread: &ThreadInfo, message: &serenity::model::channel::Message) -> bool {
+        // The bot's own embeds are errors and notices, not part of the conversation, unless they're replies.
+        let me_id = self.me_id();
+        (!message.content.is_empty()
+            || (message.author.id != me_id && (!message.embeds.is_empty() || !message.sticker_items.is_empty()))
+            || reply_embed_text(message, me_id).is_some())
+            && !thread.is_settings_continuation(message)
+            && (message.kind == serenity::model::channel::MessageType::Regular
+                || message.kind == serenity::model::channel::MessageType::InlineReply
+                || message.kind == serenity::model::channel::MessageType::ChatInputCommand)
+            && !self.is_excluded(thread, message)
+    }
+
+    /// Whether a message was left out of the prompt, with an exclude reaction or /exclude. With `exclude_replies_with_prompt`, the bot's
+    /// replies to a message that was left out are too.
+    fn is_excluded(&self, thread: &ThreadInfo, message: &serenity::model::channel::Message) -> bool {
+        if self.has_reaction_action(message, ReactionAction::Exclude) || thread.state.excluded.contains(&message.id) {
+            return true;
+        }
+        if !self.config.exclude_replies_with_prompt || message.author.id != self.me_id() {
+            return false;
+        }
+        message
+            .message_reference
+            .as_ref()
+            .and_then(|r| r.message_id)
+            .map(|prompt_id| {
+                thread.state.excluded.contains(&prompt_id)
+                    || thread
+                        .messages
+                        .get(&prompt_id)
+                        .map(|prompt| self.has_reaction_action(prompt, ReactionAction::Exclude))
+                        .unwrap_or(false)
+            })
+            .unwrap_or(false)
+    }
+
+    /// The message a button on one of the bot's notices is about, if the person who pressed it may act on it. Only whoever sent the message
+    /// and people who can manage the thread may.
+    async fn button_prompt(
+        &self,
+        ctx: &serenity::client::Context,
+        component: &serenity::model::application::interaction::message_component::MessageComponentInteraction,
+        thread: &ThreadInfo,
+        message_id: serenity::model::id::MessageId,
+    ) -> Result<Option<serenity::model::channel::Message>, anyhow::Error> {
+        let mut prompt = if let Some(prompt) = thread
+            .messages
+            .get(&message_id)
+            .or_else(|| Some(&thread.primary_message).filter(|m| m.id == message_id))
+        {
+            prompt.clone()
+        } else {
+            return Ok(None);
+        };
+        prompt.guild_id = Some(thread.guild_id);
+
+        let roles = component.member.as_ref().map(|m| m.roles.as_slice()).unwrap_or(&[]);
+        if !self.config.access.is_permitted(component.user.id, roles)
+            || (component.user.id != prompt.author.id && !thread.can_manage(component.user.id, component.member.as_ref().and_then(|m| m.permissions)))
+        {
+            component
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content(NOT_PERMITTED_MESSAGE))
+                })
+                .await?;
+            return Ok(None);
+        }
+        Ok(Some(prompt))
+    }
+
+    /// Continues a reply that was cut off partway, when the continue button on the notice is pressed.
+    async fn handle_continue_button(
+        &self,
+        ctx: &serenity::client::Context,
+        component: &serenity::model::application::interaction::message_component::MessageComponentInteraction,
+        message_id: serenity::model::id::MessageId,
+    ) -> Result<(), anyhow::Error> {
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, component.channel_id).await? {
+            thread
+        } else {
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        let prompt = if let Some(prompt) = self.button_prompt(ctx, component, &thread, message_id).await? {
+            prompt
+        } else {
+            return Ok(());
+        };
+
+        let partial = if let Some(partial) = thread.state.incomplete_replies.remove(&message_id) {
+            partial
+        } else {
+            component
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("That reply can't be continued anymore."))
+                })
+                .await?;
+            return Ok(());
+        };
+        self.store.save_thread(thread.primary_message.channel_id, &thread.state).await?;
+
+        component
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(serenity::model::application::interaction::InteractionResponseType::DeferredUpdateMessage)
+            })
+            .await?;
+
+        // The notice would only be confusing once the reply goes on.
+        component.message.delete(&ctx.http).await?;
+        thread.messages.remove(&component.message.id);
+        thread.requester = Some(component.user.id);
+        let r = self.reply_or_continue(ctx, &mut thread, &prompt, Some(partial)).await;
+        thread.requester = None;
+        r
+    }
+
+    /// Records a rating of a reply along with its prompt. Anyone who may talk to the bot may rate any reply; each press is recorded, so
+    /// whoever reads the ratings decides what to make of people changing their minds.
+    async fn handle_rate_button(
+        &self,
+        ctx: &serenity::client::Context,
+        component: &serenity::model::application::interaction::message_component::MessageComponentInteraction,
+        message_id: serenity::model::id::MessageId,
+        good: bool,
+    ) -> Result<(), anyhow::Error> {
+        let roles = component.member.as_ref().map(|m| m.roles.as_slice()).unwrap_or(&[]);
+        if !self.config.access.is_permitted(component.user.id, roles) {
+            component
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content(NOT_PERMITTED_MESSAGE))
+                })
+                .await?;
+            return Ok(());
+        }
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, component.channel_id).await? {
+            thread
+        } else {
+            return Ok(());
+        };
+        let reply = thread.lock().await.rateable.lock().get(&message_id).cloned();
+        let reply = if let Some(reply) = reply {
+            reply
+        } else {
+            component
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("That reply can't be rated anymore."))
+                })
+                .await?;
+            return Ok(());
+        };
+
+        self.store
+            .record_rating(&store::Rating {
+                at: chrono::Utc::now(),
+                thread_id: component.channel_id,
+                message_id,
+                user_id: component.user.id,
+                backend: reply.backend,
+                good,
+                prompt: reply.prompt,
+                response: reply.response,
+            })
+            .await?;
+
+        component
+            .create_interaction_response(&ctx.http, |r| {
+                r.interaction_response_data(|d| d.ephemeral(true).content("Thanks for the feedback!"))
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Swaps another variant of a reply in for the one that's posted. The posted messages are edited in place, with more sent or the rest
+    /// deleted if the variant takes a different number of them, and the history catches up from the events for those as usual.
+    async fn handle_variant_select(
+        &self,
+        ctx: &serenity::client::Context,
+        component: &serenity::model::application::interaction::message_component::MessageComponentInteraction,
+        prompt_id: serenity::model::id::MessageId,
+    ) -> Result<(), anyhow::Error> {
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, component.channel_id).await? {
+            thread
+        } else {
+            return Ok(());
+        };
+        let thread = thread.lock().await;
+
+        let reply_variants = thread.reply_variants.lock().get(&prompt_id).cloned();
+        let mut reply_variants = if let Some(reply_variants) = reply_variants {
+            reply_variants
+        } else {
+            component
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| d.ephemeral(true).content("The variants of that reply aren't around anymore."))
+                })
+                .await?;
+            return Ok(());
+        };
+
+        if component.user.id != reply_variants.requester {
+            component
+                .create_interaction_response(&ctx.http, |r| {
+                    r.interaction_response_data(|d| {
+                        d.ephemeral(true)
+                            .content("Only whoever asked for this reply can pick between its variants.")
+                    })
+                })
+                .await?;
+            return Ok(());
+        }
+
+        let selected = if let Some(selected) = component
+            .data
+            .values
+            .first()
+            .and_then(|v| v.parse::<usize>().ok())
+            .filter(|i| *i < reply_variants.variants.len())
+        {
+            selected
+        } else {
+            return Ok(());
+        };
+
+        component
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(serenity::model::application::interaction::InteractionResponseType::DeferredUpdateMessage)
+            })
+            .await?;
+        if selected == reply_variants.selected {
+            return Ok(());
+        }
+
+        let mut chunker = unichunk::Chunker::new(if self.config.embed_replies {
+            EMBED_DESCRIPTION_MAX_LENGTH
+        } else {
+            MESSAGE_MAX_LENGTH
+        });
+        let mut chunks = chunker.push(&reply_variants.variants[selected]);
+        let c = chunker.flush();
+        if !c.is_empty() {
+            chunks.push(c);
+        }
+
+        let rateable = thread.rateable.lock().get(&prompt_id).is_some();
+        let persona = reply_variants.persona.clone().unwrap_or_default();
+        let mut message_ids = vec![];
+        for (i, c) in chunks.iter().enumerate() {
+            let last = i + 1 == chunks.len();
+            let message_id = if let Some(message_id) = reply_variants.message_ids.get(i) {
+                *message_id
+            } else {
+                // Extra messages are sent like any other reply's. Only the last one needs editing after, to move the components onto it.
+                let prompt = if let Some(prompt) = thread.messages.get(&prompt_id) {
+                    prompt.clone()
+                } else {
+                    component.channel_id.message(&ctx.http, prompt_id).await?
+                };
+                let message_id = self
+                    .send_reply_chunk(&ctx.http, &prompt, reply_variants.persona.as_ref(), c, i == 0, None)
+                    .await?
+                    .id;
+                if !last {
+                    message_ids.push(message_id);
+                    continue;
+                }
+                message_id
+            };
+            component
+                .channel_id
+                .edit_message(&ctx.http, message_id, |m| {
+                    if self.config.embed_replies {
+                        m.content("").embed(|e| build_reply_embed(e, &persona, c, i == 0));
+                    } else {
+                        m.content(c);
+                    }
+                    m.allowed_mentions(reply_mentions).components(|components| {
+                        if last {
+                            build_reply_components(components, prompt_id, rateable, &reply_variants.variants, selected)
+                        } else {
+                            components
+                        }
+                    })
+                })
+                .await
+                .map_err(|e| anyhow::format_err!("edit_message: {}", e))?;
+            message_ids.push(message_id);
+        }
+        for message_id in reply_variants.message_ids.iter().skip(chunks.len()) {
+            component.channel_id.delete_message(&ctx.http, *message_id).await?;
+        }
+
+        if let Some(reply) = thread.rateable.lock().get_mut(&prompt_id) {
+            reply.response = reply_variants.variants[selected].clone();
+        }
+        reply_variants.message_ids = message_ids;
+        reply_variants.selected = selected;
+        thread.reply_variants.lock().put(prompt_id, reply_variants);
+
+        Ok(())
+    }
+
+    /// Catches up on the forum after being offline: threads created in the meantime are tracked, threads active in the meantime get the
+    /// messages they missed, and mentions that went unanswered are answered, oldest first, if they're recent enough. Otherwise they get an
+    /// offline notice, if those are on.
+    async fn catch_up(&self, ctx: &serenity::client::Context, offline_since: chrono::DateTime<chrono::Utc>) -> Result<(), anyhow::Error> {
+        let me_id = self.me_id();
+        let guild_id = if let serenity::model::channel::Channel::Guild(parent_channel) = self.parent_channel_id.to_channel(&ctx.http).await? {
+            parent_channel.guild_id
+        } else {
+            return Ok(());
+        };
+
+        let threads = guild_id
+            .get_active_threads(&ctx.http)
+            .await?
+            .threads
+            .into_iter()
+            .filter(|thread| thread.parent_id == Some(self.parent_channel_id))
+            .collect::<Vec<_>>();
+
+        let new_thread_ids = {
+            let mut thread_cache = self.thread_cache.lock().await;
+            let mut new_thread_ids = vec![];
+            for thread in threads.iter() {
+                if !thread_cache.contains(thread.id) {
+                    log::info!("thread {} created while offline, scheduled for load", thread.id);
+                    thread_cache.add(thread.id);
+                    new_thread_ids.push(thread.id);
+                }
+            }
+            new_thread_ids
+        };
+        for thread_id in new_thread_ids {
+            if let Err(e) = thread_id.join_thread(&ctx.http).await {
+                log::warn!("failed to join {}: {:?}", thread_id, e);
+            }
+        }
+
+        let active_thread_ids = threads
+            .iter()
+            .filter(|thread| {
+                thread
+                    .last_message_id
+                    .map(|last_message_id| last_message_id.created_at().with_timezone(&chrono::Utc) >= offline_since)
+                    .unwrap_or(false)
+            })
+            .map(|thread| thread.id)
+            .collect::<Vec<_>>();
+
+        let now = chrono::Utc::now();
+        let answer_since = self
+            .config
+            .catch_up_mentions
+            .map(|window| chrono::Duration::from_std(window).map(|window| std::cmp::max(offline_since, now - window)))
+            .transpose()?;
+        let mut to_answer = vec![];
+        // One thread going wrong shouldn't stop the rest from catching up.
+        for thread_id in active_thread_ids {
+            if let Err(e) = async {
+                let thread = if let Some(thread) = self.load_thread(&ctx.http, thread_id).await? {
+                    thread
+                } else {
+                    return Ok(());
+                };
+                let mut thread = thread.lock().await;
+
+                let num_missed = thread.backfill(&ctx.http).await?;
+                log::info!("thread {} caught up on {} messages", thread_id, num_missed);
+
+                for message in thread.missed_mentions(offline_since, me_id) {
+                    if answer_since
+                        .map(|answer_since| message.timestamp.with_timezone(&chrono::Utc) >= answer_since)
+                        .unwrap_or(false)
+                    {
+                        to_answer.push((message.id, thread_id));
+                        continue;
+                    }
+
+                    if !self.config.offline_notice {
+                        continue;
+                    }
+
+                    if let Err(e) = message
+                        .channel_id
+                        .send_message(&ctx.http, |m| {
+                            m.embed(|e| {
+                                e.title("Missed while offline")
+                                    .color(serenity::utils::colours::css::WARNING)
+                                    .description(format!(
+                                        "I was offline from <t:{}:t> to <t:{}:t>, so I missed this.",
+                                        offline_since.timestamp(),
+                                        now.timestamp()
+                                    ))
+                            })
+                            .reference_message(message)
+                            .allowed_mentions(reply_mentions)
+                        })
+                        .await
+                    {
+                        log::warn!("failed to send offline notice for {}: {:?}", message.id, e);
+                    }
+                }
+                Ok::<_, anyhow::Error>(())
+            }
+            .await
+            {
+                log::warn!("failed to catch up on {}: {:?}", thread_id, e);
+            }
+        }
+
+        to_answer.sort();
+        for (i, (message_id, thread_id)) in to_answer.into_iter().enumerate() {
+            if i > 0 {
+                tokio::time::sleep(CATCH_UP_INTERVAL).await;
+            }
+            if let Err(e) = self.answer_missed_mention(ctx, thread_id, message_id).await {
+                log::warn!("failed to answer missed mention {} in {}: {:?}", message_id, thread_id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replies to a mention that arrived while the bot was offline, if it's still unanswered and whoever sent it may still get replies.
+    async fn answer_missed_mention(
+        &self,
+        ctx: &serenity::client::Context,
+        thread_id: serenity::model::id::ChannelId,
+        message_id: serenity::model::id::MessageId,
+    ) -> Result<(), anyhow::Error> {
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, thread_id).await? {
+            thread
+        } else {
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        let mut message = if let Some(message) = thread
+            .missed_mentions(message_id.created_at().with_timezone(&chrono::Utc), self.me_id())
+            .into_iter()
+            .find(|m| m.id == message_id)
+        {
+            message.clone()
+        } else {
+            return Ok(());
+        };
+        message.guild_id = Some(thread.guild_id);
+
+        let roles = thread
+            .guild_id
+            .member(ctx, message.author.id)
+            .await
+            .map(|member| member.roles)
+            .unwrap_or_default();
+        if !self.config.access.is_permitted(message.author.id, &roles) {
+            return Ok(());
+        }
+
+        log::info!("answering mention {} in {} missed while offline", message_id, thread_id);
+        self.reply(ctx, &mut thread, &message).await
+    }
+
+    /// Tries replying to a message that failed again, when the retry button on the error is pressed.
+    async fn handle_retry_button(
+        &self,
+        ctx: &serenity::client::Context,
+        component: &serenity::model::application::interaction::message_component::MessageComponentInteraction,
+        message_id: serenity::model::id::MessageId,
+    ) -> Result<(), anyhow::Error> {
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, component.channel_id).await? {
+            thread
+        } else {
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        let prompt = if let Some(prompt) = self.button_prompt(ctx, component, &thread, message_id).await? {
+            prompt
+        } else {
+            return Ok(());
+        };
+
+        component
+            .create_interaction_response(&ctx.http, |r| {
+                r.kind(serenity::model::application::interaction::InteractionResponseType::DeferredUpdateMessage)
+            })
+            .await?;
+
+        prompt.channel_id.delete_reaction(&ctx.http, prompt.id, None, failed_reaction()).await?;
+        thread.requester = Some(component.user.id);
+        let r = self.regenerate(ctx, &mut thread, &prompt).await;
+        thread.requester = None;
+        r
+    }
+
+    /// Converts a thread message into a backend message, or None if it shouldn't be sent to the backend.
+    async fn convert_message(
+        &self,
+        http: &serenity::http::Http,
+        resolver: &mut Resolver,
+        thread: &ThreadInfo,
+        new_message: &serenity::model::channel::Message,
+        message: &serenity::model::channel::Message,
+        multi_format: &template::MultiFormat,
+    ) -> Result<Option<backend::Message>, anyhow::Error> {
+        let me_id = self.me_id();
+
+        if let Some(injected) = thread.state.injected.get(&message.id) {
+            let (role, content) = match injected.role {
+                store::InjectedRole::Assistant => (backend::Role::Assistant, message.content.clone()),
+                store::InjectedRole::System => (backend::Role::System, message.content.clone()),
+                store::InjectedRole::User => {
+                    let name = if let Some(name) = injected.name.as_ref() {
+                        name.clone()
+                    } else if let Some(interaction) = message.interaction.as_ref() {
+                        resolver
+                            .resolve_display_name(http, new_message.guild_id.unwrap(), interaction.user.id)
+                            .await
+                            .map_err(|e| anyhow::format_err!("resolve_display_name: {}", e))?
+                            .to_string()
+                    } else {
+                        message.author.name.clone()
+                    };
+                    return Ok(Some(format_user_message(
+                        &thread.mode,
+                        multi_format,
+                        name,
+                        message.timestamp.with_timezone(&chrono::Utc),
+                        message.content.clone(),
+                        false,
+                    )));
+                }
+            };
+            return Ok(Some(backend::Message {
+                role,
+                name: None,
+                content,
+                mentioned: false,
+            }));
+        }
+
+        let translated = thread.translations.lock().get(&message.id).cloned();
+        let embed_text = reply_embed_text(message, me_id);
+        let source = translated.as_deref().or(embed_text.as_deref()).unwrap_or(&message.content);
+
+        Ok(Some(if message.author.id == me_id {
+            // The rest of a translated reply is already part of its first message.
+            if translated.as_deref() == Some("") {
+                return Ok(None);
+            }
+
+            // In ensembles, the rest of the cast speak like anyone else in the thread would.
+            if let Some(speaker) = thread
+                .state
+                .speakers
+                .get(&message.id)
+                .filter(|speaker| thread.speaker.as_ref() != Some(*speaker))
+            {
+                return Ok(Some(format_user_message(
+                    &ThreadMode::Multi,
+                    multi_format,
+                    self.persona_name(speaker),
+                    message.timestamp.with_timezone(&chrono::Utc),
+                    backend::strip_reasoning(source).to_string(),
+                    false,
+                )));
+            }
+
+            // Messages injected before their roles were kept in the thread's state are only marked by the command that injected them.
+            if message.interaction.as_ref().is_some_and(|i| {
+                i.kind == serenity::model::application::interaction::InteractionType::ApplicationCommand && i.name == INJECT_SYSTEM_COMMAND_NAME
+            }) {
+                return Ok(Some(backend::Message {
+                    role: backend::Role::System,
+                    name: None,
+                    content: message.content.clone(),
+                    mentioned: false,
+                }));
+            }
+
+            backend::Message {
+                role: backend::Role::Assistant,
+                name: None,
+                content: backend::strip_reasoning(source).to_string(),
+                mentioned: false,
+            }
+        } else {
+            if thread.mode == ThreadMode::Single && !thread.no_mention && !message.mentions_user_id(me_id) {
+                return Ok(None);
+            }
+
+            if self.filters.matches_input(&message.content) {
+                return Ok(None);
+            }
+
+            let guild_id = new_message.guild_id.unwrap();
+            let display_name = resolver
+                .resolve_display_name(http, guild_id, message.author.id)
+                .await
+                .map_err(|e| anyhow::format_err!("resolve_display_name: {}", e))?
+                .to_string();
+
+            let content = match thread.mode {
+                ThreadMode::Single => resolver
+                    .resolve_message(
+                        http,
+                        guild_id,
+                        &STRIP_SINGLE_USER_REGEX.replace(source, |c: &regex::Captures| {
+                            if c["user_id"].parse::<u64>().ok() == Some(me_id.0) {
+                                "".to_string()
+                            } else {
+                                c[0].to_string()
+                            }
+                        }),
+                    )
+                    .await
+                    .map_err(|e| anyhow::format_err!("resolve_message: {}", e))?,
+                ThreadMode::Multi => resolver
+                    .resolve_message(http, guild_id, source)
+                    .await
+                    .map_err(|e| anyhow::format_err!("resolve_message: {}", e))?,
+            };
+
+            // Reaction GIFs are just links otherwise, so say what they're of, going by their embeds' titles if they have any.
+            let mut content = gif::replace_links(&content, |url| {
+                message
+                    .embeds
+                    .iter()
+                    .find(|embed| embed.url.as_deref() == Some(url))
+                    .and_then(|embed| embed.title.clone())
+            });
+
+            // Link previews, bot embeds and stickers are often half of what's going on, so describe them too.
+            let extras = describe_embeds_and_stickers(message);
+            if !extras.is_empty() {
+                if !content.is_empty() {
+                    content.push('\n');
+                }
+                content.push_str(&extras.join("\n"));
+            }
+            if message.content.is_empty() && extras.is_empty() {
+                return Ok(None);
+            }
+
+            // Quote whatever the message replies to, so it's clear what it's about. There's no need if it's replying to the message right before it.
+            if let Some(referenced_message) = message
+                .message_reference
+                .as_ref()
+                .and_then(|r| r.message_id)
+                .and_then(|message_id| thread.messages.get(&message_id))
+                .or(message.referenced_message.as_deref())
+            {
+                let previous_id = thread.messages.range(..message.id).next_back().map(|(id, _)| *id);
+                if previous_id != Some(referenced_message.id) && !referenced_message.content.is_empty() {
+                    let excerpt = resolver
+                        .resolve_message(http, guild_id, &referenced_message.content)
+                        .await
+                        .map_err(|e| anyhow::format_err!("resolve_message: {}", e))?;
+                    content = format!("{}\n{}", quote_excerpt(&excerpt), content);
+                }
+            }
+
+            format_user_message(
+                &thread.mode,
+                multi_format,
+                display_name,
+                message.timestamp.with_timezone(&chrono::Utc),
+                content,
+                message.mentions_user_id(me_id),
+            )
+        }))
+    }
+
+    /// Fetches older messages until there's enough history before a prompt to fill the backend's input budget, or there's none left.
+    async fn load_history_for_prompt(
+        &self,
+        http: &serenity::http::Http,
+        thread: &mut ThreadInfo,
+        prompt_id: serenity::model::id::MessageId,
+        backend_name: &str,
+        backend: &std::sync::Arc<dyn backend::Backend + Send + Sync>,
+        max_input_tokens: usize,
+    ) -> Result<(), anyhow::Error> {
+        let me_id = self.me_id();
+
+        loop {
+            let loaded_from = if let Some(loaded_from) = thread.loaded_from {
+                loaded_from
+            } else {
+                return Ok(());
+            };
+
+            // This only needs to be a rough estimate of how much of the budget the loaded history would take up.
+            let mut reached_forget_break = false;
+            let mut messages = vec![];
+            for message in thread.messages.range(loaded_from..=prompt_id).rev().map(|(_, m)| m) {
+                if thread.is_forget_break(message, me_id) {
+                    reached_forget_break = true;
+                    break;
+                }
+
+                messages.push(backend::Message {
+                    role: backend::Role::Assistant,
+                    name: None,
+                    content: message.content.clone(),
+                    mentioned: false,
+                });
+            }
+
+            let (_, counts) = count_thread_message_tokens(thread, backend_name, backend, messages).await?;
+            if reached_forget_break || backend.num_overhead_tokens() + counts.into_iter().sum::<usize>() >= max_input_tokens {
+                return Ok(());
+            }
+
+            if !thread.extend_history(http).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Assembles the messages to send to the backend in reply to a message, as many as fit in the input budget, and counts how many tokens
+    /// of input they are. Also returns the newest message that was left out for lack of room, if any were.
+    #[allow(clippy::too_many_arguments)]
+    async fn build_prompt(
+        &self,
+        http: &serenity::http::Http,
+        thread: &ThreadInfo,
+        new_message: &serenity::model::channel::Message,
+        settings: &ChatSettings,
+        backend_name: &str,
+        binding: &BackendBinding,
+        input_budget: usize,
+    ) -> Result<(Vec<backend::Message>, usize, Option<serenity::model::id::MessageId>), anyhow::Error> {
+        let me_id = self.me_id();
+        let BackendBinding { backend, multi_format, .. } = binding;
+
+        let mut resolver = self.resolver.lock().await;
+
+        let mut multi_format = settings.multi_format.as_ref().unwrap_or(multi_format).clone();
+        if !backend.supports_name_field() {
+            multi_format.use_name_field = Some(false);
+        }
+        multi_format.utc_offset.get_or_insert(self.config.utc_offset);
+        let multi_format = &multi_format;
+
+        let system_message = std::iter::once(settings.system_message.as_str())
+            .chain(
+                settings
+                    .system_segments
+                    .iter()
+                    .filter_map(|name| self.config.system_segments.get(name).map(|segment| segment.as_str())),
+            )
+            .filter(|s| !s.trim().is_empty())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        let now = chrono::Utc::now().with_timezone(&self.config.utc_offset.0);
+        let system_prompt = if template::has_placeholders(&system_message) {
+            let guild_id = new_message.guild_id.unwrap();
+            let vars = std::collections::HashMap::from([
+                (
+                    "bot_name",
+                    resolver
+                        .resolve_display_name(http, guild_id, me_id)
+                        .await
+                        .map_err(|e| anyhow::format_err!("resolve_display_name: {}", e))?
+                        .to_string(),
+                ),
+                (
+                    "user",
+                    resolver
+                        .resolve_display_name(http, guild_id, new_message.author.id)
+                        .await
+                        .map_err(|e| anyhow::format_err!("resolve_display_name: {}", e))?
+                        .to_string(),
+                ),
+                ("date", now.format("%Y-%m-%d").to_string()),
+                ("time", now.format("%H:%M").to_string()),
+                (
+                    "guild",
+                    resolver
+                        .resolve_guild_name(http, guild_id)
+                        .await
+                        .map_err(|e| anyhow::format_err!("resolve_guild_name: {}", e))?
+                        .to_string(),
+                ),
+                ("thread_title", thread.title.clone()),
+            ]);
+            template::expand(&system_message, &vars)
+        } else {
+            system_message
+        };
+
+        let system_prompt = match self.config.cast_list.as_ref().filter(|_| thread.mode == ThreadMode::Multi) {
+            Some(cast_list) => {
+                let cast_list = self.cast_list(http, &mut resolver, thread, new_message, cast_list).await?;
+                [system_prompt, cast_list]
+                    .iter()
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n\n")
+            }
+            None => system_prompt,
+        };
+        let system_prompt = if settings.current_time {
+            let current_time = format!("The current time is {}.", now.format("%A, %Y-%m-%d %H:%M (UTC%:z)"));
+            [system_prompt, current_time]
+                .iter()
+                .filter(|s| !s.is_empty())
+                .map(|s| s.as_str())
+                .collect::<Vec<_>>()
+                .join("\n\n")
+        } else {
+            system_prompt
+        };
+
+        let system_message = backend::Message {
+            role: backend::Role::System,
+            name: None,
+            content: if thread.mode == ThreadMode::Multi {
+                format!(
+                    "Your name is {}.\n\n{}\n\nDo not prefix your replies with your name and timestamp.",
+                    if let Some(speaker) = thread.speaker.as_ref() {
+                        self.persona_name(speaker)
+                    } else {
+                        resolver
+                            .resolve_display_name(http, new_message.guild_id.unwrap(), me_id)
+                            .await
+                            .map_err(|e| anyhow::format_err!("resolve_display_name: {}", e))?
+                            .to_string()
+                    },
+                    system_prompt
+                )
+            } else {
+                system_prompt
+            },
+            mentioned: false,
+        };
+
+        let mut pinned_messages = vec![system_message];
+        for (_, message) in thread.messages.range(..=new_message.id) {
+            if !self.is_prompt_message(thread, message) || !thread.state.pinned.contains(&message.id) {
+                continue;
+            }
+
+            if let Some(oai_message) = self
+                .convert_message(http, &mut resolver, thread, new_message, message, multi_format)
+                .await?
+            {
+                pinned_messages.push(oai_message);
+            }
+        }
+
+        let history = if thread.branching {
+            thread.ancestry(new_message.id, me_id)
+        } else {
+            thread.messages.range(..=new_message.id).rev().map(|(_, m)| m).collect()
+        };
+
+        let forget_break = history.iter().position(|message| thread.is_forget_break(message, me_id));
+        let forget_break_id = forget_break.map(|i| history[i].id);
+        let reached_start =
+            forget_break.is_none() && (thread.loaded_from.is_none() || (settings.overflow == preset::Overflow::DropMiddle && thread.has_opening()));
+
+        // The summary of what was left out before comes right after the pinned messages, unless it's of something since forgotten.
+        if let Some(memory) = thread.state.memory.as_ref().filter(|memory| {
+            settings.overflow.summarizes() && memory.until < new_message.id && forget_break_id.map(|id| memory.until > id).unwrap_or(true)
+        }) {
+            pinned_messages.push(backend::Message {
+                role: backend::Role::System,
+                name: None,
+                content: format!("Summary of the earlier conversation:\n{}", memory.content),
+                mentioned: false,
+            });
+        }
+
+        if let Some(prefix) = settings.prefix.as_ref() {
+            pinned_messages.push(backend::Message {
+                role: backend::Role::System,
+                name: None,
+                content: prefix.clone(),
+                mentioned: false,
+            });
+        }
+
+        let (mut pinned_messages, counts) = count_thread_message_tokens(thread, backend_name, backend, pinned_messages).await?;
+        let system_placement = settings.system_placement.unwrap_or(binding.system_placement);
+        let mut input_tokens = backend.num_overhead_tokens() + counts.iter().sum::<usize>();
+        if system_placement == preset::SystemPlacement::Both {
+            input_tokens += counts[0];
+        }
+        let system_message = pinned_messages.remove(0);
+
+        let mut messages = vec![];
+        if let Some(suffix) = settings.suffix.as_ref() {
+            let (suffix_messages, counts) = count_thread_message_tokens(
+                thread,
+                backend_name,
+                backend,
+                vec![backend::Message {
+                    role: backend::Role::System,
+                    name: None,
+                    content: suffix.clone(),
+                    mentioned: false,
+                }],
+            )
+            .await?;
+            messages.extend(suffix_messages);
+            input_tokens += counts.into_iter().sum::<usize>();
+        }
+
+        // Pinned messages were already added above.
+        let mut history = history
+            .into_iter()
+            .take(forget_break.unwrap_or(usize::MAX))
+            .filter(|message| self.is_prompt_message(thread, message) && !thread.state.pinned.contains(&message.id))
+            .collect::<Vec<_>>();
+
+        // The primary message is pinned like a settings continuation would be, so it has to be added on its own.
+        if reached_start && thread.primary_is_prompt(&self.config.defaults) {
+            history.push(&thread.primary_message);
+        }
+
+        // Dropping from the middle keeps the first exchange, as long as it fits, and fills the rest with the latest messages.
+        let mut head_messages = vec![];
+        if settings.overflow == preset::Overflow::DropMiddle && reached_start {
+            let head = history.split_off(history.len().saturating_sub(DROP_MIDDLE_HEAD_SIZE));
+            for message in head.iter() {
+                if let Some(oai_message) = self
+                    .convert_message(http, &mut resolver, thread, new_message, message, multi_format)
+                    .await?
+                {
+                    head_messages.push(oai_message);
+                }
+            }
+            let counts;
+            (head_messages, counts) = count_thread_message_tokens(thread, backend_name, backend, head_messages).await?;
+            let head_tokens = counts.into_iter().sum::<usize>();
+            if input_tokens + head_tokens <= input_budget {
+                input_tokens += head_tokens;
+            } else {
+                head_messages = vec![];
+                history.extend(head);
+            }
+        }
+
+        // With prefix caching, prompts keep starting from where the last trimmed one did, rather than shifting with every new message.
+        let caches_prefixes = backend.caches_prompt_prefixes();
+        if let Some(cache_floor) = thread.cache_floor.lock().filter(|floor| caches_prefixes && *floor <= new_message.id) {
+            history.retain(|message| message.id >= cache_floor);
+        }
+
+        // Messages are weighed against each other before any are left out, so this goes back as far as any message could still be kept.
+        let history_budget = input_budget.saturating_sub(input_tokens);
+        let mut selector = retention::Selector::new(&self.config.retention);
+        let mut candidates = vec![];
+        for batch in history.chunks(TOKEN_COUNT_BATCH_SIZE) {
+            if selector.is_full(history_budget) {
+                break;
+            }
+
+            let mut oai_messages = vec![];
+            let mut ids = vec![];
+            for message in batch {
+                if let Some(oai_message) = self
+                    .convert_message(http, &mut resolver, thread, new_message, message, multi_format)
+                    .await?
+                {
+                    oai_messages.push(oai_message);
+                    ids.push((message.id, message.author.id == me_id));
+                }
+            }
+
+            let (oai_messages, counts) = count_thread_message_tokens(thread, backend_name, backend, oai_messages).await?;
+            for ((oai_message, message_tokens), (id, from_bot)) in oai_messages.into_iter().zip(counts).zip(ids) {
+                selector.push(from_bot, message_tokens);
+                candidates.push((id, oai_message, message_tokens));
+            }
+        }
+
+        let mut keep = selector.select(history_budget);
+        if caches_prefixes && keep.contains(&false) {
+            // Trimming further than needed leaves room for the conversation to grow before the prompt's start has to move again.
+            keep = selector.select(history_budget * CACHED_PREFIX_TRIM_PERCENT / 100);
+            *thread.cache_floor.lock() = candidates
+                .iter()
+                .zip(keep.iter())
+                .filter(|(_, keep)| **keep)
+                .map(|((id, _, _), _)| *id)
+                .min();
+        }
+
+        let mut left_out = None;
+        for ((id, oai_message, message_tokens), keep) in candidates.into_iter().zip(keep) {
+            if keep {
+                messages.push(oai_message);
+                input_tokens += message_tokens;
+            } else if left_out.is_none() {
+                left_out = Some(id);
+            }
+        }
+
+        messages.extend(head_messages);
+        messages.extend(pinned_messages.into_iter().rev());
+        if system_placement != preset::SystemPlacement::Last {
+            messages.push(system_message.clone());
+        }
+        messages.reverse();
+        if system_placement != preset::SystemPlacement::First {
+            messages.push(system_message);
+        }
+
+        Ok((messages, input_tokens, left_out))
+    }
+
+    /// Replies to a message in a thread, streaming the response back in chunks. If replying fails, the error is posted with a button to try
+    /// again, or the message is deleted so it can be sent again if failed messages are deleted.
+    async fn reply(
+        &self,
+        ctx: &serenity::client::Context,
+        thread: &mut ThreadInfo,
+        new_message: &serenity::model::channel::Message,
+    ) -> Result<(), anyhow::Error> {
+        if thread.cast.len() > 1 {
+            // Later exchanges reply to the cast's own messages, but they're still for whoever asked for this one.
+            let requester = thread.requester;
+            thread.requester = requester.or(Some(new_message.author.id));
+            let r = self.reply_as_ensemble(ctx, thread, new_message).await;
+            thread.requester = requester;
+            return r;
+        }
+        self.reply_or_continue(ctx, thread, new_message, None).await
+    }
+
+    /// What a preset's persona is called in ensembles: its persona's name, or else the preset's.
+    fn persona_name(&self, preset_name: &str) -> String {
+        self.presets
+            .get(preset_name)
+            .and_then(|preset| preset.persona.as_ref())
+            .and_then(|persona| persona.name.clone())
+            .unwrap_or_else(|| preset_name.to_string())
+    }
+
+    /// Replies in an ensemble thread: one of the cast replies to the message, and then, up to `max_exchanges` times, another of the cast
+    /// replies to that, if they're being talked to.
+    async fn reply_as_ensemble(
+        &self,
+        ctx: &serenity::client::Context,
+        thread: &mut ThreadInfo,
+        new_message: &serenity::model::channel::Message,
+    ) -> Result<(), anyhow::Error> {
+        let mut prompt = new_message.clone();
+        let mut previous = thread.state.speakers.get(&new_message.id).cloned();
+        for _ in 0..=self.config.ensemble.max_exchanges {
+            let speaker = if let Some(speaker) = self.pick_speaker(thread, &prompt, previous.as_deref(), previous.is_none()).await? {
+                speaker
+            } else {
+                break;
+            };
+            prompt = if let Some(reply) = self.speak(ctx, thread, &prompt, Some(speaker.clone())).await? {
+                reply
+            } else {
+                break;
+            };
+            previous = Some(speaker);
+        }
+        Ok(())
+    }
+
+    /// Replies to a message, as one of an ensemble's cast if a speaker is given, and returns the last message of the reply, if there was
+    /// one. The thread stays locked until everyone's done, so the reply is picked up here to be seen by whoever's next.
+    async fn speak(
+        &self,
+        ctx: &serenity::client::Context,
+        thread: &mut ThreadInfo,
+        prompt: &serenity::model::channel::Message,
+        speaker: Option<String>,
+    ) -> Result<Option<serenity::model::channel::Message>, anyhow::Error> {
+        let me_id = self.me_id();
+
+        thread.speaker = speaker.clone();
+        let r = self.reply_or_continue(ctx, thread, prompt, None).await;
+        thread.speaker = None;
+        r?;
+
+        let mut replies = vec![];
+        for message in prompt.channel_id.messages(&ctx.http, |b| b.after(prompt.id)).await?.into_iter().rev() {
+            if message.author.id == me_id
+                && message.message_reference.as_ref().and_then(|r| r.message_id) == Some(prompt.id)
+                && self.is_prompt_message(thread, &message)
+            {
+                replies.push(message.clone());
+            }
+            thread.insert_message(message, self.config.message_history_size);
+        }
+
+        if let Some(speaker) = speaker.filter(|_| !replies.is_empty()) {
+            for reply in replies.iter() {
+                thread.state.speakers.insert(reply.id, speaker.clone());
+            }
+            self.store.save_thread(thread.primary_message.channel_id, &thread.state).await?;
+        }
+
+        Ok(replies.pop().map(|mut reply| {
+            reply.guild_id = Some(thread.guild_id);
+            reply
+        }))
+    }
+
+    /// Who of an ensemble's cast replies to a message, if anyone. Whoever the message names speaks; otherwise the router backend decides, if
+    /// there is one. If someone has to reply, e.g. to people, the cast take turns if need be, but otherwise they only reply to each other
+    /// when they're talked to.
+    async fn pick_speaker(
+        &self,
+        thread: &ThreadInfo,
+        prompt: &serenity::model::channel::Message,
+        previous: Option<&str>,
+        must_reply: bool,
+    ) -> Result<Option<String>, anyhow::Error> {
+        let me_id = self.me_id();
+        let candidates = thread.cast.iter().filter(|name| Some(name.as_str()) != previous).collect::<Vec<_>>();
+        let names = |s: &str| {
+            candidates
+                .iter()
+                .find(|name| {
+                    regex::Regex::new(&format!(r"(?i)\b{}\b", regex::escape(&self.persona_name(name))))
+                        .map(|re| re.is_match(s))
+                        .unwrap_or(false)
+                })
+                .map(|name| name.to_string())
+        };
+
+        let content = reply_embed_text(prompt, me_id).unwrap_or_else(|| prompt.content.clone());
+        if let Some(speaker) = names(&content) {
+            return Ok(Some(speaker));
+        }
+
+        if let Some(router) = self.config.ensemble.router_backend.as_ref().and_then(|name| self.backends.get(name)) {
+            let mut transcript = thread
+                .messages
+                .range(..=prompt.id)
+                .rev()
+                .filter(|(_, m)| self.is_prompt_message(thread, m))
+                .take(ROUTER_HISTORY_SIZE)
+                .map(|(_, m)| {
+                    let name = if m.author.id == me_id {
+                        thread
+                            .state
+                            .speakers
+                            .get(&m.id)
+                            .map(|speaker| self.persona_name(speaker))
+                            .unwrap_or_default()
+                    } else {
+                        m.author.name.clone()
+                    };
+                    format!("{}: {}", name, reply_embed_text(m, me_id).unwrap_or_else(|| m.content.clone()))
+                })
+                .collect::<Vec<_>>();
+            transcript.reverse();
+
+            let vars = std::collections::HashMap::from([(
+                "names",
+                candidates.iter().map(|name| self.persona_name(name)).collect::<Vec<_>>().join(", "),
+            )]);
+            let messages = [
+                backend::Message {
+                    role: backend::Role::System,
+                    name: None,
+                    content: template::expand(&self.config.ensemble.router_prompt, &vars),
+                    mentioned: false,
+                },
+                backend::Message {
+                    role: backend::Role::User("user".to_string()),
+                    name: None,
+                    content: transcript.join("\n\n"),
+                    mentioned: false,
+                },
+            ];
+            match router.complete(&messages, &parameters_default()).await {
+                Ok(response) => {
+                    if let Some(speaker) = names(&response) {
+                        return Ok(Some(speaker));
+                    }
+                }
+                Err(e) => {
+                    log::warn!("failed to pick a speaker for {}: {:?}", prompt.id, e);
+                }
+            }
+        }
+
+        if !must_reply {
+            return Ok(None);
+        }
+
+        // Take turns, starting after whoever spoke last.
+        let last_speaker = thread.messages.range(..prompt.id).rev().find_map(|(id, _)| thread.state.speakers.get(id));
+        let next = last_speaker
+            .and_then(|last_speaker| thread.cast.iter().position(|name| name == last_speaker))
+            .map(|i| (i + 1) % thread.cast.len())
+            .unwrap_or(0);
+        Ok(Some(thread.cast[next].clone()))
+    }
+
+    /// Logs a request for /analytics, and counts it against the backend's budget. Failing to log it is only worth a warning.
+    async fn record_usage(
+        &self,
+        http: &serenity::http::Http,
+        new_message: &serenity::model::channel::Message,
+        backend_name: &str,
+        usage: backend::Usage,
+        failed: bool,
+    ) {
+        let now = chrono::Utc::now();
+        if let Err(e) = self
+            .store
+            .record_usage(&store::UsageRecord {
+                at: now,
+                thread_id: new_message.channel_id,
+                user_id: new_message.author.id,
+                backend: backend_name.to_string(),
+                prompt_tokens: usage.prompt_tokens,
+                completion_tokens: usage.completion_tokens,
+                failed,
+            })
+            .await
+        {
+            log::warn!("failed to record usage for {}: {:?}", new_message.id, e);
+        }
+
+        let budget = if let Some(budget) = self.backends.get(backend_name).and_then(|binding| binding.budget.as_ref()) {
+            budget
+        } else {
+            return;
+        };
+        let crossed = self.budget.lock().record(
+            backend_name,
+            budget,
+            budget.cost(usage.prompt_tokens, usage.completion_tokens),
+            now.with_timezone(&self.config.utc_offset.0),
+        );
+        for (period, spent) in crossed {
+            self.alert_budget(http, backend_name, budget, period, spent).await;
+        }
+    }
+
+    /// Tells the audit channel, if there is one, that a backend has reached one of its limits.
+    async fn alert_budget(&self, http: &serenity::http::Http, backend_name: &str, budget: &budget::Config, period: budget::Period, spent: f64) {
+        log::warn!("backend {} has reached its {} budget: {:.2} spent", backend_name, period.name(), spent);
+
+        let audit_channel_id = if let Some(audit_channel_id) = self.config.audit_channel_id {
+            serenity::model::id::ChannelId(audit_channel_id)
+        } else {
+            return;
+        };
+        let until = match period {
+            budget::Period::Day => "the end of the day",
+            budget::Period::Month => "the end of the month",
+        };
+        let mut description = format!(
+            "The {} backend has spent {:.2} so far, reaching its {} budget.",
+            backend_name,
+            spent,
+            period.name()
+        );
+        if let Some(fallback_backend) = budget.fallback_backend.as_ref() {
+            description.push_str(&format!(" New requests will go to {} instead until {}.", fallback_backend, until));
+        }
+        if let Err(e) = audit_channel_id
+            .send_message(http, |m| {
+                m.embed(|e| {
+                    e.title("Budget reached")
+                        .color(serenity::utils::colours::css::WARNING)
+                        .description(description)
+                })
+            })
+            .await
+        {
+            log::warn!("failed to send budget alert for {}: {:?}", backend_name, e);
+        }
+    }
+
+    /// The backend to use instead of one that's over its budget, if it has a fallback. The thread's parameters are reset if they don't
+    /// make sense to the fallback.
+    fn backend_within_budget<'a>(
+        &'a self,
+        settings: &mut ChatSettings,
+        backend_name: &'a String,
+        binding: &'a BackendBinding,
+    ) -> (&'a String, &'a BackendBinding) {
+        let (fallback_backend_name, fallback_binding) = if let Some((fallback_backend_name, fallback_binding)) = binding
+            .budget
+            .as_ref()
+            .and_then(|budget| budget.fallback_backend.as_ref())
+            .and_then(|fallback_backend| self.backends.get_key_value(fallback_backend))
+        {
+            (fallback_backend_name, fallback_binding)
+        } else {
+            return (backend_name, binding);
+        };
+
+        if !self
+            .budget
+            .lock()
+            .is_over(backend_name, chrono::Utc::now().with_timezone(&self.config.utc_offset.0))
+        {
+            return (backend_name, binding);
+        }
+
+        if fallback_binding.backend.validate_parameters(&settings.parameters).is_err() {
+            settings.parameters = toml::Value::Table(Default::default());
+        }
+        (fallback_backend_name, fallback_binding)
+    }
+
+    /// The backend to reply to a message with: the thread's own, unless it's only for roles whoever asked for the reply doesn't have, in
+    /// which case the default backend, telling them why the first time. None if they can't use that either.
+    async fn backend_for_requester<'a>(
+        &'a self,
+        ctx: &serenity::client::Context,
+        thread: &mut ThreadInfo,
+        new_message: &serenity::model::channel::Message,
+        settings: &mut ChatSettings,
+        backend_name: &'a String,
+        binding: &'a BackendBinding,
+    ) -> Result<Option<(&'a String, &'a BackendBinding)>, anyhow::Error> {
+        if binding.allowed_roles.is_empty() {
+            return Ok(Some((backend_name, binding)));
+        }
+
+        let requester = thread.requester.unwrap_or(new_message.author.id);
+        let roles = if let Some(member) = new_message.member.as_ref().filter(|_| requester == new_message.author.id) {
+            member.roles.clone()
+        } else {
+            thread
+                .guild_id
+                .member(ctx, requester)
+                .await
+                .map(|member| member.roles)
+                .unwrap_or_default()
+        };
+        let may_use = |binding: &BackendBinding| binding.allowed_roles.is_empty() || roles.iter().any(|role| binding.allowed_roles.contains(&role.0));
+        if may_use(binding) {
+            return Ok(Some((backend_name, binding)));
+        }
+
+        let (default_backend_name, default_binding) = if let Some((default_backend_name, default_binding)) = self
+            .config
+            .defaults
+            .backend
+            .as_ref()
+            .and_then(|backend_name| self.backends.get_key_value(backend_name))
+            .or_else(|| self.backends.first())
+            .filter(|(default_backend_name, default_binding)| *default_backend_name != backend_name && may_use(default_binding))
+        {
+            (default_backend_name, default_binding)
+        } else {
+            new_message
+                .channel_id
+                .send_message(&ctx.http, |m| {
+                    m.embed(|e| {
+                        e.title("Backend not available")
+                            .color(serenity::utils::colours::css::DANGER)
+                            .description(format!("The {} backend is only for some roles.", backend_name))
+                    })
+                    .reference_message(new_message)
+                })
+                .await?;
+            return Ok(None);
+        };
+
+        if thread.told_backend_fallback.insert(requester) {
+            new_message
+                .channel_id
+                .send_message(&ctx.http, |m| {
+                    m.embed(|e| {
+                        e.title("Using another backend")
+                            .color(serenity::utils::colours::css::WARNING)
+                            .description(format!(
+                                "The {} backend is only for some roles, so I'll reply to you with {} instead.",
+                                backend_name, default_backend_name
+                            ))
+                    })
+                    .reference_message(new_message)
+                })
+                .await?;
+        }
+
+        // The thread's parameters are for its own backend, so they may not make sense to this one.
+        if default_binding.backend.validate_parameters(&settings.parameters).is_err() {
+            settings.parameters = toml::Value::Table(Default::default());
+        }
+        Ok(Some((default_backend_name, default_binding)))
+    }
+
+    /// Replies to a message, or continues a reply to it that was cut off partway, given what was generated before it was. If a reply is cut
+    /// off after some of it was sent, what was generated is kept so it can be continued.
+    async fn reply_or_continue(
+        &self,
+        ctx: &serenity::client::Context,
+        thread: &mut ThreadInfo,
+        new_message: &serenity::model::channel::Message,
+        partial: Option<String>,
+    ) -> Result<(), anyhow::Error> {
+        let me_id = self.me_id();
+
+        if self.filters.matches_input(&new_message.content) {
+            new_message
+                .channel_id
+                .send_message(&ctx.http, |m| {
+                    m.embed(|e| {
+                        e.title("Refused")
+                            .color(serenity::utils::colours::css::DANGER)
+                            .description("Sorry, I can't respond to that here.")
+                    })
+                    .reference_message(new_message)
+                })
+                .await?;
+            return Ok(());
+        }
+
+        let mut settings = if let Some(settings) = self.check_settings(&ctx.http, thread).await? {
+            settings
+        } else {
+            return Ok(());
+        };
+
+        let (backend_name, binding) = if let Some((backend_name, binding)) = self.resolve_backend(thread) {
+            (backend_name, binding)
+        } else {
+            return Ok(());
+        };
+        let (backend_name, binding) = if let Some((backend_name, binding)) = self
+            .backend_for_requester(ctx, thread, new_message, &mut settings, backend_name, binding)
+            .await?
+        {
+            (backend_name, binding)
+        } else {
+            return Ok(());
+        };
+        let (backend_name, binding) = self.backend_within_budget(&mut settings, backend_name, binding);
+        let input_budget = binding.input_budget();
+        let BackendBinding {
+            backend,
+            nsfw_allowed,
+            sfw_allowed,
+            ..
+        } = binding;
+
+        let age_restricted = self.gateway.parent_nsfw.load(std::sync::atomic::Ordering::Relaxed);
+        if (age_restricted && !nsfw_allowed) || (!age_restricted && !sfw_allowed) {
+            new_message
+                .channel_id
+                .send_message(&ctx.http, |m| {
+                    m.embed(|e| {
+                        e.title("Backend not available")
+                            .color(serenity::utils::colours::css::DANGER)
+                            .description(format!(
+                                "The {} backend can't be used in {} channels.",
+                                backend_name,
+                                if age_restricted { "age-restricted" } else { "non-age-restricted" }
+                            ))
+                    })
+                    .reference_message(new_message)
+                })
+                .await?;
+            return Ok(());
+        }
+
+        if let Err(e) = self
+            .load_history_for_prompt(&ctx.http, thread, new_message.id, backend_name, backend, input_budget)
+            .await
+        {
+            log::warn!("failed to load more history for {}: {:?}", new_message.channel_id, e);
+        }
+
+        self.describe_new_emoji(thread).await;
+        self.describe_new_avatars(thread, new_message).await;
+
+        let translation = self
+            .config
+            .translation
+            .as_ref()
+            .filter(|_| thread.translate)
+            .and_then(|translation| Some((translation, self.backends.get(&translation.backend)?)));
+
+        // The placeholder becomes the first piece of the reply, so it's only left over if there's nothing to reply with.
+        let pending_placeholder = parking_lot::Mutex::new(None);
+
+        // The newest message the prompt had no room for, so the summary of what's left out can catch up once the reply is done.
+        let left_out = parking_lot::Mutex::new(None);
+
+        let r = (|| async {
+            let language = if let Some((translation, translation_binding)) = translation {
+                self.translate_incoming(thread, new_message, translation, translation_binding).await?
+            } else {
+                None
+            };
+
+            let (mut messages, input_tokens, prompt_left_out) = self
+                .build_prompt(&ctx.http, thread, new_message, &settings, backend_name, binding, input_budget)
+                .await?;
+            *left_out.lock() = prompt_left_out;
+            if partial.is_none() {
+                push_prefill(&mut messages, binding, &settings);
+            }
+            if let Some(partial) = partial.as_ref() {
+                messages.push(backend::Message {
+                    role: backend::Role::Assistant,
+                    name: None,
+                    content: partial.clone(),
+                    mentioned: false,
+                });
+                messages.push(backend::Message {
+                    role: backend::Role::System,
+                    name: None,
+                    content: self.config.continue_prompt.clone(),
+                    mentioned: false,
+                });
+            }
+
+            if self.config.log_prompts {
+                log::info!("{} ({:?}) <- {:#?}", backend_name, settings.parameters, messages);
+            } else {
+                log::info!(
+                    "{} ({:?}) <- {} messages, {} tokens",
+                    backend_name,
+                    settings.parameters,
+                    messages.len(),
+                    input_tokens
+                );
+            }
+
+            // Typing stops as soon as the reply starts showing up, or if it fails.
+            let mut typing = Some(typing::Keepalive::start(ctx.http.clone(), new_message.channel_id));
+
+            if let Some(placeholder) = self.config.placeholder.as_ref() {
+                let vars = std::collections::HashMap::from([("model", thread.state.model.as_deref().unwrap_or(backend.model()).to_string())]);
+                *pending_placeholder.lock() = Some(
+                    new_message
+                        .channel_id
+                        .send_message(&ctx.http, |m| {
+                            m.content(template::expand(placeholder, &vars))
+                                .reference_message(new_message)
+                                .allowed_mentions(reply_mentions)
+                        })
+                        .await
+                        .map_err(|e| anyhow::format_err!("send_message: {}", e))?,
+                );
+            }
+
+            // If the content filter stops a reply before any of it is sent, it's tried again as configured: first with a softened system
+            // message, then on the fallback backend.
+            let content_filter = &self.config.content_filter;
+            let mut attempt_backend_name = backend_name;
+            let mut attempt_binding = binding;
+            let mut attempt_messages = messages.clone();
+            let mut attempt_parameters = settings.parameters.clone();
+            let mut softened = false;
+            let mut fell_back = false;
+            let mut repetition_retried = false;
+            // Translated replies can only be sent once they're done, and replies that might be looping once they've been checked.
+            let buffered = language.is_some() || self.config.repetition_guard.is_some();
+            // Replies that start by saying who they're from, the way messages in multi-user threads are attributed, have that taken off.
+            let reply_names = if self.config.strip_reply_names && partial.is_none() {
+                let mut names = vec![self
+                    .resolver
+                    .lock()
+                    .await
+                    .resolve_display_name(&ctx.http, thread.guild_id, self.me_id())
+                    .await
+                    .map_err(|e| anyhow::format_err!("resolve_display_name: {}", e))?
+                    .to_string()];
+                names.extend(settings.persona.as_ref().and_then(|persona| persona.name.clone()));
+                Some(names)
+            } else {
+                None
+            };
+
+            let mut stream_error;
+            let mut response;
+            let mut stopper;
+            let mut thinker;
+            let mut unattributor;
+            let mut redactor;
+            let mut trimmer;
+            let mut fencer;
+            let mut chunker;
+            let mut sent;
+            let mut usage;
+            loop {
+                usage = backend::UsageSlot::default();
+                let mut stream = tokio::time::timeout(
+                    attempt_binding.request_timeout,
+                    attempt_binding.backend.request(
+                        &attempt_messages,
+                        &attempt_parameters,
+                        Some(&new_message.author.id.to_string()),
+                        usage.clone(),
+                    ),
+                )
+                .await
+                .map_err(|e| anyhow::format_err!("timed out: {}", e))??;
+
+                // The prefill is the start of the reply, so it's sent along with the rest of it.
+                if let Some(prefill) = attempt_messages
+                    .last()
+                    .filter(|m| m.role == backend::Role::Assistant)
+                    .map(|m| m.content.clone())
+                {
+                    stream = Box::pin(futures_util::stream::once(async move { Ok(prefill) }).chain(stream));
+                }
+
+                stream_error = None;
+                response = String::new();
+                stopper = stop::Stopper::new(&attempt_binding.stop_sequences);
+                thinker = attempt_binding.think_tags.filter();
+                unattributor = reply_names.as_ref().map(|names| {
+                    template::AttributionStripper::new(
+                        settings
+                            .multi_format
+                            .as_ref()
+                            .unwrap_or(&attempt_binding.multi_format)
+                            .attribution_regex(names),
+                    )
+                });
+                redactor = self.filters.redactor();
+                trimmer = if settings.trim_incomplete_sentence {
+                    Some(sentence::Trimmer::default())
+                } else {
+                    None
+                };
+                fencer = if self.config.fence_code {
+                    Some(codefence::Fencer::default())
+                } else {
+                    None
+                };
+                chunker = unichunk::Chunker::new(if self.config.embed_replies {
+                    EMBED_DESCRIPTION_MAX_LENGTH
+                } else {
+                    MESSAGE_MAX_LENGTH
+                });
+                sent = vec![];
+                while let Some(content) = match tokio::time::timeout(attempt_binding.chunk_timeout, stream.next()).await {
+                    Ok(content) => content,
+                    // Once some of the reply has been sent, it's kept as an incomplete reply rather than thrown away.
+                    Err(e) if !sent.is_empty() => Some(Err(backend::RequestStreamError::Other(anyhow::format_err!("timed out: {}", e)))),
+                    Err(e) => {
+                        return Err(anyhow::format_err!("timed out: {}", e));
+                    }
+                } {
+                    let content = match content {
+                        Ok(content) => content,
+                        Err(e) => {
+                            stream_error = Some(e);
+                            break;
+                        }
+                    };
+
+                    let content = stopper.push(&content);
+                    let content = if let Some(thinker) = thinker.as_mut() {
+                        thinker.push(&content)
+                    } else {
+                        content
+                    };
+                    let content = if let Some(unattributor) = unattributor.as_mut() {
+                        unattributor.push(&content)
+                    } else {
+                        content
+                    };
+                    let content = redactor.push(&content);
+                    let content = if let Some(trimmer) = trimmer.as_mut() {
+                        trimmer.push(&content)
+                    } else {
+                        content
+                    };
+                    let content = if let Some(fencer) = fencer.as_mut() {
+                        fencer.push(&content)
+                    } else {
+                        content
+                    };
+                    response.push_str(&content);
+                    if !buffered {
+                        for c in chunker.push(&content) {
+                            typing.take();
+                            let placeholder = pending_placeholder.lock().take();
+                            sent.push(
+                                self.send_reply_chunk(&ctx.http, new_message, settings.persona.as_ref(), &c, sent.is_empty(), placeholder)
+                                    .await?
+                                    .id,
+                            );
+                        }
+                    }
+                    if stopper.stopped() {
+                        break;
+                    }
+                }
+
+                if let Some(repetition_guard) = self.config.repetition_guard.as_ref().filter(|_| !repetition_retried) {
+                    if stream_error.is_none() && repetition_guard.is_repetitive(&response) {
+                        log::info!(
+                            "{} repeated itself replying to {}, retrying with a higher frequency penalty",
+                            attempt_backend_name,
+                            new_message.id
+                        );
+                        repetition_retried = true;
+                        merge_parameters(
+                            &mut attempt_parameters,
+                            toml::toml! { frequency_penalty = (repetition_guard.retry_frequency_penalty) }.into(),
+                        );
+                        continue;
+                    }
+                }
+
+                if !matches!(stream_error, Some(backend::RequestStreamError::ContentFilter)) || !sent.is_empty() || !response.is_empty() {
+                    break;
+                }
+
+                if let Some(suffix) = content_filter.retry_system_suffix.as_ref().filter(|_| !softened) {
+                    log::info!(
+                        "{} filtered the reply to {}, retrying with a softened system message",
+                        attempt_backend_name,
+                        new_message.id
+                    );
+                    softened = true;
+                    soften_system_message(&mut attempt_messages, suffix);
+                    continue;
+                }
+
+                if let Some((fallback_backend_name, fallback_binding)) = content_filter
+                    .fallback_backend
+                    .as_ref()
+                    .filter(|_| !fell_back)
+                    .and_then(|fallback_backend| self.backends.get_key_value(fallback_backend))
+                {
+                    log::info!(
+                        "{} filtered the reply to {}, retrying on {}",
+                        attempt_backend_name,
+                        new_message.id,
+                        fallback_backend_name
+                    );
+                    fell_back = true;
+                    (attempt_messages, _, _) = self
+                        .build_prompt(
+                            &ctx.http,
+                            thread,
+                            new_message,
+                            &settings,
+                            fallback_backend_name,
+                            fallback_binding,
+                            fallback_binding.input_budget(),
+                        )
+                        .await?;
+                    if partial.is_none() {
+                        push_prefill(&mut attempt_messages, fallback_binding, &settings);
+                    }
+                    // The thread's parameters are for its own backend, so they may not make sense to this one.
+                    if fallback_binding.backend.validate_parameters(&attempt_parameters).is_err() {
+                        attempt_parameters = toml::Value::Table(Default::default());
+                    }
+                    attempt_backend_name = fallback_backend_name;
+                    attempt_binding = fallback_binding;
+                    continue;
+                }
+
+                break;
+            }
+
+            if let (Some(backend::RequestStreamError::ContentFilter), Some(refusal)) = (stream_error.as_ref(), content_filter.refusal.as_ref()) {
+                if sent.is_empty() && response.is_empty() {
+                    typing.take();
+                    let placeholder = pending_placeholder.lock().take();
+                    self.send_reply_chunk(&ctx.http, new_message, settings.persona.as_ref(), refusal, true, placeholder)
+                        .await?;
+                    return Ok(None);
+                }
+            }
+
+            typing.take();
+
+            let usage = *usage.lock();
+            if let Some(usage) = usage {
+                log::info!(
+                    "{} usage: {} prompt tokens (estimated {}), {} completion tokens",
+                    attempt_backend_name,
+                    usage.prompt_tokens,
+                    input_tokens,
+                    usage.completion_tokens
+                );
+            }
+            self.record_usage(
+                &ctx.http,
+                new_message,
+                attempt_backend_name,
+                usage.unwrap_or(backend::Usage {
+                    prompt_tokens: input_tokens as u32,
+                    completion_tokens: 0,
+                }),
+                matches!(stream_error, Some(backend::RequestStreamError::Other(..))),
+            )
+            .await;
+
+            let mut content = stopper.flush();
+            if let Some(mut thinker) = thinker {
+                content = thinker.push(&content);
+                content.push_str(&thinker.flush());
+            }
+            if let Some(mut unattributor) = unattributor {
+                content = unattributor.push(&content);
+                content.push_str(&unattributor.flush());
+            }
+            let mut content = redactor.push(&content);
+            content.push_str(&redactor.flush());
+            if let Some(mut trimmer) = trimmer {
+                // A reply cut off for length ends at its last complete sentence instead, and isn't incomplete anymore.
+                let cut_off = matches!(stream_error, Some(backend::RequestStreamError::Length));
+                content = trimmer.push(&content);
+                content.push_str(&trimmer.flush(cut_off));
+                if cut_off {
+                    stream_error = None;
+                }
+            }
+            if let Some(mut fencer) = fencer {
+                content = fencer.push(&content);
+                content.push_str(&fencer.flush());
+            }
+            response.push_str(&content);
+            let mut chunks = if let (Some(language), Some((translation, translation_binding))) = (language.as_ref(), translation) {
+                let translated = self
+                    .run_translation_prompt(translation_binding, &translation.prompt, language, &response)
+                    .await?;
+                chunker.push(&self.filters.redact(&translated))
+            } else if buffered {
+                chunker.push(&response)
+            } else {
+                chunker.push(&content)
+            };
+            let c = chunker.flush();
+            if !c.is_empty() {
+                chunks.push(c);
+            }
+            for c in chunks {
+                let placeholder = pending_placeholder.lock().take();
+                sent.push(
+                    self.send_reply_chunk(&ctx.http, new_message, settings.persona.as_ref(), &c, sent.is_empty(), placeholder)
+                        .await?
+                        .id,
+                );
+            }
+            if language.is_some() {
+                let mut translations = thread.translations.lock();
+                for (i, message_id) in sent.iter().enumerate() {
+                    translations.put(*message_id, if i == 0 { response.clone() } else { "".to_string() });
+                }
+            }
+
+            if let Some(renderer) = self.latex.as_ref() {
+                if let Err(e) = self.send_rendered_math(&ctx.http, new_message, renderer, &response).await {
+                    log::warn!("failed to render math for {}: {:?}", new_message.id, e);
+                }
+            }
+
+            if let Some(stream_error) = stream_error {
+                // Replies cut off by the content filter would only be cut off again.
+                let resumable = !sent.is_empty() && !matches!(stream_error, backend::RequestStreamError::ContentFilter);
+                new_message
+                    .channel_id
+                    .send_message(&ctx.http, |m| {
+                        if resumable {
+                            m.components(|c| {
+                                c.create_action_row(|row| {
+                                    row.create_button(|b| {
+                                        b.custom_id(format!("{}{}", CONTINUE_BUTTON_ID_PREFIX, new_message.id.0))
+                                            .label("Continue")
+                                            .style(serenity::model::application::component::ButtonStyle::Secondary)
+                                    })
+                                })
+                            });
+                        }
+                        m.reference_message(new_message).allowed_mentions(reply_mentions).embed(|em| {
+                            em.title("Incomplete response")
+                                .color(serenity::utils::colours::css::WARNING)
+                                .description(&match stream_error {
+                                    backend::RequestStreamError::ContentFilter => {
+                                        "The remainder of this response was truncated due to the content filter.".to_string()
+                                    }
+                                    backend::RequestStreamError::Length => {
+                                        "The remainder of this response was truncated due to the length.".to_string()
+                                    }
+                                    backend::RequestStreamError::Other(e) => {
+                                        format!("The remainder of this response was truncated due to an unexpected error: {}", e)
+                                    }
+                                })
+                        })
+                    })
+                    .await
+                    .map_err(|send_e| anyhow::format_err!("send error: {}", send_e))?;
+                return Ok(if resumable { Some((messages, response, false)) } else { None });
+            }
+
+            // The other variants are written once the reply is posted, so the reply isn't held up by them. Translated replies only come
+            // one way.
+            let mut variants = vec![response.clone()];
+            let variant_count = thread.state.variants.unwrap_or(1);
+            if variant_count > 1 && language.is_none() {
+                match attempt_binding
+                    .complete_variants(&attempt_messages, &attempt_parameters, variant_count - 1)
+                    .await
+                {
+                    Ok(alternatives) => {
+                        let prefill = attempt_messages
+                            .last()
+                            .filter(|m| m.role == backend::Role::Assistant)
+                            .map(|m| m.content.as_str())
+                            .unwrap_or("");
+                        for alternative in alternatives {
+                            let mut variant = format!("{}{}", prefill, alternative);
+                            if let Some(names) = reply_names.as_ref() {
+                                let mut unattributor = template::AttributionStripper::new(
+                                    settings
+                                        .multi_format
+                                        .as_ref()
+                                        .unwrap_or(&attempt_binding.multi_format)
+                                        .attribution_regex(names),
+                                );
+                                variant = unattributor.push(&variant);
+                                variant.push_str(&unattributor.flush());
+                            }
+                            let mut variant = self.filters.redact(&variant);
+                            if self.config.fence_code {
+                                let mut fencer = codefence::Fencer::default();
+                                variant = fencer.push(&variant);
+                                variant.push_str(&fencer.flush());
+                            }
+                            if !variant.trim().is_empty() {
+                                variants.push(variant);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("failed to write variants of the reply to {}: {:?}", new_message.id, e);
+                    }
+                }
+            }
+
+            if let Some(last_id) = sent.last().filter(|_| self.config.collect_ratings || variants.len() > 1) {
+                new_message
+                    .channel_id
+                    .edit_message(&ctx.http, *last_id, |m| {
+                        m.components(|c| build_reply_components(c, new_message.id, self.config.collect_ratings, &variants, 0))
+                    })
+                    .await
+                    .map_err(|e| anyhow::format_err!("edit_message: {}", e))?;
+                if self.config.collect_ratings {
+                    thread.rateable.lock().put(
+                        new_message.id,
+                        RateableReply {
+                            backend: attempt_backend_name.to_string(),
+                            prompt: attempt_messages.iter().map(transcript::Message::from).collect(),
+                            response: response.clone(),
+                        },
+                    );
+                }
+                if variants.len() > 1 {
+                    thread.reply_variants.lock().put(
+                        new_message.id,
+                        ReplyVariants {
+                            requester: new_message.author.id,
+                            persona: settings.persona.clone(),
+                            message_ids: sent.clone(),
+                            variants,
+                            selected: 0,
+                        },
+                    );
+                }
+            }
+
+            Ok::<_, anyhow::Error>(Some((messages, response, true)))
+        })()
+        .await;
+
+        if let Some(placeholder) = pending_placeholder.into_inner() {
+            if let Err(e) = placeholder.delete(&ctx.http).await {
+                log::warn!("failed to delete placeholder {}: {:?}", placeholder.id, e);
+            }
+        }
+
+        if let Err(e) = &r {
+            self.record_usage(
+                &ctx.http,
+                new_message,
+                backend_name,
+                backend::Usage {
+                    prompt_tokens: 0,
+                    completion_tokens: 0,
+                },
+                true,
+            )
+            .await;
+
+            // Deleting the primary message would leave the thread without a starter, so it stays.
+            let delete = self.config.delete_failed_messages && new_message.id.0 != new_message.channel_id.0;
+            new_message
+                .channel_id
+                .send_message(&ctx.http, |m| {
+                    // The error replies to a kept message so it's cleaned up along with the rest of the replies when it's retried.
+                    if !delete {
+                        m.reference_message(new_message).allowed_mentions(reply_mentions).components(|c| {
+                            c.create_action_row(|row| {
+                                row.create_button(|b| {
+                                    b.custom_id(format!("{}{}", RETRY_BUTTON_ID_PREFIX, new_message.id.0))
+                                        .label("Retry")
+                                        .style(serenity::model::application::component::ButtonStyle::Secondary)
+                                })
+                            })
+                        });
+                    }
+                    m.embed(|em| {
+                        em.title("Error")
+                            .color(serenity::utils::colours::css::DANGER)
+                            .description(format!("{:?}", e))
+                            .field("Original message", format!("```\n{}\n```", new_message.content), false)
+                            .footer(|f| {
+                                f.icon_url(
+                                    new_message
+                                        .author
+                                        .static_avatar_url()
+                                        .unwrap_or_else(|| new_message.author.default_avatar_url()),
+                                )
+                                .text(format!("{}#{:04}", new_message.author.name, new_message.author.discriminator))
+                            })
+                    })
+                })
+                .await
+                .map_err(|send_e| anyhow::format_err!("send error: {} ({})", send_e, e))?;
+            if delete {
+                ctx.http.delete_message(new_message.channel_id.0, new_message.id.0).await?;
+            } else {
+                new_message.react(&ctx.http, failed_reaction()).await?;
+            }
+        }
+
+        let (messages, response, complete) = if let Some(exchange) = r? {
+            exchange
+        } else {
+            return Ok(());
+        };
+
+        if !complete {
+            let response = format!("{}{}", partial.as_deref().unwrap_or(""), response);
+            thread.state.incomplete_replies.insert(new_message.id, response);
+            self.store.save_thread(thread.primary_message.channel_id, &thread.state).await?;
+            return Ok(());
+        }
+
+        // The greeting is a reply to the first post, but it doesn't make this anything other than the first exchange.
+        let primary_is_prompt = thread.primary_is_prompt(&self.config.defaults);
+        let is_greeting = |m: &serenity::model::channel::Message| {
+            !primary_is_prompt && m.message_reference.as_ref().and_then(|r| r.message_id) == Some(thread.primary_message.id)
+        };
+        if partial.is_none()
+            && self.config.auto_title
+            && !thread.state.titled
+            && !thread
+                .messages
+                .range(..new_message.id)
+                .any(|(_, m)| m.author.id == me_id && !is_greeting(m))
+        {
+            if let Err(e) = self.generate_title(ctx, thread, binding, &settings.parameters, messages, response).await {
+                log::warn!("failed to generate title for {}: {:?}", new_message.channel_id, e);
+            }
+        }
+
+        if let Some(left_out) = left_out.into_inner().filter(|_| settings.overflow.summarizes()) {
+            if let Err(e) = self.update_memory(&ctx.http, thread, settings.overflow, left_out).await {
+                log::warn!("failed to update the summary of what's left out of {}: {:?}", new_message.channel_id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends a piece of a reply, as an embed styled after the thread's persona if replies are sent as embeds. Only the first piece gets the
+    /// persona's name and avatar. If there's a placeholder, it's edited into the piece instead.
+    async fn send_reply_chunk(
+        &self,
+        http: &serenity::http::Http,
+        new_message: &serenity::model::channel::Message,
+        persona: Option<&preset::Persona>,
+        content: &str,
+        first: bool,
+        placeholder: Option<serenity::model::channel::Message>,
+    ) -> Result<serenity::model::channel::Message, anyhow::Error> {
+        let persona = persona.cloned().unwrap_or_default();
+        if let Some(placeholder) = placeholder {
+            return new_message
+                .channel_id
+                .edit_message(http, placeholder.id, |m| {
+                    if self.config.embed_replies {
+                        m.content("").embed(|e| build_reply_embed(e, &persona, content, first))
+                    } else {
+                        m.content(content)
+                    }
+                })
+                .await
+                .map_err(|e| anyhow::format_err!("edit_message: {}", e));
+        }
+
+        new_message
+            .channel_id
+            .send_message(http, |m| {
+                if self.config.embed_replies {
+                    m.embed(|e| build_reply_embed(e, &persona, content, first));
+                } else {
+                    m.content(content);
+                }
+                m.reference_message(new_message).allowed_mentions(reply_mentions)
+            })
+            .await
+            .map_err(|e| anyhow::format_err!("send_message: {}", e))
+    }
+
+    /// Posts a preset's greeting in reply to the primary message, so it's part of the conversation like any other reply.
+    async fn send_greeting(
+        &self,
+        ctx: &serenity::client::Context,
+        thread: &ThreadInfo,
+        settings: &ChatSettings,
+        greeting: &str,
+    ) -> Result<(), anyhow::Error> {
+        let mut primary_message = thread.primary_message.clone();
+        primary_message.guild_id = Some(thread.guild_id);
+
+        let greeting = if template::has_placeholders(greeting) {
+            let mut resolver = self.resolver.lock().await;
+            let vars = std::collections::HashMap::from([
+                (
+                    "bot_name",
+                    resolver
+                        .resolve_display_name(&ctx.http, thread.guild_id, self.me_id())
+                        .await
+                        .map_err(|e| anyhow::format_err!("resolve_display_name: {}", e))?
+                        .to_string(),
+                ),
+                (
+                    "user",
+                    resolver
+                        .resolve_display_name(&ctx.http, thread.guild_id, primary_message.author.id)
+                        .await
+                        .map_err(|e| anyhow::format_err!("resolve_display_name: {}", e))?
+                        .to_string(),
+                ),
+            ]);
+            template::expand(greeting, &vars)
+        } else {
+            greeting.to_string()
+        };
+
+        let mut chunker = unichunk::Chunker::new(if self.config.embed_replies {
+            EMBED_DESCRIPTION_MAX_LENGTH
+        } else {
+            MESSAGE_MAX_LENGTH
+        });
+        let mut chunks = chunker.push(&self.filters.redact(&greeting));
+        let c = chunker.flush();
+        if !c.is_empty() {
+            chunks.push(c);
+        }
+        for (i, c) in chunks.iter().enumerate() {
+            self.send_reply_chunk(&ctx.http, &primary_message, settings.persona.as_ref(), c, i == 0, None)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Sends the display math in a reply as images, in a message of its own. It has no text, so it's left out of the prompt.
+    async fn send_rendered_math(
+        &self,
+        http: &serenity::http::Http,
+        new_message: &serenity::model::channel::Message,
+        renderer: &latex::Renderer,
+        response: &str,
+    ) -> Result<(), anyhow::Error> {
+        let mut files = vec![];
+        for (i, latex) in latex::extract(response).into_iter().take(renderer.max_images).enumerate() {
+            files.push(serenity::model::channel::AttachmentType::Bytes {
+                data: renderer.render(&latex).await?.into(),
+                filename: format!("math{}.png", i + 1),
+            });
+        }
+        if files.is_empty() {
+            return Ok(());
+        }
+
+        new_message
+            .channel_id
+            .send_message(http, |m| {
+                m.add_files(files).reference_message(new_message).allowed_mentions(reply_mentions)
+            })
+            .await
+            .map_err(|e| anyhow::format_err!("send_message: {}", e))?;
+        Ok(())
+    }
+
+    /// Describes custom emoji in the thread that haven't been described yet, keeping the descriptions for all prompts from then on.
+    async fn describe_new_emoji(&self, thread: &ThreadInfo) {
+        let (emoji, binding) = if let Some(emoji) = self.config.emoji.as_ref() {
+            (emoji, &self.backends[&emoji.backend])
+        } else {
+            return;
+        };
+
+        let new_emoji = {
+            let resolver = self.resolver.lock().await;
+            thread
+                .messages
+                .values()
+                .flat_map(|message| custom_emoji(&message.content))
+                .filter(|(emoji_id, _)| !resolver.emoji_descriptions.contains_key(emoji_id))
+                .map(|(emoji_id, name)| (emoji_id, name.to_string()))
+                .collect::<std::collections::BTreeMap<_, _>>()
+        };
+
+        for (emoji_id, name) in new_emoji.into_iter().take(MAX_EMOJI_DESCRIPTIONS_PER_REPLY) {
+            let vars = std::collections::HashMap::from([("name", name.clone())]);
+            let description = match binding
+                .backend
+                .describe_image(
+                    &template::expand(&emoji.prompt, &vars),
+                    &format!("https://cdn.discordapp.com/emojis/{}.png", emoji_id.0),
+                )
+                .await
+            {
+                Ok(description) => description,
+                Err(e) => {
+                    log::warn!("failed to describe emoji {}: {:?}", emoji_id, e);
+                    self.resolver.lock().await.hint_emoji_description(emoji_id, None);
+                    continue;
+                }
+            };
+
+            if let Err(e) = self
+                .store
+                .record_emoji_description(&store::EmojiDescription {
+                    emoji_id,
+                    name,
+                    description: description.clone(),
+                })
+                .await
+            {
+                log::warn!("failed to record description of emoji {}: {:?}", emoji_id, e);
+            }
+            self.resolver.lock().await.hint_emoji_description(emoji_id, Some(description));
+        }
+    }
+
+    /// Describes the avatars of people in the cast list that haven't been described yet, as with emoji.
+    async fn describe_new_avatars(&self, thread: &ThreadInfo, new_message: &serenity::model::channel::Message) {
+        if thread.mode != ThreadMode::Multi {
+            return;
+        }
+        let (cast_list, avatar_backend) = if let Some((cast_list, avatar_backend)) = self
+            .config
+            .cast_list
+            .as_ref()
+            .and_then(|cast_list| Some((cast_list, cast_list.avatar_backend.as_ref()?)))
+        {
+            (cast_list, avatar_backend)
+        } else {
+            return;
+        };
+        let binding = &self.backends[avatar_backend];
+
+        let new_avatars = {
+            let resolver = self.resolver.lock().await;
+            self.cast(thread, new_message)
+                .into_iter()
+                .filter_map(avatar_url)
+                .filter(|url| !resolver.avatar_descriptions.contains_key(url))
+                .collect::<Vec<_>>()
+        };
+
+        for url in new_avatars.into_iter().take(MAX_AVATAR_DESCRIPTIONS_PER_REPLY) {
+            let description = match binding.backend.describe_image(&cast_list.avatar_prompt, &url).await {
+                Ok(description) => description,
+                Err(e) => {
+                    log::warn!("failed to describe avatar {}: {:?}", url, e);
+                    self.resolver.lock().await.hint_avatar_description(url, None);
+                    continue;
+                }
+            };
+
+            if let Err(e) = self
+                .store
+                .record_avatar_description(&store::AvatarDescription {
+                    url: url.clone(),
+                    description: description.clone(),
+                })
+                .await
+            {
+                log::warn!("failed to record description of avatar {}: {:?}", url, e);
+            }
+            self.resolver.lock().await.hint_avatar_description(url, Some(description));
+        }
+    }
+
+    /// The people taking part in a thread up to a message, besides the bot, most recently active first.
+    fn cast<'a>(&self, thread: &'a ThreadInfo, new_message: &serenity::model::channel::Message) -> Vec<&'a serenity::model::user::User> {
+        let me_id = self.me_id();
+        let mut seen = std::collections::HashSet::new();
+        thread
+            .messages
+            .range(..=new_message.id)
+            .rev()
+            .map(|(_, message)| message)
+            .filter(|message| message.author.id != me_id && self.is_prompt_message(thread, message))
+            .filter(|message| seen.insert(message.author.id))
+            .map(|message| &message.author)
+            .take(MAX_CAST_LIST_SIZE)
+            .collect()
+    }
+
+    /// Lists who's in a thread, one line each, with whatever the cast list config says to say about them.
+    async fn cast_list(
+        &self,
+        http: &serenity::http::Http,
+        resolver: &mut Resolver,
+        thread: &ThreadInfo,
+        new_message: &serenity::model::channel::Message,
+        cast_list: &CastListConfig,
+    ) -> Result<String, anyhow::Error> {
+        let guild_id = new_message.guild_id.unwrap();
+        let bios = self.bios.lock().await;
+        let mut lines = vec!["People in this conversation:".to_string()];
+        for user in self.cast(thread, new_message) {
+            let mut parts = vec![];
+            if cast_list.roles {
+                let roles = resolver.cached_role_names(guild_id, user.id);
+                if !roles.is_empty() {
+                    parts.push(format!("roles: {}", roles.join(", ")));
+                }
+            }
+            if let Some(Some(description)) = avatar_url(user).and_then(|url| resolver.avatar_descriptions.get(&url)) {
+                parts.push(format!("avatar: {}", description));
+            }
+            if let Some(bio) = bios.get(&user.id).filter(|_| cast_list.bios) {
+                parts.push(format!("bio: {}", bio));
+            }
+
+            let display_name = resolver
+                .resolve_display_name(http, guild_id, user.id)
+                .await
+                .map_err(|e| anyhow::format_err!("resolve_display_name: {}", e))?
+                .to_string();
+            lines.push(if parts.is_empty() {
+                format!("- {}", display_name)
+            } else {
+                format!("- {} ({})", display_name, parts.join("; "))
+            });
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /// Asks the translation backend to do something with a piece of text, as instructed by a prompt.
+    async fn run_translation_prompt(&self, binding: &BackendBinding, prompt: &str, language: &str, text: &str) -> Result<String, anyhow::Error> {
+        let vars = std::collections::HashMap::from([("language", language.to_string())]);
+        let messages = [
+            backend::Message {
+                role: backend::Role::System,
+                name: None,
+                content: template::expand(prompt, &vars),
+                mentioned: false,
+            },
+            backend::Message {
+                role: backend::Role::User("user".to_string()),
+                name: None,
+                content: text.to_string(),
+                mentioned: false,
+            },
+        ];
+        Ok(binding.complete(&messages, &parameters_default()).await?.trim().to_string())
+    }
+
+    /// Translates a message into the language the thread's backend works in, keeping the translation for the prompt. Returns the language
+    /// the message was in, if it needed translating.
+    async fn translate_incoming(
+        &self,
+        thread: &ThreadInfo,
+        message: &serenity::model::channel::Message,
+        translation: &TranslationConfig,
+        binding: &BackendBinding,
+    ) -> Result<Option<String>, anyhow::Error> {
+        if message.content.trim().is_empty() {
+            return Ok(None);
+        }
+
+        let language = self
+            .run_translation_prompt(binding, &translation.detect_prompt, &translation.language, &message.content)
+            .await?;
+        let language = language.trim_end_matches('.');
+        if language.eq_ignore_ascii_case(&translation.language) {
+            return Ok(None);
+        }
+
+        let translated = self
+            .run_translation_prompt(binding, &translation.prompt, &translation.language, &message.content)
+            .await?;
+        thread.translations.lock().put(message.id, translated);
+        Ok(Some(language.to_string()))
+    }
+
+    /// Asks the backend to title a thread after its first exchange, and renames the thread to match.
+    async fn generate_title(
+        &self,
+        ctx: &serenity::client::Context,
+        thread: &mut ThreadInfo,
+        binding: &BackendBinding,
+        parameters: &toml::Value,
+        messages: Vec<backend::Message>,
+        response: String,
+    ) -> Result<(), anyhow::Error> {
+        // The thread's own system message would only get the backend to stay in character, so leave it out.
+        let mut messages = messages.into_iter().skip(1).collect::<Vec<_>>();
+        messages.push(backend::Message {
+            role: backend::Role::Assistant,
+            name: None,
+            content: backend::strip_reasoning(&response).to_string(),
+            mentioned: false,
+        });
+        messages.push(backend::Message {
+            role: backend::Role::System,
+            name: None,
+            content: self.config.auto_title_prompt.clone(),
+            mentioned: false,
+        });
+
+        let title = self.filters.redact(&binding.complete(&messages, parameters).await?);
+        let title = title
+            .lines()
+            .map(|line| line.trim().trim_matches(|c| c == '"' || c == '“' || c == '”').trim())
+            .find(|line| !line.is_empty())
+            .ok_or_else(|| anyhow::format_err!("empty title"))?
+            .chars()
+            .take(THREAD_NAME_MAX_LENGTH)
+            .collect::<String>();
+
+        let thread_id = thread.primary_message.channel_id;
+        thread_id
+            .edit_thread(&ctx.http, |t| t.name(&title))
+            .await
+            .map_err(|e| anyhow::format_err!("edit_thread: {}", e))?;
+        thread.title = title;
+
+        thread.state.titled = true;
+        self.store.save_thread(thread_id, &thread.state).await?;
+        Ok(())
+    }
+
+    /// Summarizes a thread's conversation up to and including a message, and after another if given, newest first as far as the summary
+    /// backend's input budget goes. An earlier summary to build on goes before the messages. Returns None if there's nothing to summarize.
+    async fn summarize_history(
+        &self,
+        http: &serenity::http::Http,
+        thread: &ThreadInfo,
+        binding: &BackendBinding,
+        until: serenity::model::id::MessageId,
+        after: Option<serenity::model::id::MessageId>,
+        previous: Option<&str>,
+    ) -> Result<Option<String>, anyhow::Error> {
+        let mut multi_format = binding.multi_format.clone();
+        if !binding.backend.supports_name_field() {
+            multi_format.use_name_field = Some(false);
+        }
+        multi_format.utc_offset.get_or_insert(self.config.utc_offset);
+
+        let mut preamble = vec![backend::Message {
+            role: backend::Role::System,
+            name: None,
+            content: self.config.summary_prompt.clone(),
+            mentioned: false,
+        }];
+        if let Some(previous) = previous {
+            preamble.push(backend::Message {
+                role: backend::Role::System,
+                name: None,
+                content: format!("Summary of the conversation before this:\n{}", previous),
+                mentioned: false,
+            });
+        }
+
+        let (mut messages, system_message) = {
+            let last_message = if let Some(message) = thread.messages.get(&until) {
+                message
+            } else {
+                return Ok(None);
+            };
+
+            let mut resolver = self.resolver.lock().await;
+            let (mut preamble, counts) = count_message_tokens(&binding.backend, preamble).await?;
+            let system_message = preamble.remove(0);
+            let mut input_tokens = binding.backend.num_overhead_tokens() + counts.into_iter().sum::<usize>();
+
+            let history = thread
+                .messages
+                .range(..=until)
+                .rev()
+                .map(|(_, message)| message)
+                .take_while(|message| after.map(|after| message.id > after).unwrap_or(true))
+                .filter(|message| self.is_prompt_message(thread, message))
+                .collect::<Vec<_>>();
+
+            let mut messages = vec![];
+            'history: for batch in history.chunks(TOKEN_COUNT_BATCH_SIZE) {
+                let mut batch_messages = vec![];
+                for message in batch {
+                    if let Some(message) = self
+                        .convert_message(http, &mut resolver, thread, last_message, message, &multi_format)
+                        .await?
+                    {
+                        batch_messages.push(message);
+                    }
+                }
+
+                let (batch_messages, counts) = count_message_tokens(&binding.backend, batch_messages).await?;
+                for (message, message_tokens) in batch_messages.into_iter().zip(counts) {
+                    if input_tokens + message_tokens > binding.input_budget() {
+                        break 'history;
+                    }
+
+                    messages.push(message);
+                    input_tokens += message_tokens;
+                }
+            }
+            if messages.is_empty() {
+                return Ok(None);
+            }
+            messages.extend(preamble);
+            (messages, system_message)
+        };
+
+        messages.reverse();
+        messages.push(system_message);
+
+        let summary = self.filters.redact(&binding.complete(&messages, &toml::Table::new().into()).await?);
+        let summary = summary.trim();
+        if summary.is_empty() {
+            return Err(anyhow::format_err!("empty summary"));
+        }
+        Ok(Some(summary.to_string()))
+    }
+
+    /// Brings a thread's summary of what's been left out of the prompt up to date, if the latest prompt left out messages it doesn't cover.
+    /// With a sliding window, the new messages are folded into the summary; otherwise, everything left out is summarized again.
+    async fn update_memory(
+        &self,
+        http: &serenity::http::Http,
+        thread: &mut ThreadInfo,
+        overflow: preset::Overflow,
+        left_out: serenity::model::id::MessageId,
+    ) -> Result<(), anyhow::Error> {
+        let binding = if let Some(binding) = self.config.summary_backend.as_ref().and_then(|name| self.backends.get(name)) {
+            binding
+        } else {
+            return Ok(());
+        };
+
+        let me_id = self.me_id();
+        let forget_break = thread
+            .messages
+            .range(..=left_out)
+            .rev()
+            .find(|(_, message)| thread.is_forget_break(message, me_id))
+            .map(|(message_id, _)| *message_id);
+        let memory = thread
+            .state
+            .memory
+            .as_ref()
+            .filter(|memory| forget_break.map(|forget_break| memory.until > forget_break).unwrap_or(true));
+        if memory.map(|memory| memory.until >= left_out).unwrap_or(false) {
+            return Ok(());
+        }
+
+        let (after, previous) = match memory {
+            Some(memory) if overflow == preset::Overflow::SlidingWindowWithMemory => (Some(memory.until), Some(memory.content.clone())),
+            _ => (forget_break, None),
+        };
+        let content = if let Some(content) = self
+            .summarize_history(http, thread, binding, left_out, after, previous.as_deref())
+            .await?
+        {
+            content
+        } else {
+            return Ok(());
+        };
+
+        thread.state.memory = Some(store::Memory { until: left_out, content });
+        self.store.save_thread(thread.primary_message.channel_id, &thread.state).await
+    }
+
+    /// Posts a closing summary of an archived thread and keeps it in the store. Posting unarchives the thread, so it's archived again
+    /// afterwards.
+    async fn summarize_thread(&self, ctx: &serenity::client::Context, channel: &serenity::model::channel::GuildChannel) -> Result<(), anyhow::Error> {
+        let binding = if let Some(binding) = self.config.summary_backend.as_ref().and_then(|name| self.backends.get(name)) {
+            binding
+        } else {
+            return Ok(());
+        };
+
+        let thread = if let Some(thread) = self.load_thread(&ctx.http, channel.id).await? {
+            thread
+        } else {
+            return Ok(());
+        };
+        let mut thread = thread.lock().await;
+
+        // Archiving the thread again after posting the summary comes back around here, so don't summarize it twice.
+        if thread
+            .state
+            .summary
+            .as_ref()
+            .map(|summary| Some(summary.message_id) == channel.last_message_id)
+            .unwrap_or(false)
+        {
+            return Ok(());
+        }
+
+        let last_message_id = if let Some((message_id, _)) = thread.messages.iter().next_back() {
+            *message_id
+        } else {
+            return Ok(());
+        };
+        let summary = if let Some(summary) = self.summarize_history(&ctx.http, &thread, binding, last_message_id, None, None).await? {
+            summary
+        } else {
+            return Ok(());
+        };
+
+        let mut chunker = unichunk::Chunker::new(MESSAGE_MAX_LENGTH);
+        let mut chunks = chunker.push(&summary);
+        let c = chunker.flush();
+        if !c.is_empty() {
+            chunks.push(c);
+        }
+
+        let mut message_id = None;
+        for c in chunks {
+            message_id = Some(
+                channel
+                    .id
+                    .send_message(&ctx.http, |m| m.content(c).allowed_mentions(reply_mentions))
+                    .await
+                    .map_err(|e| anyhow::format_err!("send_message: {}", e))?
+                    .id,
+            );
+        }
+
+        if let Some(message_id) = message_id {
+            thread.state.summary = Some(store::Summary {
+                message_id,
+                content: summary,
+            });
+            self.store.save_thread(channel.id, &thread.state).await?;
+        }
+
+        channel
+            .id
+            .edit_thread(&ctx.http, |t| t.archived(true))
+            .await
+            .map_err(|e| anyhow::format_err!("edit_thread: {}", e))?;
+        Ok(())
+    }
+}
+
+struct ThreadCache {
+    ids: std::collections::HashSet<serenity::model::id::ChannelId>,
+    last_active: std::collections::HashMap<serenity::model::id::ChannelId, chrono::DateTime<chrono::Utc>>,
+    infos: lru::LruCache<serenity::model::id::ChannelId, std::sync::Arc<tokio::sync::Mutex<ThreadInfo>>>,
+}
+
+impl ThreadCache {
+    fn new(cache_size: usize) -> Self {
+        Self {
+            ids: std::collections::HashSet::new(),
+            last_active: std::collections::HashMap::new(),
+            infos: lru::LruCache::new(std::num::NonZeroUsize::new(cache_size).unwrap()),
+        }
+    }
+
+    fn flush(&mut self) {
+        self.infos.clear();
+    }
+
+    fn contains(&self, thread_id: serenity::model::id::ChannelId) -> bool {
+        self.ids.contains(&thread_id)
+    }
+
+    fn add(&mut self, thread_id: serenity::model::id::ChannelId) {
         self.ids.insert(thread_id);
+        self.last_active.entry(thread_id).or_insert_with(chrono::Utc::now);
+    }
+
+    fn remove(&mut self, thread_id: serenity::model::id::ChannelId) {
+        self.ids.remove(&thread_id);
+        self.last_active.remove(&thread_id);
+        self.infos.pop(&thread_id);
+    }
+
+    /// Drops a thread's loaded info, so it's loaded afresh next time, but keeps tracking it.
+    fn evict(&mut self, thread_id: serenity::model::id::ChannelId) {
+        self.infos.pop(&thread_id);
+    }
+
+    /// Records activity in a thread, if it's one being tracked.
+    fn touch(&mut self, thread_id: serenity::model::id::ChannelId, at: chrono::DateTime<chrono::Utc>) {
+        if self.ids.contains(&thread_id) {
+            self.last_active.insert(thread_id, at);
+        }
+    }
+
+    /// Threads that haven't seen any activity since the cutoff.
+    fn idle_since(&self, cutoff: chrono::DateTime<chrono::Utc>) -> Vec<serenity::model::id::ChannelId> {
+        self.last_active
+            .iter()
+            .filter(|(_, last_active)| **last_active < cutoff)
+            .map(|(thread_id, _)| *thread_id)
+            .collect()
+    }
+
+    fn get(&mut self, thread_id: serenity::model::id::ChannelId) -> Option<std::sync::Arc<tokio::sync::Mutex<ThreadInfo>>> {
+        self.infos.get(&thread_id).cloned()
+    }
+
+    /// Adds a thread that was loaded without the cache held. If it was loaded in the meantime by someone else, theirs is kept, and if it
+    /// stopped being tracked, it's dropped.
+    fn insert(
+        &mut self,
+        thread_id: serenity::model::id::ChannelId,
+        thread_info: ThreadInfo,
+    ) -> Option<std::sync::Arc<tokio::sync::Mutex<ThreadInfo>>> {
+        if !self.ids.contains(&thread_id) {
+            return None;
+        }
+
+        if let Some(info) = self.infos.get(&thread_id) {
+            return Some(info.clone());
+        }
+
+        let thread_info = std::sync::Arc::new(tokio::sync::Mutex::new(thread_info));
+        self.infos.put(thread_id, thread_info.clone());
+        Some(thread_info)
+    }
+}
+
+const HISTORY_PAGE_SIZE: u64 = 100;
+
+const PRELOAD_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+const IDLE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// How long to wait between answering mentions missed while offline, so coming back doesn't flood the backend.
+const CATCH_UP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Archives tracked threads that have been idle for too long, optionally posting a note first. Archiving a thread is what takes it
+/// out of the thread cache, via thread_update.
+async fn archive_idle_threads(
+    http: std::sync::Arc<serenity::http::Http>,
+    thread_cache: std::sync::Arc<tokio::sync::Mutex<ThreadCache>>,
+    idle_after: chrono::Duration,
+    note: Option<String>,
+) {
+    let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let idle = thread_cache.lock().await.idle_since(chrono::Utc::now() - idle_after);
+        for thread_id in idle {
+            log::info!("thread {} idle, archiving", thread_id);
+            if let Err(e) = (|| async {
+                if let Some(note) = note.as_ref() {
+                    thread_id
+                        .send_message(&http, |m| m.embed(|e| e.description(note)))
+                        .await
+                        .map_err(|e| anyhow::format_err!("send_message: {}", e))?;
+                }
+                thread_id
+                    .edit_thread(&http, |t| t.archived(true))
+                    .await
+                    .map_err(|e| anyhow::format_err!("edit_thread: {}", e))?;
+                Ok::<_, anyhow::Error>(())
+            })()
+            .await
+            {
+                log::error!("failed to archive idle thread {}: {:?}", thread_id, e);
+
+                // Don't try again until it's been idle for another while.
+                thread_cache.lock().await.touch(thread_id, chrono::Utc::now());
+            }
+        }
     }
+}
 
-    fn remove(&mut self, thread_id: serenity::model::id::ChannelId) {
-        self.ids.remove(&thread_id);
-        self.infos.pop(&thread_id);
+static MESSAGE_LINK_REGEX: once_cell::sync::Lazy<regex::Regex> = once_cell::sync::Lazy::new(|| {
+    regex::Regex::new(r"^https://(?:\w+\.)?discord(?:app)?\.com/channels/(?:\d+|@me)/(?P<channel_id>\d+)/(?P<message_id>\d+)$").unwrap()
+});
+
+static STRIP_SINGLE_USER_REGEX: once_cell::sync::Lazy<regex::Regex> =
+    once_cell::sync::Lazy::new(|| regex::Regex::new(r"^\s*<@!?(?P<user_id>\d+)>\s*").unwrap());
+
+/// Builds a user message, attributing it to its author in whatever way the thread calls for.
+fn format_user_message(
+    mode: &ThreadMode,
+    multi_format: &template::MultiFormat,
+    name: String,
+    timestamp: chrono::DateTime<chrono::Utc>,
+    content: String,
+    mentioned: bool,
+) -> backend::Message {
+    match mode {
+        ThreadMode::Single => backend::Message {
+            role: backend::Role::User(name),
+            name: None,
+            content,
+            mentioned,
+        },
+        ThreadMode::Multi if multi_format.use_name_field.unwrap_or(false) && backend::is_plain_name(&name) => backend::Message {
+            role: backend::Role::User(name.clone()),
+            name: Some(name),
+            content,
+            mentioned,
+        },
+        ThreadMode::Multi => backend::Message {
+            content: multi_format.format(&name, timestamp, chrono::Utc::now(), &content),
+            role: backend::Role::User(name),
+            name: None,
+            mentioned,
+        },
     }
+}
 
-    fn get(&mut self, thread_id: serenity::model::id::ChannelId) -> Option<std::sync::Arc<tokio::sync::Mutex<ThreadInfo>>> {
-        self.infos.get(&thread_id).cloned()
+const THREAD_NAME_MAX_LENGTH: usize = 100;
+
+const QUOTE_EXCERPT_MAX_LENGTH: usize = 200;
+const EMBED_EXCERPT_MAX_LENGTH: usize = 300;
+
+/// Cuts text down to at most `max_length` characters, marking it if anything was cut.
+fn excerpt(content: &str, max_length: usize) -> String {
+    let mut excerpt = content.chars().take(max_length).collect::<String>();
+    if excerpt.len() < content.len() {
+        excerpt.push('…');
     }
+    excerpt
+}
 
-    async fn load(
-        &mut self,
-        http: impl AsRef<serenity::http::Http>,
-        thread_id: serenity::model::id::ChannelId,
-        tags: &std::collections::HashMap<serenity::model::id::ForumTagId, String>,
-        message_history_size: usize,
-    ) -> Result<Option<std::sync::Arc<tokio::sync::Mutex<ThreadInfo>>>, serenity::Error> {
-        if !self.ids.contains(&thread_id) {
-            return Ok(None);
-        }
+/// Formats the start of a message as a Markdown quote.
+fn quote_excerpt(content: &str) -> String {
+    excerpt(content, QUOTE_EXCERPT_MAX_LENGTH)
+        .lines()
+        .map(|line| format!("> {}", line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-        if let Some(info) = self.infos.get(&thread_id) {
-            return Ok(Some(info.clone()));
+/// Keeps a message from pinging anyone but the author of the message it replies to, since responses and injected messages can repeat
+/// mention syntax from the conversation.
+fn reply_mentions(allowed_mentions: &mut serenity::builder::CreateAllowedMentions) -> &mut serenity::builder::CreateAllowedMentions {
+    allowed_mentions.empty_parse().replied_user(true)
+}
+
+/// Posts messages as injected messages, split up as needed, and records their roles in a thread's state.
+async fn post_injected_messages(
+    http: &serenity::http::Http,
+    channel_id: serenity::model::id::ChannelId,
+    state: &mut store::ThreadState,
+    messages: &[transcript::Message],
+) -> Result<(), anyhow::Error> {
+    for message in messages.iter().filter(|message| !message.content.trim().is_empty()) {
+        let role = match message.role {
+            transcript::Role::System => store::InjectedRole::System,
+            transcript::Role::Assistant => store::InjectedRole::Assistant,
+            transcript::Role::User => store::InjectedRole::User,
+        };
+
+        let mut chunker = unichunk::Chunker::new(MESSAGE_MAX_LENGTH);
+        let mut chunks = chunker.push(&message.content);
+        chunks.push(chunker.flush());
+        for c in chunks.into_iter().filter(|c| !c.is_empty()) {
+            let posted = channel_id.send_message(http, |m| m.content(&c).allowed_mentions(reply_mentions)).await?;
+            state.injected.insert(
+                posted.id,
+                store::InjectedMessage {
+                    role,
+                    name: message.name.clone(),
+                },
+            );
         }
+    }
+    Ok(())
+}
+
+/// The buttons for rating a reply, and the menu for picking between its variants if it has more than one.
+fn build_reply_components<'a>(
+    c: &'a mut serenity::builder::CreateComponents,
+    prompt_id: serenity::model::id::MessageId,
+    rateable: bool,
+    variants: &[String],
+    selected: usize,
+) -> &'a mut serenity::builder::CreateComponents {
+    if rateable {
+        c.create_action_row(|row| {
+            row.create_button(|b| {
+                b.custom_id(format!("{}{}", RATE_UP_BUTTON_ID_PREFIX, prompt_id.0))
+                    .emoji(serenity::model::channel::ReactionType::Unicode("👍".to_string()))
+                    .style(serenity::model::application::component::ButtonStyle::Secondary)
+            })
+            .create_button(|b| {
+                b.custom_id(format!("{}{}", RATE_DOWN_BUTTON_ID_PREFIX, prompt_id.0))
+                    .emoji(serenity::model::channel::ReactionType::Unicode("👎".to_string()))
+                    .style(serenity::model::application::component::ButtonStyle::Secondary)
+            })
+        });
+    }
+    if variants.len() > 1 {
+        c.create_action_row(|row| {
+            row.create_select_menu(|menu| {
+                menu.custom_id(format!("{}{}", VARIANT_SELECT_ID_PREFIX, prompt_id.0)).options(|o| {
+                    for (i, variant) in variants.iter().enumerate() {
+                        o.create_option(|option| {
+                            let mut description = variant.split_whitespace().collect::<Vec<_>>().join(" ");
+                            if description.chars().count() > SELECT_OPTION_DESCRIPTION_MAX_LENGTH {
+                                description = description.chars().take(SELECT_OPTION_DESCRIPTION_MAX_LENGTH - 1).collect::<String>() + "…";
+                            }
+                            option
+                                .label(format!("Variant {}", i + 1))
+                                .value(i.to_string())
+                                .description(description)
+                                .default_selection(i == selected)
+                        });
+                    }
+                    o
+                })
+            })
+        });
+    }
+    c
+}
+
+/// Styles a piece of a reply after the thread's persona.
+fn build_reply_embed<'a>(
+    e: &'a mut serenity::builder::CreateEmbed,
+    persona: &preset::Persona,
+    content: &str,
+    first: bool,
+) -> &'a mut serenity::builder::CreateEmbed {
+    e.description(content);
+    if let Some(color) = persona.color {
+        e.color(color);
+    }
+    if let Some(name) = persona.name.as_ref().filter(|_| first) {
+        e.author(|a| {
+            if let Some(avatar_url) = persona.avatar_url.as_ref() {
+                a.icon_url(avatar_url);
+            }
+            a.name(name)
+        });
+    }
+    e
+}
+
+/// Marks a message the bot failed to reply to.
+fn failed_reaction() -> serenity::model::channel::ReactionType {
+    serenity::model::channel::ReactionType::Unicode("⚠️".to_string())
+}
+
+/// Ends the prompt with the preset's assistant prefill, if it has one and the backend supports it.
+fn push_prefill(messages: &mut Vec<backend::Message>, binding: &BackendBinding, settings: &ChatSettings) {
+    if let Some(prefill) = settings.assistant_prefill.as_ref().filter(|_| binding.backend.supports_prefill()) {
+        messages.push(backend::Message {
+            role: backend::Role::Assistant,
+            name: None,
+            content: prefill.clone(),
+            mentioned: false,
+        });
+    }
+}
+
+/// Appends to the system message, or adds one if there isn't one.
+fn soften_system_message(messages: &mut Vec<backend::Message>, suffix: &str) {
+    if let Some(message) = messages.first_mut().filter(|message| message.role == backend::Role::System) {
+        message.content.push_str("\n\n");
+        message.content.push_str(suffix);
+    } else {
+        messages.insert(
+            0,
+            backend::Message {
+                role: backend::Role::System,
+                name: None,
+                content: suffix.to_string(),
+                mentioned: false,
+            },
+        );
+    }
+}
+
+/// The text of a reply the bot sent as embeds, or None if the message isn't one. Replies are the bot's only embeds sent in reply to a
+/// message that have no title; notices and errors all have titles or aren't replies.
+fn reply_embed_text(message: &serenity::model::channel::Message, me_id: serenity::model::id::UserId) -> Option<String> {
+    if message.author.id != me_id
+        || !message.content.is_empty()
+        || message.kind != serenity::model::channel::MessageType::InlineReply
+        || message.embeds.is_empty()
+        || message.embeds.iter().any(|embed| embed.title.is_some())
+    {
+        return None;
+    }
+    Some(
+        message
+            .embeds
+            .iter()
+            .filter_map(|embed| embed.description.as_deref())
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
+}
 
-        let thread_info = std::sync::Arc::new(tokio::sync::Mutex::new(
-            ThreadInfo::new(http, thread_id, tags, message_history_size).await?,
+/// Describes what's different between two versions of a thread's settings, one line each, for telling whoever changed them what they
+/// changed. Parameters are listed one by one, but the system message is too long to show, so it's only said to have changed.
+fn describe_settings_changes(old: &ChatSettings, new: &ChatSettings) -> Vec<String> {
+    let mut lines = vec![];
+    if old.preset != new.preset {
+        let show = |preset: &Option<String>| {
+            preset
+                .as_ref()
+                .map(|preset| format!("`{}`", preset))
+                .unwrap_or_else(|| "none".to_string())
+        };
+        lines.push(format!("Preset: {} → {}", show(&old.preset), show(&new.preset)));
+    }
+    if old.system_message != new.system_message {
+        lines.push(format!(
+            "System message changed ({} → {} characters).",
+            old.system_message.chars().count(),
+            new.system_message.chars().count()
         ));
-        self.infos.put(thread_id, thread_info.clone());
-        Ok(Some(thread_info))
     }
+
+    let empty = toml::Table::new();
+    let old_parameters = old.parameters.as_table().unwrap_or(&empty);
+    let new_parameters = new.parameters.as_table().unwrap_or(&empty);
+    let keys = old_parameters
+        .keys()
+        .chain(new_parameters.keys())
+        .collect::<std::collections::BTreeSet<_>>();
+    for key in keys {
+        match (old_parameters.get(key), new_parameters.get(key)) {
+            (Some(old_value), Some(new_value)) if old_value != new_value => {
+                lines.push(format!("`{}`: `{}` → `{}`", key, old_value, new_value));
+            }
+            (None, Some(new_value)) => {
+                lines.push(format!("`{}`: set to `{}`", key, new_value));
+            }
+            (Some(old_value), None) => {
+                lines.push(format!("`{}`: `{}` → unset", key, old_value));
+            }
+            _ => {}
+        }
+    }
+    lines
 }
 
-static STRIP_SINGLE_USER_REGEX: once_cell::sync::Lazy<regex::Regex> =
-    once_cell::sync::Lazy::new(|| regex::Regex::new(r"^\s*<@!?(?P<user_id>\d+)>\s*").unwrap());
+/// Describes a message's embeds and stickers, one line each, e.g. `[embed: Title — Description]` or `[sticker: Name]`.
+fn describe_embeds_and_stickers(message: &serenity::model::channel::Message) -> Vec<String> {
+    let collapse = |s: &str| s.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    let mut lines = vec![];
+    for embed in message.embeds.iter() {
+        // GIF links are already described where they are in the message.
+        if embed.url.as_deref().is_some_and(gif::is_link) {
+            continue;
+        }
+
+        let mut parts = vec![];
+        if let Some(title) = embed.title.as_ref().map(|title| collapse(title)).filter(|title| !title.is_empty()) {
+            parts.push(title);
+        }
+        if let Some(description) = embed
+            .description
+            .as_ref()
+            .map(|description| collapse(description))
+            .filter(|description| !description.is_empty())
+        {
+            parts.push(excerpt(&description, EMBED_EXCERPT_MAX_LENGTH));
+        }
+        for field in embed.fields.iter() {
+            parts.push(format!(
+                "{}: {}",
+                collapse(&field.name),
+                excerpt(&collapse(&field.value), EMBED_EXCERPT_MAX_LENGTH)
+            ));
+        }
+        if !parts.is_empty() {
+            lines.push(format!("[embed: {}]", parts.join(" — ")));
+        }
+    }
+    for sticker in message.sticker_items.iter() {
+        lines.push(format!("[sticker: {}]", sticker.name));
+    }
+    lines
+}
 
 const FORGET_COMMAND_NAME: &str = "forget";
 const INJECT_COMMAND_NAME: &str = "inject";
 const INJECT_SYSTEM_COMMAND_NAME: &str = "injectsystem";
+const INJECT_USER_COMMAND_NAME: &str = "injectuser";
+const SETTINGS_COMMAND_NAME: &str = "settings";
+const MODEL_COMMAND_NAME: &str = "model";
+const STATUS_COMMAND_NAME: &str = "status";
+const TOKENS_COMMAND_NAME: &str = "tokens";
+const DEBUG_COMMAND_NAME: &str = "debug";
+const SWEEP_COMMAND_NAME: &str = "sweep";
+/// Each value is a whole reply, so there can't be too many.
+const MAX_SWEEP_VALUES: usize = 5;
+const DEBUG_PROMPT_SUBCOMMAND_NAME: &str = "prompt";
+const EXPORT_COMMAND_NAME: &str = "export";
+const EXPORT_FORMAT_MARKDOWN: &str = "markdown";
+const EXPORT_FORMAT_JSON: &str = "json";
+const IMPORT_COMMAND_NAME: &str = "import";
+const ANALYTICS_COMMAND_NAME: &str = "analytics";
+const AUTOPLAY_COMMAND_NAME: &str = "autoplay";
+const FORK_COMMAND_NAME: &str = "fork";
+const CHECKPOINT_COMMAND_NAME: &str = "checkpoint";
+const ROLLBACK_COMMAND_NAME: &str = "rollback";
+const RESYNC_COMMAND_NAME: &str = "resync";
+const SET_SYSTEM_COMMAND_NAME: &str = "setsystem";
+const PREFILL_COMMAND_NAME: &str = "prefill";
+const VARIANTS_COMMAND_NAME: &str = "variants";
+const BIO_COMMAND_NAME: &str = "bio";
+const BIO_SET_SUBCOMMAND_NAME: &str = "set";
+const BIO_CLEAR_SUBCOMMAND_NAME: &str = "clear";
+/// Bios are in every prompt their owners take part in, so they're kept short.
+const MAX_BIO_LENGTH: usize = 300;
+/// Each variant costs as much as the reply itself, so there can't be too many.
+const MAX_VARIANTS: usize = 5;
+const FORGET_BEFORE_COMMAND_NAME: &str = "Forget everything before this";
+const EXCLUDE_COMMAND_NAME: &str = "Exclude from context";
+const INCLUDE_COMMAND_NAME: &str = "Include again";
+const REWRITE_COMMAND_NAME: &str = "Rewrite";
+
+/// Commands that are restricted to admin roles, if any are configured.
+const ADMIN_COMMAND_NAMES: &[&str] = &[
+    INJECT_COMMAND_NAME,
+    INJECT_SYSTEM_COMMAND_NAME,
+    INJECT_USER_COMMAND_NAME,
+    DEBUG_COMMAND_NAME,
+    SWEEP_COMMAND_NAME,
+    IMPORT_COMMAND_NAME,
+    ANALYTICS_COMMAND_NAME,
+];
+
+const NOT_PERMITTED_MESSAGE: &str = "Sorry, you're not permitted to do that here.";
+const TRANSIENT_NOTICE_LIFETIME: std::time::Duration = std::time::Duration::from_secs(10);
+
+const SETTINGS_MODAL_ID: &str = "settings";
+const SETTINGS_MODAL_SYSTEM_MESSAGE_ID: &str = "system_message";
+const SETTINGS_MODAL_PARAMETERS_ID: &str = "parameters";
+
+const REWRITE_MODAL_ID_PREFIX: &str = "rewrite:";
+const REWRITE_MODAL_CONTENT_ID: &str = "content";
+
+/// How many messages are fetched at a time when rolling back, the most Discord allows.
+const ROLLBACK_PAGE_SIZE: u64 = 100;
+
+/// How many of the latest messages an ensemble's router backend sees when picking who speaks next.
+const ROUTER_HISTORY_SIZE: usize = 10;
+
+const RETRY_BUTTON_ID_PREFIX: &str = "retry:";
+const CONTINUE_BUTTON_ID_PREFIX: &str = "continue:";
+const STOP_AUTOPLAY_BUTTON_ID_PREFIX: &str = "stop_autoplay:";
+const RATE_UP_BUTTON_ID_PREFIX: &str = "rate_up:";
+const VARIANT_SELECT_ID_PREFIX: &str = "variant:";
+const RATE_DOWN_BUTTON_ID_PREFIX: &str = "rate_down:";
+
+const MESSAGE_MAX_LENGTH: usize = 2000;
+const SELECT_OPTION_DESCRIPTION_MAX_LENGTH: usize = 100;
+const EMBED_DESCRIPTION_MAX_LENGTH: usize = 4096;
+
+/// Transcripts bigger than this aren't imported, since every message in them gets posted to the thread.
+const IMPORT_MAX_SIZE: u64 = 1024 * 1024;
+
+/// How much of the history budget a prompt is trimmed down to when it has to be trimmed for a backend that caches prompt prefixes.
+const CACHED_PREFIX_TRIM_PERCENT: usize = 75;
+
+/// How many messages have their tokens counted at a time while filling the input budget.
+const TOKEN_COUNT_BATCH_SIZE: usize = 32;
+
+/// How many of the conversation's first messages are kept when dropping from the middle.
+const DROP_MIDDLE_HEAD_SIZE: usize = 2;
+
+/// How many of a thread's first messages are fetched for prompts that keep the conversation's opening, since some of them may not be part
+/// of the conversation, e.g. settings continuations.
+const OPENING_PAGE_SIZE: u64 = 10;
+
+const MODAL_INPUT_TEXT_MAX_LENGTH: usize = 4000;
+const AUTOCOMPLETE_MAX_CHOICES: usize = 25;
 
 #[async_trait::async_trait]
 impl serenity::client::EventHandler for Handler {
     async fn ready(&self, ctx: serenity::client::Context, data_about_bot: serenity::model::gateway::Ready) {
         if let Err(e) = (|| async {
-            *self.me_id.lock() = data_about_bot.user.id;
+            let _ = self.gateway.me_id.set(data_about_bot.user.id);
+            let num_ready = {
+                let mut ready_shards = self.gateway.ready_shards.lock();
+                ready_shards.insert(ctx.shard_id);
+                ready_shards.len()
+            };
+            log::info!(
+                "shard {} ready ({} of {} shards)",
+                ctx.shard_id,
+                num_ready,
+                data_about_bot.shard.map(|[_, num_shards]| num_shards).unwrap_or(1)
+            );
+
+            // A new session doesn't replay what happened while the bot was offline, so it has to be fetched.
+            let offline_since = self.gateway.offline_since.lock().take();
+            if let Some(offline_since) = offline_since {
+                if let Err(e) = self.catch_up(&ctx, offline_since).await {
+                    log::warn!("failed to catch up since {}: {:?}", offline_since, e);
+                }
+            }
+
+            // Commands are global, so only one shard needs to set them.
+            if ctx.shard_id != 0 {
+                return Ok(());
+            }
+
+            self.resolver.lock().await.set_cache(ctx.cache.clone());
 
             serenity::model::application::command::Command::set_global_application_commands(&ctx.http, |cmds| {
                 cmds.create_application_command(|c| {
                     c.name(FORGET_COMMAND_NAME)
                         .description("Add a break in the chat log to forget everything before it.")
+                        .create_option(|o| {
+                            o.name("count")
+                                .description("Only forget this many of the latest messages.")
+                                .kind(serenity::model::application::command::CommandOptionType::Integer)
+                                .min_int_value(1)
+                                .required(false)
+                        })
+                        .create_option(|o| {
+                            o.name("message_link")
+                                .description("Forget everything before this message instead.")
+                                .kind(serenity::model::application::command::CommandOptionType::String)
+                                .required(false)
+                        })
                 })
                 .create_application_command(|c| {
                     c.name(INJECT_COMMAND_NAME)
@@ -285,6 +7260,212 @@ impl serenity::client::EventHandler for Handler {
                                 .required(true)
                         })
                 })
+                .create_application_command(|c| {
+                    c.name(INJECT_USER_COMMAND_NAME)
+                        .description("Inject a message as if a user had said it.")
+                        .create_option(|o| {
+                            o.name("content")
+                                .description("The text to say.")
+                                .kind(serenity::model::application::command::CommandOptionType::String)
+                                .required(true)
+                        })
+                        .create_option(|o| {
+                            o.name("name")
+                                .description("Who said it, if not you.")
+                                .kind(serenity::model::application::command::CommandOptionType::String)
+                                .required(false)
+                        })
+                })
+                .create_application_command(|c| c.name(SETTINGS_COMMAND_NAME).description("Edit the settings for this thread."))
+                .create_application_command(|c| {
+                    c.name(FORGET_BEFORE_COMMAND_NAME)
+                        .kind(serenity::model::application::command::CommandType::Message)
+                })
+                .create_application_command(|c| {
+                    c.name(EXCLUDE_COMMAND_NAME)
+                        .kind(serenity::model::application::command::CommandType::Message)
+                })
+                .create_application_command(|c| {
+                    c.name(INCLUDE_COMMAND_NAME)
+                        .kind(serenity::model::application::command::CommandType::Message)
+                })
+                .create_application_command(|c| {
+                    c.name(REWRITE_COMMAND_NAME)
+                        .kind(serenity::model::application::command::CommandType::Message)
+                })
+                .create_application_command(|c| {
+                    c.name(MODEL_COMMAND_NAME)
+                        .description("Switch the backend this thread uses.")
+                        .create_option(|o| {
+                            o.name("backend")
+                                .description("The backend to use.")
+                                .kind(serenity::model::application::command::CommandOptionType::String)
+                                .required(true)
+                                .set_autocomplete(true)
+                        })
+                        .create_option(|o| {
+                            o.name("model")
+                                .description("The model to use, if the backend serves several.")
+                                .kind(serenity::model::application::command::CommandOptionType::String)
+                                .required(false)
+                                .set_autocomplete(true)
+                        })
+                })
+                .create_application_command(|c| {
+                    c.name(PREFILL_COMMAND_NAME)
+                        .description("Start every reply in this thread with some text, for backends that support it.")
+                        .create_option(|o| {
+                            o.name("text")
+                                .description("What replies should start with, e.g. a code fence. Leave it out to stop.")
+                                .kind(serenity::model::application::command::CommandOptionType::String)
+                                .required(false)
+                        })
+                })
+                .create_application_command(|c| {
+                    c.name(VARIANTS_COMMAND_NAME)
+                        .description("Write each reply in this thread several ways, so whoever asked can pick one.")
+                        .create_option(|o| {
+                            o.name("count")
+                                .description("How many ways to write each reply. 1 goes back to just one.")
+                                .kind(serenity::model::application::command::CommandOptionType::Integer)
+                                .min_int_value(1)
+                                .max_int_value(MAX_VARIANTS)
+                                .required(true)
+                        })
+                })
+                .create_application_command(|c| {
+                    c.name(BIO_COMMAND_NAME)
+                        .description("Tell the bot about yourself, so you don't have to in every thread.")
+                        .create_option(|o| {
+                            o.name(BIO_SET_SUBCOMMAND_NAME)
+                                .description("Set what the bot knows about you.")
+                                .kind(serenity::model::application::command::CommandOptionType::SubCommand)
+                                .create_sub_option(|o| {
+                                    o.name("text")
+                                        .description("A few words about yourself.")
+                                        .kind(serenity::model::application::command::CommandOptionType::String)
+                                        .required(true)
+                                })
+                        })
+                        .create_option(|o| {
+                            o.name(BIO_CLEAR_SUBCOMMAND_NAME)
+                                .description("Stop telling the bot about yourself.")
+                                .kind(serenity::model::application::command::CommandOptionType::SubCommand)
+                        })
+                })
+                .create_application_command(|c| {
+                    c.name(STATUS_COMMAND_NAME)
+                        .description("Show which backend this thread uses and how much of its history fits.")
+                })
+                .create_application_command(|c| {
+                    c.name(TOKENS_COMMAND_NAME)
+                        .description("Show how the thread's history fits in the backend's token budget.")
+                })
+                .create_application_command(|c| {
+                    c.name(DEBUG_COMMAND_NAME).description("Debugging tools.").create_option(|o| {
+                        o.name(DEBUG_PROMPT_SUBCOMMAND_NAME)
+                            .description("Show exactly what would be sent to the backend right now.")
+                            .kind(serenity::model::application::command::CommandOptionType::SubCommand)
+                    })
+                })
+                .create_application_command(|c| {
+                    c.name(SWEEP_COMMAND_NAME)
+                        .description("Try a reply to the newest message at several values of a parameter.")
+                        .create_option(|o| {
+                            o.name("parameter")
+                                .description("The parameter to vary, e.g. temperature.")
+                                .kind(serenity::model::application::command::CommandOptionType::String)
+                                .required(true)
+                        })
+                        .create_option(|o| {
+                            o.name("values")
+                                .description("The values to try, separated by spaces, e.g. 0.3 0.7 1.1.")
+                                .kind(serenity::model::application::command::CommandOptionType::String)
+                                .required(true)
+                        })
+                })
+                .create_application_command(|c| {
+                    c.name(EXPORT_COMMAND_NAME)
+                        .description("Download the conversation as the bot sees it.")
+                        .create_option(|o| {
+                            o.name("format")
+                                .description("The format to download it in. Markdown by default.")
+                                .kind(serenity::model::application::command::CommandOptionType::String)
+                                .required(false)
+                                .add_string_choice("Markdown", EXPORT_FORMAT_MARKDOWN)
+                                .add_string_choice("JSON", EXPORT_FORMAT_JSON)
+                        })
+                })
+                .create_application_command(|c| {
+                    c.name(IMPORT_COMMAND_NAME)
+                        .description("Continue a conversation from a transcript made with /export.")
+                        .create_option(|o| {
+                            o.name("file")
+                                .description("The transcript, in Markdown or JSON.")
+                                .kind(serenity::model::application::command::CommandOptionType::Attachment)
+                                .required(true)
+                        })
+                })
+                .create_application_command(|c| {
+                    c.name(CHECKPOINT_COMMAND_NAME)
+                        .description("Remember what I can see of the conversation right now, to go back to with /rollback.")
+                        .create_option(|o| {
+                            o.name("name")
+                                .description("What to call the checkpoint. A checkpoint with the same name is replaced.")
+                                .kind(serenity::model::application::command::CommandOptionType::String)
+                                .required(true)
+                        })
+                })
+                .create_application_command(|c| {
+                    c.name(ROLLBACK_COMMAND_NAME)
+                        .description("Go back to a checkpoint, forgetting everything since.")
+                        .create_option(|o| {
+                            o.name("name")
+                                .description("The checkpoint to go back to.")
+                                .kind(serenity::model::application::command::CommandOptionType::String)
+                                .required(true)
+                                .set_autocomplete(true)
+                        })
+                })
+                .create_application_command(|c| {
+                    c.name(SET_SYSTEM_COMMAND_NAME)
+                        .description("Replace this thread's system prompt, e.g. if its first post was deleted.")
+                        .create_option(|o| {
+                            o.name("prompt")
+                                .description("The new system prompt. \\n starts a new line.")
+                                .kind(serenity::model::application::command::CommandOptionType::String)
+                                .required(true)
+                        })
+                })
+                .create_application_command(|c| {
+                    c.name(RESYNC_COMMAND_NAME)
+                        .description("Fetch the thread again, in case I've missed something.")
+                })
+                .create_application_command(|c| {
+                    c.name(FORK_COMMAND_NAME)
+                        .description("Start a new thread with the same settings and tags.")
+                        .create_option(|o| {
+                            o.name("message_link")
+                                .description("Also copy the conversation up to this message.")
+                                .kind(serenity::model::application::command::CommandOptionType::String)
+                                .required(false)
+                        })
+                })
+                .create_application_command(|c| {
+                    c.name(AUTOPLAY_COMMAND_NAME)
+                        .description("Keep the conversation going on my own for a while, in self-chat threads.")
+                        .create_option(|o| {
+                            o.name("turns")
+                                .description("How many replies to write.")
+                                .kind(serenity::model::application::command::CommandOptionType::Integer)
+                                .min_int_value(1)
+                                .required(true)
+                        })
+                })
+                .create_application_command(|c| {
+                    c.name(ANALYTICS_COMMAND_NAME)
+                        .description("Summarize how the bot has been used over the last week.")
+                })
             })
             .await?;
 
@@ -296,50 +7477,174 @@ impl serenity::client::EventHandler for Handler {
         }
     }
 
+    async fn resume(&self, _ctx: serenity::client::Context, _: serenity::model::event::ResumedEvent) {
+        // Discord replays whatever was missed when a session is resumed, so there's nothing to catch up on.
+        self.gateway.offline_since.lock().take();
+    }
+
+    async fn shard_stage_update(&self, _ctx: serenity::client::Context, event: serenity::client::bridge::gateway::event::ShardStageUpdateEvent) {
+        // Resuming a session doesn't send another ready event, so reconnecting counts as being ready again.
+        if event.new == serenity::gateway::ConnectionStage::Connected {
+            self.gateway.ready_shards.lock().insert(event.shard_id.0);
+            return;
+        }
+
+        if self.gateway.ready_shards.lock().remove(&event.shard_id.0) {
+            self.gateway.offline_since.lock().get_or_insert_with(chrono::Utc::now);
+            log::warn!("shard {} disconnected ({:?})", event.shard_id.0, event.new);
+        }
+    }
+
     async fn interaction_create(&self, ctx: serenity::client::Context, interaction: serenity::model::application::interaction::Interaction) {
         if let Err(e) = (|| async {
-            let app_command = if let Some(app_command) = interaction.application_command() {
-                app_command
-            } else {
-                return Ok(());
-            };
-
-            match app_command.kind {
-                serenity::model::application::interaction::InteractionType::ApplicationCommand => match app_command.data.name.as_str() {
-                    FORGET_COMMAND_NAME => {
+            match interaction {
+                serenity::model::application::interaction::Interaction::ApplicationCommand(app_command) => {
+                    let roles = app_command.member.as_ref().map(|m| m.roles.as_slice()).unwrap_or(&[]);
+                    let permitted = if ADMIN_COMMAND_NAMES.contains(&app_command.data.name.as_str()) {
+                        self.config.access.is_admin(app_command.user.id, roles)
+                    } else {
+                        self.config.access.is_permitted(app_command.user.id, roles)
+                    };
+                    if !permitted {
                         app_command
                             .create_interaction_response(&ctx.http, |r| {
-                                r.interaction_response_data(|d| {
-                                    d.embed(|e| {
-                                        e.color(serenity::utils::colours::css::POSITIVE).description(
-                                            "Okay, forgetting everything from here. If you want me to remember, just delete this message.",
-                                        )
-                                    })
-                                })
+                                r.interaction_response_data(|d| d.ephemeral(true).content(NOT_PERMITTED_MESSAGE))
+                            })
+                            .await?;
+                        return Ok(());
+                    }
+
+                    match app_command.data.name.as_str() {
+                        FORGET_COMMAND_NAME => {
+                            self.handle_forget_command(&ctx, &app_command).await?;
+                        }
+                        INJECT_COMMAND_NAME => {
+                            self.handle_inject_command(&ctx, &app_command, store::InjectedRole::Assistant).await?;
+                        }
+                        INJECT_SYSTEM_COMMAND_NAME => {
+                            self.handle_inject_command(&ctx, &app_command, store::InjectedRole::System).await?;
+                        }
+                        INJECT_USER_COMMAND_NAME => {
+                            self.handle_inject_command(&ctx, &app_command, store::InjectedRole::User).await?;
+                        }
+                        SETTINGS_COMMAND_NAME => {
+                            self.handle_settings_command(&ctx, &app_command).await?;
+                        }
+                        MODEL_COMMAND_NAME => {
+                            self.handle_model_command(&ctx, &app_command).await?;
+                        }
+                        PREFILL_COMMAND_NAME => {
+                            self.handle_prefill_command(&ctx, &app_command).await?;
+                        }
+                        VARIANTS_COMMAND_NAME => {
+                            self.handle_variants_command(&ctx, &app_command).await?;
+                        }
+                        BIO_COMMAND_NAME => {
+                            self.handle_bio_command(&ctx, &app_command).await?;
+                        }
+                        STATUS_COMMAND_NAME => {
+                            self.handle_status_command(&ctx, &app_command).await?;
+                        }
+                        TOKENS_COMMAND_NAME => {
+                            self.handle_tokens_command(&ctx, &app_command).await?;
+                        }
+                        SWEEP_COMMAND_NAME => {
+                            self.handle_sweep_command(&ctx, &app_command).await?;
+                        }
+                        DEBUG_COMMAND_NAME => {
+                            self.handle_debug_command(&ctx, &app_command).await?;
+                        }
+                        EXPORT_COMMAND_NAME => {
+                            self.handle_export_command(&ctx, &app_command).await?;
+                        }
+                        IMPORT_COMMAND_NAME => {
+                            self.handle_import_command(&ctx, &app_command).await?;
+                        }
+                        CHECKPOINT_COMMAND_NAME => {
+                            self.handle_checkpoint_command(&ctx, &app_command).await?;
+                        }
+                        ROLLBACK_COMMAND_NAME => {
+                            self.handle_rollback_command(&ctx, &app_command).await?;
+                        }
+                        FORK_COMMAND_NAME => {
+                            self.handle_fork_command(&ctx, &app_command).await?;
+                        }
+                        SET_SYSTEM_COMMAND_NAME => {
+                            self.handle_set_system_command(&ctx, &app_command).await?;
+                        }
+                        RESYNC_COMMAND_NAME => {
+                            self.handle_resync_command(&ctx, &app_command).await?;
+                        }
+                        AUTOPLAY_COMMAND_NAME => {
+                            self.handle_autoplay_command(&ctx, &app_command).await?;
+                        }
+                        ANALYTICS_COMMAND_NAME => {
+                            self.handle_analytics_command(&ctx, &app_command).await?;
+                        }
+                        FORGET_BEFORE_COMMAND_NAME => {
+                            self.handle_forget_before_command(&ctx, &app_command).await?;
+                        }
+                        EXCLUDE_COMMAND_NAME => {
+                            self.handle_exclude_command(&ctx, &app_command, true).await?;
+                        }
+                        INCLUDE_COMMAND_NAME => {
+                            self.handle_exclude_command(&ctx, &app_command, false).await?;
+                        }
+                        REWRITE_COMMAND_NAME => {
+                            self.handle_rewrite_command(&ctx, &app_command).await?;
+                        }
+                        _ => {}
+                    }
+                }
+                serenity::model::application::interaction::Interaction::Autocomplete(autocomplete) => match autocomplete.data.name.as_str() {
+                    MODEL_COMMAND_NAME => {
+                        self.handle_model_autocomplete(&ctx, &autocomplete).await?;
+                    }
+                    ROLLBACK_COMMAND_NAME => {
+                        self.handle_rollback_autocomplete(&ctx, &autocomplete).await?;
+                    }
+                    _ => {}
+                },
+                serenity::model::application::interaction::Interaction::MessageComponent(component) => {
+                    let custom_id = component.data.custom_id.as_str();
+                    if let Some(message_id) = custom_id.strip_prefix(RETRY_BUTTON_ID_PREFIX).and_then(|id| id.parse::<u64>().ok()) {
+                        self.handle_retry_button(&ctx, &component, serenity::model::id::MessageId(message_id))
+                            .await?;
+                    } else if let Some(message_id) = custom_id.strip_prefix(CONTINUE_BUTTON_ID_PREFIX).and_then(|id| id.parse::<u64>().ok()) {
+                        self.handle_continue_button(&ctx, &component, serenity::model::id::MessageId(message_id))
+                            .await?;
+                    } else if let Some(thread_id) = custom_id
+                        .strip_prefix(STOP_AUTOPLAY_BUTTON_ID_PREFIX)
+                        .and_then(|id| id.parse::<u64>().ok())
+                    {
+                        self.autoplaying.lock().remove(&serenity::model::id::ChannelId(thread_id));
+                        component
+                            .create_interaction_response(&ctx.http, |r| {
+                                r.kind(serenity::model::application::interaction::InteractionResponseType::UpdateMessage)
+                                    .interaction_response_data(|d| d.content("Stopping after this turn.").components(|c| c))
                             })
                             .await?;
-                    }
-                    INJECT_COMMAND_NAME => {
-                        let content = if let Some(content) = app_command.data.options.get(0).and_then(|v| v.value.as_ref()).and_then(|v| v.as_str()) {
-                            content
-                        } else {
-                            return Ok(());
-                        };
-                        app_command
-                            .create_interaction_response(&ctx.http, |r| r.interaction_response_data(|d| d.content(content)))
+                    } else if let Some(message_id) = custom_id.strip_prefix(RATE_UP_BUTTON_ID_PREFIX).and_then(|id| id.parse::<u64>().ok()) {
+                        self.handle_rate_button(&ctx, &component, serenity::model::id::MessageId(message_id), true)
                             .await?;
-                    }
-                    INJECT_SYSTEM_COMMAND_NAME => {
-                        let content = if let Some(content) = app_command.data.options.get(0).and_then(|v| v.value.as_ref()).and_then(|v| v.as_str()) {
-                            content
-                        } else {
-                            return Ok(());
-                        };
-                        app_command
-                            .create_interaction_response(&ctx.http, |r| r.interaction_response_data(|d| d.content(content)))
+                    } else if let Some(message_id) = custom_id.strip_prefix(RATE_DOWN_BUTTON_ID_PREFIX).and_then(|id| id.parse::<u64>().ok()) {
+                        self.handle_rate_button(&ctx, &component, serenity::model::id::MessageId(message_id), false)
+                            .await?;
+                    } else if let Some(message_id) = custom_id.strip_prefix(VARIANT_SELECT_ID_PREFIX).and_then(|id| id.parse::<u64>().ok()) {
+                        self.handle_variant_select(&ctx, &component, serenity::model::id::MessageId(message_id))
                             .await?;
                     }
-                    _ => {}
+                }
+                serenity::model::application::interaction::Interaction::ModalSubmit(modal_submit) => match modal_submit.data.custom_id.as_str() {
+                    SETTINGS_MODAL_ID => {
+                        self.handle_settings_modal(&ctx, &modal_submit).await?;
+                    }
+                    custom_id => {
+                        if let Some(message_id) = custom_id.strip_prefix(REWRITE_MODAL_ID_PREFIX).and_then(|id| id.parse::<u64>().ok()) {
+                            self.handle_rewrite_modal(&ctx, &modal_submit, serenity::model::id::MessageId(message_id))
+                                .await?;
+                        }
+                    }
                 },
                 _ => {}
             }
@@ -352,21 +7657,34 @@ impl serenity::client::EventHandler for Handler {
         }
     }
 
-    async fn guild_create(&self, ctx: serenity::client::Context, guild: serenity::model::guild::Guild) {
+    async fn guild_create(&self, ctx: serenity::client::Context, guild: serenity::model::guild::Guild, _is_new: bool) {
         if let Err(e) = (|| async {
-            let mut thread_cache = self.thread_cache.lock().await;
-            for thread in guild.threads.iter() {
-                if !thread.parent_id.map(|thread_id| self.parent_channel_id == thread_id).unwrap_or(false) {
-                    continue;
-                }
+            self.resolver.lock().await.hint_guild_name(guild.id, guild.name.clone());
 
+            let threads = guild
+                .threads
+                .iter()
+                .filter(|thread| thread.parent_id.map(|thread_id| self.parent_channel_id == thread_id).unwrap_or(false))
+                .collect::<Vec<_>>();
+            for thread in threads.iter() {
                 if thread.member.is_none() {
-                    thread.id.join_thread(&ctx.http).await?;
+                    if let Err(e) = thread.id.join_thread(&ctx.http).await {
+                        log::warn!("failed to join {}: {:?}", thread.id, e);
+                    }
                 }
+            }
 
+            let mut thread_cache = self.thread_cache.lock().await;
+            let mut recent_threads = vec![];
+            for thread in threads {
                 log::info!("thread {} scheduled for load", thread.id);
                 thread_cache.add(thread.id);
+                if let Some(last_message_id) = thread.last_message_id {
+                    thread_cache.touch(thread.id, last_message_id.created_at().with_timezone(&chrono::Utc));
+                    recent_threads.push((last_message_id, thread.id));
+                }
             }
+            drop(thread_cache);
 
             let parent_channel = if let serenity::model::channel::Channel::Guild(guild_channel) = &guild.channels[&self.parent_channel_id] {
                 guild_channel
@@ -374,14 +7692,40 @@ impl serenity::client::EventHandler for Handler {
                 return Ok(());
             };
 
-            let mut tags = self.tags.lock().await;
-            *tags = parent_channel
+            self.gateway.parent_nsfw.store(parent_channel.nsfw, std::sync::atomic::Ordering::Relaxed);
+
+            *self.gateway.tags.lock().await = parent_channel
                 .available_tags
                 .iter()
                 .map(|tag| (tag.id, tag.name.clone()))
                 .collect::<std::collections::HashMap<_, _>>();
 
-            Ok::<_, anyhow::Error>(())
+            // Warm up the most recently active threads, so the first message in each of them doesn't have to wait for its history to be
+            // fetched. The thread cache isn't held while each one loads, so other events go on in the meantime, and so does catching up.
+            recent_threads.sort_by(|a, b| b.cmp(a));
+            let preload = async {
+                for (_, thread_id) in recent_threads
+                    .into_iter()
+                    .take(std::cmp::min(self.config.preload_threads, self.config.thread_cache_size))
+                {
+                    if let Err(e) = self.load_thread(&ctx.http, thread_id).await {
+                        log::warn!("failed to preload {}: {:?}", thread_id, e);
+                    }
+                    tokio::time::sleep(PRELOAD_INTERVAL).await;
+                }
+            };
+
+            // There's no telling how long the bot was down before it started, so mentions are answered as far back as it's willing to go.
+            let catch_up = async {
+                if let Some(window) = self.config.catch_up_mentions {
+                    if !self.gateway.caught_up_at_startup.swap(true, std::sync::atomic::Ordering::Relaxed) {
+                        self.catch_up(&ctx, chrono::Utc::now() - chrono::Duration::from_std(window)?).await?;
+                    }
+                }
+                Ok::<_, anyhow::Error>(())
+            };
+
+            tokio::join!(preload, catch_up).1
         })()
         .await
         {
@@ -389,7 +7733,12 @@ impl serenity::client::EventHandler for Handler {
         }
     }
 
-    async fn channel_update(&self, _ctx: serenity::client::Context, channel: serenity::model::channel::Channel) {
+    async fn channel_update(
+        &self,
+        _ctx: serenity::client::Context,
+        _old: Option<serenity::model::channel::Channel>,
+        channel: serenity::model::channel::Channel,
+    ) {
         if let Err(e) = (|| async {
             let channel = if let serenity::model::channel::Channel::Guild(guild_channel) = channel {
                 guild_channel
@@ -401,12 +7750,14 @@ impl serenity::client::EventHandler for Handler {
                 return Ok(());
             }
 
+            self.gateway.parent_nsfw.store(channel.nsfw, std::sync::atomic::Ordering::Relaxed);
+
             let mut thread_cache = self.thread_cache.lock().await;
 
             // Changing tags may cause the currenet tags we have to be invalidated. Just flush the cache entirely at ths point.
             thread_cache.flush();
 
-            let mut tags = self.tags.lock().await;
+            let mut tags = self.gateway.tags.lock().await;
             *tags = channel
                 .available_tags
                 .iter()
@@ -436,12 +7787,45 @@ impl serenity::client::EventHandler for Handler {
                 log::warn!("could not pin first message: {:?}", e);
             }
 
-            let mut thread_cache = self.thread_cache.lock().await;
-            thread_cache.add(thread.id);
+            self.thread_cache.lock().await.add(thread.id);
 
             // Optimization only, not strictly required.
-            let tags = self.tags.lock().await;
-            thread_cache.load(&ctx.http, thread.id, &*tags, self.config.message_history_size).await?;
+            let t = if let Some(t) = self.load_thread(&ctx.http, thread.id).await? {
+                t
+            } else {
+                return Ok(());
+            };
+
+            let mut t = t.lock().await;
+            // Forks are set up by whoever forked them.
+            if t.primary_message.author.id == self.me_id() {
+                return Ok(());
+            }
+            let settings = if let Some(settings) = self.check_settings(&ctx.http, &mut t).await? {
+                settings
+            } else {
+                return Ok(());
+            };
+            if !t.primary_is_prompt(&self.config.defaults) {
+                if let Some(greeting) = settings.greeting.as_ref() {
+                    self.send_greeting(&ctx, &t, &settings, greeting).await?;
+                }
+                return Ok(());
+            }
+
+            // The thread was opened with just a question, so answer it. Messages fetched over HTTP don't say which guild they're from.
+            let mut primary_message = t.primary_message.clone();
+            primary_message.guild_id = Some(t.guild_id);
+            let roles = thread
+                .guild_id
+                .member(&ctx, primary_message.author.id)
+                .await
+                .map(|member| member.roles)
+                .unwrap_or_default();
+            if !self.config.access.is_permitted(primary_message.author.id, &roles) {
+                return Ok(());
+            }
+            self.reply(&ctx, &mut t, &primary_message).await?;
 
             Ok::<_, anyhow::Error>(())
         })()
@@ -451,22 +7835,26 @@ impl serenity::client::EventHandler for Handler {
         }
     }
 
-    async fn thread_update(&self, _ctx: serenity::client::Context, thread: serenity::model::channel::GuildChannel) {
+    async fn thread_update(&self, ctx: serenity::client::Context, thread: serenity::model::channel::GuildChannel) {
         if let Err(e) = (|| async {
             if !thread.parent_id.map(|thread_id| self.parent_channel_id == thread_id).unwrap_or(false) {
                 return Ok(());
             }
 
-            let mut thread_cache = self.thread_cache.lock().await;
             if thread.thread_metadata.unwrap().archived {
                 log::info!("thread {} archived", thread.id);
-                thread_cache.remove(thread.id);
+                if let Err(e) = self.summarize_thread(&ctx, &thread).await {
+                    log::warn!("failed to summarize {}: {:?}", thread.id, e);
+                }
+                self.thread_cache.lock().await.remove(thread.id);
             } else {
+                let mut thread_cache = self.thread_cache.lock().await;
                 thread_cache.add(thread.id);
                 if let Some(t) = thread_cache.get(thread.id) {
                     let mut t = t.lock().await;
-                    let tags = self.tags.lock().await;
-                    t.update_from_tags(&thread, &*tags);
+                    let tags = self.gateway.tags.lock().await;
+                    t.update_from_channel(&thread, &*tags, &self.config);
+                    self.check_settings(&ctx.http, &mut t).await?;
                 }
             }
 
@@ -483,6 +7871,7 @@ impl serenity::client::EventHandler for Handler {
             let mut thread_cache = self.thread_cache.lock().await;
             log::info!("thread {} deleted", thread.id);
             thread_cache.remove(thread.id);
+            self.store.delete_thread(thread.id).await?;
             Ok::<_, anyhow::Error>(())
         })()
         .await
@@ -491,10 +7880,15 @@ impl serenity::client::EventHandler for Handler {
         }
     }
 
-    async fn guild_member_update(&self, _ctx: serenity::client::Context, event: serenity::model::event::GuildMemberUpdateEvent) {
+    async fn guild_member_update(
+        &self,
+        _ctx: serenity::client::Context,
+        _old_if_available: Option<serenity::model::guild::Member>,
+        member: serenity::model::guild::Member,
+    ) {
         if let Err(e) = (|| async {
             let mut resolver = self.resolver.lock().await;
-            resolver.hint_display_name(event.guild_id, event.user.id, event.nick.unwrap_or(event.user.name));
+            resolver.hint_display_name(member.guild_id, member.user.id, member.display_name().into_owned());
             Ok::<_, anyhow::Error>(())
         })()
         .await
@@ -505,30 +7899,39 @@ impl serenity::client::EventHandler for Handler {
 
     async fn message(&self, ctx: serenity::client::Context, new_message: serenity::model::channel::Message) {
         if let Err(e) = (|| async {
-            let me_id = self.me_id.lock().clone();
+            let me_id = self.me_id();
 
-            let thread = {
-                let mut thread_cache = self.thread_cache.lock().await;
-                let tags = self.tags.lock().await;
-                let thread = if let Some(thread) = thread_cache
-                    .load(&ctx.http, new_message.channel_id, &*tags, self.config.message_history_size)
-                    .await?
-                {
-                    thread
-                } else {
-                    return Ok(());
-                };
+            let thread = if let Some(thread) = self.load_thread(&ctx.http, new_message.channel_id).await? {
                 thread
+            } else {
+                return Ok(());
             };
+            self.thread_cache
+                .lock()
+                .await
+                .touch(new_message.channel_id, new_message.timestamp.with_timezone(&chrono::Utc));
 
-            let should_reply = new_message.author.id != me_id
-                && new_message.mentions_user_id(me_id)
+            let can_reply = new_message.author.id != me_id
                 && (new_message.kind == serenity::model::channel::MessageType::Regular
                     || new_message.kind == serenity::model::channel::MessageType::InlineReply);
-
-            let mut thread = if let Ok(thread) = thread.try_lock() {
-                thread
-            } else if should_reply {
+            let should_reply = can_reply && new_message.mentions_user_id(me_id);
+
+            let permitted = self.config.access.is_permitted(
+                new_message.author.id,
+                new_message.member.as_ref().map(|m| m.roles.as_slice()).unwrap_or(&[]),
+            );
+            if should_reply && !permitted && self.config.access.notify_denied {
+                self.send_transient_notice(&ctx, &new_message, serenity::utils::colours::css::DANGER, NOT_PERMITTED_MESSAGE)
+                    .await?;
+            }
+            let can_reply = can_reply && permitted;
+            let should_reply = should_reply && permitted;
+
+            // Whether this is a continuation of the settings depends on who started the thread, which isn't known until it's locked, so
+            // something that might be one waits rather than being turned away.
+            let (mut thread, waited) = if let Ok(thread) = thread.try_lock() {
+                (thread, false)
+            } else if should_reply && !new_message.content.starts_with(SETTINGS_CONTINUATION_MARKER) {
                 ctx.http.delete_message(new_message.channel_id.0, new_message.id.0).await?;
                 new_message
                     .channel_id
@@ -552,280 +7955,39 @@ impl serenity::client::EventHandler for Handler {
                     .await?;
                 return Ok(());
             } else {
-                thread.lock().await
+                (thread.lock().await, true)
             };
 
-            while thread.messages.len() >= self.config.message_history_size {
-                thread.messages.pop_first();
-            }
-            thread.messages.insert(new_message.id, new_message.clone());
+            thread.insert_message(new_message.clone(), self.config.message_history_size);
 
-            if !should_reply {
-                return Ok(());
-            }
+            let can_reply = can_reply && !thread.is_settings_continuation(&new_message);
+            let should_reply = should_reply && can_reply;
 
-            let settings = ChatSettings::new(&thread.primary_message.content)?;
+            // In no-mention threads every message is addressed to the bot, but if it was busy replying, just keep the message as context for
+            // the next reply.
+            let addressed = should_reply || (can_reply && !waited && thread.no_mention);
+            let should_reply = addressed || (can_reply && !waited && self.should_reply_ambiently(&ctx.http, &mut thread, &new_message).await?);
 
-            let (
-                backend_name,
-                BackendBinding {
-                    backend,
-                    request_timeout,
-                    chunk_timeout,
-                    max_input_tokens,
-                },
-            ) = if let Some((backend_name, backend)) = thread
-                .backend
-                .as_ref()
-                .and_then(|backend_name| self.backends.get(backend_name).map(|backend| (backend_name, backend)))
-                .or_else(|| self.backends.first())
-            {
-                (backend_name, backend)
-            } else {
+            if !should_reply {
                 return Ok(());
-            };
-
-            let r = (|| async {
-                let messages = {
-                    let mut resolver = self.resolver.lock().await;
-
-                    let system_message = backend::Message {
-                        role: backend::Role::System,
-                        name: None,
-                        content: if thread.mode == ThreadMode::Multi {
-                            format!(
-                                "Your name is {}.\n\n{}\n\nDo not prefix your replies with your name and timestamp.",
-                                resolver
-                                    .resolve_display_name(&ctx.http, new_message.guild_id.unwrap(), me_id,)
-                                    .await
-                                    .map_err(|e| anyhow::format_err!("resolve_display_name: {}", e))?,
-                                settings.system_message
-                            )
-                        } else {
-                            settings.system_message.clone()
-                        },
-                        mentioned: false,
-                    };
-
-                    let mut input_tokens = backend.num_overhead_tokens() + backend.count_message_tokens(&system_message);
-
-                    let mut messages = vec![];
-
-                    for (_, message) in thread.messages.iter().rev() {
-                        if message.author.id == me_id
-                            && message
-                                .interaction
-                                .as_ref()
-                                .map(|i| {
-                                    i.kind == serenity::model::application::interaction::InteractionType::ApplicationCommand
-                                        && i.name == FORGET_COMMAND_NAME
-                                })
-                                .unwrap_or(false)
-                        {
-                            break;
-                        }
-
-                        if message.content.is_empty() {
-                            continue;
-                        }
-
-                        if message.kind != serenity::model::channel::MessageType::Regular
-                            && message.kind != serenity::model::channel::MessageType::InlineReply
-                            && message.kind != serenity::model::channel::MessageType::ChatInputCommand
-                        {
-                            continue;
-                        }
-
-                        if message
-                            .reactions
-                            .iter()
-                            .any(|r| r.reaction_type == serenity::model::channel::ReactionType::Unicode(FORGET_EMOJI.to_string()))
-                        {
-                            continue;
-                        }
-
-                        let oai_message = if message.author.id == me_id {
-                            backend::Message {
-                                role: if message
-                                    .interaction
-                                    .as_ref()
-                                    .map(|i| {
-                                        i.kind == serenity::model::application::interaction::InteractionType::ApplicationCommand
-                                            && i.name == INJECT_SYSTEM_COMMAND_NAME
-                                    })
-                                    .unwrap_or(false)
-                                {
-                                    backend::Role::System
-                                } else {
-                                    backend::Role::Assistant
-                                },
-                                name: None,
-                                content: message.content.clone(),
-                                mentioned: false,
-                            }
-                        } else {
-                            backend::Message {
-                                role: backend::Role::User(
-                                    resolver
-                                        .resolve_display_name(&ctx.http, new_message.guild_id.unwrap(), message.author.id)
-                                        .await?
-                                        .to_string(),
-                                ),
-                                name: None,
-                                content: match thread.mode {
-                                    ThreadMode::Single => {
-                                        if !message.mentions_user_id(me_id) {
-                                            continue;
-                                        }
-
-                                        resolver
-                                            .resolve_message(
-                                                &ctx.http,
-                                                new_message.guild_id.unwrap(),
-                                                &STRIP_SINGLE_USER_REGEX.replace(&message.content, |c: &regex::Captures| {
-                                                    if serenity::model::id::UserId(c["user_id"].parse::<u64>().unwrap()) == me_id {
-                                                        "".to_string()
-                                                    } else {
-                                                        c[0].to_string()
-                                                    }
-                                                }),
-                                            )
-                                            .await
-                                            .map_err(|e| anyhow::format_err!("resolve_message: {}", e))?
-                                    }
-                                    ThreadMode::Multi => format!(
-                                        "{} at {} said:\n{}",
-                                        resolver
-                                            .resolve_display_name(&ctx.http, new_message.guild_id.unwrap(), message.author.id)
-                                            .await
-                                            .map_err(|e| anyhow::format_err!("resolve_display_name: {}", e))?
-                                            .to_owned(),
-                                        new_message.timestamp.with_timezone(&chrono::Utc).to_rfc3339(),
-                                        resolver
-                                            .resolve_message(&ctx.http, new_message.guild_id.unwrap(), &message.content)
-                                            .await
-                                            .map_err(|e| anyhow::format_err!("resolve_message: {}", e))?
-                                            .to_owned()
-                                    ),
-                                },
-                                mentioned: message.mentions_user_id(me_id),
-                            }
-                        };
-
-                        let message_tokens = backend.count_message_tokens(&oai_message);
-
-                        if input_tokens + message_tokens > *max_input_tokens as usize {
-                            break;
-                        }
-
-                        messages.push(oai_message);
-                        input_tokens += message_tokens;
-                    }
-
-                    messages.push(system_message);
-                    messages.reverse();
-
-                    messages
-                };
-
-                log::info!("{} ({:?}) <- {:#?}", backend_name, settings.parameters, messages);
-
-                let mut typing = Some(new_message.channel_id.start_typing(&ctx.http)?);
-
-                let mut stream = tokio::time::timeout(*request_timeout, backend.request(&messages, &settings.parameters))
-                    .await
-                    .map_err(|e| anyhow::format_err!("timed out: {}", e))??;
-
-                let mut stream_error = None;
-                let mut chunker = unichunk::Chunker::new(2000);
-                while let Some(content) = tokio::time::timeout(*chunk_timeout, stream.next())
-                    .await
-                    .map_err(|e| anyhow::format_err!("timed out: {}", e))?
-                {
-                    let content = match content {
-                        Ok(content) => content,
-                        Err(e) => {
-                            stream_error = Some(e);
-                            break;
-                        }
-                    };
-
-                    for c in chunker.push(&content) {
-                        typing.take();
-                        new_message
-                            .channel_id
-                            .send_message(&ctx.http, |m| m.content(&c).reference_message(&new_message))
-                            .await
-                            .map_err(|e| anyhow::format_err!("send_message: {}", e))?;
-                        typing = Some(new_message.channel_id.start_typing(&ctx.http)?);
-                    }
-                }
-
-                typing.take();
-
-                let c = chunker.flush();
-                if !c.is_empty() {
-                    new_message
-                        .channel_id
-                        .send_message(&ctx.http, |m| m.content(&c).reference_message(&new_message))
-                        .await
-                        .map_err(|e| anyhow::format_err!("send_message: {}", e))?;
-                }
+            }
 
-                if let Some(stream_error) = stream_error {
-                    new_message
-                        .channel_id
-                        .send_message(&ctx.http, |m| {
-                            m.embed(|em| {
-                                em.title("Incomplete response")
-                                    .color(serenity::utils::colours::css::WARNING)
-                                    .description(&match stream_error {
-                                        backend::RequestStreamError::ContentFilter => {
-                                            "The remainder of this response was truncated due to the content filter.".to_string()
-                                        }
-                                        backend::RequestStreamError::Length => {
-                                            "The remainder of this response was truncated due to the length.".to_string()
-                                        }
-                                        backend::RequestStreamError::Other(e) => {
-                                            format!("The remainder of this response was truncated due to an unexpected error: {}", e)
-                                        }
-                                    })
-                            })
-                        })
-                        .await
-                        .map_err(|send_e| anyhow::format_err!("send error: {}", send_e))?;
+            if let Some(remaining) = self.cooldown_remaining(&thread, new_message.author.id) {
+                // Only complain to people who were actually talking to the bot.
+                if addressed {
+                    self.send_transient_notice(
+                        &ctx,
+                        &new_message,
+                        serenity::utils::colours::css::WARNING,
+                        &format!("Slow down! Try again in {} seconds.", remaining.as_secs() + 1),
+                    )
+                    .await?;
                 }
-
-                Ok::<_, anyhow::Error>(())
-            })()
-            .await;
-
-            if let Err(e) = &r {
-                new_message
-                    .channel_id
-                    .send_message(&ctx.http, |m| {
-                        m.embed(|em| {
-                            em.title("Error")
-                                .color(serenity::utils::colours::css::DANGER)
-                                .description(format!("{:?}", e))
-                                .field("Original message", format!("```\n{}\n```", new_message.content), false)
-                                .footer(|f| {
-                                    f.icon_url(
-                                        new_message
-                                            .author
-                                            .static_avatar_url()
-                                            .unwrap_or_else(|| new_message.author.default_avatar_url()),
-                                    )
-                                    .text(format!("{}#{:04}", new_message.author.name, new_message.author.discriminator))
-                                })
-                        })
-                    })
-                    .await
-                    .map_err(|send_e| anyhow::format_err!("send error: {} ({})", send_e, e))?;
-                ctx.http.delete_message(new_message.channel_id.0, new_message.id.0).await?;
+                return Ok(());
             }
+            self.record_request(&mut thread, new_message.author.id);
 
-            r
+            self.reply(&ctx, &mut thread, &new_message).await
         })()
         .await
         {
@@ -833,7 +7995,13 @@ impl serenity::client::EventHandler for Handler {
         }
     }
 
-    async fn message_update(&self, _ctx: serenity::client::Context, new_event: serenity::model::event::MessageUpdateEvent) {
+    async fn message_update(
+        &self,
+        ctx: serenity::client::Context,
+        _old_if_available: Option<serenity::model::channel::Message>,
+        _new: Option<serenity::model::channel::Message>,
+        new_event: serenity::model::event::MessageUpdateEvent,
+    ) {
         if let Err(e) = (|| async {
             let thread = {
                 let mut thread_cache = self.thread_cache.lock().await;
@@ -847,6 +8015,14 @@ impl serenity::client::EventHandler for Handler {
             };
 
             let mut thread = thread.lock().await;
+            if thread.is_missing(new_event.id) {
+                log::warn!("{} in {} was never loaded, resyncing", new_event.id, new_event.channel_id);
+                thread.resync(&ctx.http, &self.config).await?;
+            }
+
+            // The settings as they were, to tell whoever edited them what changed.
+            let old_settings = self.validate_settings(&thread).ok();
+
             let message = if new_event.id.0 == new_event.channel_id.0 {
                 &mut thread.primary_message
             } else if let Some(message) = thread.messages.get_mut(&new_event.id) {
@@ -855,6 +8031,8 @@ impl serenity::client::EventHandler for Handler {
                 return Ok(());
             };
 
+            let content_changed = new_event.content.as_ref().map(|content| *content != message.content).unwrap_or(false);
+
             if let Some(x) = new_event.attachments {
                 message.attachments = x
             }
@@ -898,6 +8076,54 @@ impl serenity::client::EventHandler for Handler {
             //     message.sticker_items = x
             // }
 
+            if new_event.id.0 == new_event.channel_id.0
+                || thread
+                    .messages
+                    .get(&new_event.id)
+                    .map(|message| thread.is_settings_continuation(message))
+                    .unwrap_or(false)
+            {
+                let new_settings = self.check_settings(&ctx.http, &mut thread).await?;
+                if let Some(new_settings) = new_settings.as_ref().filter(|_| content_changed) {
+                    let changes = if let Some(old_settings) = old_settings.as_ref() {
+                        describe_settings_changes(old_settings, new_settings)
+                    } else {
+                        vec!["The settings are fixed, so I can reply again.".to_string()]
+                    };
+                    if !changes.is_empty() {
+                        new_event
+                            .channel_id
+                            .send_message(&ctx.http, |m| {
+                                m.embed(|e| {
+                                    e.title("Settings updated")
+                                        .color(serenity::utils::colours::css::POSITIVE)
+                                        .description(changes.join("\n"))
+                                })
+                            })
+                            .await?;
+                    }
+                }
+            }
+
+            if thread.reply_on_edit && content_changed {
+                let me_id = self.me_id();
+
+                // Only the message that the bot most recently replied to gets a new reply.
+                let last_prompt_id = thread
+                    .messages
+                    .values()
+                    .rev()
+                    .find(|m| m.author.id == me_id && m.message_reference.is_some())
+                    .and_then(|m| m.message_reference.as_ref().and_then(|r| r.message_id));
+
+                if last_prompt_id == Some(new_event.id) {
+                    let prompt = thread.messages[&new_event.id].clone();
+                    if prompt.mentions_user_id(me_id) {
+                        self.regenerate(&ctx, &mut thread, &prompt).await?;
+                    }
+                }
+            }
+
             Ok::<_, anyhow::Error>(())
         })()
         .await
@@ -906,9 +8132,9 @@ impl serenity::client::EventHandler for Handler {
         }
     }
 
-    async fn reaction_add(&self, _ctx: serenity::client::Context, reaction: serenity::model::channel::Reaction) {
+    async fn reaction_add(&self, ctx: serenity::client::Context, reaction: serenity::model::channel::Reaction) {
         if let Err(e) = (|| async {
-            let me_id = self.me_id.lock().clone();
+            let me_id = self.me_id();
 
             let thread = {
                 let mut thread_cache = self.thread_cache.lock().await;
@@ -922,6 +8148,10 @@ impl serenity::client::EventHandler for Handler {
             };
 
             let mut thread = thread.lock().await;
+            if thread.is_missing(reaction.message_id) {
+                log::warn!("{} in {} was never loaded, resyncing", reaction.message_id, reaction.channel_id);
+                thread.resync(&ctx.http, &self.config).await?;
+            }
             let message = if let Some(message) = thread.messages.get_mut(&reaction.message_id) {
                 message
             } else {
@@ -938,45 +8168,119 @@ impl serenity::client::EventHandler for Handler {
                         name: String,
                     }
 
-                    #[derive(serde::Serialize)]
-                    struct MessageReaction {
-                        count: u64,
-                        me: bool,
-                        emoji: Emoji,
+                    #[derive(serde::Serialize)]
+                    struct MessageReaction {
+                        count: u64,
+                        me: bool,
+                        emoji: Emoji,
+                    }
+
+                    serde_json::to_value(&MessageReaction {
+                        count: 0,
+                        me: false,
+                        emoji: Emoji { name: "".to_string() },
+                    })
+                    .and_then(serde_json::from_value)
+                    .unwrap()
+                });
+
+                let mut message_reaction = EMPTY_MESSAGE_REACTION.clone();
+                message_reaction.me = reaction
+                    .member
+                    .as_ref()
+                    .and_then(|member| member.user.as_ref().map(|user| user.id == me_id))
+                    .unwrap_or(false);
+                message_reaction.reaction_type = reaction.emoji.clone();
+
+                message.reactions.push(message_reaction);
+                message.reactions.last_mut().unwrap()
+            };
+            message_reaction.count += 1;
+
+            let action = if let Some(action) = self.reaction_action(&reaction.emoji) {
+                action
+            } else {
+                return Ok(());
+            };
+
+            let (user_id, guild_id) = if let (Some(user_id), Some(guild_id)) = (reaction.user_id, reaction.guild_id) {
+                (user_id, guild_id)
+            } else {
+                return Ok(());
+            };
+
+            if user_id == me_id {
+                return Ok(());
+            }
+
+            let message = thread.messages[&reaction.message_id].clone();
+
+            // For bot replies, the message being replied to is the one the reply references.
+            let prompt = if message.author.id == me_id {
+                message
+                    .message_reference
+                    .as_ref()
+                    .and_then(|r| r.message_id)
+                    .and_then(|message_id| thread.messages.get(&message_id))
+                    .cloned()
+            } else {
+                Some(message.clone())
+            };
+
+            let owner_id = prompt.as_ref().map(|prompt| prompt.author.id).unwrap_or(message.author.id);
+            if !self
+                .config
+                .access
+                .is_permitted(user_id, reaction.member.as_ref().map(|m| m.roles.as_slice()).unwrap_or(&[]))
+                || (user_id != owner_id && !thread.can_manage(user_id, Some(self.member_permissions(&ctx.http, guild_id, user_id).await?)))
+            {
+                reaction.delete(&ctx.http).await?;
+                return Ok(());
+            }
+
+            match action {
+                ReactionAction::Exclude => {}
+                ReactionAction::Pin => {
+                    self.set_pinned(&mut thread, message.id, true).await?;
+                }
+                ReactionAction::Regenerate => {
+                    let prompt = if let Some(prompt) = prompt {
+                        prompt
+                    } else {
+                        return Ok(());
+                    };
+
+                    // If the reaction was on the prompt itself, clear it so it can be used again.
+                    if message.id == prompt.id {
+                        reaction.delete(&ctx.http).await?;
                     }
 
-                    serde_json::to_value(&MessageReaction {
-                        count: 0,
-                        me: false,
-                        emoji: Emoji { name: "".to_string() },
-                    })
-                    .and_then(serde_json::from_value)
-                    .unwrap()
-                });
-
-                let mut message_reaction = EMPTY_MESSAGE_REACTION.clone();
-                message_reaction.me = reaction
-                    .member
-                    .and_then(|member| member.user.map(|user| user.id == me_id))
-                    .unwrap_or(false);
-                message_reaction.reaction_type = reaction.emoji;
+                    thread.requester = reaction.user_id;
+                    let r = self.regenerate(&ctx, &mut thread, &prompt).await;
+                    thread.requester = None;
+                    r?;
+                }
+                ReactionAction::Delete => {
+                    if message.author.id != me_id {
+                        return Ok(());
+                    }
 
-                message.reactions.push(message_reaction);
-                message.reactions.last_mut().unwrap()
-            };
-            message_reaction.count += 1;
+                    message.channel_id.delete_message(&ctx.http, message.id).await?;
+                    thread.messages.remove(&message.id);
+                }
+            }
 
             Ok::<_, anyhow::Error>(())
         })()
         .await
         {
-            log::error!("error in reaction_remove_all: {:?}", e);
+            log::error!("error in reaction_add: {:?}", e);
         }
     }
 
-    async fn reaction_remove(&self, _ctx: serenity::client::Context, reaction: serenity::model::channel::Reaction) {
+    async fn reaction_remove(&self, ctx: serenity::client::Context, reaction: serenity::model::channel::Reaction) {
         if let Err(e) = (|| async {
-            let me_id = self.me_id.lock().clone();
+            let me_id = self.me_id();
 
             let thread = {
                 let mut thread_cache = self.thread_cache.lock().await;
@@ -990,6 +8294,10 @@ impl serenity::client::EventHandler for Handler {
             };
 
             let mut thread = thread.lock().await;
+            if thread.is_missing(reaction.message_id) {
+                log::warn!("{} in {} was never loaded, resyncing", reaction.message_id, reaction.channel_id);
+                thread.resync(&ctx.http, &self.config).await?;
+            }
             let message = if let Some(message) = thread.messages.get_mut(&reaction.message_id) {
                 message
             } else {
@@ -1021,6 +8329,10 @@ impl serenity::client::EventHandler for Handler {
                 .filter(|r| r.count > 0)
                 .collect();
 
+            if !self.has_reaction_action(message, ReactionAction::Pin) {
+                self.set_pinned(&mut thread, reaction.message_id, false).await?;
+            }
+
             Ok::<_, anyhow::Error>(())
         })()
         .await
@@ -1055,6 +8367,7 @@ impl serenity::client::EventHandler for Handler {
             };
 
             message.reactions.clear();
+            self.set_pinned(&mut thread, message_id, false).await?;
 
             Ok::<_, anyhow::Error>(())
         })()
@@ -1066,7 +8379,7 @@ impl serenity::client::EventHandler for Handler {
 
     async fn message_delete(
         &self,
-        _ctx: serenity::client::Context,
+        ctx: serenity::client::Context,
         channel_id: serenity::model::id::ChannelId,
         deleted_message_id: serenity::model::id::MessageId,
         _guild_id: Option<serenity::model::id::GuildId>,
@@ -1084,7 +8397,10 @@ impl serenity::client::EventHandler for Handler {
             };
 
             let mut thread = thread.lock().await;
-            thread.messages.remove(&deleted_message_id);
+            self.remove_message(&mut thread, deleted_message_id).await?;
+            if self.config.delete_replies_with_prompt {
+                self.delete_replies(&ctx.http, &mut thread, deleted_message_id).await?;
+            }
 
             Ok::<_, anyhow::Error>(())
         })()
@@ -1096,7 +8412,7 @@ impl serenity::client::EventHandler for Handler {
 
     async fn message_delete_bulk(
         &self,
-        _ctx: serenity::client::Context,
+        ctx: serenity::client::Context,
         channel_id: serenity::model::id::ChannelId,
         multiple_deleted_messages_id: Vec<serenity::model::id::MessageId>,
         _guild_id: Option<serenity::model::id::GuildId>,
@@ -1114,8 +8430,16 @@ impl serenity::client::EventHandler for Handler {
             };
 
             let mut thread = thread.lock().await;
+            // Every message is forgotten even if something goes wrong with one of them, so none are left to end up in prompts.
             for deleted_message_id in multiple_deleted_messages_id {
-                thread.messages.remove(&deleted_message_id);
+                if let Err(e) = self.remove_message(&mut thread, deleted_message_id).await {
+                    log::warn!("failed to remove deleted message {}: {:?}", deleted_message_id, e);
+                }
+                if self.config.delete_replies_with_prompt {
+                    if let Err(e) = self.delete_replies(&ctx.http, &mut thread, deleted_message_id).await {
+                        log::warn!("failed to delete replies to {}: {:?}", deleted_message_id, e);
+                    }
+                }
             }
 
             Ok::<_, anyhow::Error>(())
@@ -1127,16 +8451,281 @@ impl serenity::client::EventHandler for Handler {
     }
 }
 
+/// Settings for threads that don't bring their own, so a thread can be opened with just a question.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DefaultsConfig {
+    /// Used when a thread has no `use` tag, instead of the first backend.
+    #[serde(default)]
+    backend: Option<String>,
+
+    /// If set, a primary message without any settings syntax is taken as the thread's first prompt, and this is used as the system
+    /// message instead.
+    #[serde(default)]
+    system_message: Option<String>,
+
+    /// Used underneath the parameters from tags, for threads whose primary message is a prompt.
+    #[serde(default = "parameters_default")]
+    parameters: toml::Value,
+
+    /// Settings for threads whose first post was deleted, written like a first post. If not set, they get no settings of their own.
+    #[serde(default)]
+    missing_first_post_settings: Option<String>,
+}
+
+fn parameters_default() -> toml::Value {
+    toml::Table::new().into()
+}
+
+impl Default for DefaultsConfig {
+    fn default() -> Self {
+        Self {
+            backend: None,
+            system_message: None,
+            parameters: parameters_default(),
+            missing_first_post_settings: None,
+        }
+    }
+}
+
+fn translation_language_default() -> String {
+    "English".to_string()
+}
+
+fn translation_detect_prompt_default() -> String {
+    "Reply with just the name, in English, of the language the user's message is written in. If it's in {{language}}, reply with just \"{{language}}\"."
+        .to_string()
+}
+
+fn translation_prompt_default() -> String {
+    "Translate the user's message into {{language}}. Reply with just the translation, keeping its formatting, mentions and links as they are."
+        .to_string()
+}
+
+const fn cast_list_bios_default() -> bool {
+    true
+}
+
+fn cast_list_avatar_prompt_default() -> String {
+    "This is someone's profile picture. Describe what it shows in a few words.".to_string()
+}
+
+fn emoji_prompt_default() -> String {
+    "This is a custom emoji called :{{name}}:. Describe what it shows in a few words, without mentioning that it's an emoji.".to_string()
+}
+
+fn ensemble_router_prompt_default() -> String {
+    "You're directing a conversation between the user and these characters: {{names}}. Given the conversation so far, reply with just \
+     the name of the character who should speak next, or \"nobody\" if it's the user's turn."
+        .to_string()
+}
+
+/// How threads with several preset tags take turns.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnsembleConfig {
+    /// Asks this backend who should speak next when a message doesn't name anyone. If not set, the cast take turns.
+    router_backend: Option<String>,
+
+    /// Asks for who should speak next, with `{{names}}` standing in for the cast.
+    #[serde(default = "ensemble_router_prompt_default")]
+    router_prompt: String,
+
+    /// How many times the cast may reply to each other before it's someone else's turn.
+    #[serde(default)]
+    max_exchanges: u32,
+}
+
+impl Default for EnsembleConfig {
+    fn default() -> Self {
+        Self {
+            router_backend: None,
+            router_prompt: ensemble_router_prompt_default(),
+            max_exchanges: 0,
+        }
+    }
+}
+
+/// What to do when the content filter stops a reply before any of it is sent. Each is tried in turn, and if none are set the reply fails.
+#[derive(serde::Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ContentFilterConfig {
+    /// Tries once more with this appended to the system message.
+    retry_system_suffix: Option<String>,
+
+    /// Tries once more on this backend.
+    fallback_backend: Option<String>,
+
+    /// Replies with this instead, as if the backend had said it.
+    refusal: Option<String>,
+}
+
+/// A backend to translate threads with the translate tag with, for models that only work well in one language.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TranslationConfig {
+    backend: String,
+
+    /// The language the thread's backend works in.
+    #[serde(default = "translation_language_default")]
+    language: String,
+
+    /// Asks for the language of a message, with `{{language}}` standing in for `language`.
+    #[serde(default = "translation_detect_prompt_default")]
+    detect_prompt: String,
+
+    /// Asks for a message to be translated into `{{language}}`: `language` for messages to the backend, and whatever language the message
+    /// being replied to was in for replies.
+    #[serde(default = "translation_prompt_default")]
+    prompt: String,
+}
+
+/// A backend that can see images, to describe custom emoji with so prompts say what they show rather than just what they're called.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EmojiConfig {
+    backend: String,
+
+    /// Asks for a description of an emoji, with `{{name}}` standing in for its name.
+    #[serde(default = "emoji_prompt_default")]
+    prompt: String,
+}
+
+/// Lists who's in a multi-user thread at the end of the system message, so the model knows who it's talking to.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CastListConfig {
+    /// Lists each person's roles in the server.
+    #[serde(default)]
+    roles: bool,
+
+    /// A backend that can see images, to describe each person's avatar with. Each avatar is described once.
+    #[serde(default)]
+    avatar_backend: Option<String>,
+
+    #[serde(default = "cast_list_avatar_prompt_default")]
+    avatar_prompt: String,
+
+    /// Includes what people have said about themselves with /bio.
+    #[serde(default = "cast_list_bios_default")]
+    bios: bool,
+}
+
+/// Who may get the bot to do things. Denials win over allowances, and if nobody is allowed explicitly, everybody is.
+#[derive(serde::Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct AccessConfig {
+    #[serde(default)]
+    allowed_users: Vec<u64>,
+
+    #[serde(default)]
+    allowed_roles: Vec<u64>,
+
+    #[serde(default)]
+    denied_users: Vec<u64>,
+
+    #[serde(default)]
+    denied_roles: Vec<u64>,
+
+    /// Roles that may use admin commands, e.g. the inject commands. If empty, anybody permitted may.
+    #[serde(default)]
+    admin_roles: Vec<u64>,
+
+    /// Tells denied users they aren't permitted when they mention the bot, instead of ignoring them.
+    #[serde(default)]
+    notify_denied: bool,
+}
+
+impl AccessConfig {
+    fn is_permitted(&self, user_id: serenity::model::id::UserId, roles: &[serenity::model::id::RoleId]) -> bool {
+        if self.denied_users.contains(&user_id.0) || roles.iter().any(|role| self.denied_roles.contains(&role.0)) {
+            return false;
+        }
+
+        (self.allowed_users.is_empty() && self.allowed_roles.is_empty())
+            || self.allowed_users.contains(&user_id.0)
+            || roles.iter().any(|role| self.allowed_roles.contains(&role.0))
+    }
+
+    fn is_admin(&self, user_id: serenity::model::id::UserId, roles: &[serenity::model::id::RoleId]) -> bool {
+        self.is_permitted(user_id, roles) && (self.admin_roles.is_empty() || roles.iter().any(|role| self.admin_roles.contains(&role.0)))
+    }
+}
+
 #[derive(clap::Parser)]
 struct Opts {
     #[clap(default_value = "config.toml")]
     config: std::path::PathBuf,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Writes the replies people rated well, with their prompts, to stdout as a fine-tuning dataset, instead of running the bot.
+    ExportDataset {
+        /// Only ratings from this date (UTC) on are used.
+        #[clap(long)]
+        since: Option<chrono::NaiveDate>,
+
+        #[clap(long, value_enum, default_value = "openai-jsonl")]
+        format: dataset::Format,
+    },
 }
 
 const fn max_input_tokens_default() -> u32 {
     2048
 }
 
+const fn reserved_output_tokens_default() -> u32 {
+    256
+}
+
+const fn nsfw_allowed_default() -> bool {
+    true
+}
+
+const fn sfw_allowed_default() -> bool {
+    true
+}
+
+const fn strip_reply_names_default() -> bool {
+    true
+}
+
+const fn ambient_reply_probability_default() -> f64 {
+    0.1
+}
+
+const fn ambient_cooldown_default() -> std::time::Duration {
+    std::time::Duration::from_secs(60)
+}
+
+const fn reaction_refresh_interval_default() -> std::time::Duration {
+    std::time::Duration::from_secs(10 * 60)
+}
+
+const fn autoplay_max_turns_default() -> u32 {
+    20
+}
+
+const fn autoplay_delay_default() -> std::time::Duration {
+    std::time::Duration::from_secs(5)
+}
+
+fn summary_prompt_default() -> String {
+    "Summarize the conversation so far in a short paragraph, covering what was discussed and anything that was settled.".to_string()
+}
+
+fn continue_prompt_default() -> String {
+    "Your last reply was cut off. Continue it from exactly where it left off, without repeating any of it.".to_string()
+}
+
+fn auto_title_prompt_default() -> String {
+    "Write a short title for the conversation so far, in a few words. Reply with only the title.".to_string()
+}
+
 const fn request_timeout_default() -> std::time::Duration {
     std::time::Duration::from_secs(30)
 }
@@ -1157,6 +8746,19 @@ const fn message_history_size_default() -> usize {
     2000
 }
 
+fn reactions_default() -> std::collections::HashMap<String, ReactionAction> {
+    std::collections::HashMap::from([
+        ("❌".to_string(), ReactionAction::Exclude),
+        ("📌".to_string(), ReactionAction::Pin),
+        ("🔄".to_string(), ReactionAction::Regenerate),
+        ("🗑️".to_string(), ReactionAction::Delete),
+    ])
+}
+
+fn state_dir_default() -> std::path::PathBuf {
+    "state".into()
+}
+
 #[derive(serde::Deserialize)]
 struct BackendConfig {
     r#type: String,
@@ -1164,12 +8766,45 @@ struct BackendConfig {
     #[serde(default = "max_input_tokens_default")]
     max_input_tokens: u32,
 
+    /// Input is trimmed so that at least this many tokens are left for the response.
+    #[serde(default = "reserved_output_tokens_default")]
+    reserved_output_tokens: u32,
+
     #[serde(default = "request_timeout_default")]
     request_timeout: std::time::Duration,
 
     #[serde(default = "chunk_timeout_default")]
     chunk_timeout: std::time::Duration,
 
+    #[serde(default)]
+    multi_format: template::MultiFormat,
+
+    #[serde(default = "nsfw_allowed_default")]
+    nsfw_allowed: bool,
+
+    #[serde(default = "sfw_allowed_default")]
+    sfw_allowed: bool,
+
+    /// Where the system message goes in the prompt: `first`, `last`, or `both`.
+    #[serde(default)]
+    system_placement: preset::SystemPlacement,
+
+    /// For models that write out their reasoning in tags at the start of their responses.
+    #[serde(default)]
+    think_tags: think::Config,
+
+    /// Responses are cut off at any of these, for backends whose APIs can't stop at them themselves.
+    #[serde(default)]
+    stop_sequences: Vec<String>,
+
+    /// If set, only people with one of these roles get replies from the backend. Everyone else gets replies from the default backend.
+    #[serde(default)]
+    allowed_roles: Vec<u64>,
+
+    /// What the backend costs, and how much it may spend a day or month before alerting the audit channel.
+    #[serde(default)]
+    budget: Option<budget::Config>,
+
     #[serde(flatten)]
     rest: toml::Value,
 }
@@ -1178,18 +8813,174 @@ struct BackendConfig {
 struct Config {
     backends: indexmap::IndexMap<String, BackendConfig>,
 
-    discord_token: String,
+    discord_token: secret::Secret,
 
     parent_channel_id: u64,
 
+    /// Where to tell moderators about things like backends reaching their budgets.
+    #[serde(default)]
+    audit_channel_id: Option<u64>,
+
     #[serde(default = "display_name_resolver_cache_size_default")]
     display_name_resolver_cache_size: usize,
 
+    /// The time zone to show times in, e.g. `+09:00`, for timestamps in multi-user threads and the time in system messages.
+    #[serde(default)]
+    utc_offset: template::UtcOffset,
+
     #[serde(default = "thread_cache_size_default")]
     thread_cache_size: usize,
 
+    /// How many shards to connect with. If not set, Discord's recommendation is used, which is one shard per 1000 or so guilds.
+    shards: Option<u64>,
+
     #[serde(default = "message_history_size_default")]
     message_history_size: usize,
+
+    presets_dir: Option<std::path::PathBuf>,
+
+    #[serde(default = "state_dir_default")]
+    state_dir: std::path::PathBuf,
+
+    #[serde(default)]
+    parameter_tags: std::collections::HashMap<String, toml::Value>,
+
+    #[serde(default = "reactions_default")]
+    reactions: std::collections::HashMap<String, ReactionAction>,
+
+    #[serde(default)]
+    delete_replies_with_prompt: bool,
+
+    /// Leaves the bot's replies to a message out of the prompt too when the message is left out.
+    #[serde(default)]
+    exclude_replies_with_prompt: bool,
+
+    /// Sends replies as embeds, styled after the thread's preset's persona, if it has one.
+    #[serde(default)]
+    embed_replies: bool,
+
+    /// Takes the bot's name off the start of replies that begin with it, e.g. `peebot:`, or with how the multi-user format attributes
+    /// messages.
+    #[serde(default = "strip_reply_names_default")]
+    strip_reply_names: bool,
+
+    /// Wraps code in responses that the backend didn't fence in a fenced code block, with a guess at the language.
+    #[serde(default)]
+    fence_code: bool,
+
+    /// Logs the full prompt sent for each reply. Off by default, since prompts are whatever people said in the thread.
+    #[serde(default)]
+    log_prompts: bool,
+
+    #[serde(default = "ambient_reply_probability_default")]
+    ambient_reply_probability: f64,
+
+    #[serde(default = "ambient_cooldown_default")]
+    ambient_cooldown: std::time::Duration,
+
+    /// How old the reactions on a thread's messages may get before they're fetched again ahead of a reply, in case any reaction events
+    /// were missed.
+    #[serde(default = "reaction_refresh_interval_default")]
+    reaction_refresh_interval: std::time::Duration,
+
+    #[serde(default)]
+    auto_title: bool,
+
+    #[serde(default = "auto_title_prompt_default")]
+    auto_title_prompt: String,
+
+    /// Asks for a reply that was cut off partway to be continued.
+    #[serde(default = "continue_prompt_default")]
+    continue_prompt: String,
+
+    summary_backend: Option<String>,
+
+    #[serde(default = "summary_prompt_default")]
+    summary_prompt: String,
+
+    archive_idle_after: Option<std::time::Duration>,
+
+    archive_idle_note: Option<String>,
+
+    /// Posts a thread's settings in a pinned message whenever they're changed with a command, and keeps it up to date, so everyone can
+    /// see what the bot is going by when it isn't the first post.
+    #[serde(default)]
+    settings_message: bool,
+
+    #[serde(default)]
+    preload_threads: usize,
+
+    #[serde(default)]
+    access: AccessConfig,
+
+    #[serde(default)]
+    defaults: DefaultsConfig,
+
+    translation: Option<TranslationConfig>,
+
+    emoji: Option<EmojiConfig>,
+
+    cast_list: Option<CastListConfig>,
+
+    #[serde(default)]
+    ensemble: EnsembleConfig,
+
+    #[serde(default)]
+    retention: retention::Config,
+
+    repetition_guard: Option<repetition::Config>,
+
+    /// Pieces of system message that presets can add to theirs by name, e.g. safety or formatting rules shared by several presets.
+    #[serde(default)]
+    system_segments: std::collections::HashMap<String, String>,
+
+    #[serde(default)]
+    content_filter: ContentFilterConfig,
+
+    /// Answers mentions that arrived while the bot was offline, on startup and after reconnecting, as long as they're no older than this.
+    catch_up_mentions: Option<std::time::Duration>,
+
+    /// After reconnecting, replies to mentions that went unanswered while the bot was offline with a note saying so.
+    #[serde(default)]
+    offline_notice: bool,
+
+    /// Posted as soon as the bot starts replying and edited into the reply once it arrives, with `{{model}}` standing in for the model,
+    /// e.g. `"✍️ thinking with {{model}}…"`.
+    placeholder: Option<String>,
+
+    /// The most turns /autoplay will play at once.
+    #[serde(default = "autoplay_max_turns_default")]
+    autoplay_max_turns: u32,
+
+    /// How long /autoplay waits between turns.
+    #[serde(default = "autoplay_delay_default")]
+    autoplay_delay: std::time::Duration,
+
+    /// Deletes messages the bot failed to reply to, so they can be sent again, instead of offering to retry them.
+    #[serde(default)]
+    delete_failed_messages: bool,
+
+    #[serde(default)]
+    dataset: dataset::Config,
+
+    /// Puts 👍/👎 buttons on replies, and records each rating with the reply's prompt and response in `ratings.jsonl` in the state directory.
+    #[serde(default)]
+    collect_ratings: bool,
+
+    /// Renders display math in replies to images, since Discord doesn't render LaTeX.
+    latex: Option<latex::Config>,
+
+    user_cooldown: Option<std::time::Duration>,
+
+    thread_cooldown: Option<std::time::Duration>,
+
+    #[serde(default)]
+    filters: filter::Config,
+
+    #[serde(default)]
+    nsfw_allowed: bool,
+
+    nsfw_backend: Option<String>,
 }
 
 #[tokio::main]
@@ -1202,19 +8993,158 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let config = toml::from_str::<Config>(std::str::from_utf8(&std::fs::read(opts.config)?)?)?;
 
+    if let Some(Command::ExportDataset { since, format }) = opts.command {
+        let since = since
+            .map(|since| chrono::TimeZone::from_utc_datetime(&chrono::Utc, &since.and_hms_opt(0, 0, 0).unwrap()))
+            .unwrap_or(chrono::DateTime::<chrono::Utc>::MIN_UTC);
+        let ratings = store::Store::new(config.state_dir.clone())?.load_ratings(since).await?;
+        let n = dataset::write(&config.dataset, format, &ratings, &mut std::io::stdout().lock())?;
+        log::info!("exported {} examples from {} ratings", n, ratings.len());
+        return Ok(());
+    }
+
     let mut backends: indexmap::IndexMap<String, BackendBinding> = indexmap::IndexMap::new();
     for (name, c) in config.backends.iter() {
+        c.multi_format.validate().map_err(|e| anyhow::format_err!("backend {}: {}", name, e))?;
         backends.insert(
             name.clone(),
             BackendBinding {
                 max_input_tokens: c.max_input_tokens,
+                reserved_output_tokens: c.reserved_output_tokens,
                 request_timeout: c.request_timeout,
                 chunk_timeout: c.chunk_timeout,
-                backend: backend::new_backend_from_config(c.r#type.clone(), c.rest.clone())?,
+                multi_format: c.multi_format.clone(),
+                nsfw_allowed: c.nsfw_allowed,
+                sfw_allowed: c.sfw_allowed,
+                system_placement: c.system_placement,
+                think_tags: c.think_tags.clone(),
+                stop_sequences: c.stop_sequences.clone(),
+                allowed_roles: c.allowed_roles.clone(),
+                budget: c.budget.clone(),
+                models: None,
+                backend: backend::new_backend_from_config(c.r#type.clone(), c.rest.clone())?.into(),
             },
         );
     }
 
+    for (name, binding) in backends.iter_mut() {
+        let models = match tokio::time::timeout(binding.request_timeout, binding.backend.list_models()).await {
+            Ok(Ok(models)) => models,
+            Ok(Err(e)) => {
+                log::warn!("backend {}: could not list models: {:?}", name, e);
+                continue;
+            }
+            Err(_) => {
+                log::warn!("backend {}: timed out listing models", name);
+                continue;
+            }
+        };
+        if let Some(models) = models.as_ref() {
+            if !models.iter().any(|model| model == binding.backend.model()) {
+                return Err(anyhow::format_err!("backend {}: model {} is not one of: {}", name, binding.backend.model(), models.join(", ")).into());
+            }
+        }
+        binding.models = models;
+    }
+
+    for (name, binding) in backends.iter() {
+        let budget = if let Some(budget) = binding.budget.as_ref() {
+            budget
+        } else {
+            continue;
+        };
+        budget.validate().map_err(|e| anyhow::format_err!("backend {}: budget: {}", name, e))?;
+        if let Some(fallback_backend) = budget.fallback_backend.as_ref() {
+            if !backends.contains_key(fallback_backend) || fallback_backend == name {
+                return Err(anyhow::format_err!("backend {}: unknown budget fallback backend: {}", name, fallback_backend).into());
+            }
+        }
+    }
+
+    if let Some(fallback_backend) = config.content_filter.fallback_backend.as_ref() {
+        if !backends.contains_key(fallback_backend) {
+            return Err(anyhow::format_err!("unknown content filter fallback backend: {}", fallback_backend).into());
+        }
+    }
+
+    config.retention.validate()?;
+    if let Some(repetition_guard) = config.repetition_guard.as_ref() {
+        repetition_guard.validate()?;
+    }
+
+    if let Some(router_backend) = config.ensemble.router_backend.as_ref() {
+        if !backends.contains_key(router_backend) {
+            return Err(anyhow::format_err!("unknown ensemble router backend: {}", router_backend).into());
+        }
+    }
+
+    if let Some(translation) = config.translation.as_ref() {
+        if !backends.contains_key(&translation.backend) {
+            return Err(anyhow::format_err!("unknown translation backend: {}", translation.backend).into());
+        }
+    }
+
+    if let Some(emoji) = config.emoji.as_ref() {
+        let binding = if let Some(binding) = backends.get(&emoji.backend) {
+            binding
+        } else {
+            return Err(anyhow::format_err!("unknown emoji backend: {}", emoji.backend).into());
+        };
+        if !binding.backend.supports_images() {
+            return Err(anyhow::format_err!("emoji backend {} can't see images", emoji.backend).into());
+        }
+    }
+
+    if let Some(avatar_backend) = config.cast_list.as_ref().and_then(|cast_list| cast_list.avatar_backend.as_ref()) {
+        let binding = if let Some(binding) = backends.get(avatar_backend) {
+            binding
+        } else {
+            return Err(anyhow::format_err!("unknown cast list avatar backend: {}", avatar_backend).into());
+        };
+        if !binding.backend.supports_images() {
+            return Err(anyhow::format_err!("cast list avatar backend {} can't see images", avatar_backend).into());
+        }
+    }
+
+    if let Some(summary_backend) = config.summary_backend.as_ref() {
+        if !backends.contains_key(summary_backend) {
+            return Err(anyhow::format_err!("unknown summary backend: {}", summary_backend).into());
+        }
+    }
+
+    if let Some(nsfw_backend) = config.nsfw_backend.as_ref() {
+        if !backends.contains_key(nsfw_backend) {
+            return Err(anyhow::format_err!("unknown nsfw backend: {}", nsfw_backend).into());
+        }
+    }
+
+    if let Some(default_backend) = config.defaults.backend.as_ref() {
+        if !backends.contains_key(default_backend) {
+            return Err(anyhow::format_err!("unknown default backend: {}", default_backend).into());
+        }
+    }
+
+    let presets = if let Some(presets_dir) = config.presets_dir.as_ref() {
+        preset::load_presets(presets_dir)?
+    } else {
+        std::collections::HashMap::new()
+    };
+    log::info!("loaded {} presets", presets.len());
+
+    for (name, preset) in presets.iter() {
+        for segment in preset.system_segments.iter() {
+            if !config.system_segments.contains_key(segment) {
+                return Err(anyhow::format_err!("preset {}: unknown system segment: {}", name, segment).into());
+            }
+        }
+    }
+
+    let store = store::Store::new(config.state_dir.clone())?;
+
+    let filters = filter::Filters::new(&config.filters)?;
+
+    let latex = config.latex.as_ref().map(latex::Renderer::new).transpose()?;
+
     let intents = serenity::model::gateway::GatewayIntents::default()
         | serenity::model::gateway::GatewayIntents::MESSAGE_CONTENT
         | serenity::model::gateway::GatewayIntents::GUILD_MESSAGES
@@ -1222,22 +9152,71 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         | serenity::model::gateway::GatewayIntents::GUILDS
         | serenity::model::gateway::GatewayIntents::GUILD_MEMBERS;
 
-    let resolver = tokio::sync::Mutex::new(Resolver::new(config.display_name_resolver_cache_size));
-    let thread_cache = tokio::sync::Mutex::new(ThreadCache::new(config.thread_cache_size));
+    let mut resolver = Resolver::new(config.display_name_resolver_cache_size);
+    for description in store.load_emoji_descriptions().await? {
+        resolver.hint_emoji_description(description.emoji_id, Some(description.description));
+    }
+    for description in store.load_avatar_descriptions().await? {
+        resolver.hint_avatar_description(description.url, Some(description.description));
+    }
+    let resolver = tokio::sync::Mutex::new(resolver);
+    let bios = store.load_bios().await?;
+
+    // What's been spent since the start of the month counts toward budgets, but alerts for it will have been sent already.
+    let month_start = chrono::Datelike::with_day(&chrono::Utc::now().with_timezone(&config.utc_offset.0).date_naive(), 1)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap();
+    let month_start = chrono::TimeZone::from_local_datetime(&config.utc_offset.0, &month_start).unwrap();
+    let mut budget = budget::Tracker::new(month_start);
+    for record in store.load_usage(month_start.with_timezone(&chrono::Utc)).await? {
+        if let Some(backend_budget) = backends.get(&record.backend).and_then(|binding| binding.budget.as_ref()) {
+            budget.record(
+                &record.backend,
+                backend_budget,
+                backend_budget.cost(record.prompt_tokens, record.completion_tokens),
+                record.at.with_timezone(&config.utc_offset.0),
+            );
+        }
+    }
+    let thread_cache = std::sync::Arc::new(tokio::sync::Mutex::new(ThreadCache::new(config.thread_cache_size)));
+    let archive_idle_after = config.archive_idle_after.map(chrono::Duration::from_std).transpose()?;
+    let archive_idle_note = config.archive_idle_note.clone();
+    let shards = config.shards;
 
-    serenity::client::ClientBuilder::new(&config.discord_token, intents)
+    let mut client = serenity::client::ClientBuilder::new(config.discord_token.expose(), intents)
         .event_handler(Handler {
             resolver,
-            me_id: parking_lot::Mutex::new(serenity::model::id::UserId::default()),
+            gateway: GatewayState::default(),
             parent_channel_id: serenity::model::id::ChannelId(config.parent_channel_id),
-            tags: tokio::sync::Mutex::new(std::collections::HashMap::new()),
             config,
             backends,
-            thread_cache,
+            presets,
+            store,
+            thread_cache: thread_cache.clone(),
+            filters,
+            latex,
+            last_user_requests: parking_lot::Mutex::new(std::collections::HashMap::new()),
+            autoplaying: parking_lot::Mutex::new(std::collections::HashSet::new()),
+            bios: tokio::sync::Mutex::new(bios),
+            budget: parking_lot::Mutex::new(budget),
         })
-        .await?
-        .start()
         .await?;
 
+    if let Some(archive_idle_after) = archive_idle_after {
+        tokio::spawn(archive_idle_threads(
+            client.cache_and_http.http.clone(),
+            thread_cache,
+            archive_idle_after,
+            archive_idle_note,
+        ));
+    }
+
+    if let Some(shards) = shards {
+        client.start_shards(shards).await?;
+    } else {
+        client.start_autosharded().await?;
+    }
+
     Ok(())
 }
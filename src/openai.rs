@@ -4,10 +4,14 @@ use futures_util::StreamExt;
 
 pub mod chat;
 pub mod completions;
+pub mod models;
 pub mod moderations;
 
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
 pub struct Client {
     client: reqwest::Client,
+    base_url: String,
 }
 
 #[derive(serde::Serialize)]
@@ -37,40 +41,64 @@ pub enum Error {
     #[error("stream: {0}")]
     Stream(String),
 
-    #[error("malformed stream item")]
-    MalformedStreamItem(Vec<u8>),
+    #[error("invalid {0} header")]
+    InvalidHeader(&'static str),
 }
 
 fn into_sse_stream(mut resp: reqwest::Response) -> impl futures_core::stream::Stream<Item = Result<Vec<u8>, Error>> {
-    let mut buf = bytes::BytesMut::new();
+    let mut parser = crate::sse::Parser::default();
 
     async_stream::try_stream! {
         while let Some(c) = resp.chunk().await.map_err(|e| e.without_url())? {
-            buf.extend_from_slice(&c);
-
-            while let Some(i) = buf.windows(2).position(|x| x == b"\n\n") {
-                let payload = buf.split_to(i + 2);
-                let payload = &payload[..payload.len() - 2];
-
-                if !payload.starts_with(b"data: ") {
-                    Err(Error::MalformedStreamItem(payload.to_vec()))?;
-                }
-
-                let payload = &payload[6..];
-                yield payload.to_vec();
+            for payload in parser.push(&c) {
+                yield payload;
             }
         }
+
+        if let Some(payload) = parser.flush() {
+            yield payload;
+        }
     }
 }
 
 impl Client {
-    pub fn new(api_key: impl AsRef<str>) -> Self {
+    pub fn new(
+        api_key: impl AsRef<str>,
+        organization: Option<&str>,
+        project: Option<&str>,
+        base_url: impl Into<String>,
+        builder: reqwest::ClientBuilder,
+    ) -> Result<Self, Error> {
         let mut headers = reqwest::header::HeaderMap::new();
         headers.insert(reqwest::header::CONTENT_TYPE, "application/json".parse().unwrap());
-        headers.insert(reqwest::header::AUTHORIZATION, format!("Bearer {}", api_key.as_ref()).parse().unwrap());
-        Self {
-            client: reqwest::ClientBuilder::new().default_headers(headers).build().unwrap(),
+        headers.insert(reqwest::header::AUTHORIZATION, crate::secret::bearer_header(api_key.as_ref()));
+        if let Some(organization) = organization {
+            headers.insert(
+                "OpenAI-Organization",
+                organization.parse().map_err(|_| Error::InvalidHeader("organization"))?,
+            );
+        }
+        if let Some(project) = project {
+            headers.insert("OpenAI-Project", project.parse().map_err(|_| Error::InvalidHeader("project"))?);
+        }
+        Ok(Self {
+            client: builder.default_headers(headers).build()?,
+            base_url: base_url.into(),
+        })
+    }
+
+    async fn do_get_request<Resp>(&self, url: &str) -> Result<Resp, Error>
+    where
+        Resp: serde::de::DeserializeOwned,
+    {
+        let resp = self.client.get(url).send().await.map_err(|e| e.without_url())?;
+
+        if let Err(e) = resp.error_for_status_ref() {
+            let body = resp.text().await.map_err(|e| e.without_url())?;
+            return Err(Error::ReqwestWithBody(e.without_url(), body));
         }
+
+        Ok(resp.json().await?)
     }
 
     async fn do_request<Req>(&self, url: &str, req: &Req) -> Result<reqwest::Response, Error>
@@ -130,17 +158,32 @@ impl Client {
         &self,
         req: &chat::completions::CreateRequest,
     ) -> Result<impl futures_core::stream::Stream<Item = Result<chat::completions::Chunk, Error>>, Error> {
-        Ok(self.do_streaming_request("https://api.openai.com/v1/chat/completions", req).await?)
+        Ok(self.do_streaming_request(&format!("{}/chat/completions", self.base_url), req).await?)
+    }
+
+    /// For models that can't stream.
+    pub async fn create_chat_completion_unstreamed(&self, req: &chat::completions::CreateRequest) -> Result<chat::completions::Response, Error> {
+        self.do_simple_request(&format!("{}/chat/completions", self.base_url), &WrappedRequest { stream: false, req })
+            .await
     }
 
     pub async fn create_completion(
         &self,
         req: &completions::CreateRequest,
     ) -> Result<impl futures_core::stream::Stream<Item = Result<completions::Chunk, Error>>, Error> {
-        Ok(self.do_streaming_request("https://api.openai.com/v1/completions", req).await?)
+        Ok(self.do_streaming_request(&format!("{}/completions", self.base_url), req).await?)
+    }
+
+    pub async fn list_models(&self) -> Result<models::ListResponse, Error> {
+        self.do_get_request(&format!("{}/models", self.base_url)).await
+    }
+
+    /// For Ollama servers, whose OpenAI-compatible API lives under `/v1` and may not list models itself.
+    pub async fn list_ollama_models(&self) -> Result<models::OllamaListResponse, Error> {
+        self.do_get_request(&format!("{}/api/tags", self.base_url.trim_end_matches("/v1"))).await
     }
 
     pub async fn create_moderation(&self, req: &moderations::CreateRequest) -> Result<moderations::CreateResponse, Error> {
-        Ok(self.do_simple_request("https://api.openai.com/v1/moderations", req).await?)
+        Ok(self.do_simple_request(&format!("{}/moderations", self.base_url), req).await?)
     }
 }
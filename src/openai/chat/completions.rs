@@ -2,6 +2,7 @@
 #[serde(rename_all = "snake_case")]
 pub enum Role {
     System,
+    Developer,
     Assistant,
     User,
 }
@@ -11,7 +12,27 @@ pub struct Message {
     pub role: Role,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
-    pub content: String,
+    pub content: Content,
+}
+
+/// Either plain text, or parts that may include images for models that can see them.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ImageUrl {
+    pub url: String,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq)]
@@ -28,6 +49,10 @@ pub struct Delta {
     pub role: Option<Role>,
     pub name: Option<String>,
     pub content: Option<String>,
+
+    /// Not part of the OpenAI API, but some OpenAI-compatible servers send reasoning this way.
+    #[serde(default)]
+    pub reasoning_content: Option<String>,
 }
 
 #[derive(serde::Deserialize, Clone, Debug)]
@@ -37,6 +62,13 @@ pub struct Choice {
     pub finish_reason: Option<FinishReason>,
 }
 
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
 #[derive(serde::Deserialize, Clone, Debug)]
 pub struct Chunk {
     pub id: String,
@@ -44,6 +76,43 @@ pub struct Chunk {
     pub created: i64,
     pub model: String,
     pub choices: Vec<Choice>,
+
+    /// Only set on the last chunk, which has no choices, if usage was requested in the stream options.
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct ResponseMessage {
+    pub role: Role,
+    pub content: Option<String>,
+
+    #[serde(default)]
+    pub reasoning_content: Option<String>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct ResponseChoice {
+    pub message: ResponseMessage,
+    pub index: i64,
+    pub finish_reason: Option<FinishReason>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct Response {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<ResponseChoice>,
+
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+#[derive(serde::Serialize, Clone, Debug)]
+pub struct StreamOptions {
+    pub include_usage: bool,
 }
 
 #[derive(serde::Serialize, Clone, Debug)]
@@ -67,6 +136,13 @@ pub struct CreateRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub max_tokens: Option<u32>,
 
+    /// Replaces max_tokens for reasoning models, since it includes reasoning tokens too.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_completion_tokens: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_effort: Option<String>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub presence_penalty: Option<f64>,
 
@@ -74,10 +150,21 @@ pub struct CreateRequest {
     pub frequency_penalty: Option<f64>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub logit_bias: Option<std::collections::HashMap<u32, u32>>,
+    pub logit_bias: Option<std::collections::HashMap<u32, f64>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
+
+    /// Only valid for streaming requests.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+
+    /// Server-specific fields, sent as-is.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl CreateRequest {
@@ -90,10 +177,15 @@ impl CreateRequest {
             n: None,
             stop: None,
             max_tokens: None,
+            max_completion_tokens: None,
+            reasoning_effort: None,
             presence_penalty: None,
             frequency_penalty: None,
             logit_bias: None,
+            seed: None,
             user: None,
+            stream_options: None,
+            extra: serde_json::Map::new(),
         }
     }
 }
@@ -0,0 +1,20 @@
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct Model {
+    pub id: String,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct ListResponse {
+    pub data: Vec<Model>,
+}
+
+/// Not part of the OpenAI API: Ollama lists its models at `/api/tags` instead, outside of its OpenAI-compatible API.
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct OllamaModel {
+    pub name: String,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct OllamaListResponse {
+    pub models: Vec<OllamaModel>,
+}
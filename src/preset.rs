@@ -0,0 +1,141 @@
+fn parameters_default() -> toml::Value {
+    toml::Table::new().into()
+}
+
+/// How replies look when they're sent as embeds.
+#[derive(serde::Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Persona {
+    /// Shown as the author of each reply.
+    #[serde(default)]
+    pub name: Option<String>,
+
+    /// The embed color, e.g. `0xe67e22`.
+    #[serde(default)]
+    pub color: Option<u32>,
+
+    /// Shown next to the name.
+    #[serde(default)]
+    pub avatar_url: Option<String>,
+}
+
+/// What to leave out of a conversation that's too long to fit in the prompt.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    /// The oldest messages.
+    #[default]
+    DropOldest,
+
+    /// The oldest messages, with a summary of them in their place.
+    Summarize,
+
+    /// The messages after the first exchange, so the conversation's opening is kept along with the latest messages.
+    DropMiddle,
+
+    /// The oldest messages, with a running summary of everything that's been left out, built up a little at a time.
+    SlidingWindowWithMemory,
+}
+
+impl Overflow {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "drop-oldest" => Some(Self::DropOldest),
+            "summarize" => Some(Self::Summarize),
+            "drop-middle" => Some(Self::DropMiddle),
+            "sliding-window-with-memory" => Some(Self::SlidingWindowWithMemory),
+            _ => None,
+        }
+    }
+
+    /// Whether messages that are left out are summarized.
+    pub fn summarizes(self) -> bool {
+        matches!(self, Self::Summarize | Self::SlidingWindowWithMemory)
+    }
+}
+
+/// Where the system message goes in the prompt. Some models pay it more attention at the end, or at both ends.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemPlacement {
+    #[default]
+    First,
+    Last,
+    Both,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    pub system_message: String,
+
+    #[serde(default = "parameters_default")]
+    pub parameters: toml::Value,
+
+    /// Overrides the backend's multi-user message format.
+    #[serde(default)]
+    pub multi_format: Option<crate::template::MultiFormat>,
+
+    /// Styles replies in threads using the preset, if replies are sent as embeds.
+    #[serde(default)]
+    pub persona: Option<Persona>,
+
+    /// Posted by the bot as its first message in new threads using the preset, and part of the conversation from then on, like a
+    /// character card's first message. `{{user}}` and `{{bot_name}}` are filled in as in the system message.
+    #[serde(default)]
+    pub greeting: Option<String>,
+
+    /// What to leave out when the conversation doesn't fit in the prompt.
+    #[serde(default)]
+    pub overflow: Option<Overflow>,
+
+    /// Overrides the backend's system message placement.
+    #[serde(default)]
+    pub system_placement: Option<SystemPlacement>,
+
+    /// Sent as a system message just before the conversation.
+    #[serde(default)]
+    pub prefix: Option<String>,
+
+    /// Sent as a system message just after the conversation, e.g. for instructions the model should have fresh in mind when it replies.
+    #[serde(default)]
+    pub suffix: Option<String>,
+
+    /// The start of every reply, which the model carries on from, for backends that support it.
+    #[serde(default)]
+    pub assistant_prefill: Option<String>,
+
+    /// Names of segments from `system_segments` in the config file to add to the system message, in this order.
+    #[serde(default)]
+    pub system_segments: Vec<String>,
+
+    /// Ends the system message with the current time, for personas that need to know it.
+    #[serde(default)]
+    pub current_time: bool,
+}
+
+pub fn load_presets(dir: &std::path::Path) -> Result<std::collections::HashMap<String, Preset>, anyhow::Error> {
+    let mut presets = std::collections::HashMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().map(|ext| ext != "toml").unwrap_or(true) {
+            continue;
+        }
+
+        let name = if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+            name.to_string()
+        } else {
+            continue;
+        };
+
+        let preset = toml::from_str::<Preset>(std::str::from_utf8(&std::fs::read(&path)?)?)
+            .map_err(|e| anyhow::format_err!("preset {}: {}", path.display(), e))?;
+        if let Some(multi_format) = preset.multi_format.as_ref() {
+            multi_format
+                .validate()
+                .map_err(|e| anyhow::format_err!("preset {}: {}", path.display(), e))?;
+        }
+        presets.insert(name, preset);
+    }
+    Ok(presets)
+}
@@ -0,0 +1,90 @@
+/// Responses with fewer word n-grams than this are too short to tell whether they're looping.
+const MIN_NGRAMS: usize = 20;
+
+const fn ngram_size_default() -> usize {
+    4
+}
+
+const fn max_repeated_ratio_default() -> f64 {
+    0.5
+}
+
+const fn retry_frequency_penalty_default() -> f64 {
+    1.0
+}
+
+/// Catches responses that go round in loops, as smaller models' often do, and asks for them again with a higher frequency penalty.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// How many words in a row make up each n-gram.
+    #[serde(default = "ngram_size_default")]
+    pub ngram_size: usize,
+
+    /// The share of a response's n-grams that can be repeats of earlier ones before it counts as looping.
+    #[serde(default = "max_repeated_ratio_default")]
+    pub max_repeated_ratio: f64,
+
+    /// The frequency penalty to ask for the response again with. Every backend supports at least 0.0 to 1.0.
+    #[serde(default = "retry_frequency_penalty_default")]
+    pub retry_frequency_penalty: f64,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.ngram_size == 0 {
+            return Err(anyhow::format_err!("repetition_guard.ngram_size must be at least 1"));
+        }
+        if !(self.max_repeated_ratio > 0.0 && self.max_repeated_ratio <= 1.0) {
+            return Err(anyhow::format_err!(
+                "repetition_guard.max_repeated_ratio must be more than 0 and at most 1"
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn is_repetitive(&self, text: &str) -> bool {
+        repeated_ratio(text, self.ngram_size)
+            .map(|ratio| ratio > self.max_repeated_ratio)
+            .unwrap_or(false)
+    }
+}
+
+/// The share of the text's word n-grams that repeat an earlier one, if there are enough of them to tell.
+fn repeated_ratio(text: &str, n: usize) -> Option<f64> {
+    let words = text.split_whitespace().map(|w| w.to_lowercase()).collect::<Vec<_>>();
+    let ngrams = words.windows(n).collect::<Vec<_>>();
+    if ngrams.len() < MIN_NGRAMS {
+        return None;
+    }
+    let distinct = ngrams.iter().collect::<std::collections::HashSet<_>>().len();
+    Some((ngrams.len() - distinct) as f64 / ngrams.len() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            ngram_size: ngram_size_default(),
+            max_repeated_ratio: max_repeated_ratio_default(),
+            retry_frequency_penalty: retry_frequency_penalty_default(),
+        }
+    }
+
+    #[test]
+    fn test_looping() {
+        assert!(config().is_repetitive(&"I am so happy to see you again, ".repeat(10)));
+    }
+
+    #[test]
+    fn test_not_looping() {
+        let config = config();
+        assert!(!config.is_repetitive(
+            "The quick brown fox jumps over the lazy dog, and then the dog wakes up and chases the fox all the way back to its den \
+             at the edge of the forest, where the fox's family is waiting with dinner."
+        ));
+        assert!(!config.is_repetitive("Ha ha ha ha."));
+    }
+}
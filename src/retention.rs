@@ -0,0 +1,123 @@
+const fn weight_default() -> f64 {
+    1.0
+}
+
+/// How much each kind of message counts for when a conversation is too long for the prompt. Messages are left out oldest first, except
+/// that a message with twice the weight is kept as if it were half as old. Pinned messages are always kept, whatever their weight.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default = "weight_default")]
+    pub bot_replies: f64,
+
+    #[serde(default = "weight_default")]
+    pub user_messages: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bot_replies: weight_default(),
+            user_messages: weight_default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if !(self.bot_replies > 0.0 && self.user_messages > 0.0) {
+            return Err(anyhow::format_err!("retention weights must be positive"));
+        }
+        Ok(())
+    }
+
+    fn weight(&self, from_bot: bool) -> f64 {
+        if from_bot {
+            self.bot_replies
+        } else {
+            self.user_messages
+        }
+    }
+}
+
+/// Picks which of a conversation's messages to keep in a prompt. Messages are pushed newest first, and each is ranked by how far back it
+/// is over its weight.
+pub struct Selector<'a> {
+    config: &'a Config,
+    candidates: Vec<(f64, usize)>,
+}
+
+impl<'a> Selector<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        Self { config, candidates: vec![] }
+    }
+
+    /// Adds the next older message, with how many tokens it takes up.
+    pub fn push(&mut self, from_bot: bool, tokens: usize) {
+        let rank = (self.candidates.len() + 1) as f64 / self.config.weight(from_bot);
+        self.candidates.push((rank, tokens));
+    }
+
+    /// Whether the messages pushed so far that outrank any older message would already fill the budget, so there's no need to push more.
+    pub fn is_full(&self, budget: usize) -> bool {
+        let lowest_older_rank = (self.candidates.len() + 1) as f64 / self.config.bot_replies.max(self.config.user_messages);
+        self.candidates
+            .iter()
+            .filter(|(rank, _)| *rank <= lowest_older_rank)
+            .map(|(_, tokens)| tokens)
+            .sum::<usize>()
+            > budget
+    }
+
+    /// Which of the messages to keep, in the order they were pushed: the best ranked ones, up until one doesn't fit in the budget.
+    pub fn select(&self, budget: usize) -> Vec<bool> {
+        let mut order = (0..self.candidates.len()).collect::<Vec<_>>();
+        order.sort_by(|a, b| self.candidates[*a].0.total_cmp(&self.candidates[*b].0));
+
+        let mut keep = vec![false; self.candidates.len()];
+        let mut used = 0;
+        for i in order {
+            let (_, tokens) = self.candidates[i];
+            if used + tokens > budget {
+                break;
+            }
+            used += tokens;
+            keep[i] = true;
+        }
+        keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_unweighted() {
+        let config = Config::default();
+        let mut selector = Selector::new(&config);
+        for tokens in [10, 10, 10, 10] {
+            selector.push(false, tokens);
+        }
+        assert!(selector.is_full(25));
+        assert!(!selector.is_full(40));
+        assert_eq!(selector.select(25), vec![true, true, false, false]);
+    }
+
+    #[test]
+    fn test_select_weighted() {
+        let config = Config {
+            bot_replies: 2.0,
+            user_messages: 1.0,
+        };
+        let mut selector = Selector::new(&config);
+        for from_bot in [false, true, false, true, false] {
+            selector.push(from_bot, 10);
+        }
+        // Ranks: 1, 1, 3, 2, 5.
+        assert_eq!(selector.select(30), vec![true, true, false, true, false]);
+        assert!(selector.is_full(30));
+        // With more room, a bot reply further back could still outrank the oldest user messages here.
+        assert!(!selector.is_full(40));
+    }
+}
@@ -0,0 +1,48 @@
+/// A string from the config that mustn't end up in logs, like an API key. Its Debug output is redacted.
+#[derive(serde::Deserialize, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(s: &str) -> Self {
+        Self(s.to_string())
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+/// Builds an Authorization header that's marked sensitive, so it's redacted from the Debug output of requests and clients.
+pub fn bearer_header(token: &str) -> reqwest::header::HeaderValue {
+    let mut value: reqwest::header::HeaderValue = format!("Bearer {}", token).parse().unwrap();
+    value.set_sensitive(true);
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_redacted() {
+        let secret = Secret::from("sk-1234");
+        assert_eq!(format!("{:?}", secret), "[redacted]");
+        assert_eq!(secret.expose(), "sk-1234");
+    }
+
+    #[test]
+    fn test_bearer_header_sensitive() {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(reqwest::header::AUTHORIZATION, bearer_header("sk-1234"));
+        assert!(!format!("{:?}", headers).contains("sk-1234"));
+    }
+}
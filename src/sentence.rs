@@ -0,0 +1,98 @@
+/// Characters that end a sentence.
+const TERMINALS: &[char] = &['.', '!', '?', '…', '。', '！', '？'];
+
+/// Characters that can follow a sentence's terminal and still be part of it, like the quotes around dialogue or the asterisks around an
+/// action.
+const CLOSERS: &[char] = &['"', '\'', '”', '’', ')', ']', '*', '_', '~'];
+
+/// Where the last complete sentence in s ends, if anywhere. Line breaks end sentences too. A terminal at the very end of s only counts if s
+/// is done, since more of the same sentence might follow it, as in "3." before "5".
+fn last_sentence_end(s: &str, done: bool) -> Option<usize> {
+    let mut end = None;
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == '\n' {
+            end = Some(i + 1);
+            continue;
+        }
+        if !TERMINALS.contains(&c) {
+            continue;
+        }
+        let mut j = i + c.len_utf8();
+        while let Some((k, c)) = chars.next_if(|(_, c)| TERMINALS.contains(c) || CLOSERS.contains(c)) {
+            j = k + c.len_utf8();
+        }
+        match chars.peek() {
+            Some((_, c)) if c.is_whitespace() => end = Some(j),
+            None if done => end = Some(j),
+            _ => {}
+        }
+    }
+    end
+}
+
+/// Holds back whatever follows the last complete sentence of a stream, so it can be dropped if the stream is cut off partway through a
+/// sentence.
+#[derive(Default)]
+pub struct Trimmer {
+    pending: String,
+    emitted: bool,
+}
+
+impl Trimmer {
+    /// Pushes a piece of the stream, returning every complete sentence that's ready.
+    pub fn push(&mut self, s: &str) -> String {
+        self.pending.push_str(s);
+        let end = if let Some(end) = last_sentence_end(&self.pending, false) {
+            end
+        } else {
+            return String::new();
+        };
+        let out = self.pending.drain(..end).collect::<String>();
+        self.emitted |= !out.trim().is_empty();
+        out
+    }
+
+    /// Returns the rest of the stream. If it was cut off, an incomplete sentence at the end is left out, unless it's all there is.
+    pub fn flush(self, cut_off: bool) -> String {
+        if !cut_off || !self.emitted {
+            return self.pending;
+        }
+        let end = last_sentence_end(&self.pending, true).unwrap_or(0);
+        self.pending[..end].to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trim_cut_off() {
+        let mut trimmer = Trimmer::default();
+        let mut out = String::new();
+        for piece in ["*She smiles.* \"Hello", " there!\" Version 3.", "5 is out, and it", " has"] {
+            out.push_str(&trimmer.push(piece));
+        }
+        assert_eq!(out, "*She smiles.* \"Hello there!\"");
+        out.push_str(&trimmer.flush(true));
+        assert_eq!(out, "*She smiles.* \"Hello there!\"");
+    }
+
+    #[test]
+    fn test_trim_done() {
+        let mut trimmer = Trimmer::default();
+        let mut out = trimmer.push("First line\nand a second without a period");
+        assert_eq!(out, "First line\n");
+        out.push_str(&trimmer.flush(false));
+        assert_eq!(out, "First line\nand a second without a period");
+    }
+
+    #[test]
+    fn test_trim_nothing_complete() {
+        let mut trimmer = Trimmer::default();
+        let mut out = trimmer.push("no sentence ever ends here");
+        out.push_str(&trimmer.flush(true));
+        assert_eq!(out, "no sentence ever ends here");
+    }
+}
@@ -0,0 +1,116 @@
+/// Parses server-sent events as described in https://html.spec.whatwg.org/multipage/server-sent-events.html, keeping only their data.
+///
+/// This works on bytes rather than text: line breaks can't appear inside a UTF-8 sequence, so a sequence split across chunks is put back
+/// together before the data is used.
+#[derive(Default)]
+pub struct Parser {
+    line: Vec<u8>,
+    data: Vec<u8>,
+    /// Whether the last chunk ended in a CR, in which case an LF at the start of the next one is part of the same line break.
+    after_cr: bool,
+}
+
+impl Parser {
+    /// Pushes a chunk of the stream, returning the data of any events it completes.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        let mut events = vec![];
+        for &b in chunk {
+            let after_cr = std::mem::replace(&mut self.after_cr, false);
+            match b {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    self.after_cr = b == b'\r';
+                    let line = std::mem::take(&mut self.line);
+                    if let Some(data) = self.process_line(&line) {
+                        events.push(data);
+                    }
+                }
+                _ => {
+                    self.line.push(b);
+                }
+            }
+        }
+        events
+    }
+
+    /// Flushes an event left unterminated at the end of the stream. The spec says to discard it, but some servers leave off the final
+    /// blank line.
+    pub fn flush(mut self) -> Option<Vec<u8>> {
+        // If the stream ended with a single newline, the pending line is already the blank one that dispatches the event.
+        let line = std::mem::take(&mut self.line);
+        self.process_line(&line).or_else(|| self.process_line(b""))
+    }
+
+    fn process_line(&mut self, line: &[u8]) -> Option<Vec<u8>> {
+        if line.is_empty() {
+            if self.data.is_empty() {
+                return None;
+            }
+            let mut data = std::mem::take(&mut self.data);
+            data.pop();
+            return Some(data);
+        }
+
+        // Lines starting with a colon are comments, often sent as keepalives.
+        if line[0] == b':' {
+            return None;
+        }
+
+        let (field, value) = match line.iter().position(|&b| b == b':') {
+            Some(i) => {
+                let value = &line[i + 1..];
+                (&line[..i], value.strip_prefix(b" ").unwrap_or(value))
+            }
+            None => (line, &b""[..]),
+        };
+
+        // Other fields (event, id and retry) aren't used by any API here.
+        if field == b"data" {
+            self.data.extend_from_slice(value);
+            self.data.push(b'\n');
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut parser = Parser::default();
+        let mut events = chunks.iter().flat_map(|chunk| parser.push(chunk)).collect::<Vec<_>>();
+        events.extend(parser.flush());
+        events
+    }
+
+    #[test]
+    fn test_parser() {
+        assert_eq!(parse(&[b"data: a\n\ndata: b\n\n"]), vec![b"a".to_vec(), b"b".to_vec()]);
+    }
+
+    #[test]
+    fn test_parser_crlf() {
+        assert_eq!(parse(&[b"data: a\r", b"\n\r\ndata: b\r\r"]), vec![b"a".to_vec(), b"b".to_vec()]);
+    }
+
+    #[test]
+    fn test_parser_fields() {
+        assert_eq!(
+            parse(&[b": keepalive\n\nevent: message\nid: 1\ndata:a\ndata: b\n\n"]),
+            vec![b"a\nb".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_parser_split_utf8() {
+        let s = "data: 👻\n\n".as_bytes();
+        assert_eq!(parse(&[&s[..8], &s[8..]]), vec!["👻".as_bytes().to_vec()]);
+    }
+
+    #[test]
+    fn test_parser_unterminated() {
+        assert_eq!(parse(&[b"data: a\n\ndata: [DONE]"]), vec![b"a".to_vec(), b"[DONE]".to_vec()]);
+        assert_eq!(parse(&[b"data: a\n\ndata: last\n"]), vec![b"a".to_vec(), b"last".to_vec()]);
+    }
+}
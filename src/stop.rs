@@ -0,0 +1,85 @@
+/// Cuts a stream off at the first of some stop sequences, for backends whose APIs can't stop at them themselves. Anything that might be
+/// the start of a stop sequence is held back until it's clear whether it is.
+pub struct Stopper<'a> {
+    stop_sequences: &'a [String],
+    pending: String,
+    stopped: bool,
+}
+
+impl<'a> Stopper<'a> {
+    pub fn new(stop_sequences: &'a [String]) -> Self {
+        Self {
+            stop_sequences,
+            pending: String::new(),
+            stopped: false,
+        }
+    }
+
+    /// Whether a stop sequence has been seen, after which the rest of the stream should be thrown away.
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Pushes a piece of the stream, returning whatever is ready, up to but not including a stop sequence.
+    pub fn push(&mut self, s: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.pending.push_str(s);
+
+        if let Some(i) = self
+            .stop_sequences
+            .iter()
+            .filter(|stop| !stop.is_empty())
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min()
+        {
+            self.stopped = true;
+            self.pending.truncate(i);
+            return std::mem::take(&mut self.pending);
+        }
+
+        // Hold back the longest end of what's pending that some stop sequence starts with.
+        let held = self
+            .pending
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|i| self.stop_sequences.iter().any(|stop| stop.starts_with(&self.pending[*i..])))
+            .unwrap_or(self.pending.len());
+        let rest = self.pending.split_off(held);
+        std::mem::replace(&mut self.pending, rest)
+    }
+
+    pub fn flush(self) -> String {
+        self.pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stop_streamed() {
+        let stop_sequences = vec!["\nUser:".to_string(), "###".to_string()];
+        let mut stopper = Stopper::new(&stop_sequences);
+        let mut out = String::new();
+        for piece in ["Sure, here", " you go.\nUs", "ually not.\n", "User: and", " more"] {
+            out.push_str(&stopper.push(piece));
+        }
+        assert!(stopper.stopped());
+        out.push_str(&stopper.flush());
+        assert_eq!(out, "Sure, here you go.\nUsually not.");
+    }
+
+    #[test]
+    fn test_no_stop() {
+        let stop_sequences = vec!["###".to_string()];
+        let mut stopper = Stopper::new(&stop_sequences);
+        let mut out = stopper.push("Heading ##");
+        assert_eq!(out, "Heading ");
+        assert!(!stopper.stopped());
+        out.push_str(&stopper.flush());
+        assert_eq!(out, "Heading ##");
+    }
+}
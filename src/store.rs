@@ -0,0 +1,310 @@
+/// The role a message sent with one of the inject commands plays in the prompt.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InjectedRole {
+    Assistant,
+    System,
+    User,
+}
+
+/// A summary of a thread, posted when it was archived.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Summary {
+    /// The last message the summary was posted as.
+    pub message_id: serenity::model::id::MessageId,
+
+    pub content: String,
+}
+
+/// A summary of messages that were left out of the prompt for lack of room, which stands in for them.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Memory {
+    /// The newest message the summary covers.
+    pub until: serenity::model::id::MessageId,
+
+    pub content: String,
+}
+
+/// One request to a backend, for analytics.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct UsageRecord {
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub thread_id: serenity::model::id::ChannelId,
+    pub user_id: serenity::model::id::UserId,
+    pub backend: String,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub failed: bool,
+}
+
+/// Someone's rating of a reply, with the prompt and response it was for.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Rating {
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub thread_id: serenity::model::id::ChannelId,
+
+    /// The message the rated reply was to.
+    pub message_id: serenity::model::id::MessageId,
+
+    pub user_id: serenity::model::id::UserId,
+    pub backend: String,
+    pub good: bool,
+    pub prompt: Vec<crate::transcript::Message>,
+    pub response: String,
+}
+
+/// What a custom emoji looks like, as described by the emoji backend the first time it came up.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct EmojiDescription {
+    pub emoji_id: serenity::model::id::EmojiId,
+    pub name: String,
+    pub description: String,
+}
+
+/// What someone's avatar looks like, as described by the cast list's avatar backend.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct AvatarDescription {
+    /// Avatars are described again whenever they change, which also changes their URLs.
+    pub url: String,
+
+    pub description: String,
+}
+
+/// What the bot saw of a thread at some point, to go back to with /rollback.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct Checkpoint {
+    /// The newest message when the checkpoint was made. Everything after it is left out after rolling back.
+    pub message_id: serenity::model::id::MessageId,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned: Vec<serenity::model::id::MessageId>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded: Vec<serenity::model::id::MessageId>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forget_before: Option<serenity::model::id::MessageId>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct InjectedMessage {
+    pub role: InjectedRole,
+
+    /// For user messages, who the message is from. If not set, it's from whoever injected it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// Per-thread state that the bot keeps on its own, rather than reading it out of Discord.
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct ThreadState {
+    /// Overrides the settings in the primary message, in the same format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings: Option<String>,
+
+    /// The message the bot posted the overriding settings in, if `settings_message` is set in the config file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settings_message: Option<serenity::model::id::MessageId>,
+
+    /// Who may manage the thread, if not whoever posted its first message, e.g. for forks, which the bot posts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<serenity::model::id::UserId>,
+
+    /// Overrides the backend selected by tags.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+
+    /// Overrides the backend's model, for backends that serve several.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+
+    /// Overrides the preset's assistant prefill.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub assistant_prefill: Option<String>,
+
+    /// How many variants of each reply to write, for whoever asked to pick between.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variants: Option<usize>,
+
+    /// Messages that are always included in the prompt, no matter how old they are.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned: Vec<serenity::model::id::MessageId>,
+
+    /// Messages that are left out of the prompt, in addition to ones with an exclude reaction.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded: Vec<serenity::model::id::MessageId>,
+
+    /// Messages sent with one of the inject commands, and the roles they play in the prompt.
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub injected: std::collections::BTreeMap<serenity::model::id::MessageId, InjectedMessage>,
+
+    /// Messages before this one are left out of the prompt, as if there were a forget break just before it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forget_before: Option<serenity::model::id::MessageId>,
+
+    /// Replies that were cut off partway, by the message they reply to, with what was generated before they were.
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub incomplete_replies: std::collections::BTreeMap<serenity::model::id::MessageId, String>,
+
+    /// In threads with several personas, which one (by preset name) sent each of the bot's replies.
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub speakers: std::collections::BTreeMap<serenity::model::id::MessageId, String>,
+
+    /// Checkpoints made with /checkpoint, by name.
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub checkpoints: std::collections::BTreeMap<String, Checkpoint>,
+
+    /// For threads that summarize what's left out of the prompt, the latest summary.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory: Option<Memory>,
+
+    /// Whether the thread has been warned that its first post was deleted.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub missing_first_post_noticed: bool,
+
+    /// Whether the thread has already been given a generated title.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub titled: bool,
+
+    /// The summary posted when the thread was last archived.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<Summary>,
+}
+
+pub struct Store {
+    dir: std::path::PathBuf,
+}
+
+impl Store {
+    pub fn new(dir: std::path::PathBuf) -> Result<Self, anyhow::Error> {
+        std::fs::create_dir_all(dir.join("threads"))?;
+        Ok(Self { dir })
+    }
+
+    fn bios_path(&self) -> std::path::PathBuf {
+        self.dir.join("bios.json")
+    }
+
+    fn thread_path(&self, thread_id: serenity::model::id::ChannelId) -> std::path::PathBuf {
+        self.dir.join("threads").join(format!("{}.json", thread_id.0))
+    }
+
+    pub async fn load_thread(&self, thread_id: serenity::model::id::ChannelId) -> Result<ThreadState, anyhow::Error> {
+        let buf = match tokio::fs::read(self.thread_path(thread_id)).await {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(ThreadState::default());
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+        };
+        Ok(serde_json::from_slice(&buf)?)
+    }
+
+    pub async fn save_thread(&self, thread_id: serenity::model::id::ChannelId, state: &ThreadState) -> Result<(), anyhow::Error> {
+        write_atomic(&self.thread_path(thread_id), &serde_json::to_vec_pretty(state)?).await
+    }
+
+    /// Everyone's /bio, by user.
+    pub async fn load_bios(&self) -> Result<std::collections::HashMap<serenity::model::id::UserId, String>, anyhow::Error> {
+        let buf = match tokio::fs::read(self.bios_path()).await {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(std::collections::HashMap::new());
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+        };
+        Ok(serde_json::from_slice(&buf)?)
+    }
+
+    pub async fn save_bios(&self, bios: &std::collections::HashMap<serenity::model::id::UserId, String>) -> Result<(), anyhow::Error> {
+        write_atomic(&self.bios_path(), &serde_json::to_vec_pretty(bios)?).await
+    }
+
+    /// Appends a record to a log, one JSON record per line.
+    async fn append(&self, name: &str, record: &impl serde::Serialize) -> Result<(), anyhow::Error> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut f = tokio::fs::OpenOptions::new().create(true).append(true).open(self.dir.join(name)).await?;
+        tokio::io::AsyncWriteExt::write_all(&mut f, &line).await?;
+        Ok(())
+    }
+
+    pub async fn record_usage(&self, record: &UsageRecord) -> Result<(), anyhow::Error> {
+        self.append("usage.jsonl", record).await
+    }
+
+    pub async fn record_rating(&self, rating: &Rating) -> Result<(), anyhow::Error> {
+        self.append("ratings.jsonl", rating).await
+    }
+
+    pub async fn record_emoji_description(&self, description: &EmojiDescription) -> Result<(), anyhow::Error> {
+        self.append("emoji.jsonl", description).await
+    }
+
+    pub async fn record_avatar_description(&self, description: &AvatarDescription) -> Result<(), anyhow::Error> {
+        self.append("avatars.jsonl", description).await
+    }
+
+    /// Reads a log written with append. Lines that can't be read are skipped.
+    async fn load_log<T: serde::de::DeserializeOwned>(&self, name: &str) -> Result<Vec<T>, anyhow::Error> {
+        let buf = match tokio::fs::read(self.dir.join(name)).await {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(vec![]);
+            }
+            Err(e) => {
+                return Err(e.into());
+            }
+        };
+        Ok(buf.split(|b| *b == b'\n').filter_map(|line| serde_json::from_slice(line).ok()).collect())
+    }
+
+    pub async fn load_usage(&self, since: chrono::DateTime<chrono::Utc>) -> Result<Vec<UsageRecord>, anyhow::Error> {
+        let mut records = self.load_log::<UsageRecord>("usage.jsonl").await?;
+        records.retain(|record| record.at >= since);
+        Ok(records)
+    }
+
+    pub async fn load_ratings(&self, since: chrono::DateTime<chrono::Utc>) -> Result<Vec<Rating>, anyhow::Error> {
+        let mut ratings = self.load_log::<Rating>("ratings.jsonl").await?;
+        ratings.retain(|rating| rating.at >= since);
+        Ok(ratings)
+    }
+
+    pub async fn load_emoji_descriptions(&self) -> Result<Vec<EmojiDescription>, anyhow::Error> {
+        self.load_log::<EmojiDescription>("emoji.jsonl").await
+    }
+
+    pub async fn load_avatar_descriptions(&self) -> Result<Vec<AvatarDescription>, anyhow::Error> {
+        self.load_log::<AvatarDescription>("avatars.jsonl").await
+    }
+
+    pub async fn delete_thread(&self, thread_id: serenity::model::id::ChannelId) -> Result<(), anyhow::Error> {
+        match tokio::fs::remove_file(self.thread_path(thread_id)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+async fn write_atomic(path: &std::path::Path, buf: &[u8]) -> Result<(), anyhow::Error> {
+    let tmp_path = path.with_extension("tmp");
+    tokio::fs::write(&tmp_path, buf).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
@@ -0,0 +1,342 @@
+static PLACEHOLDER_REGEX: once_cell::sync::Lazy<regex::Regex> =
+    once_cell::sync::Lazy::new(|| regex::Regex::new(r"\{\{\s*(?P<name>\w+)\s*\}\}").unwrap());
+
+pub fn has_placeholders(s: &str) -> bool {
+    PLACEHOLDER_REGEX.is_match(s)
+}
+
+/// Expands `{{name}}` placeholders. Unknown placeholders are left as-is.
+pub fn expand(s: &str, vars: &std::collections::HashMap<&str, String>) -> String {
+    PLACEHOLDER_REGEX
+        .replace_all(s, |c: &regex::Captures| vars.get(&c["name"]).cloned().unwrap_or_else(|| c[0].to_string()))
+        .into_owned()
+}
+
+fn multi_format_template_default() -> String {
+    "{{name}} at {{timestamp}} said:\n{{content}}".to_string()
+}
+
+/// The default template, for when timestamps are omitted.
+const MULTI_FORMAT_TEMPLATE_WITHOUT_TIMESTAMP: &str = "{{name}} said:\n{{content}}";
+
+/// A fixed offset from UTC, written like `+09:00` or `-05:30`, to show times in.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct UtcOffset(pub chrono::FixedOffset);
+
+impl Default for UtcOffset {
+    fn default() -> Self {
+        Self(chrono::FixedOffset::east_opt(0).unwrap())
+    }
+}
+
+impl TryFrom<String> for UtcOffset {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let invalid = || anyhow::format_err!("invalid UTC offset {:?}, expected e.g. +09:00", s);
+        let (sign, rest) = match s.chars().next() {
+            Some('+') => (1, &s[1..]),
+            Some('-') => (-1, &s[1..]),
+            _ => return Err(invalid()),
+        };
+        let (hours, minutes) = rest.split_once(':').ok_or_else(invalid)?;
+        let hours = hours.parse::<i32>().map_err(|_| invalid())?;
+        let minutes = minutes.parse::<i32>().map_err(|_| invalid())?;
+        if minutes >= 60 {
+            return Err(invalid());
+        }
+        Ok(Self(
+            chrono::FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(invalid)?,
+        ))
+    }
+}
+
+/// How `{{timestamp}}` is shown.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Timestamps {
+    /// The time the message was sent, formatted with `timestamp_format`.
+    #[default]
+    Absolute,
+
+    /// How long ago the message was sent, e.g. `5 minutes ago`.
+    Relative,
+
+    /// Not at all. The default template leaves out the timestamp, but custom templates should leave out `{{timestamp}}` themselves.
+    Omit,
+}
+
+/// How long ago something was, roughly, e.g. `just now` or `3 hours ago`.
+pub fn relative_time(elapsed: chrono::Duration) -> String {
+    let (n, unit) = if elapsed.num_minutes() < 1 {
+        return "just now".to_string();
+    } else if elapsed.num_hours() < 1 {
+        (elapsed.num_minutes(), "minute")
+    } else if elapsed.num_days() < 1 {
+        (elapsed.num_hours(), "hour")
+    } else {
+        (elapsed.num_days(), "day")
+    };
+    format!("{} {}{} ago", n, unit, if n == 1 { "" } else { "s" })
+}
+
+/// How user messages are attributed to their authors in multi-user threads.
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct MultiFormat {
+    /// Template for each message, with `{{name}}`, `{{timestamp}}` and `{{content}}` placeholders.
+    #[serde(default = "multi_format_template_default")]
+    pub template: String,
+
+    /// strftime-style format for `{{timestamp}}`. If not set, RFC 3339 is used.
+    #[serde(default)]
+    pub timestamp_format: Option<String>,
+
+    #[serde(default)]
+    pub timestamps: Timestamps,
+
+    /// The time zone timestamps are shown in. If not set, `utc_offset` from the config file is used.
+    #[serde(default)]
+    pub utc_offset: Option<UtcOffset>,
+
+    /// Sends the author's name in the API message's name field instead of using the template, if the backend supports it. Names the field
+    /// can't hold as they are, e.g. ones with spaces or non-Latin letters, still go through the template, so nobody loses their name or
+    /// ends up sharing one.
+    #[serde(default)]
+    pub use_name_field: Option<bool>,
+}
+
+impl Default for MultiFormat {
+    fn default() -> Self {
+        Self {
+            template: multi_format_template_default(),
+            timestamp_format: None,
+            timestamps: Timestamps::default(),
+            utc_offset: None,
+            use_name_field: None,
+        }
+    }
+}
+
+impl MultiFormat {
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if let Some(timestamp_format) = self.timestamp_format.as_ref() {
+            if chrono::format::StrftimeItems::new(timestamp_format).any(|item| item == chrono::format::Item::Error) {
+                return Err(anyhow::format_err!("invalid timestamp format: {}", timestamp_format));
+            }
+        }
+        Ok(())
+    }
+
+    /// The template, less the timestamp if timestamps are omitted and the template is the default.
+    fn template(&self) -> &str {
+        if self.timestamps == Timestamps::Omit && self.template == multi_format_template_default() {
+            MULTI_FORMAT_TEMPLATE_WITHOUT_TIMESTAMP
+        } else {
+            &self.template
+        }
+    }
+
+    /// Formats a message as of `now`, which relative timestamps are relative to.
+    pub fn format(&self, name: &str, timestamp: chrono::DateTime<chrono::Utc>, now: chrono::DateTime<chrono::Utc>, content: &str) -> String {
+        let timestamp = match self.timestamps {
+            Timestamps::Absolute => {
+                let timestamp = timestamp.with_timezone(&self.utc_offset.unwrap_or_default().0);
+                if let Some(timestamp_format) = self.timestamp_format.as_ref() {
+                    timestamp.format(timestamp_format).to_string()
+                } else {
+                    timestamp.to_rfc3339()
+                }
+            }
+            Timestamps::Relative => relative_time(now - timestamp),
+            Timestamps::Omit => "".to_string(),
+        };
+        let vars = std::collections::HashMap::from([("name", name.to_string()), ("timestamp", timestamp), ("content", content.to_string())]);
+        expand(self.template(), &vars)
+    }
+
+    /// A regex for how a message by someone with one of the names would start, either the way the template attributes it or as a plain
+    /// `name:`, for taking the attribution back off replies that copy it.
+    pub fn attribution_regex(&self, names: &[String]) -> regex::Regex {
+        let names = format!("(?:{})", names.iter().map(|name| regex::escape(name)).collect::<Vec<_>>().join("|"));
+
+        let template = self.template();
+        let mut template_prefix = String::new();
+        let mut last = 0;
+        for captures in PLACEHOLDER_REGEX.captures_iter(template) {
+            let m = captures.get(0).unwrap();
+            template_prefix.push_str(&regex::escape(&template[last..m.start()]));
+            last = m.end();
+            match &captures["name"] {
+                "content" => break,
+                "name" => template_prefix.push_str(&names),
+                _ => template_prefix.push_str(r"[^\n]*?"),
+            }
+        }
+
+        let mut alternatives = vec![format!(r"{}[ \t]*:", names)];
+        if !template_prefix.trim().is_empty() {
+            alternatives.push(template_prefix);
+        }
+        regex::Regex::new(&format!(r"^\s*(?:{})\s*", alternatives.join("|"))).unwrap()
+    }
+}
+
+/// Anything over this long at the start of a reply is taken to be more than an attribution.
+const MAX_ATTRIBUTION_LENGTH: usize = 200;
+
+/// Takes an attribution off the start of a stream, holding back the start until it's clear whether there is one.
+pub struct AttributionStripper {
+    regex: regex::Regex,
+    pending: String,
+    done: bool,
+}
+
+impl AttributionStripper {
+    pub fn new(regex: regex::Regex) -> Self {
+        Self {
+            regex,
+            pending: String::new(),
+            done: false,
+        }
+    }
+
+    /// Pushes a piece of the stream, returning whatever is ready. The start is held back until a line of it is followed by more, since an
+    /// attribution can take up a line of its own.
+    pub fn push(&mut self, s: &str) -> String {
+        if self.done {
+            return s.to_string();
+        }
+        self.pending.push_str(s);
+        let trimmed = self.pending.trim_start();
+        let first_line_ended = trimmed.find('\n').map(|i| !trimmed[i..].trim().is_empty()).unwrap_or(false);
+        if !first_line_ended && self.pending.len() < MAX_ATTRIBUTION_LENGTH {
+            return String::new();
+        }
+        self.done = true;
+        self.strip()
+    }
+
+    pub fn flush(mut self) -> String {
+        if self.done {
+            return String::new();
+        }
+        self.strip()
+    }
+
+    fn strip(&mut self) -> String {
+        let pending = std::mem::take(&mut self.pending);
+        let end = self.regex.find(&pending).map(|m| m.end()).unwrap_or(0);
+        pending[end..].to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let vars = std::collections::HashMap::from([("bot_name", "peebot".to_string()), ("user", "alice".to_string())]);
+        assert_eq!(
+            expand("You are {{bot_name}}, talking to {{ user }}.", &vars),
+            "You are peebot, talking to alice."
+        );
+    }
+
+    #[test]
+    fn test_expand_unknown() {
+        let vars = std::collections::HashMap::new();
+        assert_eq!(expand("Today is {{date}}.", &vars), "Today is {{date}}.");
+    }
+
+    #[test]
+    fn test_multi_format_default() {
+        let timestamp = chrono::DateTime::parse_from_rfc3339("2023-03-01T12:34:56+00:00")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        assert_eq!(
+            MultiFormat::default().format("alice", timestamp, timestamp, "hi {{name}}"),
+            "alice at 2023-03-01T12:34:56+00:00 said:\nhi {{name}}"
+        );
+    }
+
+    #[test]
+    fn test_multi_format_timestamps() {
+        let timestamp = chrono::DateTime::parse_from_rfc3339("2023-03-01T12:34:56+00:00")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let now = timestamp + chrono::Duration::minutes(5);
+
+        let multi_format = MultiFormat {
+            utc_offset: Some(UtcOffset::try_from("+09:00".to_string()).unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            multi_format.format("alice", timestamp, now, "hi"),
+            "alice at 2023-03-01T21:34:56+09:00 said:\nhi"
+        );
+
+        let multi_format = MultiFormat {
+            timestamps: Timestamps::Relative,
+            ..Default::default()
+        };
+        assert_eq!(multi_format.format("alice", timestamp, now, "hi"), "alice at 5 minutes ago said:\nhi");
+
+        let multi_format = MultiFormat {
+            timestamps: Timestamps::Omit,
+            ..Default::default()
+        };
+        assert_eq!(multi_format.format("alice", timestamp, now, "hi"), "alice said:\nhi");
+    }
+
+    #[test]
+    fn test_relative_time() {
+        assert_eq!(relative_time(chrono::Duration::seconds(30)), "just now");
+        assert_eq!(relative_time(chrono::Duration::minutes(1)), "1 minute ago");
+        assert_eq!(relative_time(chrono::Duration::minutes(150)), "2 hours ago");
+        assert_eq!(relative_time(chrono::Duration::days(3)), "3 days ago");
+        assert!(UtcOffset::try_from("-05:30".to_string()).is_ok());
+        assert!(UtcOffset::try_from("0900".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_multi_format_timestamp_format() {
+        let timestamp = chrono::DateTime::parse_from_rfc3339("2023-03-01T12:34:56+00:00")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let multi_format = MultiFormat {
+            template: "[{{timestamp}}] {{name}}: {{content}}".to_string(),
+            timestamp_format: Some("%H:%M".to_string()),
+            use_name_field: Some(false),
+            ..Default::default()
+        };
+        assert!(multi_format.validate().is_ok());
+        assert_eq!(multi_format.format("alice", timestamp, timestamp, "hi"), "[12:34] alice: hi");
+    }
+
+    #[test]
+    fn test_strip_attribution() {
+        let regex = MultiFormat::default().attribution_regex(&["peebot".to_string(), "Pee Bot".to_string()]);
+        for (reply, stripped) in [
+            ("peebot at 2023-03-01T12:34:56+00:00 said:\nHello!", "Hello!"),
+            ("Pee Bot: Hello!\nHow are you?", "Hello!\nHow are you?"),
+            ("Hello, peebot: nice to meet you.", "Hello, peebot: nice to meet you."),
+        ] {
+            let mut stripper = AttributionStripper::new(regex.clone());
+            let mut out = String::new();
+            for c in reply.chars() {
+                out.push_str(&stripper.push(&c.to_string()));
+            }
+            out.push_str(&stripper.flush());
+            assert_eq!(out, stripped);
+        }
+    }
+
+    #[test]
+    fn test_has_placeholders() {
+        assert!(has_placeholders("{{guild}}"));
+        assert!(!has_placeholders("{guild}"));
+    }
+}
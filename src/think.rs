@@ -0,0 +1,182 @@
+/// What to do with reasoning that a model writes out at the start of its response, e.g. DeepSeek-R1's `<think>...</think>`.
+#[derive(serde::Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// Posts it as part of the response.
+    #[default]
+    Keep,
+
+    /// Leaves it out.
+    Strip,
+
+    /// Posts it in a spoiler before the response, the same way as reasoning the server sends separately.
+    Spoiler,
+}
+
+fn tag_default() -> String {
+    "think".to_string()
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub mode: Mode,
+
+    /// The name of the tag the reasoning is in.
+    #[serde(default = "tag_default")]
+    pub tag: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mode: Mode::default(),
+            tag: tag_default(),
+        }
+    }
+}
+
+impl Config {
+    /// A filter for a response as it streams in, unless reasoning is kept as is.
+    pub fn filter(&self) -> Option<Filter<'_>> {
+        match self.mode {
+            Mode::Keep => None,
+            Mode::Strip => Some(Filter::new(&self.tag, false)),
+            Mode::Spoiler => Some(Filter::new(&self.tag, true)),
+        }
+    }
+
+    /// Leaves the reasoning out of a whole response, whatever the mode, for responses that aren't posted as they are.
+    pub fn strip(&self, response: &str) -> String {
+        if self.mode == Mode::Keep {
+            return response.to_string();
+        }
+        let mut filter = Filter::new(&self.tag, false);
+        let mut out = filter.push(response);
+        out.push_str(&filter.flush());
+        out
+    }
+}
+
+enum State {
+    /// Not enough of the response has come in yet to tell whether it starts with reasoning.
+    Start,
+    Thinking,
+    Done,
+}
+
+/// Takes reasoning out of the start of a stream, either dropping it or turning it into a spoiler once it's all in.
+pub struct Filter<'a> {
+    tag: &'a str,
+    spoiler: bool,
+    state: State,
+    pending: String,
+}
+
+impl<'a> Filter<'a> {
+    fn new(tag: &'a str, spoiler: bool) -> Self {
+        Self {
+            tag,
+            spoiler,
+            state: State::Start,
+            pending: String::new(),
+        }
+    }
+
+    /// Pushes a piece of the stream, returning whatever is ready. Nothing is returned while the reasoning is still coming in.
+    pub fn push(&mut self, s: &str) -> String {
+        if let State::Done = self.state {
+            return s.to_string();
+        }
+        self.pending.push_str(s);
+
+        if let State::Start = self.state {
+            let open = format!("<{}>", self.tag);
+            let trimmed = self.pending.trim_start();
+            if let Some(rest) = trimmed.strip_prefix(&open) {
+                self.pending = rest.to_string();
+                self.state = State::Thinking;
+            } else if open.starts_with(trimmed) {
+                return String::new();
+            } else {
+                self.state = State::Done;
+                return std::mem::take(&mut self.pending);
+            }
+        }
+
+        let close = format!("</{}>", self.tag);
+        let i = if let Some(i) = self.pending.find(&close) {
+            i
+        } else {
+            return String::new();
+        };
+        let mut out = self.reasoning(&self.pending[..i]);
+        out.push_str(self.pending[i + close.len()..].trim_start());
+        self.pending.clear();
+        self.state = State::Done;
+        out
+    }
+
+    /// Returns the rest of the stream. Reasoning that was cut off before its closing tag is handled as if it had been closed.
+    pub fn flush(self) -> String {
+        match self.state {
+            State::Start => self.pending,
+            State::Thinking => self.reasoning(&self.pending),
+            State::Done => String::new(),
+        }
+    }
+
+    fn reasoning(&self, reasoning: &str) -> String {
+        if self.spoiler && !reasoning.trim().is_empty() {
+            crate::backend::format_reasoning(reasoning)
+        } else {
+            String::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_streamed() {
+        let config = Config {
+            mode: Mode::Strip,
+            tag: tag_default(),
+        };
+        let mut filter = config.filter().unwrap();
+        let mut out = String::new();
+        for piece in ["\n<th", "ink>Hmm, they want", " a greeting.</thi", "nk>\n\nHello!", " How are you?"] {
+            out.push_str(&filter.push(piece));
+        }
+        out.push_str(&filter.flush());
+        assert_eq!(out, "Hello! How are you?");
+    }
+
+    #[test]
+    fn test_spoiler() {
+        let config = Config {
+            mode: Mode::Spoiler,
+            tag: tag_default(),
+        };
+        let mut filter = config.filter().unwrap();
+        let mut out = filter.push("<think>Say hi.</think>Hi!");
+        out.push_str(&filter.flush());
+        assert_eq!(out, crate::backend::format_reasoning("Say hi.") + "Hi!");
+    }
+
+    #[test]
+    fn test_no_reasoning() {
+        let config = Config {
+            mode: Mode::Strip,
+            tag: tag_default(),
+        };
+        assert_eq!(
+            config.strip("<b>Bold</b> and <think>not at the start</think>"),
+            "<b>Bold</b> and <think>not at the start</think>"
+        );
+        assert_eq!(config.strip("<th"), "<th");
+    }
+}
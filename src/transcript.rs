@@ -0,0 +1,188 @@
+/// Who a message in a transcript is from.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    System,
+    Assistant,
+    User,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    pub role: Role,
+
+    /// For user messages, who the message is from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    pub content: String,
+}
+
+/// A thread's conversation as the backend sees it, for sharing or moving it elsewhere.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Transcript {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+
+    pub messages: Vec<Message>,
+}
+
+impl Transcript {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut buf = String::new();
+        if let Some(title) = self.title.as_ref() {
+            buf.push_str(&format!("# {}\n\n", title));
+        }
+        for message in self.messages.iter() {
+            let heading = match (message.role, message.name.as_ref()) {
+                (Role::System, _) => "System".to_string(),
+                (Role::Assistant, _) => "Assistant".to_string(),
+                (Role::User, Some(name)) => format!("User: {}", name),
+                (Role::User, None) => "User".to_string(),
+            };
+            buf.push_str(&format!("### {}\n\n{}\n\n", heading, message.content.trim_end()));
+        }
+        buf
+    }
+
+    /// Parses a transcript in the format to_markdown writes. Message content can't contain lines that look like headings.
+    pub fn from_markdown(s: &str) -> Result<Self, anyhow::Error> {
+        let mut transcript = Self {
+            title: None,
+            messages: vec![],
+        };
+        let mut current: Option<(Role, Option<String>, Vec<&str>)> = None;
+        let flush = |current: Option<(Role, Option<String>, Vec<&str>)>, messages: &mut Vec<Message>| {
+            if let Some((role, name, lines)) = current {
+                messages.push(Message {
+                    role,
+                    name,
+                    content: lines.join("\n").trim().to_string(),
+                });
+            }
+        };
+
+        for line in s.lines() {
+            if let Some(heading) = line.strip_prefix("### ") {
+                let (role, name) = match heading.trim() {
+                    "System" => (Role::System, None),
+                    "Assistant" => (Role::Assistant, None),
+                    "User" => (Role::User, None),
+                    heading => {
+                        if let Some(name) = heading.strip_prefix("User: ") {
+                            (Role::User, Some(name.to_string()))
+                        } else {
+                            return Err(anyhow::format_err!("unknown heading: {}", heading));
+                        }
+                    }
+                };
+                flush(current.take(), &mut transcript.messages);
+                current = Some((role, name, vec![]));
+                continue;
+            }
+
+            match current.as_mut() {
+                Some((_, _, lines)) => lines.push(line),
+                None => {
+                    if let Some(title) = line.strip_prefix("# ") {
+                        transcript.title = Some(title.trim().to_string());
+                    } else if !line.trim().is_empty() {
+                        return Err(anyhow::format_err!("expected a heading, got: {}", line));
+                    }
+                }
+            }
+        }
+        flush(current, &mut transcript.messages);
+
+        Ok(transcript)
+    }
+
+    /// Parses a transcript written by either to_json or to_markdown.
+    pub fn parse(s: &str) -> Result<Self, anyhow::Error> {
+        if s.trim_start().starts_with('{') {
+            Ok(serde_json::from_str(s)?)
+        } else {
+            Self::from_markdown(s)
+        }
+    }
+}
+
+impl From<&crate::backend::Message> for Message {
+    fn from(message: &crate::backend::Message) -> Self {
+        match &message.role {
+            crate::backend::Role::System => Self {
+                role: Role::System,
+                name: None,
+                content: message.content.clone(),
+            },
+            crate::backend::Role::Assistant => Self {
+                role: Role::Assistant,
+                name: None,
+                content: message.content.clone(),
+            },
+            crate::backend::Role::User(name) => Self {
+                role: Role::User,
+                name: Some(name.clone()),
+                content: message.content.clone(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcript() -> Transcript {
+        Transcript {
+            title: Some("Pirates".to_string()),
+            messages: vec![
+                Message {
+                    role: Role::System,
+                    name: None,
+                    content: "You are a pirate.".to_string(),
+                },
+                Message {
+                    role: Role::User,
+                    name: Some("alice".to_string()),
+                    content: "Ahoy!".to_string(),
+                },
+                Message {
+                    role: Role::Assistant,
+                    name: None,
+                    content: "Arr.\n\nWhat be ye wantin'?".to_string(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_to_markdown() {
+        assert_eq!(
+            transcript().to_markdown(),
+            "# Pirates\n\n### System\n\nYou are a pirate.\n\n### User: alice\n\nAhoy!\n\n### Assistant\n\nArr.\n\nWhat be ye wantin'?\n\n"
+        );
+    }
+
+    #[test]
+    fn test_markdown_roundtrip() {
+        let transcript = transcript();
+        assert_eq!(Transcript::from_markdown(&transcript.to_markdown()).unwrap(), transcript);
+    }
+
+    #[test]
+    fn test_from_markdown_invalid() {
+        assert!(Transcript::from_markdown("hello\n\n### User\n\nhi").is_err());
+        assert!(Transcript::from_markdown("### Narrator\n\nhi").is_err());
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let transcript = transcript();
+        assert_eq!(serde_json::from_str::<Transcript>(&transcript.to_json()).unwrap(), transcript);
+    }
+}
@@ -0,0 +1,29 @@
+/// Discord shows the typing indicator for about 10 seconds after it's triggered, so it's triggered again a little sooner than that.
+const INTERVAL: std::time::Duration = std::time::Duration::from_secs(8);
+
+/// Keeps the typing indicator showing in a channel until it's dropped.
+pub struct Keepalive {
+    handle: tokio::task::JoinHandle<()>,
+}
+
+impl Keepalive {
+    pub fn start(http: std::sync::Arc<serenity::http::Http>, channel_id: serenity::model::id::ChannelId) -> Self {
+        Self {
+            handle: tokio::spawn(async move {
+                let mut interval = tokio::time::interval(INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Err(e) = channel_id.broadcast_typing(&http).await {
+                        log::warn!("failed to trigger typing in {}: {:?}", channel_id, e);
+                    }
+                }
+            }),
+        }
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}